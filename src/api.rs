@@ -0,0 +1,82 @@
+//! Read-only HTTP API over the in-memory records, served on `api_addr`
+//! while the monitor runs.
+
+use anyhow::{anyhow, Result};
+use axum::extract::{Path as UrlPath, State};
+use axum::http::StatusCode;
+use axum::routing::get;
+use axum::{Json, Router};
+use log::{error, info};
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tokio_util::sync::CancellationToken;
+
+use crate::metrics::MetricsSnapshot;
+use crate::storage::FileRecord;
+
+#[derive(Clone)]
+struct ApiState {
+    records: Arc<RwLock<HashMap<PathBuf, FileRecord>>>,
+    target_folder: PathBuf,
+}
+
+/// The routes of the API:
+/// - `GET /files`: every record, keyed by its path relative to the target folder
+/// - `GET /files/{relative_path}`: one record, or 404
+/// - `GET /summary`: record counts per status, as in a metrics snapshot
+pub fn router(records: Arc<RwLock<HashMap<PathBuf, FileRecord>>>, target_folder: PathBuf) -> Router {
+    Router::new()
+        .route("/files", get(list_files))
+        .route("/files/*relative_path", get(get_file))
+        .route("/summary", get(summary))
+        .with_state(ApiState { records, target_folder })
+}
+
+/// Serves `router` at `addr` until `shutdown` is cancelled. Returns the
+/// bound address, useful when `addr` asks for any free port.
+pub fn serve_api(
+    addr: SocketAddr,
+    records: Arc<RwLock<HashMap<PathBuf, FileRecord>>>,
+    target_folder: PathBuf,
+    shutdown: CancellationToken,
+) -> Result<(SocketAddr, tokio::task::JoinHandle<()>)> {
+    let server = axum::Server::try_bind(&addr)
+        .map_err(|e| anyhow!("Failed to bind API server to {}: {}", addr, e))?
+        .serve(router(records, target_folder).into_make_service());
+    let bound = server.local_addr();
+    let handle = tokio::spawn(async move {
+        if let Err(e) = server.with_graceful_shutdown(shutdown.cancelled_owned()).await {
+            error!("API server failed: {}", e);
+        }
+    });
+    info!("Serving the status API on http://{}", bound);
+    Ok((bound, handle))
+}
+
+async fn list_files(State(state): State<ApiState>) -> Json<BTreeMap<String, FileRecord>> {
+    let records = state.records.read().await;
+    Json(records.iter()
+        .map(|(path, record)| {
+            let relative = path.strip_prefix(&state.target_folder).unwrap_or(path);
+            (relative.display().to_string(), record.clone())
+        })
+        .collect())
+}
+
+async fn get_file(
+    State(state): State<ApiState>,
+    UrlPath(relative_path): UrlPath<String>,
+) -> Result<Json<FileRecord>, StatusCode> {
+    let path = state.target_folder.join(relative_path.trim_start_matches('/'));
+    state.records.read().await.get(&path)
+        .cloned()
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn summary(State(state): State<ApiState>) -> Json<MetricsSnapshot> {
+    Json(MetricsSnapshot::from_records(&*state.records.read().await))
+}
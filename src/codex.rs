@@ -1,12 +1,32 @@
 use anyhow::{anyhow, Result};
-use reqwest::Client as HttpClient;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Client as HttpClient, RequestBuilder, Response};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
-use log::{info, error, debug};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use log::{info, debug, error, warn};
 use tokio::fs;
+use tokio_util::io::ReaderStream;
 
+use crate::config::{CidValidation, Config, HttpVersion};
+use crate::eligibility;
+use crate::metrics::PrometheusMetrics;
+use crate::telemetry;
+
+/// Longest slice of an unexpected response body quoted in an error.
+const ERROR_BODY_SNIPPET_CHARS: usize = 200;
+
+/// Response size limit for clients not built from a config.
+const DEFAULT_MAX_RESPONSE_BYTES: u64 = 1024 * 1024;
+
+/// Timeouts for clients not built from a config, matching the config defaults.
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_UPLOAD_TIMEOUT: Duration = Duration::from_secs(3600);
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StorageRequest {
@@ -27,6 +47,9 @@ pub struct PurchaseResponse {
     #[serde(rename = "purchaseId")]
     pub purchase_id: String,
     pub request: StorageRequestInfo,
+    /// The response body exactly as the node sent it, for `debug_capture`.
+    #[serde(skip)]
+    pub raw_body: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -43,6 +66,42 @@ pub struct ContentInfo {
 pub struct PurchaseStatus {
     pub state: String,
     pub request: StorageRequestInfo,
+    /// Only reported by nodes that expose it.
+    #[serde(default, rename = "filledSlots", skip_serializing_if = "Option::is_none")]
+    pub filled_slots: Option<u32>,
+    /// The response body exactly as the node sent it, for `debug_capture`.
+    #[serde(skip)]
+    pub raw_body: String,
+}
+
+impl PurchaseStatus {
+    /// Whether the purchase has started with at least `min_filled_slots`
+    /// slots filled. Without a reported count, `started` is taken at its word.
+    pub fn has_started(&self, min_filled_slots: Option<u32>) -> bool {
+        self.state == "started" && self.has_enough_slots(min_filled_slots)
+    }
+    
+    pub fn has_enough_slots(&self, min_filled_slots: Option<u32>) -> bool {
+        match (min_filled_slots, self.filled_slots) {
+            (Some(min), Some(filled)) => filled >= min,
+            _ => true,
+        }
+    }
+}
+
+/// What `upload_file` stored, and on which node.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UploadResult {
+    pub cid: String,
+    pub endpoint: String,
+}
+
+/// How `wait_for_purchase_start` ended when the purchase didn't fail.
+#[derive(Debug, Clone)]
+pub enum PurchaseWait {
+    Started(PurchaseStatus),
+    /// Still in a non-terminal state, last seen as `state`, when the wait ran out.
+    TimedOut { state: String },
 }
 
 #[derive(Debug, Clone)]
@@ -50,26 +109,408 @@ pub struct Client {
     endpoints: Vec<String>,
     http_client: HttpClient,
     current_endpoint: Arc<AtomicUsize>,
+    cid_validation: CidValidation,
+    max_response_bytes: u64,
+    min_file_size: u64,
+    max_file_size: u64,
+    /// Sent as `Authorization: Bearer <token>` with every request.
+    auth_token: Option<String>,
+    /// Endpoints taken out of rotation until a probe finds them reachable.
+    unhealthy: Arc<RwLock<HashSet<String>>>,
+    /// Failed requests in a row per endpoint; reaching
+    /// `failure_threshold` marks the endpoint unhealthy.
+    consecutive_failures: Arc<Mutex<HashMap<String, u32>>>,
+    failure_threshold: u32,
+    /// Pause between status checks in `wait_for_purchase_start`.
+    purchase_start_poll: Duration,
+    /// Replaces the client's request timeout for uploads and downloads of
+    /// file contents.
+    upload_timeout: Duration,
+    metrics: PrometheusMetrics,
+}
+
+/// Every configured endpoint is currently marked unhealthy, or none are
+/// configured. Callers can downcast to this to pause instead of failing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NoHealthyEndpoint;
+
+impl std::fmt::Display for NoHealthyEndpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "No healthy Codex endpoint is available")
+    }
+}
+
+impl std::error::Error for NoHealthyEndpoint {}
+
+/// The node answered a storage request with 402: it lacks the tokens to pay
+/// for it. Retrying won't help until the node is funded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InsufficientTokens;
+
+impl std::fmt::Display for InsufficientTokens {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Insufficient tokens to create storage request")
+    }
+}
+
+impl std::error::Error for InsufficientTokens {}
+
+/// A request to a node ran past its timeout, found in the chain of errors
+/// from requests that did. Retrying may help once the node responds again.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RequestTimedOut;
+
+impl std::fmt::Display for RequestTimedOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Request timed out")
+    }
+}
+
+impl std::error::Error for RequestTimedOut {}
+
+/// `message` and the reason the request failed, with the `reqwest::Error`
+/// underneath, or `RequestTimedOut` when it ran out of time.
+fn request_error(message: String, e: reqwest::Error) -> anyhow::Error {
+    let message = format!("{}: {}", message, e);
+    if e.is_timeout() {
+        anyhow::Error::new(RequestTimedOut).context(message)
+    } else {
+        anyhow::Error::new(e).context(message)
+    }
+}
+
+/// The node answered a purchase status query with 404: it has no record of
+/// the purchase, e.g. because it was never created or the node's state was
+/// reset.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UnknownPurchase;
+
+impl std::fmt::Display for UnknownPurchase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Codex has no record of the purchase")
+    }
+}
+
+impl std::error::Error for UnknownPurchase {}
+
+const CID_MIN_LEN: usize = 32;
+const CID_MAX_LEN: usize = 128;
+const BASE58_ALPHABET: &str = "123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+/// Lowercases the scheme and host and drops trailing slashes, so the same
+/// node written two ways compares equal.
+pub fn normalize_endpoint(endpoint: &str) -> String {
+    let trimmed = endpoint.trim();
+    match reqwest::Url::parse(trimmed) {
+        Ok(url) => url.as_str().trim_end_matches('/').to_string(),
+        Err(_) => trimmed.trim_end_matches('/').to_string(),
+    }
+}
+
+/// Normalizes endpoints and drops repeats, keeping the first occurrence so
+/// the configured order is preserved.
+fn dedup_endpoints(endpoints: Vec<String>) -> Vec<String> {
+    let mut unique: Vec<String> = Vec::with_capacity(endpoints.len());
+    for endpoint in endpoints {
+        let normalized = normalize_endpoint(&endpoint);
+        if unique.contains(&normalized) {
+            warn!("Ignoring duplicate Codex endpoint {}", endpoint);
+        } else {
+            unique.push(normalized);
+        }
+    }
+    unique
+}
+
+/// Checks that an upload response looks like a CID rather than, say, an error
+/// message a proxy returned with a 200.
+pub fn validate_cid(cid: &str, mode: CidValidation) -> Result<()> {
+    if mode == CidValidation::Off {
+        return Ok(());
+    }
+    
+    let invalid = |reason: &str| anyhow!("Upload returned an invalid CID ({}): {}", reason, body_snippet(cid));
+    
+    if cid.len() < CID_MIN_LEN || cid.len() > CID_MAX_LEN {
+        return Err(invalid(&format!("length {} outside {}..={}", cid.len(), CID_MIN_LEN, CID_MAX_LEN)));
+    }
+    if !cid.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(invalid("unexpected characters"));
+    }
+    
+    if mode == CidValidation::Strict {
+        let is_base58 = |s: &str| s.chars().all(|c| BASE58_ALPHABET.contains(c));
+        let valid = if cid.starts_with("Qm") {
+            // CIDv0: a bare base58btc sha2-256 multihash.
+            cid.len() == 46 && is_base58(cid)
+        } else if let Some(rest) = cid.strip_prefix('z') {
+            is_base58(rest)
+        } else if let Some(rest) = cid.strip_prefix('b') {
+            rest.chars().all(|c| c.is_ascii_lowercase() || ('2'..='7').contains(&c))
+        } else if let Some(rest) = cid.strip_prefix('f') {
+            rest.chars().all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+        } else {
+            false
+        };
+        if !valid {
+            return Err(invalid("not a CIDv0 or a base58btc, base32 or base16 CIDv1"));
+        }
+    }
+    
+    Ok(())
+}
+
+/// Opens `file_path` as a streaming request body, so uploads never hold the
+/// whole file in memory. Also returns the file size, which is sent as the
+/// Content-Length.
+pub async fn file_body(file_path: &Path) -> Result<(reqwest::Body, u64)> {
+    let file = fs::File::open(file_path).await
+        .map_err(|e| anyhow!("Failed to read file {}: {}", file_path.display(), e))?;
+    let file_size = file.metadata().await
+        .map_err(|e| anyhow!("Failed to read metadata of {}: {}", file_path.display(), e))?
+        .len();
+    Ok((reqwest::Body::wrap_stream(ReaderStream::new(file)), file_size))
+}
+
+/// The filename sent with an upload so the node lists something readable
+/// next to the CID. Characters a header can't carry are replaced with `_`.
+pub fn upload_filename(file_path: &Path) -> Option<String> {
+    let name = file_path.file_name()?.to_string_lossy();
+    Some(name.chars()
+        .map(|c| if (c.is_ascii_graphic() || c == ' ') && c != '"' && c != '\\' { c } else { '_' })
+        .collect())
+}
+
+/// Trims a response body down to something fit for an error message, so a
+/// proxy's full HTML error page doesn't flood the logs.
+fn body_snippet(body: &str) -> String {
+    let trimmed = body.trim();
+    if trimmed.chars().count() > ERROR_BODY_SNIPPET_CHARS {
+        let snippet: String = trimmed.chars().take(ERROR_BODY_SNIPPET_CHARS).collect();
+        format!("{}...", snippet)
+    } else {
+        trimmed.to_string()
+    }
+}
+
+/// Reads a response body as text, giving up as soon as it grows past `limit`
+/// bytes so a misbehaving endpoint can't make us buffer an unbounded body.
+async fn read_text_limited(response: Response, limit: u64, what: &str) -> Result<String> {
+    let body = read_bytes_limited(response, limit, what, "max_response_bytes").await?;
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+/// Reads a response body, failing once it grows past `limit` bytes, the
+/// value of the `setting` named in the error.
+async fn read_bytes_limited(mut response: Response, limit: u64, what: &str, setting: &str) -> Result<Vec<u8>> {
+    let too_large = || anyhow!("The {} response is larger than the {} byte limit ({})", what, limit, setting);
+    
+    if response.content_length().is_some_and(|length| length > limit) {
+        return Err(too_large());
+    }
+    
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await
+        .map_err(|e| request_error(format!("Failed to read {} response", what), e))?
+    {
+        if (body.len() + chunk.len()) as u64 > limit {
+            return Err(too_large());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    
+    Ok(body)
+}
+
+/// Parses a JSON body, reporting the status and a body snippet instead of a
+/// bare serde error when something in front of the node answered with a
+/// non-JSON page. Returns the raw body alongside the parsed value.
+async fn read_json<T: DeserializeOwned>(response: Response, limit: u64, what: &str) -> Result<(T, String)> {
+    let status = response.status();
+    let content_type = response.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    
+    let body = read_text_limited(response, limit, what).await?;
+    
+    if !content_type.is_empty() && !content_type.contains("json") {
+        return Err(anyhow!("Expected JSON for {} but got {} (status {}): {}",
+                         what, content_type, status, body_snippet(&body)));
+    }
+    
+    let value = serde_json::from_str(&body)
+        .map_err(|e| anyhow!("Failed to parse {} (status {}): {}; body: {}",
+                           what, status, e, body_snippet(&body)))?;
+    Ok((value, body))
+}
+
+/// An HTTP client speaking `version`, giving up on requests after
+/// `timeout` and on connecting after `connect_timeout`.
+fn http_client(version: HttpVersion, timeout: Duration, connect_timeout: Duration) -> HttpClient {
+    let builder = HttpClient::builder()
+        .timeout(timeout)
+        .connect_timeout(connect_timeout);
+    let builder = match version {
+        HttpVersion::Auto => builder,
+        HttpVersion::Http1 => builder.http1_only(),
+        HttpVersion::Http2 => builder.http2_prior_knowledge(),
+    };
+    // Fails only where `HttpClient::new` would panic too.
+    builder.build().expect("Failed to build HTTP client")
 }
 
 impl Client {
     pub fn new(endpoints: Vec<String>) -> Self {
         Self {
-            endpoints,
-            http_client: HttpClient::new(),
+            endpoints: dedup_endpoints(endpoints),
+            http_client: http_client(HttpVersion::default(), DEFAULT_REQUEST_TIMEOUT, DEFAULT_CONNECT_TIMEOUT),
             current_endpoint: Arc::new(AtomicUsize::new(0)),
+            cid_validation: CidValidation::default(),
+            max_response_bytes: DEFAULT_MAX_RESPONSE_BYTES,
+            min_file_size: eligibility::MIN_FILE_SIZE,
+            max_file_size: eligibility::MAX_FILE_SIZE,
+            auth_token: None,
+            unhealthy: Arc::new(RwLock::new(HashSet::new())),
+            consecutive_failures: Arc::new(Mutex::new(HashMap::new())),
+            failure_threshold: 3,
+            purchase_start_poll: Duration::from_secs(5),
+            upload_timeout: DEFAULT_UPLOAD_TIMEOUT,
+            metrics: PrometheusMetrics::new(),
+        }
+    }
+    
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            http_client: http_client(
+                config.http_version,
+                Duration::from_secs(config.request_timeout_secs),
+                Duration::from_secs(config.connect_timeout_secs),
+            ),
+            cid_validation: config.cid_validation,
+            max_response_bytes: config.max_response_bytes,
+            min_file_size: config.min_file_size,
+            max_file_size: config.max_file_size,
+            auth_token: config.auth_token.clone(),
+            failure_threshold: config.endpoint_failure_threshold,
+            purchase_start_poll: Duration::from_secs(config.purchase_start_poll_secs),
+            upload_timeout: Duration::from_secs(config.upload_timeout_secs),
+            ..Self::new(config.codex_endpoints.clone())
+        }
+    }
+    
+    /// Adds the configured bearer token, if any, to a request.
+    fn authed(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.auth_token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+    
+    /// `preferred` when it is a configured, healthy endpoint, such as the one
+    /// a file was uploaded to; otherwise the next healthy one, round robin.
+    fn endpoint_for(&self, preferred: Option<&str>) -> Result<String> {
+        if let Some(preferred) = preferred {
+            let unhealthy = self.unhealthy.read().unwrap_or_else(|e| e.into_inner());
+            if self.endpoints.iter().any(|endpoint| endpoint == preferred) && !unhealthy.contains(preferred) {
+                return Ok(preferred.to_string());
+            }
+        }
+        Ok(self.next_healthy_endpoint().ok_or(NoHealthyEndpoint)?.to_string())
+    }
+    
+    /// The next healthy endpoint, round robin, or `None` when every endpoint
+    /// is marked unhealthy.
+    fn next_healthy_endpoint(&self) -> Option<&str> {
+        let unhealthy = self.unhealthy.read().unwrap_or_else(|e| e.into_inner());
+        let healthy: Vec<&String> = self.endpoints.iter()
+            .filter(|endpoint| !unhealthy.contains(*endpoint))
+            .collect();
+        if healthy.is_empty() {
+            return None;
+        }
+        
+        let index = self.current_endpoint.fetch_add(1, Ordering::Relaxed) % healthy.len();
+        Some(healthy[index].as_str())
+    }
+    
+    /// Counts a request to `endpoint` towards its health. Connection errors
+    /// and 5xx responses are failures; any other response means the node is
+    /// up, even if it rejected the request.
+    fn track_health(&self, endpoint: &str, response: &reqwest::Result<Response>) {
+        let failed = match response {
+            Ok(response) => response.status().is_server_error(),
+            Err(_) => true,
+        };
+        
+        let mut failures = self.consecutive_failures.lock().unwrap_or_else(|e| e.into_inner());
+        if !failed {
+            failures.remove(endpoint);
+            return;
+        }
+        
+        let count = failures.entry(endpoint.to_string()).or_default();
+        *count += 1;
+        if *count >= self.failure_threshold {
+            failures.remove(endpoint);
+            drop(failures);
+            self.mark_unhealthy(endpoint);
+        }
+    }
+    
+    /// Takes `endpoint` out of rotation until `probe_unhealthy` finds it
+    /// reachable again.
+    pub fn mark_unhealthy(&self, endpoint: &str) {
+        let endpoint = normalize_endpoint(endpoint);
+        let mut unhealthy = self.unhealthy.write().unwrap_or_else(|e| e.into_inner());
+        if unhealthy.insert(endpoint.clone()) {
+            warn!("Marked Codex endpoint {} unhealthy", endpoint);
+            if self.endpoints.iter().all(|endpoint| unhealthy.contains(endpoint)) {
+                error!("No healthy Codex endpoint left; pausing uploads until one recovers");
+            }
+        }
+    }
+    
+    pub fn mark_healthy(&self, endpoint: &str) {
+        let endpoint = normalize_endpoint(endpoint);
+        if self.unhealthy.write().unwrap_or_else(|e| e.into_inner()).remove(&endpoint) {
+            info!("Codex endpoint {} is healthy again", endpoint);
         }
     }
     
-    fn get_endpoint(&self) -> &str {
-        let index = self.current_endpoint.fetch_add(1, Ordering::Relaxed) % self.endpoints.len();
-        &self.endpoints[index]
+    pub fn has_healthy_endpoint(&self) -> bool {
+        let unhealthy = self.unhealthy.read().unwrap_or_else(|e| e.into_inner());
+        self.endpoints.iter().any(|endpoint| !unhealthy.contains(endpoint))
+    }
+    
+    /// Checks every unhealthy endpoint and puts the reachable ones back into
+    /// rotation. Returns how many recovered.
+    pub async fn probe_unhealthy(&self) -> usize {
+        let unhealthy: Vec<String> = self.unhealthy.read().unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .cloned()
+            .collect();
+        
+        let mut recovered = 0;
+        for endpoint in unhealthy {
+            let url = format!("{}/api/codex/v1/debug/info", endpoint);
+            match self.authed(self.http_client.get(&url)).send().await {
+                Ok(response) if response.status().is_success() => {
+                    self.mark_healthy(&endpoint);
+                    recovered += 1;
+                }
+                Ok(response) => debug!("Endpoint {} is still unhealthy: status {}", endpoint, response.status()),
+                Err(e) => debug!("Endpoint {} is still unhealthy: {}", endpoint, e),
+            }
+        }
+        recovered
     }
     
     pub async fn check_connectivity(&self) -> Result<()> {
         for endpoint in &self.endpoints {
             let url = format!("{}/api/codex/v1/debug/info", endpoint);
-            match self.http_client.get(&url).send().await {
+            match self.authed(self.http_client.get(&url)).send().await {
                 Ok(response) => {
                     if response.status().is_success() {
                         info!("Endpoint {} is reachable", endpoint);
@@ -78,57 +519,91 @@ impl Client {
                     }
                 }
                 Err(e) => {
-                    return Err(anyhow!("Failed to connect to endpoint {}: {}", endpoint, e));
+                    return Err(request_error(format!("Failed to connect to endpoint {}", endpoint), e));
                 }
             }
         }
         Ok(())
     }
     
-    pub async fn upload_file(&self, file_path: &Path) -> Result<String> {
-        let endpoint = self.get_endpoint();
+    pub async fn upload_file(&self, file_path: &Path) -> Result<UploadResult> {
+        self.upload_file_to(file_path, None).await
+    }
+    
+    /// Uploads `file_path`, preferring `endpoint` while it is healthy.
+    pub async fn upload_file_to(&self, file_path: &Path, endpoint: Option<&str>) -> Result<UploadResult> {
+        let attributes = vec![("file.path", file_path.display().to_string())];
+        let started = std::time::Instant::now();
+        let result = telemetry::traced("codex.upload_file", attributes, self.upload_file_inner(file_path, endpoint)).await;
+        if result.is_ok() {
+            self.metrics.uploads.with_label_values(&["succeeded"]).inc();
+            self.metrics.upload_duration.observe(started.elapsed().as_secs_f64());
+        } else {
+            self.metrics.uploads.with_label_values(&["failed"]).inc();
+        }
+        result
+    }
+    
+    async fn upload_file_inner(&self, file_path: &Path, endpoint: Option<&str>) -> Result<UploadResult> {
+        let endpoint = self.endpoint_for(endpoint)?;
         let url = format!("{}/api/codex/v1/data", endpoint);
         
         debug!("Uploading file {} to endpoint {}", file_path.display(), endpoint);
         
-        let file_content = fs::read(file_path).await
-            .map_err(|e| anyhow!("Failed to read file {}: {}", file_path.display(), e))?;
-        
-        let file_size = file_content.len();
-        if file_size < 1024 * 1024 {
-            return Err(anyhow!("File {} is too small ({} bytes). Minimum size is 1MB", 
-                             file_path.display(), file_size));
+        let (body, file_size) = file_body(file_path).await?;
+        if file_size < self.min_file_size {
+            return Err(anyhow!("File {} is too small ({} bytes). Minimum size is {} bytes", 
+                             file_path.display(), file_size, self.min_file_size));
         }
         
-        if file_size > 1024 * 1024 * 1024 {
-            return Err(anyhow!("File {} is too large ({} bytes). Maximum size is 1GB", 
-                             file_path.display(), file_size));
+        if file_size > self.max_file_size {
+            return Err(anyhow!("File {} is too large ({} bytes). Maximum size is {} bytes", 
+                             file_path.display(), file_size, self.max_file_size));
         }
         
-        let response = self.http_client
-            .post(&url)
+        let mut request = self.authed(self.http_client.post(&url))
             .header("Content-Type", "application/octet-stream")
-            .body(file_content)
+            .header("Content-Length", file_size)
+            .timeout(self.upload_timeout);
+        if let Some(filename) = upload_filename(file_path) {
+            request = request.header("Content-Disposition", format!("attachment; filename=\"{}\"", filename));
+        }
+        
+        let response = request
+            .body(body)
             .send()
-            .await
-            .map_err(|e| anyhow!("Failed to upload file to {}: {}", endpoint, e))?;
+            .await;
+        self.track_health(&endpoint, &response);
+        let response = response.map_err(|e| request_error(format!("Failed to upload file to {}", endpoint), e))?;
         
         if !response.status().is_success() {
             let status = response.status();
             let error_text = response.text().await.unwrap_or_default();
-            return Err(anyhow!("Upload failed with status {}: {}", status, error_text));
+            return Err(anyhow!("Upload failed with status {}: {}", status, body_snippet(&error_text)));
         }
         
         let cid = response.text().await
             .map_err(|e| anyhow!("Failed to parse upload response: {}", e))?;
         
         let cid = cid.trim();
+        validate_cid(cid, self.cid_validation)?;
         info!("Successfully uploaded file {} with CID: {}", file_path.display(), cid);
-        Ok(cid.to_string())
+        Ok(UploadResult { cid: cid.to_string(), endpoint })
     }
     
-    pub async fn create_storage_request(&self, cid: &str, storage_params: &crate::config::StorageParams) -> Result<PurchaseResponse> {
-        let endpoint = self.get_endpoint();
+    /// Requests storage for `cid`, preferring `endpoint`, normally the node
+    /// the content was uploaded to.
+    pub async fn create_storage_request(&self, cid: &str, storage_params: &crate::config::StorageParams, endpoint: Option<&str>) -> Result<PurchaseResponse> {
+        let attributes = vec![("codex.cid", cid.to_string())];
+        let result = telemetry::traced("codex.create_storage_request", attributes,
+                                       self.create_storage_request_inner(cid, storage_params, endpoint)).await;
+        let outcome = if result.is_ok() { "created" } else { "failed" };
+        self.metrics.storage_requests.with_label_values(&[outcome]).inc();
+        result
+    }
+    
+    async fn create_storage_request_inner(&self, cid: &str, storage_params: &crate::config::StorageParams, endpoint: Option<&str>) -> Result<PurchaseResponse> {
+        let endpoint = self.endpoint_for(endpoint)?;
         let url = format!("{}/api/codex/v1/storage/request/{}", endpoint, cid);
         
         debug!("Creating storage request for CID {} at endpoint {}", cid, endpoint);
@@ -146,28 +621,29 @@ impl Client {
             collateral: storage_params.collateral.to_string(),
         };
         
-        let response = self.http_client
-            .post(&url)
+        let response = self.authed(self.http_client.post(&url))
             .json(&request)
             .send()
-            .await
-            .map_err(|e| anyhow!("Failed to create storage request: {}", e))?;
+            .await;
+        self.track_health(&endpoint, &response);
+        let response = response.map_err(|e| request_error("Failed to create storage request".to_string(), e))?;
         
         if !response.status().is_success() {
             let status = response.status();
-            let error_text = response.text().await.unwrap_or_default();
+            let error_text = read_text_limited(response, self.max_response_bytes, "storage request").await
+                .unwrap_or_else(|e| e.to_string());
             
             if status.as_u16() == 402 {
-                return Err(anyhow!("Insufficient tokens to create storage request"));
+                return Err(InsufficientTokens.into());
             }
             
-            return Err(anyhow!("Storage request failed with status {}: {}", status, error_text));
+            return Err(anyhow!("Storage request failed with status {}: {}", status, body_snippet(&error_text)));
         }
         
-        let purchase_id = response.text().await
+        let raw_body = read_text_limited(response, self.max_response_bytes, "storage request").await
             .map_err(|e| anyhow!("Failed to get purchase ID: {}", e))?;
         
-        let purchase_id = purchase_id.trim().to_string();
+        let purchase_id = raw_body.trim().to_string();
         
         // Create a purchase response with the ID we got and the CID we requested
         let purchase_response = PurchaseResponse {
@@ -176,57 +652,174 @@ impl Client {
                 content: ContentInfo {
                     cid: cid.to_string(),
                 }
-            }
+            },
+            raw_body,
         };
         
         info!("Created storage request for CID {} with purchase ID: {}", cid, purchase_response.purchase_id);
         Ok(purchase_response)
     }
     
-    pub async fn get_purchase_status(&self, purchase_id: &str) -> Result<PurchaseStatus> {
-        let endpoint = self.get_endpoint();
+    /// Fetches a purchase's status, preferring `endpoint`, normally the one
+    /// stored on the record that owns the purchase.
+    pub async fn get_purchase_status(&self, purchase_id: &str, endpoint: Option<&str>) -> Result<PurchaseStatus> {
+        let attributes = vec![("codex.purchase_id", purchase_id.to_string())];
+        telemetry::traced("codex.get_purchase_status", attributes, self.get_purchase_status_inner(purchase_id, endpoint)).await
+    }
+    
+    async fn get_purchase_status_inner(&self, purchase_id: &str, endpoint: Option<&str>) -> Result<PurchaseStatus> {
+        let endpoint = self.endpoint_for(endpoint)?;
         let url = format!("{}/api/codex/v1/storage/purchases/{}", endpoint, purchase_id);
         
-        let response = self.http_client
-            .get(&url)
+        let response = self.authed(self.http_client.get(&url))
             .send()
-            .await
-            .map_err(|e| anyhow!("Failed to get purchase status: {}", e))?;
+            .await;
+        self.track_health(&endpoint, &response);
+        let response = response.map_err(|e| request_error("Failed to get purchase status".to_string(), e))?;
+        
+        if response.status().as_u16() == 404 {
+            return Err(anyhow::Error::new(UnknownPurchase)
+                .context(format!("Failed to get purchase status for {} from {}", purchase_id, endpoint)));
+        }
         
         if !response.status().is_success() {
             let status = response.status();
-            let error_text = response.text().await.unwrap_or_default();
+            let error_text = read_text_limited(response, self.max_response_bytes, "purchase status").await
+                .unwrap_or_else(|e| e.to_string());
             return Err(anyhow!("Failed to get purchase status with status {}: {}", 
-                             status, error_text));
+                             status, body_snippet(&error_text)));
         }
         
-        let status: PurchaseStatus = response.json().await
-            .map_err(|e| anyhow!("Failed to parse purchase status: {}", e))?;
+        let (mut status, raw_body): (PurchaseStatus, String) =
+            read_json(response, self.max_response_bytes, "purchase status").await?;
+        status.raw_body = raw_body;
         
         debug!("Purchase {} status: {}", purchase_id, status.state);
         Ok(status)
     }
     
-    pub async fn wait_for_purchase_start(&self, purchase_id: &str, timeout_secs: u64) -> Result<PurchaseStatus> {
+    /// Cancels a purchase, preferring `endpoint`, normally the one stored on
+    /// the record that owns it. A purchase the node doesn't know is treated
+    /// as already gone.
+    pub async fn cancel_purchase(&self, purchase_id: &str, endpoint: Option<&str>) -> Result<()> {
+        let endpoint = self.endpoint_for(endpoint)?;
+        let url = format!("{}/api/codex/v1/storage/purchases/{}/cancel", endpoint, purchase_id);
+        
+        let response = self.authed(self.http_client.post(&url))
+            .send()
+            .await;
+        self.track_health(&endpoint, &response);
+        let response = response.map_err(|e| request_error(format!("Failed to cancel purchase {}", purchase_id), e))?;
+        
+        if response.status().as_u16() == 404 {
+            debug!("Purchase {} is unknown to {}; nothing to cancel", purchase_id, endpoint);
+            return Ok(());
+        }
+        
+        if !response.status().is_success() {
+            let status = response.status();
+            let error_text = read_text_limited(response, self.max_response_bytes, "purchase cancellation").await
+                .unwrap_or_else(|e| e.to_string());
+            return Err(anyhow!("Cancelling purchase {} failed with status {}: {}",
+                             purchase_id, status, body_snippet(&error_text)));
+        }
+        
+        info!("Cancelled purchase {}", purchase_id);
+        Ok(())
+    }
+    
+    pub fn purchase_start_poll(&self) -> Duration {
+        self.purchase_start_poll
+    }
+    
+    /// The counters this client and the file processor using it update.
+    pub fn metrics(&self) -> &PrometheusMetrics {
+        &self.metrics
+    }
+    
+    pub fn endpoints(&self) -> &[String] {
+        &self.endpoints
+    }
+    
+    /// The configured endpoints not currently marked unhealthy.
+    pub fn healthy_endpoints(&self) -> Vec<String> {
+        let unhealthy = self.unhealthy.read().unwrap_or_else(|e| e.into_inner());
+        self.endpoints.iter()
+            .filter(|endpoint| !unhealthy.contains(*endpoint))
+            .cloned()
+            .collect()
+    }
+    
+    /// Fetches the content stored under `cid`, up to `max_file_size` bytes.
+    pub async fn download_file(&self, cid: &str) -> Result<Vec<u8>> {
+        let endpoint = self.next_healthy_endpoint().ok_or(NoHealthyEndpoint)?.to_string();
+        let url = format!("{}/api/codex/v1/data/{}", endpoint, cid);
+        
+        debug!("Downloading CID {} from endpoint {}", cid, endpoint);
+        
+        let response = self.authed(self.http_client.get(&url))
+            .timeout(self.upload_timeout)
+            .send()
+            .await;
+        self.track_health(&endpoint, &response);
+        let response = response.map_err(|e| request_error(format!("Failed to download CID {}", cid), e))?;
+        
+        if !response.status().is_success() {
+            let status = response.status();
+            let error_text = read_text_limited(response, self.max_response_bytes, "download").await
+                .unwrap_or_else(|e| e.to_string());
+            return Err(anyhow!("Download of CID {} failed with status {}: {}", cid, status, body_snippet(&error_text)));
+        }
+        
+        read_bytes_limited(response, self.max_file_size, "download", "max_file_size").await
+    }
+    
+    /// Whether `endpoint` can fetch the manifest for `cid` from the network.
+    /// A 404 means it can't; any other failure is an error.
+    pub async fn has_cid(&self, endpoint: &str, cid: &str) -> Result<bool> {
+        let url = format!("{}/api/codex/v1/data/{}/network/manifest", endpoint, cid);
+        
+        let response = self.authed(self.http_client.get(&url))
+            .send()
+            .await
+            .map_err(|e| request_error(format!("Failed to check CID {} at {}", cid, endpoint), e))?;
+        
+        let status = response.status();
+        if status.is_success() {
+            return Ok(true);
+        }
+        if status.as_u16() == 404 {
+            return Ok(false);
+        }
+        
+        let error_text = read_text_limited(response, self.max_response_bytes, "manifest").await
+            .unwrap_or_else(|e| e.to_string());
+        Err(anyhow!("Checking CID {} at {} failed with status {}: {}",
+                    cid, endpoint, status, body_snippet(&error_text)))
+    }
+    
+    /// Polls until the purchase starts or `timeout_secs` pass. A purchase that
+    /// reaches a final state without starting is an error.
+    pub async fn wait_for_purchase_start(&self, purchase_id: &str, endpoint: Option<&str>, timeout_secs: u64, min_filled_slots: Option<u32>) -> Result<PurchaseWait> {
         let start_time = std::time::Instant::now();
-        let timeout = std::time::Duration::from_secs(timeout_secs);
+        let timeout = Duration::from_secs(timeout_secs);
         
         loop {
-            let status = self.get_purchase_status(purchase_id).await?;
+            let status = self.get_purchase_status(purchase_id, endpoint).await?;
             
             match status.state.as_str() {
-                "started" => {
+                "started" if status.has_enough_slots(min_filled_slots) => {
                     info!("Purchase {} started successfully", purchase_id);
-                    return Ok(status);
+                    return Ok(PurchaseWait::Started(status));
                 }
                 "cancelled" | "expired" | "failed" => {
                     return Err(anyhow!("Purchase {} reached final state: {}", purchase_id, status.state));
                 }
                 _ => {
                     if start_time.elapsed() > timeout {
-                        return Err(anyhow!("Timeout waiting for purchase {} to start", purchase_id));
+                        return Ok(PurchaseWait::TimedOut { state: status.state });
                     }
-                    tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+                    tokio::time::sleep(self.purchase_start_poll).await;
                 }
             }
         }
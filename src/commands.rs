@@ -0,0 +1,1096 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use log::{info, warn};
+use sha2::Digest;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::codex::Client;
+use crate::config::{Command, Config, OutputStructure, StorageParams};
+use crate::eligibility::{self, Eligibility, PathFilter};
+use crate::event_log;
+use crate::file_processor::FileProcessor;
+use crate::manifest;
+use crate::storage::{FileRecord, FileStatus, StorageManager};
+use crate::usage::{UsageLedger, UsagePeriod, USAGE_FILE_NAME};
+
+pub async fn run(command: &Command, config: &Config) -> Result<()> {
+    match command {
+        Command::MigrateStructure { to, remove_old } => {
+            migrate_structure(config, to.clone(), *remove_old).await?;
+        }
+        Command::Usage { since, monthly } => {
+            let period = if *monthly { UsagePeriod::Month } else { UsagePeriod::Day };
+            print_usage(config, *since, period).await?;
+        }
+        Command::Expiring { within } => {
+            print_expiring(config, *within).await?;
+        }
+        Command::AuditAvailability { sample } => {
+            let client = Client::from_config(config);
+            let report = audit_availability(config, &client, *sample).await?;
+            print_audit(&report);
+            if !report.unavailable.is_empty() {
+                return Err(anyhow!("{} of {} checked files are unavailable on every endpoint",
+                                   report.unavailable.len(), report.checked));
+            }
+        }
+        Command::RewriteEndpoint { from, to } => {
+            let rewritten = rewrite_endpoint(config, from, to).await?;
+            println!("Rewrote the endpoint on {} records", rewritten);
+        }
+        Command::Why { file } => {
+            println!("{}", why(config, file).await?);
+        }
+        Command::Diff { other, json } => {
+            let diff = diff_output_folders(config, other).await?;
+            if *json {
+                println!("{}", serde_json::to_string_pretty(&diff)
+                    .map_err(|e| anyhow!("Failed to serialize diff: {}", e))?);
+            } else {
+                print_diff(&diff, &config.output_folder, other);
+            }
+            if !diff.is_empty() {
+                return Err(anyhow!("Output folders differ"));
+            }
+        }
+        Command::WatchEstimate => {
+            let estimate = estimate_watches(config)?;
+            print_watch_estimate(&estimate);
+            if let Some(limit) = estimate.max_user_watches.filter(|limit| estimate.watches > *limit) {
+                return Err(anyhow!("Watching needs {} inotify watches but max_user_watches is {}",
+                                   estimate.watches, limit));
+            }
+        }
+        Command::Plan { upload_bytes_per_sec, purchase_overhead_secs } => {
+            let plan = plan_uploads(config, *upload_bytes_per_sec, Duration::from_secs(*purchase_overhead_secs)).await?;
+            print_upload_plan(&plan, *upload_bytes_per_sec);
+        }
+        Command::Restore { to, only } => {
+            let client = Client::from_config(config);
+            let report = restore_files(config, &client, to, only.as_deref()).await?;
+            print_restore(&report, to);
+            if !report.failed.is_empty() {
+                return Err(anyhow!("{} files could not be restored", report.failed.len()));
+            }
+        }
+        Command::RebuildFromJournal { journal, replace } => {
+            let journal = journal.as_ref().or(config.event_log_path.as_ref())
+                .ok_or_else(|| anyhow!("No journal to replay: pass --journal or set event_log_path"))?;
+            let report = rebuild_from_journal(config, journal, *replace).await?;
+            println!("Rebuilt {} records from {} events in {}", report.rebuilt, report.events, journal.display());
+            if report.outside_target > 0 {
+                println!("Skipped {} events for files outside the target folder", report.outside_target);
+            }
+            if report.unreadable_lines > 0 {
+                println!("Skipped {} unreadable lines", report.unreadable_lines);
+            }
+        }
+        Command::Status { status, json } => {
+            let records = StorageManager::from_config(config)
+                .load_existing_records(&config.target_folder)
+                .await?;
+            let duration = chrono::Duration::from_std(config.duration())?;
+            let rows = status_rows(&records, &config.target_folder, duration, status.as_ref(), Utc::now());
+            if *json {
+                println!("{}", serde_json::to_string_pretty(&rows)
+                    .map_err(|e| anyhow!("Failed to serialize records: {}", e))?);
+            } else {
+                print!("{}", format_status_table(&rows));
+            }
+        }
+        Command::Manifest { verify: Some(path), .. } => {
+            let document = std::fs::read_to_string(path)
+                .map_err(|e| anyhow!("Failed to read manifest {}: {}", path.display(), e))?;
+            let public_key = manifest::verify_manifest(&document)?;
+            println!("Manifest {} is intact, signed by ed25519 key {}", path.display(), public_key);
+        }
+        Command::Manifest { sign, output, verify: None } => {
+            let key = sign.as_deref().map(manifest::read_signing_key).transpose()?;
+            let built = manifest::build_manifest(config).await?;
+            manifest::print_summary(&built);
+            let unverified = built.unverified.len();
+            let document = serde_json::to_string_pretty(&manifest::sign_manifest(built, key.as_ref())?)
+                .map_err(|e| anyhow!("Failed to serialize manifest: {}", e))?;
+            match output {
+                Some(output) => std::fs::write(output, document + "\n")
+                    .map_err(|e| anyhow!("Failed to write manifest {}: {}", output.display(), e))?,
+                None => println!("{}", document),
+            }
+            if unverified > 0 {
+                return Err(anyhow!("{} stored files could not be verified against their records", unverified));
+            }
+        }
+    }
+    
+    Ok(())
+}
+
+/// Rewrites every record from the configured structure into `to`, verifying
+/// the converted set before anything old is removed. Returns the number of
+/// records migrated.
+pub async fn migrate_structure(config: &Config, to: OutputStructure, remove_old: bool) -> Result<usize> {
+    if config.output_structure == to {
+        info!("Records are already stored as {:?}, nothing to migrate", to);
+        return Ok(0);
+    }
+    
+    let source = StorageManager::from_config(config);
+    let destination = StorageManager::for_structure(config, to.clone());
+    
+    let records = source.load_existing_records(&config.target_folder).await?;
+    
+    let already_present = destination.load_existing_records(&config.target_folder).await?;
+    if !already_present.is_empty() {
+        return Err(anyhow!(
+            "Output folder already contains {} records stored as {:?}; refusing to overwrite them",
+            already_present.len(),
+            to
+        ));
+    }
+    
+    destination.save_all_records(&config.target_folder, &records).await?;
+    
+    let migrated = destination.load_existing_records(&config.target_folder).await?;
+    if migrated.len() != records.len() {
+        return Err(anyhow!(
+            "Migration verification failed: wrote {} records but read back {}",
+            records.len(),
+            migrated.len()
+        ));
+    }
+    
+    info!("Migrated {} records from {:?} to {:?}", records.len(), config.output_structure, to);
+    
+    if remove_old {
+        source.remove_all_records(&config.target_folder, &records).await?;
+        info!("Removed records stored as {:?}", config.output_structure);
+    }
+    
+    warn!("Set output_structure = \"{}\" in your config before the next run",
+          format!("{:?}", to).to_lowercase());
+    
+    Ok(records.len())
+}
+
+/// Replaces `from` with `to` as the stored endpoint of every record, ignoring
+/// trailing slashes. Returns the number of records changed.
+pub async fn rewrite_endpoint(config: &Config, from: &str, to: &str) -> Result<usize> {
+    let storage_manager = StorageManager::from_config(config);
+    let mut records = storage_manager.load_existing_records(&config.target_folder).await?;
+    
+    let from = from.trim_end_matches('/');
+    let to = to.trim_end_matches('/');
+    let mut rewritten = 0;
+    for record in records.values_mut() {
+        if record.codex_endpoint.as_deref().is_some_and(|endpoint| endpoint.trim_end_matches('/') == from) {
+            record.codex_endpoint = Some(to.to_string());
+            record.updated_at = Utc::now();
+            rewritten += 1;
+        }
+    }
+    
+    if rewritten > 0 {
+        storage_manager.save_all_records(&config.target_folder, &records).await?;
+    }
+    info!("Rewrote endpoint {} to {} on {} of {} records", from, to, rewritten, records.len());
+    
+    Ok(rewritten)
+}
+
+async fn print_usage(config: &Config, since: Option<chrono::NaiveDate>, period: UsagePeriod) -> Result<()> {
+    let ledger = UsageLedger::load(&config.output_folder.join(USAGE_FILE_NAME)).await?;
+    let rows = ledger.report(since, period);
+    
+    if rows.is_empty() {
+        println!("No usage recorded");
+        return Ok(());
+    }
+    
+    println!("{:<12} {:>16} {:>8} {:>17}", "PERIOD", "BYTES UPLOADED", "UPLOADS", "STORAGE REQUESTS");
+    let (mut bytes, mut uploads, mut requests) = (0, 0, 0);
+    for row in &rows {
+        println!("{:<12} {:>16} {:>8} {:>17}",
+                 row.period, row.usage.bytes_uploaded, row.usage.uploads, row.usage.storage_requests);
+        bytes += row.usage.bytes_uploaded;
+        uploads += row.usage.uploads;
+        requests += row.usage.storage_requests;
+    }
+    println!("{:<12} {:>16} {:>8} {:>17}", "TOTAL", bytes, uploads, requests);
+    
+    Ok(())
+}
+
+/// Active records expiring before `now + within`, soonest first.
+pub fn expiring_within(
+    records: &HashMap<PathBuf, FileRecord>,
+    duration: chrono::Duration,
+    within: chrono::Duration,
+    now: DateTime<Utc>,
+) -> Vec<(PathBuf, DateTime<Utc>)> {
+    let mut expiring: Vec<_> = records.iter()
+        .filter_map(|(path, record)| record.expires_at(duration).map(|at| (path.clone(), at)))
+        // A window reaching past the end of time takes in everything.
+        .filter(|(_, expires_at)| now.checked_add_signed(within).is_none_or(|limit| *expires_at <= limit))
+        .collect();
+    expiring.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+    expiring
+}
+
+/// Formats a stored UTC timestamp for display in `tz`, with the zone's
+/// abbreviation.
+pub fn format_timestamp(at: DateTime<Utc>, tz: chrono_tz::Tz) -> String {
+    at.with_timezone(&tz).format("%Y-%m-%d %H:%M:%S %Z").to_string()
+}
+
+fn format_remaining(remaining: chrono::Duration) -> String {
+    if remaining <= chrono::Duration::zero() {
+        return "expired".to_string();
+    }
+    format_span(remaining)
+}
+
+/// `remaining` to the minute, e.g. `2d 3h 4m`.
+fn format_span(remaining: chrono::Duration) -> String {
+    let days = remaining.num_days();
+    let hours = remaining.num_hours() % 24;
+    let minutes = remaining.num_minutes() % 60;
+    if days > 0 {
+        format!("{}d {}h {}m", days, hours, minutes)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    } else {
+        format!("{}m", minutes)
+    }
+}
+
+async fn print_expiring(config: &Config, within: std::time::Duration) -> Result<()> {
+    let records = StorageManager::from_config(config)
+        .load_existing_records(&config.target_folder)
+        .await?;
+    
+    let duration = chrono::Duration::from_std(config.duration())?;
+    let within = chrono::Duration::from_std(within)
+        .map_err(|_| anyhow!("Window {:?} is too long", within))?;
+    let now = Utc::now();
+    let expiring = expiring_within(&records, duration, within, now);
+    
+    if expiring.is_empty() {
+        println!("No active files expire within {}", format_remaining(within));
+        return Ok(());
+    }
+    
+    let tz = config.display_tz();
+    println!("{:<25} {:>12}  PATH", "EXPIRES AT", "REMAINING");
+    for (path, expires_at) in expiring {
+        println!("{:<25} {:>12}  {}",
+                 format_timestamp(expires_at, tz),
+                 format_remaining(expires_at - now),
+                 path.display());
+    }
+    
+    Ok(())
+}
+
+/// One record as listed by `status`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StatusRow {
+    /// Relative to the target folder.
+    pub path: PathBuf,
+    pub status: FileStatus,
+    pub cid: Option<String>,
+    pub purchase_id: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub age_seconds: i64,
+    /// When the purchase started plus its duration, for stored files only.
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Negative once expired.
+    pub expires_in_seconds: Option<i64>,
+}
+
+/// The records with `status`, or all of them, as of `now`, sorted by path.
+pub fn status_rows(
+    records: &HashMap<PathBuf, FileRecord>,
+    target_folder: &Path,
+    duration: chrono::Duration,
+    status: Option<&FileStatus>,
+    now: DateTime<Utc>,
+) -> Vec<StatusRow> {
+    // Compared by variant, so that `Unknown` matches every unknown status.
+    let mut rows: Vec<StatusRow> = records.iter()
+        .filter(|(_, record)| status.is_none_or(|status| std::mem::discriminant(&record.status) == std::mem::discriminant(status)))
+        .map(|(path, record)| {
+            let expires_at = record.expires_at(duration);
+            StatusRow {
+                path: path.strip_prefix(target_folder).unwrap_or(path).to_path_buf(),
+                status: record.status.clone(),
+                cid: record.original_cid.clone(),
+                purchase_id: record.purchase_id.clone(),
+                created_at: record.created_at,
+                age_seconds: (now - record.created_at).num_seconds(),
+                expires_at,
+                expires_in_seconds: expires_at.map(|at| (at - now).num_seconds()),
+            }
+        })
+        .collect();
+    rows.sort_by(|a, b| a.path.cmp(&b.path));
+    rows
+}
+
+/// `rows` as a table with a total line.
+pub fn format_status_table(rows: &[StatusRow]) -> String {
+    let mut table = format!("{:<12} {:>12} {:>12} {:<60} {:<20} PATH\n",
+                            "STATUS", "AGE", "EXPIRES IN", "CID", "PURCHASE");
+    for row in rows {
+        let expires_in = row.expires_in_seconds
+            .map(|seconds| format_remaining(chrono::Duration::seconds(seconds)))
+            .unwrap_or_else(|| "-".to_string());
+        table.push_str(&format!("{:<12} {:>12} {:>12} {:<60} {:<20} {}\n",
+                                format!("{:?}", row.status),
+                                format_span(chrono::Duration::seconds(row.age_seconds.max(0))),
+                                expires_in,
+                                row.cid.as_deref().unwrap_or("-"),
+                                row.purchase_id.as_deref().unwrap_or("-"),
+                                row.path.display()));
+    }
+    table.push_str(&format!("{} records\n", rows.len()));
+    table
+}
+
+/// Resolves `file` to the path the scan would see: as given when absolute or
+/// existing, otherwise relative to the target folder.
+fn resolve_scan_path(config: &Config, file: &Path) -> PathBuf {
+    if file.is_absolute() || (file.exists() && !config.target_folder.join(file).exists()) {
+        let absolute = std::path::absolute(file).unwrap_or_else(|_| file.to_path_buf());
+        // The scan walks the configured target folder, so express the path
+        // under it even if the two were spelled differently.
+        if let (Ok(canonical_file), Ok(canonical_target)) = (absolute.canonicalize(), config.target_folder.canonicalize()) {
+            if let Ok(relative) = canonical_file.strip_prefix(&canonical_target) {
+                return config.target_folder.join(relative);
+            }
+        }
+        absolute
+    } else {
+        config.target_folder.join(file)
+    }
+}
+
+/// A verdict on whether `file` will be stored, naming the rule or record
+/// state responsible when it won't.
+pub async fn why(config: &Config, file: &Path) -> Result<String> {
+    let path = resolve_scan_path(config, file);
+    
+    if !path.starts_with(&config.target_folder) {
+        return Ok(format!("{}: excluded, it is outside the target folder {}",
+                          path.display(), config.target_folder.display()));
+    }
+    if !path.exists() {
+        return Ok(format!("{}: not found", path.display()));
+    }
+    
+    let relative = path.strip_prefix(&config.target_folder).unwrap_or(&path);
+    if !eligibility::PathFilter::from_config(config)?.allows(relative) {
+        return Ok(format!("{}: excluded by include_patterns or exclude_patterns", path.display()));
+    }
+    
+    let eligibility = eligibility::check(config, &path)?;
+    if eligibility != Eligibility::Eligible {
+        return Ok(format!("{}: excluded, it is {}", path.display(), eligibility));
+    }
+    
+    let records = StorageManager::from_config(config)
+        .load_existing_records(&config.target_folder)
+        .await?;
+    
+    let verdict = match records.get(&path) {
+        None => "eligible; it has no record yet and will be uploaded by the next scan or file event".to_string(),
+        Some(record) => {
+            let purchase = record.purchase_id.as_deref().unwrap_or("none");
+            match record.status {
+                FileStatus::Active => format!(
+                    "already stored (purchase {}); it is skipped unless its contents change or the purchase needs renewal",
+                    purchase),
+                FileStatus::Baseline => "recorded by a baseline scan; it is only uploaded once it changes".to_string(),
+                FileStatus::Pending => format!("deferred: {}", record.error.as_deref().unwrap_or("no reason recorded")),
+                FileStatus::Failed => format!(
+                    "the last attempt failed: {}; it is retried when the file is written again or FileHog restarts",
+                    record.error.as_deref().unwrap_or("no error recorded")),
+                FileStatus::Expired => "its storage expired; it is stored again by the next scan".to_string(),
+                FileStatus::DryRun => "seen by a dry run; it is uploaded by the next run without --dry-run".to_string(),
+                FileStatus::Unknown(_) => "its record has a status written by a newer version of FileHog; this version leaves it alone".to_string(),
+                FileStatus::Degraded => format!(
+                    "stored (purchase {}) but degraded: {}",
+                    purchase, record.error.as_deref().unwrap_or("its CID could not be retrieved")),
+                ref status => format!("eligible; processing is in progress ({:?}, purchase {})", status, purchase),
+            }
+        }
+    };
+    
+    Ok(format!("{}: {}", path.display(), verdict))
+}
+
+#[derive(Debug, Default)]
+pub struct AuditReport {
+    pub checked: usize,
+    pub active: usize,
+    /// Files whose CID no endpoint could fetch, with what each endpoint said.
+    pub unavailable: Vec<UnavailableFile>,
+}
+
+#[derive(Debug)]
+pub struct UnavailableFile {
+    pub path: PathBuf,
+    pub cid: String,
+    pub reasons: Vec<String>,
+}
+
+/// Picks `percent` of `paths` at random, rounding up so a non-zero sample
+/// never comes out empty.
+fn sample_paths(mut paths: Vec<PathBuf>, percent: f64) -> Vec<PathBuf> {
+    if percent >= 100.0 {
+        return paths;
+    }
+    
+    let salt = uuid::Uuid::new_v4();
+    paths.sort_by_cached_key(|path| {
+        let mut hasher = sha2::Sha256::new();
+        hasher.update(salt.as_bytes());
+        hasher.update(path.to_string_lossy().as_bytes());
+        hasher.finalize()
+    });
+    let count = (paths.len() as f64 * percent / 100.0).ceil() as usize;
+    paths.truncate(count);
+    paths
+}
+
+/// Asks every endpoint for the CID of each sampled active record, stopping
+/// at the first endpoint that has it.
+pub async fn audit_availability(config: &Config, client: &Client, sample: f64) -> Result<AuditReport> {
+    if !(sample > 0.0 && sample <= 100.0) {
+        return Err(anyhow!("Sample must be a percentage between 0 and 100, got: {}", sample));
+    }
+    
+    let records = StorageManager::from_config(config)
+        .load_existing_records(&config.target_folder)
+        .await?;
+    let active: Vec<PathBuf> = records.iter()
+        .filter(|(_, record)| record.status == FileStatus::Active)
+        .map(|(path, _)| path.clone())
+        .collect();
+    
+    let mut report = AuditReport { active: active.len(), ..Default::default() };
+    let mut selected = sample_paths(active, sample);
+    selected.sort();
+    
+    for path in selected {
+        let record = &records[&path];
+        let Some(cid) = record.storage_cid.as_ref().or(record.original_cid.as_ref()) else {
+            warn!("Active record for {} has no CID", path.display());
+            continue;
+        };
+        report.checked += 1;
+        
+        let mut reasons = Vec::new();
+        let mut available = false;
+        for endpoint in client.endpoints() {
+            match client.has_cid(endpoint, cid).await {
+                Ok(true) => {
+                    available = true;
+                    break;
+                }
+                Ok(false) => reasons.push(format!("{}: not found", endpoint)),
+                Err(e) => reasons.push(e.to_string()),
+            }
+        }
+        
+        if !available {
+            report.unavailable.push(UnavailableFile { path, cid: cid.clone(), reasons });
+        }
+    }
+    
+    Ok(report)
+}
+
+fn print_audit(report: &AuditReport) {
+    for file in &report.unavailable {
+        println!("UNAVAILABLE {} ({})", file.path.display(), file.cid);
+        for reason in &file.reasons {
+            println!("    {}", reason);
+        }
+    }
+    println!("Checked {} of {} active files: {} available, {} unavailable",
+             report.checked, report.active,
+             report.checked - report.unavailable.len(), report.unavailable.len());
+}
+
+/// How the records of two output folders differ, keyed by original path.
+#[derive(Debug, Default, Serialize)]
+pub struct RecordDiff {
+    pub only_in_output: Vec<PathBuf>,
+    pub only_in_other: Vec<PathBuf>,
+    pub changed: Vec<ChangedRecord>,
+}
+
+impl RecordDiff {
+    pub fn is_empty(&self) -> bool {
+        self.only_in_output.is_empty() && self.only_in_other.is_empty() && self.changed.is_empty()
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChangedRecord {
+    pub path: PathBuf,
+    pub fields: Vec<FieldDiff>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FieldDiff {
+    pub field: &'static str,
+    pub output: Option<String>,
+    pub other: Option<String>,
+}
+
+/// Loads the records in `folder`, in whichever structure it holds them. The
+/// configured structure is tried first.
+async fn load_records_from(config: &Config, folder: &Path) -> Result<HashMap<PathBuf, FileRecord>> {
+    let folder_config = Config { output_folder: folder.to_path_buf(), ..config.clone() };
+    let alternatives = [OutputStructure::Flattened, OutputStructure::Structured, OutputStructure::Sqlite]
+        .into_iter()
+        .filter(|structure| *structure != config.output_structure);
+    
+    let records = StorageManager::from_config(&folder_config)
+        .load_existing_records(&config.target_folder)
+        .await?;
+    if !records.is_empty() {
+        return Ok(records);
+    }
+    for alternative in alternatives {
+        let records = StorageManager::for_structure(&folder_config, alternative)
+            .load_existing_records(&config.target_folder)
+            .await?;
+        if !records.is_empty() {
+            return Ok(records);
+        }
+    }
+    Ok(HashMap::new())
+}
+
+/// Compares the status, CIDs and purchase of every record in the configured
+/// output folder with its counterpart in `other`.
+pub async fn diff_output_folders(config: &Config, other: &Path) -> Result<RecordDiff> {
+    if !other.is_dir() {
+        return Err(anyhow!("Output folder to compare against does not exist: {}", other.display()));
+    }
+    
+    let output_records = load_records_from(config, &config.output_folder).await?;
+    let other_records = load_records_from(config, other).await?;
+    
+    let mut diff = RecordDiff::default();
+    for (path, record) in &output_records {
+        let Some(other_record) = other_records.get(path) else {
+            diff.only_in_output.push(path.clone());
+            continue;
+        };
+        
+        let compared = [
+            ("status", Some(format!("{:?}", record.status)), Some(format!("{:?}", other_record.status))),
+            ("original_cid", record.original_cid.clone(), other_record.original_cid.clone()),
+            ("storage_cid", record.storage_cid.clone(), other_record.storage_cid.clone()),
+            ("purchase_id", record.purchase_id.clone(), other_record.purchase_id.clone()),
+        ];
+        let fields: Vec<FieldDiff> = compared.into_iter()
+            .filter(|(_, output, other)| output != other)
+            .map(|(field, output, other)| FieldDiff { field, output, other })
+            .collect();
+        if !fields.is_empty() {
+            diff.changed.push(ChangedRecord { path: path.clone(), fields });
+        }
+    }
+    diff.only_in_other = other_records.keys()
+        .filter(|path| !output_records.contains_key(*path))
+        .cloned()
+        .collect();
+    
+    diff.only_in_output.sort();
+    diff.only_in_other.sort();
+    diff.changed.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(diff)
+}
+
+fn print_diff(diff: &RecordDiff, output: &Path, other: &Path) {
+    if diff.is_empty() {
+        println!("{} and {} hold the same records", output.display(), other.display());
+        return;
+    }
+    
+    for path in &diff.only_in_output {
+        println!("ONLY IN {}  {}", output.display(), path.display());
+    }
+    for path in &diff.only_in_other {
+        println!("ONLY IN {}  {}", other.display(), path.display());
+    }
+    if !diff.changed.is_empty() {
+        println!("{:<14} {:<50} {:<50} PATH", "FIELD", output.display(), other.display());
+        for record in &diff.changed {
+            for field in &record.fields {
+                println!("{:<14} {:<50} {:<50} {}",
+                         field.field,
+                         field.output.as_deref().unwrap_or("-"),
+                         field.other.as_deref().unwrap_or("-"),
+                         record.path.display());
+            }
+        }
+    }
+    println!("{} only in {}, {} only in {}, {} differing",
+             diff.only_in_output.len(), output.display(),
+             diff.only_in_other.len(), other.display(),
+             diff.changed.len());
+}
+
+#[derive(Debug, Default)]
+pub struct WatchEstimate {
+    /// Directories the monitor watches recursively, from `watch_roots`.
+    pub roots: Vec<PathBuf>,
+    /// One inotify watch per directory under the roots, roots included.
+    pub watches: u64,
+    /// Directories inside a subtree matched by `exclude_patterns`. Recursive
+    /// watching still covers them, so they're part of `watches`.
+    pub excluded: u64,
+    /// The system-wide per-user limit, where the platform has one.
+    pub max_user_watches: Option<u64>,
+}
+
+/// Walks every watch root the way recursive watching would and counts the
+/// directories it would have to watch.
+pub fn estimate_watches(config: &Config) -> Result<WatchEstimate> {
+    let path_filter = PathFilter::from_config(config)?;
+    let mut estimate = WatchEstimate {
+        roots: config.watch_roots(),
+        max_user_watches: max_user_watches(),
+        ..Default::default()
+    };
+    
+    for root in &estimate.roots {
+        let mut excluded_depth = None;
+        for entry in walkdir::WalkDir::new(root) {
+            let entry = entry.map_err(|e| anyhow!("Failed to read directory entry: {}", e))?;
+            if !entry.file_type().is_dir() {
+                continue;
+            }
+            if excluded_depth.is_some_and(|depth| entry.depth() <= depth) {
+                excluded_depth = None;
+            }
+            
+            estimate.watches += 1;
+            if excluded_depth.is_none() {
+                let relative = entry.path().strip_prefix(&config.target_folder).unwrap_or(entry.path());
+                if !relative.as_os_str().is_empty() && path_filter.excludes(relative) {
+                    excluded_depth = Some(entry.depth());
+                }
+            }
+            if excluded_depth.is_some() {
+                estimate.excluded += 1;
+            }
+        }
+    }
+    
+    Ok(estimate)
+}
+
+#[cfg(target_os = "linux")]
+fn max_user_watches() -> Option<u64> {
+    std::fs::read_to_string("/proc/sys/fs/inotify/max_user_watches").ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn max_user_watches() -> Option<u64> {
+    None
+}
+
+fn print_watch_estimate(estimate: &WatchEstimate) {
+    for root in &estimate.roots {
+        println!("Watch root: {}", root.display());
+    }
+    println!("Watches needed: {}", estimate.watches);
+    if estimate.excluded > 0 {
+        println!("  of which {} are directories excluded by exclude_patterns", estimate.excluded);
+    }
+    match estimate.max_user_watches {
+        Some(limit) => println!("max_user_watches: {} ({:.1}% used by FileHog alone)",
+                                limit, estimate.watches as f64 * 100.0 / limit.max(1) as f64),
+        None => println!("max_user_watches: not available on this platform"),
+    }
+}
+
+/// How long uploading the files not yet stored would take.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UploadPlan {
+    pub files: usize,
+    pub total_bytes: u64,
+    /// Time to send `total_bytes` at the given bandwidth, which concurrent
+    /// uploads share.
+    pub upload_time: Duration,
+    /// Purchase overhead per file, overlapped across `max_concurrent_uploads`.
+    pub purchase_time: Duration,
+    pub max_concurrent_uploads: usize,
+}
+
+impl UploadPlan {
+    pub fn eta(&self) -> Duration {
+        self.upload_time + self.purchase_time
+    }
+}
+
+/// Scans the target folder like the initial scan and estimates how long
+/// uploading every file it would upload takes, without contacting Codex.
+pub async fn plan_uploads(config: &Config, upload_bytes_per_sec: u64, purchase_overhead: Duration) -> Result<UploadPlan> {
+    if upload_bytes_per_sec == 0 {
+        return Err(anyhow!("Upload bandwidth must be at least 1 byte per second"));
+    }
+    
+    let processor = FileProcessor::new(std::sync::Arc::new(config.clone()), std::sync::Arc::new(Client::from_config(config)));
+    processor.load_records().await?;
+    
+    let mut files: usize = 0;
+    let mut total_bytes: u64 = 0;
+    for file_path in processor.scan_target_folder().await? {
+        if !processor.would_upload(&file_path).await? {
+            continue;
+        }
+        let size = std::fs::metadata(&file_path)
+            .map_err(|e| anyhow!("Failed to read metadata for {}: {}", file_path.display(), e))?
+            .len();
+        files += 1;
+        total_bytes += size;
+    }
+    
+    let concurrency = config.max_concurrent_uploads.max(1);
+    let rounds = files.div_ceil(concurrency) as u32;
+    Ok(UploadPlan {
+        files,
+        total_bytes,
+        upload_time: Duration::from_secs(total_bytes.div_ceil(upload_bytes_per_sec)),
+        purchase_time: purchase_overhead * rounds,
+        max_concurrent_uploads: concurrency,
+    })
+}
+
+fn print_upload_plan(plan: &UploadPlan, upload_bytes_per_sec: u64) {
+    let format = |duration: Duration| match chrono::Duration::from_std(duration) {
+        Ok(duration) if duration >= chrono::Duration::minutes(1) => format_remaining(duration),
+        _ => format!("{}s", duration.as_secs()),
+    };
+    println!("Files to upload: {}", plan.files);
+    println!("Total size: {} bytes", plan.total_bytes);
+    println!("Upload time: {} at {} bytes/s", format(plan.upload_time), upload_bytes_per_sec);
+    println!("Purchase overhead: {} ({} files at a time)", format(plan.purchase_time), plan.max_concurrent_uploads);
+    println!("Estimated time: {}", format(plan.eta()));
+}
+
+/// What `restore` did with each record it looked at.
+#[derive(Debug, Default, Serialize)]
+pub struct RestoreReport {
+    /// Paths written, relative to the restore directory.
+    pub restored: Vec<PathBuf>,
+    /// Active records without a storage CID.
+    pub skipped: Vec<PathBuf>,
+    pub failed: Vec<RestoreFailure>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RestoreFailure {
+    pub path: PathBuf,
+    pub error: String,
+}
+
+/// Downloads the content of every active record, or only the one at
+/// `only` (relative to the target folder), into `restore_dir` under the
+/// same relative path. A failed download is reported and the rest go on.
+pub async fn restore_files(config: &Config, client: &Client, restore_dir: &Path, only: Option<&Path>) -> Result<RestoreReport> {
+    let records = StorageManager::from_config(config)
+        .load_existing_records(&config.target_folder)
+        .await?;
+    
+    let mut selected: Vec<(PathBuf, &FileRecord)> = Vec::new();
+    for (path, record) in &records {
+        let relative = path.strip_prefix(&config.target_folder)
+            .map_err(|e| anyhow!("Failed to get relative path for {}: {}", path.display(), e))?;
+        if only.is_some_and(|only| only != relative) || record.status != FileStatus::Active {
+            continue;
+        }
+        selected.push((relative.to_path_buf(), record));
+    }
+    selected.sort_by(|(a, _), (b, _)| a.cmp(b));
+    
+    if let Some(only) = only {
+        if selected.is_empty() {
+            return Err(anyhow!("No active record for {}", only.display()));
+        }
+    }
+    
+    let mut report = RestoreReport::default();
+    for (relative, record) in selected {
+        let Some(cid) = &record.storage_cid else {
+            warn!("Skipping {}: its record has no storage CID", relative.display());
+            report.skipped.push(relative);
+            continue;
+        };
+        
+        match restore_file(client, cid, &restore_dir.join(&relative)).await {
+            Ok(()) => {
+                info!("Restored {} from CID {}", relative.display(), cid);
+                report.restored.push(relative);
+            }
+            Err(e) => report.failed.push(RestoreFailure { path: relative, error: e.to_string() }),
+        }
+    }
+    
+    Ok(report)
+}
+
+/// Downloads `cid` to `destination` through a temporary file, so an
+/// interrupted restore never leaves a truncated file under the real name.
+async fn restore_file(client: &Client, cid: &str, destination: &Path) -> Result<()> {
+    let content = client.download_file(cid).await?;
+    
+    if let Some(parent) = destination.parent() {
+        tokio::fs::create_dir_all(parent).await
+            .map_err(|e| anyhow!("Failed to create directory {}: {}", parent.display(), e))?;
+    }
+    let mut temporary = destination.as_os_str().to_owned();
+    temporary.push(".restoring");
+    let temporary = PathBuf::from(temporary);
+    tokio::fs::write(&temporary, content).await
+        .map_err(|e| anyhow!("Failed to write {}: {}", temporary.display(), e))?;
+    tokio::fs::rename(&temporary, destination).await
+        .map_err(|e| anyhow!("Failed to move {} to {}: {}", temporary.display(), destination.display(), e))
+}
+
+fn print_restore(report: &RestoreReport, restore_dir: &Path) {
+    for failure in &report.failed {
+        println!("FAILED  {}: {}", failure.path.display(), failure.error);
+    }
+    println!("Restored {} files to {}, skipped {} without a storage CID, {} failed",
+             report.restored.len(), restore_dir.display(), report.skipped.len(), report.failed.len());
+}
+
+#[derive(Debug, Default)]
+pub struct RebuildReport {
+    pub events: usize,
+    pub unreadable_lines: usize,
+    /// Events for files outside the target folder, which were skipped.
+    pub outside_target: usize,
+    pub rebuilt: usize,
+}
+
+/// Replays the event log at `journal` in order, rebuilding the latest
+/// record of every file in the target folder it mentions, and saves them in
+/// the configured structure. An output folder that already has records is
+/// only written to with `replace`, and then only the records of files in
+/// the log are replaced.
+pub async fn rebuild_from_journal(config: &Config, journal: &Path, replace: bool) -> Result<RebuildReport> {
+    // The output folder may have been lost along with the records.
+    tokio::fs::create_dir_all(&config.output_folder).await
+        .map_err(|e| anyhow!("Failed to create output folder {}: {}", config.output_folder.display(), e))?;
+    let storage_manager = StorageManager::from_config(config);
+    let mut records = storage_manager.load_existing_records(&config.target_folder).await?;
+    if !records.is_empty() && !replace {
+        return Err(anyhow!("Output folder {} already has {} records; pass --replace to overwrite them with the rebuilt ones",
+                           config.output_folder.display(), records.len()));
+    }
+    
+    let (events, unreadable_lines) = event_log::read_events(journal)?;
+    let mut report = RebuildReport { events: events.len(), unreadable_lines, ..Default::default() };
+    let mut rebuilt: HashMap<PathBuf, FileRecord> = HashMap::new();
+    for event in events {
+        if !event.file_path.starts_with(&config.target_folder) {
+            report.outside_target += 1;
+            continue;
+        }
+        let record = rebuilt.entry(event.file_path.clone()).or_insert_with(|| {
+            let mut record = storage_manager.create_new_record(event.file_path.clone());
+            record.created_at = event.timestamp;
+            record
+        });
+        
+        record.status = event.new_status;
+        record.original_cid = event.cid;
+        record.purchase_id = event.purchase_id;
+        record.error = event.error;
+        record.updated_at = event.timestamp;
+        // Lines from before the log carried these have no created_at; they
+        // leave the fields as earlier lines had them.
+        if let Some(created_at) = event.created_at {
+            record.created_at = created_at;
+            record.storage_cid = event.storage_cid;
+            record.codex_endpoint = event.codex_endpoint;
+            record.file_size = event.file_size;
+            record.file_modified = event.file_modified;
+            record.content_hash = event.content_hash;
+            record.uploaded_at = event.uploaded_at;
+            record.purchased_at = event.purchased_at;
+            record.active_at = event.active_at;
+        }
+        // Likewise for lines from before the log carried the purchase
+        // settings and history.
+        if let Some(history) = event.history {
+            record.history = history;
+            record.storage_params = event.storage_params;
+            record.pending_since = event.pending_since;
+            record.expiry_minutes = event.expiry_minutes;
+            record.pinned = event.pinned;
+        }
+    }
+    
+    report.rebuilt = rebuilt.len();
+    records.extend(rebuilt);
+    storage_manager.save_all_records(&config.target_folder, &records).await?;
+    info!("Rebuilt {} records from {}", report.rebuilt, journal.display());
+    Ok(report)
+}
+
+/// How the records would fare under a shadow config compared with the
+/// current one.
+#[derive(Debug, Default, Serialize)]
+pub struct ShadowReport {
+    /// Records compared: every record except baselined ones.
+    pub records: usize,
+    /// Records whose next purchase would use different storage params.
+    pub changed_params: Vec<ShadowParamsChange>,
+    /// Stored records whose renewal would come at a different time.
+    pub changed_renewals: Vec<ShadowRenewalChange>,
+    pub current_cost_per_day: f64,
+    pub shadow_cost_per_day: f64,
+    /// Cost of purchasing every compared record once more.
+    pub current_cost_per_purchase: f64,
+    pub shadow_cost_per_purchase: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ShadowParamsChange {
+    pub path: PathBuf,
+    pub fields: Vec<ParamChange>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ParamChange {
+    pub field: &'static str,
+    pub current: String,
+    pub shadow: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ShadowRenewalChange {
+    pub path: PathBuf,
+    pub current: DateTime<Utc>,
+    pub shadow: DateTime<Utc>,
+}
+
+/// Estimated tokens per day to store `size` bytes: the price per byte per
+/// second over every slot, each holding a `1 / (nodes - tolerance)` share
+/// of the erasure-coded file.
+fn cost_per_day(params: &StorageParams, size: u64) -> Result<f64> {
+    let data_slots = params.nodes.saturating_sub(params.tolerance).max(1);
+    let stored_bytes = size as f64 * params.nodes as f64 / data_slots as f64;
+    Ok(params.price.value()? * stored_bytes * 86_400.0)
+}
+
+fn param_changes(current: &StorageParams, shadow: &StorageParams) -> Vec<ParamChange> {
+    let compared = [
+        ("price", current.price.to_string(), shadow.price.to_string()),
+        ("nodes", current.nodes.to_string(), shadow.nodes.to_string()),
+        ("tolerance", current.tolerance.to_string(), shadow.tolerance.to_string()),
+        ("proof_probability", current.proof_probability.to_string(), shadow.proof_probability.to_string()),
+        ("duration_days", current.duration_days.to_string(), shadow.duration_days.to_string()),
+        ("expiry_minutes", current.expiry_minutes.to_string(), shadow.expiry_minutes.to_string()),
+        ("collateral", current.collateral.to_string(), shadow.collateral.to_string()),
+    ];
+    compared.into_iter()
+        .filter(|(_, current, shadow)| current != shadow)
+        .map(|(field, current, shadow)| ParamChange { field, current, shadow })
+        .collect()
+}
+
+/// Compares the current records' storage params, renewal times and
+/// estimated costs under `config` and under `shadow`. Nothing is uploaded
+/// or purchased; only `config`'s output folder is read.
+pub async fn shadow_compare(config: &Config, shadow: &Config) -> Result<ShadowReport> {
+    let records = StorageManager::from_config(config)
+        .load_existing_records(&config.target_folder)
+        .await?;
+    let duration = chrono::Duration::from_std(config.duration())?;
+    
+    let mut paths: Vec<&PathBuf> = records.iter()
+        .filter(|(_, record)| record.status != FileStatus::Baseline)
+        .map(|(path, _)| path)
+        .collect();
+    paths.sort();
+    
+    let mut report = ShadowReport { records: paths.len(), ..Default::default() };
+    for path in paths {
+        let record = &records[path];
+        let relative = path.strip_prefix(&config.target_folder).unwrap_or(path);
+        let endpoint = record.codex_endpoint.as_deref();
+        let current_params = config.storage_params_for(relative, endpoint);
+        let shadow_params = shadow.storage_params_for(relative, endpoint);
+        
+        let size = record.file_size.unwrap_or(0);
+        let current_cost = cost_per_day(&current_params, size)?;
+        let shadow_cost = cost_per_day(&shadow_params, size)?;
+        report.current_cost_per_day += current_cost;
+        report.shadow_cost_per_day += shadow_cost;
+        report.current_cost_per_purchase += current_cost * current_params.duration_days as f64;
+        report.shadow_cost_per_purchase += shadow_cost * shadow_params.duration_days as f64;
+        
+        let fields = param_changes(&current_params, &shadow_params);
+        if !fields.is_empty() {
+            report.changed_params.push(ShadowParamsChange { path: path.clone(), fields });
+        }
+        
+        // A running contract keeps the duration it was bought with; only
+        // how early it is renewed depends on the config.
+        if let Some(expires_at) = record.expires_at(duration) {
+            let current = expires_at - config.renewal_lead();
+            let shadow = expires_at - shadow.renewal_lead();
+            if current != shadow {
+                report.changed_renewals.push(ShadowRenewalChange { path: path.clone(), current, shadow });
+            }
+        }
+    }
+    
+    Ok(report)
+}
+
+pub fn print_shadow_report(report: &ShadowReport, tz: chrono_tz::Tz) {
+    for change in &report.changed_params {
+        for field in &change.fields {
+            println!("PARAMS   {:<18} {:>20} -> {:<20} {}",
+                     field.field, field.current, field.shadow, change.path.display());
+        }
+    }
+    for change in &report.changed_renewals {
+        println!("RENEWAL  {} -> {}  {}",
+                 format_timestamp(change.current, tz), format_timestamp(change.shadow, tz), change.path.display());
+    }
+    
+    let delta = |current: f64, shadow: f64| {
+        if current > 0.0 {
+            format!("{:+.1}%", (shadow - current) / current * 100.0)
+        } else {
+            "n/a".to_string()
+        }
+    };
+    println!("Compared {} records: {} with different storage params, {} with a different renewal time",
+             report.records, report.changed_params.len(), report.changed_renewals.len());
+    println!("Estimated cost per day:      {:.0} -> {:.0} ({})",
+             report.current_cost_per_day, report.shadow_cost_per_day,
+             delta(report.current_cost_per_day, report.shadow_cost_per_day));
+    println!("Estimated cost per purchase: {:.0} -> {:.0} ({})",
+             report.current_cost_per_purchase, report.shadow_cost_per_purchase,
+             delta(report.current_cost_per_purchase, report.shadow_cost_per_purchase));
+}
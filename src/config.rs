@@ -1,9 +1,14 @@
-use anyhow::{anyhow, Result};
-use clap::Parser;
+use anyhow::{anyhow, Context, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use chrono::{DateTime, NaiveTime, Utc};
+use globset::{GlobBuilder, GlobMatcher};
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
+use crate::eligibility;
+
 #[derive(Parser, Debug)]
 #[command(name = "filehog")]
 #[command(about = "A tool for storing files on Codex decentralized storage")]
@@ -16,6 +21,317 @@ pub struct Args {
     
     #[arg(short, long, help = "Output folder for metadata")]
     pub output_folder: Option<PathBuf>,
+    
+    #[arg(long, help = "Record files found by the initial scan as a baseline instead of uploading them")]
+    pub baseline: bool,
+    
+    #[arg(long, help = "Re-point records written for a different target folder at the configured one")]
+    pub rebase: bool,
+    
+    #[arg(long, help = "Skip files the last interrupted initial scan already processed")]
+    pub resume: bool,
+    
+    #[arg(long, help = "Scan and log the uploads and storage requests that would be made, without contacting Codex")]
+    pub dry_run: bool,
+    
+    #[arg(long, help = "Show a live status dashboard instead of logs, which go to filehog.log in the output folder")]
+    pub tui: bool,
+    
+    #[arg(long, help = "Config profile to merge over the [default] section")]
+    pub profile: Option<String>,
+    
+    #[arg(long, help = "Upload and purchase storage again for every file, even those already active")]
+    pub force_reupload: bool,
+    
+    #[arg(long, requires = "force_reupload", help = "Only force re-upload files whose relative path matches this glob")]
+    pub pattern: Option<PathGlob>,
+    
+    #[arg(long, help = "Skip the confirmation prompt for --force-reupload")]
+    pub yes: bool,
+    
+    #[arg(long, value_name = "PATH", help = "Report how the records' costs and renewals would change under another config, without uploading or purchasing")]
+    pub shadow_config: Option<PathBuf>,
+    
+    #[arg(long, global = true, help = "Print fatal errors to stderr as a JSON object")]
+    pub json_errors: bool,
+    
+    #[command(flatten)]
+    pub storage: StorageParamsOverride,
+    
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Overrides for `storage_params`: from the command line, taking precedence
+/// over the config file, or per endpoint in `endpoint_params`.
+#[derive(clap::Args, Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[command(next_help_heading = "Storage parameters")]
+#[serde(default)]
+pub struct StorageParamsOverride {
+    #[arg(long, help = "Price per byte per second")]
+    pub price: Option<TokenAmount>,
+    
+    #[arg(long, help = "Number of storage nodes")]
+    pub nodes: Option<u32>,
+    
+    #[arg(long, help = "Number of nodes that may fail")]
+    pub tolerance: Option<u32>,
+    
+    #[arg(long, help = "Proof probability, passed to the node as written")]
+    pub proof_probability: Option<ProofProbability>,
+    
+    #[arg(long, help = "Storage duration in days")]
+    pub duration_days: Option<u32>,
+    
+    #[arg(long, help = "Minutes the storage request stays open for hosts to accept it")]
+    pub expiry_minutes: Option<u32>,
+    
+    #[arg(long, help = "Collateral required from hosts")]
+    pub collateral: Option<TokenAmount>,
+}
+
+impl StorageParamsOverride {
+    fn apply(&self, params: &mut StorageParams) {
+        if let Some(price) = &self.price {
+            params.price = price.clone();
+        }
+        if let Some(nodes) = self.nodes {
+            params.nodes = nodes;
+        }
+        if let Some(tolerance) = self.tolerance {
+            params.tolerance = tolerance;
+        }
+        if let Some(proof_probability) = &self.proof_probability {
+            params.proof_probability = proof_probability.clone();
+        }
+        if let Some(duration_days) = self.duration_days {
+            params.duration_days = duration_days;
+        }
+        if let Some(expiry_minutes) = self.expiry_minutes {
+            params.expiry_minutes = expiry_minutes;
+        }
+        if let Some(collateral) = &self.collateral {
+            params.collateral = collateral.clone();
+        }
+    }
+    
+    /// Checks the settings this override sets, naming it `label` in errors.
+    /// Whether expiry fits in duration depends on what the override is
+    /// combined with, so that's checked by `StorageParams::validate_expiry`.
+    pub fn validate(&self, label: &str) -> Result<()> {
+        if let Some(duration_days) = self.duration_days {
+            if duration_days < 1 {
+                return Err(anyhow!("Duration for {} must be at least 1 day, got: {}", label, duration_days));
+            }
+        }
+        if let Some(expiry_minutes) = self.expiry_minutes {
+            if expiry_minutes < 15 {
+                return Err(anyhow!("Expiry for {} must be at least 15 minutes, got: {}", label, expiry_minutes));
+            }
+        }
+        if let Some(price) = &self.price {
+            price.validate(&format!("Price for {}", label))?;
+        }
+        if let Some(collateral) = &self.collateral {
+            collateral.validate(&format!("Collateral for {}", label))?;
+        }
+        if let Some(proof_probability) = &self.proof_probability {
+            let value = proof_probability.value()?;
+            if !value.is_finite() || !(0.0..=100.0).contains(&value) {
+                return Err(anyhow!("Proof probability for {} must be between 0 and 100, got: {}", label, proof_probability));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Convert the records in the output folder to another output structure
+    MigrateStructure {
+        #[arg(long, value_enum, help = "Output structure to convert the records to")]
+        to: OutputStructure,
+        
+        #[arg(long, help = "Remove the records in the old structure once the conversion is verified")]
+        remove_old: bool,
+    },
+    /// Report bytes uploaded and storage requests created per day or month
+    Usage {
+        #[arg(long, help = "Only include usage on or after this date (YYYY-MM-DD)")]
+        since: Option<chrono::NaiveDate>,
+        
+        #[arg(long, help = "Group usage by month instead of by day")]
+        monthly: bool,
+    },
+    /// List active files whose storage expires within a time window
+    Expiring {
+        #[arg(long, value_parser = parse_duration, help = "Window to look ahead, e.g. 48h, 7d or 90m; a bare number means hours")]
+        within: Duration,
+    },
+    /// Check that the CID of every active file can still be fetched from at least one endpoint
+    AuditAvailability {
+        #[arg(long, default_value_t = 100.0, help = "Percentage of active files to check, chosen at random")]
+        sample: f64,
+    },
+    /// Replace the Codex endpoint stored on records, e.g. after a node moves address
+    RewriteEndpoint {
+        #[arg(long, help = "Endpoint URL currently stored on the records")]
+        from: String,
+        
+        #[arg(long, help = "Endpoint URL to store instead")]
+        to: String,
+    },
+    /// Explain whether a file will be stored, and if not, which rule excludes it
+    Why {
+        #[arg(long, help = "File to check, absolute or relative to the target folder")]
+        file: PathBuf,
+    },
+    /// Compare the records in the output folder with those in another one
+    Diff {
+        #[arg(long, help = "Output folder to compare against")]
+        other: PathBuf,
+        
+        #[arg(long, help = "Print the differences as JSON")]
+        json: bool,
+    },
+    /// Count the inotify watches watching the target folder would need
+    WatchEstimate,
+    /// Estimate how long uploading every file not yet stored would take
+    Plan {
+        #[arg(long, help = "Upload bandwidth to the Codex nodes, in bytes per second")]
+        upload_bytes_per_sec: u64,
+        
+        #[arg(long, default_value_t = 60, help = "Seconds each file spends on its storage request and waiting for the purchase to start")]
+        purchase_overhead_secs: u64,
+    },
+    /// Download the content of active files from Codex into a directory
+    Restore {
+        #[arg(long, help = "Directory to restore into, keeping each file's path relative to the target folder")]
+        to: PathBuf,
+        
+        #[arg(long, help = "Only restore this file, given relative to the target folder")]
+        only: Option<PathBuf>,
+    },
+    /// Rebuild the records in the output folder by replaying the event log
+    RebuildFromJournal {
+        #[arg(long, help = "Event log to replay [default: event_log_path]")]
+        journal: Option<PathBuf>,
+        
+        #[arg(long, help = "Replace the records of files in the log even if the output folder already has records")]
+        replace: bool,
+    },
+    /// Print a table of the records in the output folder without contacting Codex
+    Status {
+        #[arg(long, value_parser = parse_file_status, help = "Only list records with this status, e.g. Active")]
+        status: Option<crate::storage::FileStatus>,
+        
+        #[arg(long, help = "Print the records as JSON")]
+        json: bool,
+    },
+    /// Write an inventory of every stored file with its size, SHA-256, CID and purchase
+    Manifest {
+        #[arg(long, help = "File holding an ed25519 secret key as 64 hex characters to sign the manifest with")]
+        sign: Option<PathBuf>,
+        
+        #[arg(long, help = "Write the manifest to this file instead of standard output")]
+        output: Option<PathBuf>,
+        
+        #[arg(long, conflicts_with_all = ["sign", "output"], help = "Check the signature of a manifest written earlier instead")]
+        verify: Option<PathBuf>,
+    },
+}
+
+/// Parses the environment variable `name`, if set.
+fn env_value<T>(var: &impl Fn(&str) -> Option<String>, name: &str) -> Result<Option<T>>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    let Some(value) = var(name) else {
+        return Ok(None);
+    };
+    value.trim().parse().map(Some).map_err(|e| anyhow!(
+        "Failed to parse environment variable {}={:?}: {} (settings are taken from defaults, then the config file, \
+         then FILEHOG_* environment variables, then command-line flags)",
+        name, value, e
+    ))
+}
+
+/// Overlays `overlay` onto `base`, merging nested tables key by key so a
+/// profile only needs to list the settings it changes.
+fn merge_toml(base: &mut toml::Value, overlay: toml::Value) {
+    match (base, overlay) {
+        (toml::Value::Table(base), toml::Value::Table(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_toml(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// Reads shared storage parameters from a TOML or (by extension) JSON file,
+/// either as top-level keys or under a `storage_params` table.
+fn load_storage_params_file(path: &Path) -> Result<toml::Value> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read storage params file {}", path.display()))?;
+    
+    let value = if path.extension().is_some_and(|extension| extension == "json") {
+        let json: serde_json::Value = serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse storage params file {}", path.display()))?;
+        toml::Value::try_from(json)
+            .with_context(|| format!("Failed to parse storage params file {}", path.display()))?
+    } else {
+        content.parse::<toml::Value>()
+            .with_context(|| format!("Failed to parse storage params file {}", path.display()))?
+    };
+    
+    let toml::Value::Table(mut table) = value else {
+        return Err(anyhow!("Storage params file {} must contain a table", path.display()));
+    };
+    Ok(match table.remove("storage_params") {
+        Some(params) => params,
+        None => toml::Value::Table(table),
+    })
+}
+
+/// Parses a duration such as `90s`, `45m`, `48h` or `7d`. A bare number is
+/// read as hours.
+pub fn parse_duration(value: &str) -> Result<Duration> {
+    let value = value.trim();
+    let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (amount, unit) = value.split_at(split);
+    let amount: u64 = amount.parse()
+        .map_err(|_| anyhow!("Invalid duration {:?}, expected e.g. 48h or 7d (a bare number means hours)", value))?;
+    
+    let unit_seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" | "" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(anyhow!("Invalid duration unit {:?} in {:?}, expected s, m, h or d", unit, value)),
+    };
+    let seconds = amount.checked_mul(unit_seconds)
+        .ok_or_else(|| anyhow!("Duration {:?} is too long", value))?;
+    
+    Ok(Duration::from_secs(seconds))
+}
+
+/// Parses a record status by name, such as `Active` or `WaitingLong`,
+/// ignoring case.
+pub fn parse_file_status(value: &str) -> Result<crate::storage::FileStatus> {
+    let name = value.trim().to_ascii_lowercase();
+    crate::storage::FileStatus::ALL.iter()
+        .find(|status| format!("{:?}", status).to_ascii_lowercase() == name)
+        .cloned()
+        .ok_or_else(|| anyhow!("Unknown status {:?}, expected one of {}", value,
+                               crate::storage::FileStatus::ALL.iter().map(|status| format!("{:?}", status))
+                                   .collect::<Vec<_>>().join(", ")))
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -25,74 +341,898 @@ pub struct Config {
     pub output_structure: OutputStructure,
     pub codex_endpoints: Vec<String>,
     pub storage_params: StorageParams,
+    #[serde(default)]
+    pub change_detection: ChangeDetection,
+    /// Seconds after a file is stored during which a detected change is
+    /// ignored if its record has no content hash to confirm it with. 0
+    /// disables the cooldown.
+    #[serde(default)]
+    pub reprocess_cooldown_secs: u64,
+    /// When a recorded file is deleted, cancel its purchase and remove its
+    /// record.
+    #[serde(default)]
+    pub cleanup_on_delete: bool,
+    #[serde(default)]
+    pub renewal_strategy: RenewalStrategy,
+    #[serde(default = "default_renewal_buffer_minutes")]
+    pub renewal_buffer_minutes: u32,
+    #[serde(default)]
+    pub baseline: bool,
+    #[serde(default)]
+    pub stuck_purchase_timeout_minutes: Option<u32>,
+    #[serde(default)]
+    pub stuck_purchase_action: StuckPurchaseAction,
+    #[serde(default)]
+    pub compress_records: bool,
+    #[serde(default)]
+    pub trigger_on_close_write: bool,
+    #[serde(default)]
+    pub rebase: bool,
+    #[serde(default)]
+    pub max_consecutive_failures: Option<u32>,
+    #[serde(default)]
+    pub max_failure_rate: Option<f64>,
+    #[serde(default = "default_failure_rate_min_files")]
+    pub failure_rate_min_files: u32,
+    #[serde(default)]
+    pub maintenance_windows: Vec<TimeRange>,
+    #[serde(default)]
+    pub otlp_endpoint: Option<String>,
+    #[serde(default)]
+    pub cid_validation: CidValidation,
+    #[serde(default)]
+    pub overrides: Vec<PathOverride>,
+    /// Storage params for purchases made through one endpoint, keyed by its
+    /// URL, applied over `storage_params` and under per-path `overrides`.
+    #[serde(default)]
+    pub endpoint_params: BTreeMap<String, StorageParamsOverride>,
+    #[serde(default)]
+    pub shard_records: bool,
+    #[serde(default)]
+    pub record_collision: RecordCollision,
+    /// Only settable from the command line, so a config file can't leave it
+    /// switched on by accident.
+    #[serde(skip)]
+    pub force_reupload: Option<ForceReupload>,
+    #[serde(default)]
+    pub max_wait_for_start_minutes: Option<u32>,
+    #[serde(default)]
+    pub min_output_free_bytes: Option<u64>,
+    /// While the node is out of tokens, how often one deferred file is let
+    /// through to check whether storage requests succeed again.
+    #[serde(default = "default_read_only_probe_secs")]
+    pub read_only_probe_secs: u64,
+    #[serde(default)]
+    pub startup_connectivity_retries: u32,
+    #[serde(default = "default_startup_connectivity_delay_seconds")]
+    pub startup_connectivity_delay_seconds: u64,
+    #[serde(default)]
+    pub min_filled_slots: Option<u32>,
+    #[serde(default)]
+    pub resume: bool,
+    /// Log what would be uploaded and purchased instead of contacting Codex.
+    #[serde(default)]
+    pub dry_run: bool,
+    #[serde(default)]
+    pub webhook_url: Option<String>,
+    /// IANA time zone that reports print timestamps in. Records are always
+    /// stored in UTC.
+    #[serde(default = "default_display_timezone")]
+    pub display_timezone: String,
+    #[serde(default)]
+    pub persistent_queue: bool,
+    #[serde(default = "default_max_response_bytes")]
+    pub max_response_bytes: u64,
+    #[serde(default = "default_persist_interval_minutes")]
+    pub persist_interval_minutes: u32,
+    #[serde(default = "default_grace_rescan_seconds")]
+    pub grace_rescan_seconds: u64,
+    /// Pause between purchase monitoring passes while the node is answering.
+    #[serde(default = "default_purchase_poll_secs")]
+    pub purchase_poll_secs: u64,
+    /// Pause between status checks while waiting for a new purchase to start.
+    #[serde(default = "default_purchase_start_poll_secs")]
+    pub purchase_start_poll_secs: u64,
+    /// How often the monitor retries deferred files and re-scans the target
+    /// folder for files the watcher missed.
+    #[serde(default = "default_periodic_scan_secs")]
+    pub periodic_scan_secs: u64,
+    /// A file written to is only processed once no event for it has arrived
+    /// for this many seconds. 0 processes each event as it comes.
+    #[serde(default = "default_debounce_secs")]
+    pub debounce_secs: u64,
+    /// How long a shutdown waits for files already being processed.
+    #[serde(default = "default_shutdown_grace_secs")]
+    pub shutdown_grace_secs: u64,
+    /// Subdirectories of `target_folder` to watch instead of the whole
+    /// tree. Scans still cover the whole tree.
+    #[serde(default)]
+    pub watch_subpaths: Vec<PathBuf>,
+    #[serde(default)]
+    pub include_patterns: Vec<String>,
+    #[serde(default)]
+    pub exclude_patterns: Vec<String>,
+    /// Files that must never lapse, by path glob relative to the target
+    /// folder. Their uploads and renewals are retried harder and their
+    /// failures escalated.
+    #[serde(default)]
+    pub pinned_paths: Vec<String>,
+    /// Like `pinned_paths`, by original or storage CID.
+    #[serde(default)]
+    pub pinned_cids: Vec<String>,
+    /// Bearer token sent with every Codex request, for nodes behind an
+    /// authenticating proxy.
+    #[serde(default)]
+    pub auth_token: Option<String>,
+    #[serde(default)]
+    pub http_version: HttpVersion,
+    /// Limit on a whole request to a Codex node, response included, other
+    /// than the transfer of file contents.
+    #[serde(default = "default_request_timeout_secs")]
+    pub request_timeout_secs: u64,
+    /// Limit on establishing a connection to a Codex node.
+    #[serde(default = "default_connect_timeout_secs")]
+    pub connect_timeout_secs: u64,
+    /// Limit on an upload or download of file contents.
+    #[serde(default = "default_upload_timeout_secs")]
+    pub upload_timeout_secs: u64,
+    /// Where to serve Prometheus metrics on `/metrics`, if anywhere.
+    #[serde(default)]
+    pub metrics_addr: Option<std::net::SocketAddr>,
+    /// Where to serve the read-only status API, if anywhere.
+    #[serde(default)]
+    pub api_addr: Option<std::net::SocketAddr>,
+    #[serde(default)]
+    pub verify_after_upload: bool,
+    /// Append the raw purchase responses for each file to a
+    /// `<relative>.codex-debug.jsonl` file in the output folder.
+    #[serde(default)]
+    pub debug_capture: bool,
+    /// Size at which a file's debug capture stops growing.
+    #[serde(default = "default_debug_capture_max_bytes")]
+    pub debug_capture_max_bytes: u64,
+    /// Days between checks that each stored file's CID is still
+    /// retrievable. Off when unset.
+    #[serde(default)]
+    pub verify_cadence_days: Option<u32>,
+    #[serde(default)]
+    pub reupload_degraded: bool,
+    #[serde(default)]
+    pub metrics_snapshot_path: Option<PathBuf>,
+    #[serde(default = "default_metrics_snapshot_interval_seconds")]
+    pub metrics_snapshot_interval_seconds: u64,
+    /// JSON lines file every record status transition is appended to.
+    #[serde(default)]
+    pub event_log_path: Option<PathBuf>,
+    #[serde(default = "default_max_concurrent_uploads")]
+    pub max_concurrent_uploads: usize,
+    /// Assign the files of the initial scan to endpoints up front so each
+    /// endpoint gets about the same number of bytes, instead of round robin.
+    #[serde(default)]
+    pub balance_by_size: bool,
+    /// Consecutive failed requests after which an endpoint is taken out of
+    /// rotation until a probe finds it reachable.
+    #[serde(default = "default_endpoint_failure_threshold")]
+    pub endpoint_failure_threshold: u32,
+    #[serde(default = "default_min_file_size")]
+    pub min_file_size: u64,
+    #[serde(default = "default_max_file_size")]
+    pub max_file_size: u64,
+    /// Output folders listed after the first when `output_folder` is given
+    /// as a list.
+    #[serde(default)]
+    pub output_mirrors: Vec<PathBuf>,
+    /// Shared storage parameters merged under `storage_params`; already
+    /// applied by the time the config is parsed.
+    #[serde(default)]
+    pub storage_params_file: Option<PathBuf>,
 }
 
+/// Files whose existing storage is discarded and redone by this run.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ForceReupload {
+    /// Glob matched against paths relative to the target folder; all files
+    /// when unset.
+    pub pattern: Option<PathGlob>,
+}
+
+impl ForceReupload {
+    pub fn matches(&self, relative_path: &Path) -> bool {
+        self.pattern.as_ref().is_none_or(|pattern| pattern.is_match(relative_path))
+    }
+}
+
+/// Compiles a glob over relative paths in which `*` stays within one
+/// directory and `**` crosses directories.
+pub fn path_glob(pattern: &str) -> Result<GlobMatcher> {
+    GlobBuilder::new(pattern)
+        .literal_separator(true)
+        .build()
+        .map(|glob| glob.compile_matcher())
+        .map_err(|e| anyhow!("Invalid path glob {:?}: {}", pattern, e))
+}
+
+/// A `path_glob` compiled once, when the config is parsed, so an invalid
+/// pattern is a config error. Written and compared as its pattern.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PathGlob {
+    pattern: String,
+    matcher: GlobMatcher,
+}
+
+impl PathGlob {
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+    
+    pub fn is_match(&self, relative_path: &Path) -> bool {
+        self.matcher.is_match(relative_path)
+    }
+}
+
+impl std::str::FromStr for PathGlob {
+    type Err = anyhow::Error;
+    
+    fn from_str(pattern: &str) -> Result<Self> {
+        Ok(PathGlob { pattern: pattern.to_string(), matcher: path_glob(pattern)? })
+    }
+}
+
+impl TryFrom<String> for PathGlob {
+    type Error = anyhow::Error;
+    
+    fn try_from(pattern: String) -> Result<Self> {
+        pattern.parse()
+    }
+}
+
+impl From<PathGlob> for String {
+    fn from(glob: PathGlob) -> Self {
+        glob.pattern
+    }
+}
+
+impl PartialEq for PathGlob {
+    fn eq(&self, other: &Self) -> bool {
+        self.pattern == other.pattern
+    }
+}
+
+impl std::fmt::Display for PathGlob {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.pattern)
+    }
+}
+
+fn default_renewal_buffer_minutes() -> u32 {
+    60
+}
+
+fn default_failure_rate_min_files() -> u32 {
+    50
+}
+
+/// Most retries of the startup connectivity check: with the wait between
+/// them capped at a minute, about an hour and a half of waiting.
+pub const MAX_STARTUP_CONNECTIVITY_RETRIES: u32 = 100;
+
+fn default_startup_connectivity_delay_seconds() -> u64 {
+    2
+}
+
+fn default_max_response_bytes() -> u64 {
+    1024 * 1024
+}
+
+fn default_persist_interval_minutes() -> u32 {
+    60
+}
+
+fn default_grace_rescan_seconds() -> u64 {
+    5
+}
+
+fn default_purchase_poll_secs() -> u64 {
+    300
+}
+
+fn default_purchase_start_poll_secs() -> u64 {
+    5
+}
+
+fn default_periodic_scan_secs() -> u64 {
+    30
+}
+
+fn default_request_timeout_secs() -> u64 {
+    60
+}
+
+fn default_connect_timeout_secs() -> u64 {
+    10
+}
+
+fn default_upload_timeout_secs() -> u64 {
+    3600
+}
+
+fn default_debounce_secs() -> u64 {
+    2
+}
+
+fn default_read_only_probe_secs() -> u64 {
+    300
+}
+
+fn default_display_timezone() -> String {
+    "UTC".to_string()
+}
+
+fn default_debug_capture_max_bytes() -> u64 {
+    1024 * 1024
+}
+
+fn default_shutdown_grace_secs() -> u64 {
+    30
+}
+
+fn default_metrics_snapshot_interval_seconds() -> u64 {
+    60
+}
+
+fn default_max_concurrent_uploads() -> usize {
+    1
+}
+
+fn default_endpoint_failure_threshold() -> u32 {
+    3
+}
+
+fn default_min_file_size() -> u64 {
+    eligibility::MIN_FILE_SIZE
+}
+
+fn default_max_file_size() -> u64 {
+    eligibility::MAX_FILE_SIZE
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ValueEnum)]
 #[serde(rename_all = "lowercase")]
 pub enum OutputStructure {
     Flattened,
     Structured,
+    /// One row per file in a SQLite database, for trees too large to
+    /// rewrite a flattened file on every save.
+    Sqlite,
+}
+
+/// What to do when a structured record file already holds the record of a
+/// different file, such as `a.txt` and `a.bin`, which both map to `a.json`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordCollision {
+    /// Write the record to the first free `<name>~1.json`, `<name>~2.json`, ...
+    #[default]
+    Suffix,
+    /// Fail to save the record, leaving the other file's record in place.
+    Error,
+}
+
+/// How an already-stored file is checked for changes before it is skipped.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeDetection {
+    /// Compare size and modification time only. Cheapest, no file reads.
+    #[default]
+    SizeMtime,
+    /// Hash the full file contents on every check.
+    Sha256,
+    /// Compare size and mtime first, and only hash when they differ.
+    Both,
+}
+
+/// When an active purchase gets renewed.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RenewalStrategy {
+    /// Renew `renewal_buffer_minutes` before expiry. The old purchase keeps
+    /// running until it ends, so the overlap is paid twice but there is no
+    /// gap in storage.
+    #[default]
+    Eager,
+    /// Renew only once the current purchase has expired. Nothing is paid
+    /// twice, but the file is unprotected until the new purchase starts.
+    JustInTime,
+}
+
+/// What the purchase monitor does with a purchase that never leaves a
+/// non-terminal state such as `submitted` or `pending`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum StuckPurchaseAction {
+    /// Cancel the purchase, move it into the record's history and upload
+    /// and purchase again.
+    #[default]
+    Recreate,
+    /// Mark the record `Failed` and leave it for the operator.
+    Fail,
+}
+
+/// How strictly the CID returned by an upload is checked before it is
+/// recorded.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CidValidation {
+    /// Require a recognised CIDv0 or multibase CIDv1 encoding.
+    Strict,
+    /// Only check that it is a plausibly sized alphanumeric token, so CID
+    /// encodings `Strict` doesn't know still upload.
+    #[default]
+    Basic,
+    /// Record whatever the node returned.
+    Off,
+}
+
+/// Which HTTP version requests to the Codex endpoints use.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum HttpVersion {
+    /// HTTP/1.1, or HTTP/2 where TLS negotiates it.
+    #[default]
+    Auto,
+    /// Only ever HTTP/1.1, one request per connection at a time.
+    Http1,
+    /// HTTP/2 from the start, without negotiating it first.
+    Http2,
+}
+
+/// Settings for files whose path, relative to the target folder, matches the
+/// `path` glob. Later entries win over earlier ones.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PathOverride {
+    pub path: PathGlob,
+    /// Any `storage_params` settings, replacing the values they'd get otherwise.
+    #[serde(flatten)]
+    pub params: StorageParamsOverride,
+}
+
+impl PathOverride {
+    pub fn matches(&self, relative_path: &Path) -> bool {
+        self.path.is_match(relative_path)
+    }
+    
+    fn apply(&self, params: &mut StorageParams) {
+        self.params.apply(params);
+    }
+}
+
+/// A period during which no storage requests are created. Times are UTC.
+/// Either a one-off range of RFC 3339 timestamps, or a window recurring every
+/// day given as `HH:MM`, which may wrap past midnight.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum TimeRange {
+    Once { start: DateTime<Utc>, end: DateTime<Utc> },
+    Daily { start: NaiveTime, end: NaiveTime },
+}
+
+impl TimeRange {
+    /// When the window containing `now` ends, or `None` if `now` is outside it.
+    pub fn end_if_active(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            TimeRange::Once { start, end } => (*start <= now && now < *end).then_some(*end),
+            TimeRange::Daily { start, end } => {
+                let time = now.time();
+                let today = now.date_naive();
+                let active = if start <= end {
+                    *start <= time && time < *end
+                } else {
+                    time >= *start || time < *end
+                };
+                if !active {
+                    return None;
+                }
+                let end_date = if time < *end { today } else { today.succ_opt()? };
+                Some(end_date.and_time(*end).and_utc())
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StorageParams {
-    pub price: u64,
+    pub price: TokenAmount,
     pub nodes: u32,
     pub tolerance: u32,
-    pub proof_probability: u32,
+    pub proof_probability: ProofProbability,
     pub duration_days: u32,
     pub expiry_minutes: u32,
-    pub collateral: u64,
+    pub collateral: TokenAmount,
+}
+
+impl StorageParams {
+    /// Checks that the storage request expires within the storage duration,
+    /// naming the params `label` in the error.
+    fn validate_expiry(&self, label: &str) -> Result<()> {
+        if self.expiry_minutes as u64 * 60 > self.duration_days as u64 * 24 * 60 * 60 {
+            return Err(anyhow!(
+                "Expiry for {} ({} minutes) cannot be greater than its duration ({} days)",
+                label,
+                self.expiry_minutes,
+                self.duration_days
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Proof probability as written in the config. Some Codex versions take an
+/// integer and others a decimal, so the value is passed to the node exactly as
+/// the user wrote it instead of being forced through an integer.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum ProofProbability {
+    Integer(u64),
+    Decimal(f64),
+    Text(String),
+}
+
+impl std::str::FromStr for ProofProbability {
+    type Err = anyhow::Error;
+    
+    fn from_str(value: &str) -> Result<Self> {
+        let value = value.trim();
+        if let Ok(integer) = value.parse::<u64>() {
+            return Ok(ProofProbability::Integer(integer));
+        }
+        value.parse::<f64>()
+            .map(ProofProbability::Decimal)
+            .map_err(|_| anyhow!("Proof probability must be a number, got: {:?}", value))
+    }
+}
+
+impl ProofProbability {
+    pub fn value(&self) -> Result<f64> {
+        match self {
+            ProofProbability::Integer(value) => Ok(*value as f64),
+            ProofProbability::Decimal(value) => Ok(*value),
+            ProofProbability::Text(text) => text.trim().parse::<f64>()
+                .map_err(|_| anyhow!("Proof probability must be a number, got: {:?}", text)),
+        }
+    }
+}
+
+impl std::fmt::Display for ProofProbability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProofProbability::Integer(value) => write!(f, "{}", value),
+            ProofProbability::Decimal(value) => write!(f, "{}", value),
+            ProofProbability::Text(text) => write!(f, "{}", text.trim()),
+        }
+    }
+}
+
+/// A token-denominated amount such as `price` or `collateral`. Written as a
+/// string it can exceed `u64` or carry decimals, and is passed to the node
+/// verbatim.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum TokenAmount {
+    Integer(u64),
+    Text(String),
+}
+
+impl TokenAmount {
+    /// The amount as a float, for estimates; exact amounts stay strings.
+    pub fn value(&self) -> Result<f64> {
+        match self {
+            TokenAmount::Integer(value) => Ok(*value as f64),
+            TokenAmount::Text(text) => text.trim().parse::<f64>()
+                .map_err(|_| anyhow!("Token amount must be a number, got: {:?}", text)),
+        }
+    }
+    
+    pub fn validate(&self, name: &str) -> Result<()> {
+        let TokenAmount::Text(text) = self else {
+            return Ok(());
+        };
+        
+        let text = text.trim();
+        let (whole, fraction) = match text.split_once('.') {
+            Some((whole, fraction)) => (whole, Some(fraction)),
+            None => (text, None),
+        };
+        let is_digits = |part: &str| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit());
+        
+        if !is_digits(whole) || fraction.is_some_and(|fraction| !is_digits(fraction)) {
+            return Err(anyhow!(
+                "{} must be a non-negative integer or decimal, got: {:?}",
+                name,
+                text
+            ));
+        }
+        
+        Ok(())
+    }
+}
+
+/// Parses a command-line value the way the config file would read it:
+/// integers that fit stay integers, anything else is kept as text.
+impl std::str::FromStr for TokenAmount {
+    type Err = anyhow::Error;
+    
+    fn from_str(value: &str) -> Result<Self> {
+        let amount = match value.trim().parse::<u64>() {
+            Ok(value) => TokenAmount::Integer(value),
+            Err(_) => TokenAmount::Text(value.to_string()),
+        };
+        amount.validate("Amount")?;
+        Ok(amount)
+    }
+}
+
+impl std::fmt::Display for TokenAmount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TokenAmount::Integer(value) => write!(f, "{}", value),
+            TokenAmount::Text(text) => write!(f, "{}", text.trim()),
+        }
+    }
 }
 
 impl Default for StorageParams {
     fn default() -> Self {
         Self {
-            price: 1000,
+            price: TokenAmount::Integer(1000),
             nodes: 10,
             tolerance: 5,
-            proof_probability: 100,
+            proof_probability: ProofProbability::Integer(100),
             duration_days: 6,
             expiry_minutes: 60,
-            collateral: 1,
+            collateral: TokenAmount::Integer(1),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            target_folder: PathBuf::from("./target"),
+            output_folder: PathBuf::from("./output"),
+            output_structure: OutputStructure::Structured,
+            codex_endpoints: vec!["http://localhost:8080".to_string()],
+            storage_params: StorageParams::default(),
+            change_detection: ChangeDetection::default(),
+            reprocess_cooldown_secs: 0,
+            cleanup_on_delete: false,
+            renewal_strategy: RenewalStrategy::default(),
+            renewal_buffer_minutes: default_renewal_buffer_minutes(),
+            baseline: false,
+            stuck_purchase_timeout_minutes: None,
+            stuck_purchase_action: StuckPurchaseAction::default(),
+            compress_records: false,
+            trigger_on_close_write: false,
+            rebase: false,
+            max_consecutive_failures: None,
+            max_failure_rate: None,
+            failure_rate_min_files: default_failure_rate_min_files(),
+            maintenance_windows: Vec::new(),
+            otlp_endpoint: None,
+            cid_validation: CidValidation::default(),
+            overrides: Vec::new(),
+            endpoint_params: BTreeMap::new(),
+            shard_records: false,
+            record_collision: RecordCollision::default(),
+            force_reupload: None,
+            max_wait_for_start_minutes: None,
+            min_output_free_bytes: None,
+            read_only_probe_secs: default_read_only_probe_secs(),
+            startup_connectivity_retries: 0,
+            startup_connectivity_delay_seconds: default_startup_connectivity_delay_seconds(),
+            min_filled_slots: None,
+            resume: false,
+            dry_run: false,
+            webhook_url: None,
+            persistent_queue: false,
+            max_response_bytes: default_max_response_bytes(),
+            persist_interval_minutes: default_persist_interval_minutes(),
+            grace_rescan_seconds: default_grace_rescan_seconds(),
+            purchase_poll_secs: default_purchase_poll_secs(),
+            purchase_start_poll_secs: default_purchase_start_poll_secs(),
+            periodic_scan_secs: default_periodic_scan_secs(),
+            debounce_secs: default_debounce_secs(),
+            shutdown_grace_secs: default_shutdown_grace_secs(),
+            display_timezone: default_display_timezone(),
+            watch_subpaths: Vec::new(),
+            include_patterns: Vec::new(),
+            exclude_patterns: Vec::new(),
+            pinned_paths: Vec::new(),
+            pinned_cids: Vec::new(),
+            auth_token: None,
+            http_version: HttpVersion::default(),
+            request_timeout_secs: default_request_timeout_secs(),
+            connect_timeout_secs: default_connect_timeout_secs(),
+            upload_timeout_secs: default_upload_timeout_secs(),
+            metrics_addr: None,
+            api_addr: None,
+            verify_after_upload: false,
+            debug_capture: false,
+            debug_capture_max_bytes: default_debug_capture_max_bytes(),
+            verify_cadence_days: None,
+            reupload_degraded: false,
+            metrics_snapshot_path: None,
+            metrics_snapshot_interval_seconds: default_metrics_snapshot_interval_seconds(),
+            event_log_path: None,
+            max_concurrent_uploads: default_max_concurrent_uploads(),
+            balance_by_size: false,
+            endpoint_failure_threshold: default_endpoint_failure_threshold(),
+            min_file_size: default_min_file_size(),
+            max_file_size: default_max_file_size(),
+            output_mirrors: Vec::new(),
+            storage_params_file: None,
         }
     }
 }
 
 impl Config {
     pub fn load() -> Result<Self> {
-        let args = Args::parse();
+        Self::from_args(&Args::parse())
+    }
+    
+    /// Parses a config file. A file may put shared settings under `[default]`
+    /// and per-environment overrides under `[profiles.<name>]`; the chosen
+    /// profile is merged over the defaults, table by table. Files without a
+    /// `[default]` section use their top-level settings as the defaults.
+    /// A relative `storage_params_file` is resolved against the working
+    /// directory; use `from_file` to resolve it against the config file's.
+    pub fn from_toml_str(content: &str, profile: Option<&str>) -> Result<Self> {
+        Self::parse_toml(content, profile, Path::new(""))
+    }
+    
+    pub fn from_file(path: &Path, profile: Option<&str>) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        Self::parse_toml(&content, profile, path.parent().unwrap_or(Path::new("")))
+    }
+    
+    fn parse_toml(content: &str, profile: Option<&str>, base_dir: &Path) -> Result<Self> {
+        let toml::Value::Table(mut root) = content.parse::<toml::Value>()
+            .context("Failed to parse config file")? else {
+            return Err(anyhow!("Config file must be a TOML table"));
+        };
+        
+        let profiles = root.remove("profiles");
+        let mut merged = match root.remove("default") {
+            // Settings outside `[default]` would otherwise be dropped without
+            // a word, so they are rejected instead.
+            Some(_) if !root.is_empty() => {
+                let stray: Vec<&str> = root.keys().map(String::as_str).collect();
+                return Err(anyhow!("Config file has settings outside [default]: {}; move them under [default]",
+                                   stray.join(", ")));
+            }
+            Some(default) => default,
+            None => toml::Value::Table(root),
+        };
         
-        let config = if let Some(config_path) = args.config {
-            let config_str = std::fs::read_to_string(&config_path)
-                .map_err(|e| anyhow!("Failed to read config file {}: {}", config_path.display(), e))?;
-            toml::from_str(&config_str)
-                .map_err(|e| anyhow!("Failed to parse config file: {}", e))?
+        if let Some(name) = profile {
+            let overrides = profiles.as_ref()
+                .and_then(|profiles| profiles.get(name))
+                .ok_or_else(|| anyhow!("Profile {:?} not found in config file", name))?;
+            merge_toml(&mut merged, overrides.clone());
+        }
+        
+        // `output_folder` may list several folders: the first is where records
+        // are read from and the rest mirror it.
+        if let toml::Value::Table(table) = &mut merged {
+            if let Some(toml::Value::Array(folders)) = table.get("output_folder").cloned() {
+                let mut folders = folders.into_iter();
+                let primary = folders.next()
+                    .ok_or_else(|| anyhow!("output_folder must list at least one folder"))?;
+                table.insert("output_folder".to_string(), primary);
+                table.insert("output_mirrors".to_string(), toml::Value::Array(folders.collect()));
+            }
+            
+            if let Some(file) = table.get("storage_params_file").cloned() {
+                let file = file.as_str()
+                    .ok_or_else(|| anyhow!("storage_params_file must be a path"))?;
+                let path = base_dir.join(file);
+                let mut params = load_storage_params_file(&path)?;
+                if let Some(inline) = table.remove("storage_params") {
+                    merge_toml(&mut params, inline);
+                }
+                table.insert("storage_params".to_string(), params);
+                table.insert("storage_params_file".to_string(), toml::Value::String(path.display().to_string()));
+            }
+        }
+        
+        merged.try_into()
+            .context("Failed to parse config file")
+    }
+    
+    /// Builds the config from defaults, then the config file, then `FILEHOG_*`
+    /// environment variables, then command-line flags, each taking precedence
+    /// over the ones before.
+    pub fn from_args(args: &Args) -> Result<Self> {
+        Self::from_args_and_env(args, |name| std::env::var(name).ok())
+    }
+    
+    /// Like `from_args`, reading environment variables through `var`.
+    pub fn from_args_and_env(args: &Args, var: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let config = if let Some(config_path) = &args.config {
+            Self::from_file(config_path, args.profile.as_deref())?
+        } else if let Some(profile) = &args.profile {
+            return Err(anyhow!("Profile {:?} requires a config file", profile));
         } else {
-            Self::default_config()
+            Self::default()
         };
         
         let mut final_config = config;
+        final_config.apply_env(var)?;
         
-        if let Some(target) = args.target_folder {
-            final_config.target_folder = target;
+        if let Some(target) = &args.target_folder {
+            final_config.target_folder = target.clone();
         }
         
-        if let Some(output) = args.output_folder {
-            final_config.output_folder = output;
+        if let Some(output) = &args.output_folder {
+            final_config.output_folder = output.clone();
         }
         
+        if args.baseline {
+            final_config.baseline = true;
+        }
+        
+        if args.rebase {
+            final_config.rebase = true;
+        }
+        
+        if args.resume {
+            final_config.resume = true;
+        }
+        
+        if args.dry_run {
+            final_config.dry_run = true;
+        }
+        
+        if args.force_reupload {
+            final_config.force_reupload = Some(ForceReupload { pattern: args.pattern.clone() });
+        }
+        
+        args.storage.apply(&mut final_config.storage_params);
+        
         Ok(final_config)
     }
     
-    fn default_config() -> Self {
-        Self {
-            target_folder: PathBuf::from("./target"),
-            output_folder: PathBuf::from("./output"),
-            output_structure: OutputStructure::Structured,
-            codex_endpoints: vec!["http://localhost:8080".to_string()],
-            storage_params: StorageParams::default(),
+    /// Overrides settings with the `FILEHOG_*` environment variables that
+    /// are set.
+    fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<()> {
+        if let Some(target) = var("FILEHOG_TARGET_FOLDER") {
+            self.target_folder = PathBuf::from(target);
+        }
+        if let Some(output) = var("FILEHOG_OUTPUT_FOLDER") {
+            self.output_folder = PathBuf::from(output);
+        }
+        if let Some(endpoints) = var("FILEHOG_CODEX_ENDPOINTS") {
+            self.codex_endpoints = endpoints.split(',')
+                .map(|endpoint| endpoint.trim().to_string())
+                .filter(|endpoint| !endpoint.is_empty())
+                .collect();
         }
+        if let Some(token) = var("FILEHOG_AUTH_TOKEN") {
+            self.auth_token = Some(token);
+        }
+        
+        let storage = StorageParamsOverride {
+            price: env_value(&var, "FILEHOG_PRICE")?,
+            nodes: env_value(&var, "FILEHOG_NODES")?,
+            tolerance: env_value(&var, "FILEHOG_TOLERANCE")?,
+            proof_probability: env_value(&var, "FILEHOG_PROOF_PROBABILITY")?,
+            duration_days: env_value(&var, "FILEHOG_DURATION_DAYS")?,
+            expiry_minutes: env_value(&var, "FILEHOG_EXPIRY_MINUTES")?,
+            collateral: env_value(&var, "FILEHOG_COLLATERAL")?,
+        };
+        storage.apply(&mut self.storage_params);
+        Ok(())
     }
     
     pub fn validate(&self) -> Result<()> {
@@ -131,6 +1271,17 @@ impl Config {
             ));
         }
         
+        self.storage_params.price.validate("Price")?;
+        self.storage_params.collateral.validate("Collateral")?;
+        
+        let proof_probability = self.storage_params.proof_probability.value()?;
+        if !proof_probability.is_finite() || !(0.0..=100.0).contains(&proof_probability) {
+            return Err(anyhow!(
+                "Proof probability must be between 0 and 100, got: {}",
+                self.storage_params.proof_probability
+            ));
+        }
+        
         let duration_minutes = self.storage_params.duration_days * 24 * 60;
         if self.storage_params.expiry_minutes > duration_minutes {
             return Err(anyhow!(
@@ -140,6 +1291,197 @@ impl Config {
             ));
         }
         
+        for (endpoint, endpoint_override) in &self.endpoint_params {
+            let normalized = crate::codex::normalize_endpoint(endpoint);
+            if !self.codex_endpoints.iter().any(|configured| crate::codex::normalize_endpoint(configured) == normalized) {
+                return Err(anyhow!("endpoint_params lists {}, which is not one of codex_endpoints", endpoint));
+            }
+            endpoint_override.validate(&format!("endpoint {}", endpoint))?;
+        }
+        
+        for path_override in &self.overrides {
+            path_override.params.validate(&format!("override {:?}", path_override.path.as_str()))?;
+        }
+        
+        // A file gets the params of its endpoint with its path overrides
+        // on top, so check each endpoint with each override.
+        let endpoints = std::iter::once(None).chain(self.endpoint_params.iter().map(Some));
+        let mut longest_expiry = (self.storage_params.expiry_minutes, "expiry_minutes".to_string());
+        for endpoint in endpoints {
+            let mut endpoint_params = self.storage_params.clone();
+            if let Some((_, endpoint_override)) = endpoint {
+                endpoint_override.apply(&mut endpoint_params);
+            }
+            let endpoint_label = endpoint.map(|(endpoint, _)| format!("endpoint {}", endpoint));
+            if let Some(label) = &endpoint_label {
+                endpoint_params.validate_expiry(label)?;
+                if endpoint_params.expiry_minutes > longest_expiry.0 {
+                    longest_expiry = (endpoint_params.expiry_minutes, format!("expiry_minutes of {}", label));
+                }
+            }
+            for path_override in &self.overrides {
+                let mut params = endpoint_params.clone();
+                path_override.apply(&mut params);
+                let label = match &endpoint_label {
+                    Some(endpoint_label) => format!("override {:?} on {}", path_override.path.as_str(), endpoint_label),
+                    None => format!("override {:?}", path_override.path.as_str()),
+                };
+                params.validate_expiry(&label)?;
+                if params.expiry_minutes > longest_expiry.0 {
+                    longest_expiry = (params.expiry_minutes, format!("expiry_minutes of {}", label));
+                }
+            }
+        }
+        
+        if self.renewal_buffer_minutes > duration_minutes {
+            return Err(anyhow!(
+                "Renewal buffer ({} minutes) cannot be greater than duration ({} minutes)",
+                self.renewal_buffer_minutes,
+                duration_minutes
+            ));
+        }
+        
+        // Overrides can lengthen the expiry window, and a purchase must be
+        // able to wait out the longest one.
+        if let Some(max_wait) = self.max_wait_for_start_minutes {
+            let (expiry_minutes, expiry_label) = &longest_expiry;
+            if max_wait < *expiry_minutes {
+                return Err(anyhow!(
+                    "max_wait_for_start_minutes ({}) cannot be less than {} ({})",
+                    max_wait,
+                    expiry_label,
+                    expiry_minutes
+                ));
+            }
+        }
+        
+        if let Some(min_filled_slots) = self.min_filled_slots {
+            if min_filled_slots == 0 || min_filled_slots > self.storage_params.nodes {
+                return Err(anyhow!(
+                    "min_filled_slots must be between 1 and nodes ({}), got: {}",
+                    self.storage_params.nodes,
+                    min_filled_slots
+                ));
+            }
+        }
+        
+        if self.stuck_purchase_timeout_minutes == Some(0) {
+            return Err(anyhow!("Stuck purchase timeout must be at least 1 minute"));
+        }
+        
+        if self.max_consecutive_failures == Some(0) {
+            return Err(anyhow!("max_consecutive_failures must be at least 1"));
+        }
+        
+        if let Some(rate) = self.max_failure_rate {
+            if !(0.0..=1.0).contains(&rate) {
+                return Err(anyhow!("max_failure_rate must be between 0 and 1, got: {}", rate));
+            }
+        }
+        
+        for window in &self.maintenance_windows {
+            if let TimeRange::Once { start, end } = window {
+                if start >= end {
+                    return Err(anyhow!("Maintenance window starting {} must end after it starts", start));
+                }
+            }
+        }
+        
+        eligibility::PathFilter::from_config(self)?;
+        eligibility::PinnedFiles::from_config(self)?;
+        
+        for subpath in &self.watch_subpaths {
+            let escapes = subpath.components().any(|c| !matches!(c, std::path::Component::Normal(_)));
+            if subpath.as_os_str().is_empty() || escapes {
+                return Err(anyhow!(
+                    "watch_subpaths entry {} must be a relative path inside the target folder",
+                    subpath.display()
+                ));
+            }
+        }
+        
+        for mirror in &self.output_mirrors {
+            if *mirror == self.target_folder || *mirror == self.output_folder {
+                return Err(anyhow!(
+                    "Mirror output folder {} must differ from the target and primary output folders",
+                    mirror.display()
+                ));
+            }
+        }
+        
+        if let Some(path) = &self.metrics_snapshot_path {
+            if self.metrics_snapshot_interval_seconds == 0 {
+                return Err(anyhow!("metrics_snapshot_interval_seconds must be greater than 0"));
+            }
+            // Inside the target folder the snapshot would be uploaded every
+            // interval; inside structured output it would be read as a record.
+            if path.starts_with(&self.target_folder) {
+                return Err(anyhow!("metrics_snapshot_path {} must be outside the target folder", path.display()));
+            }
+            if self.output_structure == OutputStructure::Structured
+                && std::iter::once(&self.output_folder).chain(&self.output_mirrors).any(|folder| path.starts_with(folder))
+            {
+                return Err(anyhow!("metrics_snapshot_path {} must be outside the output folders with the structured output structure",
+                                   path.display()));
+            }
+        }
+        
+        if self.verify_cadence_days == Some(0) {
+            return Err(anyhow!("verify_cadence_days must be at least 1"));
+        }
+        
+        if self.startup_connectivity_retries > MAX_STARTUP_CONNECTIVITY_RETRIES {
+            return Err(anyhow!("startup_connectivity_retries must be at most {}, got: {}",
+                               MAX_STARTUP_CONNECTIVITY_RETRIES, self.startup_connectivity_retries));
+        }
+        
+        if self.max_concurrent_uploads == 0 {
+            return Err(anyhow!("max_concurrent_uploads must be at least 1"));
+        }
+        
+        for (name, seconds) in [
+            ("purchase_poll_secs", self.purchase_poll_secs),
+            ("purchase_start_poll_secs", self.purchase_start_poll_secs),
+            ("periodic_scan_secs", self.periodic_scan_secs),
+            ("request_timeout_secs", self.request_timeout_secs),
+            ("connect_timeout_secs", self.connect_timeout_secs),
+            ("upload_timeout_secs", self.upload_timeout_secs),
+        ] {
+            if seconds == 0 {
+                return Err(anyhow!("{} must be greater than 0", name));
+            }
+        }
+        
+        if self.display_timezone.parse::<chrono_tz::Tz>().is_err() {
+            return Err(anyhow!("Unknown display_timezone {:?}; expected an IANA name such as \"Europe/Berlin\"",
+                               self.display_timezone));
+        }
+        
+        if self.endpoint_failure_threshold == 0 {
+            return Err(anyhow!("endpoint_failure_threshold must be at least 1"));
+        }
+        
+        if self.min_file_size >= self.max_file_size {
+            return Err(anyhow!(
+                "min_file_size ({}) must be less than max_file_size ({})",
+                self.min_file_size, self.max_file_size
+            ));
+        }
+        
+        if self.max_response_bytes == 0 {
+            return Err(anyhow!("max_response_bytes must be greater than 0"));
+        }
+        
+        if let Some(url) = &self.webhook_url {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                return Err(anyhow!("webhook_url must be an http:// or https:// URL, got '{}'", url));
+            }
+        }
+        
+        if self.otlp_endpoint.is_some() && !cfg!(feature = "otlp") {
+            return Err(anyhow!("otlp_endpoint is set but FileHog was built without the otlp feature"));
+        }
+        
         if self.codex_endpoints.is_empty() {
             return Err(anyhow!("At least one Codex endpoint must be provided"));
         }
@@ -154,6 +1496,12 @@ impl Config {
         Ok(())
     }
     
+    /// The zone reports print timestamps in; UTC if `display_timezone`
+    /// doesn't name one.
+    pub fn display_tz(&self) -> chrono_tz::Tz {
+        self.display_timezone.parse().unwrap_or(chrono_tz::UTC)
+    }
+    
     pub fn duration(&self) -> Duration {
         Duration::from_secs(self.storage_params.duration_days as u64 * 24 * 60 * 60)
     }
@@ -161,4 +1509,50 @@ impl Config {
     pub fn expiry(&self) -> Duration {
         Duration::from_secs(self.storage_params.expiry_minutes as u64 * 60)
     }
-}
\ No newline at end of file
+    
+    /// The storage params for a file purchased through `endpoint`: the
+    /// endpoint's `endpoint_params`, then every matching path override,
+    /// applied on top of `storage_params`.
+    pub fn storage_params_for(&self, relative_path: &Path, endpoint: Option<&str>) -> StorageParams {
+        let mut params = self.storage_params.clone();
+        if let Some(endpoint_override) = endpoint.and_then(|endpoint| self.endpoint_override(endpoint)) {
+            endpoint_override.apply(&mut params);
+        }
+        for path_override in self.overrides.iter().filter(|o| o.matches(relative_path)) {
+            path_override.apply(&mut params);
+        }
+        params
+    }
+    
+    fn endpoint_override(&self, endpoint: &str) -> Option<&StorageParamsOverride> {
+        let endpoint = crate::codex::normalize_endpoint(endpoint);
+        self.endpoint_params.iter()
+            .find(|(key, _)| crate::codex::normalize_endpoint(key) == endpoint)
+            .map(|(_, params)| params)
+    }
+    
+    /// The directories the file watcher watches recursively: the target
+    /// folder, or each of `watch_subpaths` under it.
+    pub fn watch_roots(&self) -> Vec<PathBuf> {
+        if self.watch_subpaths.is_empty() {
+            vec![self.target_folder.clone()]
+        } else {
+            self.watch_subpaths.iter().map(|subpath| self.target_folder.join(subpath)).collect()
+        }
+    }
+    
+    /// How long before expiry a purchase is due for renewal.
+    pub fn renewal_lead(&self) -> chrono::Duration {
+        match self.renewal_strategy {
+            RenewalStrategy::Eager => chrono::Duration::minutes(self.renewal_buffer_minutes as i64),
+            RenewalStrategy::JustInTime => chrono::Duration::zero(),
+        }
+    }
+    
+    /// If `now` falls in a maintenance window, when the latest active one ends.
+    pub fn maintenance_window_end(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.maintenance_windows.iter()
+            .filter_map(|window| window.end_if_active(now))
+            .max()
+    }
+}
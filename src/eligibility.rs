@@ -0,0 +1,184 @@
+use anyhow::{anyhow, Result};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use std::collections::HashSet;
+use std::fmt;
+use std::path::Path;
+
+use crate::config::Config;
+
+/// Default for `min_file_size`, the smallest file Codex will accept.
+pub const MIN_FILE_SIZE: u64 = 1024 * 1024;
+
+/// Default for `max_file_size`.
+pub const MAX_FILE_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Whether the scan and the file watcher will pick up a path, and if not, the
+/// rule that rules it out.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Eligibility {
+    Eligible,
+    NotAFile,
+    /// File size and the configured minimum.
+    TooSmall(u64, u64),
+    /// File size and the configured maximum.
+    TooLarge(u64, u64),
+}
+
+impl fmt::Display for Eligibility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Eligibility::Eligible => write!(f, "eligible"),
+            Eligibility::NotAFile => write!(f, "not a regular file"),
+            Eligibility::TooSmall(size, min) => write!(f, "too small: {} bytes, minimum is {} bytes", size, min),
+            Eligibility::TooLarge(size, max) => write!(f, "too large: {} bytes, maximum is {} bytes", size, max),
+        }
+    }
+}
+
+/// `include_patterns` and `exclude_patterns`, compiled. A pattern without a
+/// `/` matches the file name at any depth; otherwise it is matched against
+/// the whole path relative to the target folder.
+#[derive(Debug, Clone, Default)]
+pub struct PathFilter {
+    include: Option<GlobSet>,
+    exclude: Option<GlobSet>,
+}
+
+impl PathFilter {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        Ok(Self {
+            include: compile_patterns(&config.include_patterns)?,
+            exclude: compile_patterns(&config.exclude_patterns)?,
+        })
+    }
+    
+    /// Whether a path relative to the target folder passes the filters. An
+    /// exclude match wins over an include match, and with no include
+    /// patterns everything not excluded passes.
+    pub fn allows(&self, relative_path: &Path) -> bool {
+        if self.excludes(relative_path) {
+            return false;
+        }
+        self.include.as_ref().is_none_or(|include| include.is_match(relative_path))
+    }
+    
+    /// Whether an exclude pattern matches a path relative to the target
+    /// folder.
+    pub fn excludes(&self, relative_path: &Path) -> bool {
+        self.exclude.as_ref().is_some_and(|exclude| exclude.is_match(relative_path))
+    }
+}
+
+/// Files that must never lapse: those matching `pinned_paths`, written like
+/// `include_patterns`, or stored under a CID in `pinned_cids`.
+#[derive(Debug, Clone, Default)]
+pub struct PinnedFiles {
+    paths: Option<GlobSet>,
+    cids: HashSet<String>,
+}
+
+impl PinnedFiles {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        Ok(Self {
+            paths: compile_patterns(&config.pinned_paths)?,
+            cids: config.pinned_cids.iter().map(|cid| cid.trim().to_string()).collect(),
+        })
+    }
+    
+    /// Whether a file at `relative_path`, stored under `cids`, is pinned.
+    pub fn matches<'a>(&self, relative_path: &Path, mut cids: impl Iterator<Item = &'a str>) -> bool {
+        self.paths.as_ref().is_some_and(|paths| paths.is_match(relative_path))
+            || cids.any(|cid| self.cids.contains(cid))
+    }
+}
+
+fn compile_patterns(patterns: &[String]) -> Result<Option<GlobSet>> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let anywhere;
+        let full = if pattern.contains('/') {
+            pattern.as_str()
+        } else {
+            anywhere = format!("**/{}", pattern);
+            anywhere.as_str()
+        };
+        let glob = GlobBuilder::new(full)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| anyhow!("Invalid path glob {:?}: {}", pattern, e))?;
+        builder.add(glob);
+    }
+    builder.build()
+        .map(Some)
+        .map_err(|e| anyhow!("Failed to compile path globs: {}", e))
+}
+
+/// What a path reported by the file watcher turned out to be.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PathKind {
+    File,
+    Directory,
+    /// A FIFO, socket, device or anything else that isn't a regular file.
+    Special(&'static str),
+    /// Gone by the time the event was handled, e.g. a short-lived temp file.
+    Vanished,
+}
+
+/// Classifies `path`, following symlinks. Only unexpected metadata failures
+/// are errors; a path that no longer exists is `Vanished`.
+pub fn classify(path: &Path) -> Result<PathKind> {
+    let metadata = match path.metadata() {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(PathKind::Vanished),
+        Err(e) => return Err(anyhow!("Failed to get metadata for {}: {}", path.display(), e)),
+    };
+    
+    let file_type = metadata.file_type();
+    if file_type.is_file() {
+        return Ok(PathKind::File);
+    }
+    if file_type.is_dir() {
+        return Ok(PathKind::Directory);
+    }
+    
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        
+        if file_type.is_fifo() {
+            return Ok(PathKind::Special("FIFO"));
+        }
+        if file_type.is_socket() {
+            return Ok(PathKind::Special("socket"));
+        }
+        if file_type.is_block_device() || file_type.is_char_device() {
+            return Ok(PathKind::Special("device"));
+        }
+    }
+    
+    Ok(PathKind::Special("special file"))
+}
+
+/// Applies every eligibility rule to `path`, in the order the scan does.
+pub fn check(config: &Config, path: &Path) -> Result<Eligibility> {
+    let metadata = path.metadata()
+        .map_err(|e| anyhow!("Failed to get metadata for {}: {}", path.display(), e))?;
+    
+    if !metadata.is_file() {
+        return Ok(Eligibility::NotAFile);
+    }
+    
+    let file_size = metadata.len();
+    if file_size < config.min_file_size {
+        return Ok(Eligibility::TooSmall(file_size, config.min_file_size));
+    }
+    if file_size > config.max_file_size {
+        return Ok(Eligibility::TooLarge(file_size, config.max_file_size));
+    }
+    
+    Ok(Eligibility::Eligible)
+}
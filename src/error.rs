@@ -1,9 +1,113 @@
 use anyhow::Result;
 use log::error;
+use rand::Rng;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
+use crate::codex::{InsufficientTokens, NoHealthyEndpoint, RequestTimedOut};
+
+static JSON_ERRORS: AtomicBool = AtomicBool::new(false);
+
+/// Makes fatal errors also print a JSON object to stderr (`--json-errors`).
+pub fn enable_json_errors() {
+    JSON_ERRORS.store(true, Ordering::Relaxed);
+}
+
+pub fn json_errors_enabled() -> bool {
+    JSON_ERRORS.load(Ordering::Relaxed)
+}
+
+/// Stable identifier for the kind of failure, taken from the first error in
+/// the chain whose type is recognised. Errors only known by their message
+/// are reported as `"error"`.
+pub fn error_code(error: &anyhow::Error) -> &'static str {
+    for cause in error.chain() {
+        if cause.is::<NoHealthyEndpoint>() {
+            return "no_healthy_endpoint";
+        }
+        if cause.is::<InsufficientTokens>() {
+            return "insufficient_tokens";
+        }
+        if cause.is::<RequestTimedOut>() {
+            return "timeout";
+        }
+        if cause.is::<reqwest::Error>() {
+            return "network";
+        }
+        if cause.is::<std::io::Error>() {
+            return "io";
+        }
+        if cause.is::<toml::de::Error>() || cause.is::<serde_json::Error>() {
+            return "parse";
+        }
+    }
+    "error"
+}
+
+/// `{"error": ..., "context": [...], "code": ...}` for a fatal error, where
+/// `context` lists the causes under the top-level message, outermost first.
+pub fn error_json(error: &anyhow::Error) -> serde_json::Value {
+    serde_json::json!({
+        "error": error.to_string(),
+        "context": error.chain().skip(1).map(|cause| cause.to_string()).collect::<Vec<_>>(),
+        "code": error_code(error),
+    })
+}
+
+/// Prints `error_json` to stderr if `--json-errors` is on.
+pub fn report_fatal(error: &anyhow::Error) {
+    if json_errors_enabled() {
+        eprintln!("{}", error_json(error));
+    }
+}
+
+/// How often and how patiently a failing operation is retried.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts after the first.
+    pub max_retries: u32,
+    /// Pause before the first retry; it doubles after every failed attempt.
+    pub initial_delay: Duration,
+    /// Longest pause between attempts, jitter included. Uncapped when unset.
+    pub max_delay: Option<Duration>,
+    /// Fraction by which each pause is randomly shortened or lengthened, so
+    /// clients that failed together don't retry in lockstep. 0.5 is ±50%.
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_delay: Duration::from_secs(1),
+            max_delay: Some(Duration::from_secs(30)),
+            jitter: 0.5,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The pause after failed attempt `attempt` (0-based).
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let base = self.initial_delay.as_secs_f64() * 2f64.powi(attempt.min(63) as i32);
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let factor = if jitter > 0.0 {
+            rand::thread_rng().gen_range(1.0 - jitter..=1.0 + jitter)
+        } else {
+            1.0
+        };
+        let mut delay = base * factor;
+        if let Some(max_delay) = self.max_delay {
+            delay = delay.min(max_delay.as_secs_f64());
+        }
+        Duration::try_from_secs_f64(delay).unwrap_or(Duration::MAX)
+    }
+}
+
+/// Retries with the default policy's jittered, capped backoff.
 pub async fn retry_with_backoff<F, Fut, T, E>(
-    mut operation: F,
+    operation: F,
     operation_name: &str,
     max_retries: u32,
 ) -> Result<T, E>
@@ -12,6 +116,58 @@ where
     Fut: std::future::Future<Output = Result<T, E>>,
     E: std::fmt::Display,
 {
+    let policy = RetryPolicy { max_retries, ..Default::default() };
+    retry_with_policy(operation, operation_name, &policy).await
+}
+
+/// Like `retry_with_backoff`, but starting from `initial_delay`, which
+/// doubles after every failed attempt, exactly, up to `max_delay` or
+/// `initial_delay` if that is longer.
+pub async fn retry_with_delay<F, Fut, T, E>(
+    operation: F,
+    operation_name: &str,
+    max_retries: u32,
+    initial_delay: Duration,
+    max_delay: Duration,
+) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T, E>>,
+    E: std::fmt::Display,
+{
+    let policy = RetryPolicy { max_retries, initial_delay, max_delay: Some(max_delay.max(initial_delay)), jitter: 0.0 };
+    retry_with_policy(operation, operation_name, &policy).await
+}
+
+/// Runs `operation` until it succeeds or `policy.max_retries` retries have
+/// failed, returning the last error.
+pub async fn retry_with_policy<F, Fut, T, E>(
+    operation: F,
+    operation_name: &str,
+    policy: &RetryPolicy,
+) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T, E>>,
+    E: std::fmt::Display,
+{
+    retry_unless(operation, operation_name, policy, |_| false).await
+}
+
+/// Like `retry_with_policy`, but gives up at once on an error `permanent`
+/// accepts, one that retrying can't fix.
+pub async fn retry_unless<F, Fut, T, E>(
+    mut operation: F,
+    operation_name: &str,
+    policy: &RetryPolicy,
+    permanent: impl Fn(&E) -> bool,
+) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T, E>>,
+    E: std::fmt::Display,
+{
+    let max_retries = policy.max_retries;
     let mut last_error = None;
     
     for attempt in 0..=max_retries {
@@ -20,11 +176,13 @@ where
             Err(err) => {
                 error!("Attempt {} of {} failed for {}: {}", 
                        attempt + 1, max_retries + 1, operation_name, err);
+                if permanent(&err) {
+                    return Err(err);
+                }
                 last_error = Some(err);
                 
                 if attempt < max_retries {
-                    let delay = std::time::Duration::from_secs(2_u64.pow(attempt));
-                    tokio::time::sleep(delay).await;
+                    tokio::time::sleep(policy.delay(attempt)).await;
                 }
             }
         }
@@ -33,8 +191,65 @@ where
     Err(last_error.unwrap())
 }
 
-pub fn crash_with_error(message: &str) -> ! {
-    error!("FATAL ERROR: {}", message);
+/// Trips when a batch is failing badly enough that continuing is pointless:
+/// too many failures in a row, or too high a failure rate once enough files
+/// have been tried that a few scattered failures can't set it off.
+#[derive(Debug, Clone)]
+pub struct FailureCircuit {
+    max_consecutive_failures: Option<u32>,
+    max_failure_rate: Option<f64>,
+    min_files: u32,
+    attempts: u32,
+    failures: u32,
+    consecutive_failures: u32,
+}
+
+impl FailureCircuit {
+    pub fn new(config: &crate::config::Config) -> Self {
+        Self {
+            max_consecutive_failures: config.max_consecutive_failures,
+            max_failure_rate: config.max_failure_rate,
+            min_files: config.failure_rate_min_files,
+            attempts: 0,
+            failures: 0,
+            consecutive_failures: 0,
+        }
+    }
+    
+    pub fn record_success(&mut self) {
+        self.attempts += 1;
+        self.consecutive_failures = 0;
+    }
+    
+    /// Counts a failure and returns why the circuit tripped, if it did.
+    pub fn record_failure(&mut self) -> Option<String> {
+        self.attempts += 1;
+        self.failures += 1;
+        self.consecutive_failures += 1;
+        
+        if let Some(max) = self.max_consecutive_failures {
+            if self.consecutive_failures >= max {
+                return Some(format!("{} consecutive files failed", self.consecutive_failures));
+            }
+        }
+        
+        if let Some(max_rate) = self.max_failure_rate {
+            let rate = self.failures as f64 / self.attempts as f64;
+            if self.attempts >= self.min_files && rate > max_rate {
+                return Some(format!(
+                    "{} of {} files failed ({:.0}%, limit {:.0}%)",
+                    self.failures, self.attempts, rate * 100.0, max_rate * 100.0
+                ));
+            }
+        }
+        
+        None
+    }
+}
+
+pub fn crash_with_error(error: &anyhow::Error) -> ! {
+    error!("FATAL ERROR: {:#}", error);
+    report_fatal(error);
     std::process::exit(1);
 }
 
@@ -0,0 +1,171 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use crate::config::StorageParams;
+use crate::storage::{FileRecord, FileStatus, HistoryEntry};
+
+/// One status transition, written as a JSON line to `event_log_path`.
+/// Carries enough of the record for `rebuild-from-journal` to restore it.
+#[derive(Debug, Clone, Serialize)]
+pub struct StatusEvent<'a> {
+    pub timestamp: DateTime<Utc>,
+    pub file_path: &'a Path,
+    pub old_status: &'a FileStatus,
+    pub new_status: &'a FileStatus,
+    pub cid: Option<&'a str>,
+    pub purchase_id: Option<&'a str>,
+    pub error: Option<&'a str>,
+    pub storage_cid: Option<&'a str>,
+    pub codex_endpoint: Option<&'a str>,
+    pub created_at: DateTime<Utc>,
+    pub file_size: Option<u64>,
+    pub file_modified: Option<DateTime<Utc>>,
+    pub content_hash: Option<&'a str>,
+    pub uploaded_at: Option<DateTime<Utc>>,
+    pub purchased_at: Option<DateTime<Utc>>,
+    pub active_at: Option<DateTime<Utc>>,
+    pub storage_params: Option<&'a StorageParams>,
+    pub pending_since: Option<DateTime<Utc>>,
+    pub expiry_minutes: Option<u32>,
+    pub pinned: bool,
+    pub history: &'a [HistoryEntry],
+}
+
+/// A `StatusEvent` read back from the log. Lines written before a field
+/// was added read it as `None`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct LoggedEvent {
+    pub timestamp: DateTime<Utc>,
+    pub file_path: PathBuf,
+    pub old_status: FileStatus,
+    pub new_status: FileStatus,
+    pub cid: Option<String>,
+    pub purchase_id: Option<String>,
+    pub error: Option<String>,
+    #[serde(default)]
+    pub storage_cid: Option<String>,
+    #[serde(default)]
+    pub codex_endpoint: Option<String>,
+    #[serde(default)]
+    pub created_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub file_size: Option<u64>,
+    #[serde(default)]
+    pub file_modified: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub content_hash: Option<String>,
+    #[serde(default)]
+    pub uploaded_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub purchased_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub active_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub storage_params: Option<StorageParams>,
+    #[serde(default)]
+    pub pending_since: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub expiry_minutes: Option<u32>,
+    #[serde(default)]
+    pub pinned: bool,
+    #[serde(default)]
+    pub history: Option<Vec<HistoryEntry>>,
+}
+
+/// Every event in the log at `path`, in the order written, and how many
+/// lines couldn't be parsed, such as one cut short by a crash.
+pub fn read_events(path: &Path) -> Result<(Vec<LoggedEvent>, usize)> {
+    let file = File::open(path)
+        .map_err(|e| anyhow!("Failed to open event log {}: {}", path.display(), e))?;
+    let mut events = Vec::new();
+    let mut unreadable = 0;
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| anyhow!("Failed to read event log {}: {}", path.display(), e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(event) => events.push(event),
+            Err(e) => {
+                warn!("Skipping line {} of event log {}: {}", index + 1, path.display(), e);
+                unreadable += 1;
+            }
+        }
+    }
+    Ok((events, unreadable))
+}
+
+/// Appends status transitions to a JSON lines file. Every `EventLog` for
+/// the same path shares one writer, so lines from concurrent uploads never
+/// interleave.
+#[derive(Clone)]
+pub struct EventLog {
+    path: PathBuf,
+    writer: Arc<Mutex<Option<BufWriter<File>>>>,
+}
+
+static EVENT_LOGS: OnceLock<Mutex<HashMap<PathBuf, EventLog>>> = OnceLock::new();
+
+impl EventLog {
+    /// The process-wide log for `path`. The file is opened on the first
+    /// event.
+    pub fn shared(path: &Path) -> Self {
+        let mut logs = EVENT_LOGS.get_or_init(Default::default).lock().unwrap();
+        logs.entry(path.to_path_buf())
+            .or_insert_with(|| Self {
+                path: path.to_path_buf(),
+                writer: Arc::new(Mutex::new(None)),
+            })
+            .clone()
+    }
+
+    /// Records `record` moving from `old_status` to its current status.
+    /// Writing is best effort: a failure is logged and the transition stands.
+    pub fn record_transition(&self, record: &FileRecord, old_status: &FileStatus) {
+        let event = StatusEvent {
+            timestamp: Utc::now(),
+            file_path: &record.file_path,
+            old_status,
+            new_status: &record.status,
+            cid: record.original_cid.as_deref(),
+            purchase_id: record.purchase_id.as_deref(),
+            error: record.error.as_deref(),
+            storage_cid: record.storage_cid.as_deref(),
+            codex_endpoint: record.codex_endpoint.as_deref(),
+            created_at: record.created_at,
+            file_size: record.file_size,
+            file_modified: record.file_modified,
+            content_hash: record.content_hash.as_deref(),
+            uploaded_at: record.uploaded_at,
+            purchased_at: record.purchased_at,
+            active_at: record.active_at,
+            storage_params: record.storage_params.as_ref(),
+            pending_since: record.pending_since,
+            expiry_minutes: record.expiry_minutes,
+            pinned: record.pinned,
+            history: &record.history,
+        };
+        if let Err(e) = self.append(&event) {
+            warn!("Failed to write event log {}: {}", self.path.display(), e);
+        }
+    }
+
+    fn append(&self, event: &StatusEvent) -> std::io::Result<()> {
+        let line = serde_json::to_string(event)?;
+        let mut writer = self.writer.lock().unwrap();
+        if writer.is_none() {
+            let file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+            *writer = Some(BufWriter::new(file));
+        }
+        let writer = writer.as_mut().unwrap();
+        writeln!(writer, "{}", line)?;
+        writer.flush()
+    }
+}
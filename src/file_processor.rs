@@ -1,51 +1,817 @@
 use anyhow::{anyhow, Result};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
 use log::{info, error, debug, warn};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use tokio::sync::RwLock;
+use tokio_util::sync::CancellationToken;
 use walkdir::WalkDir;
 
-use crate::codex::Client as CodexClient;
-use crate::config::Config;
-use crate::error::retry_with_backoff;
+use crate::codex::{Client as CodexClient, InsufficientTokens, NoHealthyEndpoint, PurchaseStatus, PurchaseWait, UnknownPurchase};
+use crate::config::{ChangeDetection, Config, StorageParams, StuckPurchaseAction};
+use crate::eligibility::{self, Eligibility, PathFilter, PinnedFiles};
+use crate::error::{retry_with_backoff, retry_with_policy, retry_unless, write_crash_report, FailureCircuit, RetryPolicy};
+use crate::fingerprint::{self, detect_change, ChangeStatus};
 use crate::storage::{FileRecord, FileStatus, StorageManager};
+use crate::telemetry;
+use crate::usage::UsageTracker;
+
+/// Appended to a file's path relative to the target folder to name its
+/// `debug_capture` file in the output folder.
+pub const DEBUG_CAPTURE_SUFFIX: &str = ".codex-debug.jsonl";
+
+/// Where the initial scan records how far it got, for `--resume`.
+pub const SCAN_CHECKPOINT_FILE_NAME: &str = "scan-checkpoint.json";
+
+/// Files processed between checkpoint writes during the initial scan.
+const SCAN_CHECKPOINT_INTERVAL: usize = 100;
+
+/// The last file the initial scan finished with. Files are processed in path
+/// order, so everything up to and including it has been handled.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScanCheckpoint {
+    pub last_path: PathBuf,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// How paths compare on a case-insensitive filesystem.
+fn case_key(path: &Path) -> String {
+    path.to_string_lossy().to_lowercase()
+}
+
+/// Keeps one record per path when records were written for the same file
+/// under names differing only in case, preferring the most recently updated.
+fn collapse_case_collisions(records: &mut HashMap<PathBuf, FileRecord>) {
+    let mut newest: HashMap<String, PathBuf> = HashMap::new();
+    for (path, record) in records.iter() {
+        let key = case_key(path);
+        match newest.get(&key) {
+            Some(kept) if records[kept].updated_at >= record.updated_at => {}
+            _ => {
+                newest.insert(key, path.clone());
+            }
+        }
+    }
+    
+    if newest.len() == records.len() {
+        return;
+    }
+    let kept: HashSet<PathBuf> = newest.into_values().collect();
+    records.retain(|path, _| {
+        let keep = kept.contains(path);
+        if !keep {
+            warn!("Ignoring record for {}, which differs only in case from another record for the same file", path.display());
+        }
+        keep
+    });
+}
+
+/// The path each file is stored under, by device and inode, so that a file
+/// reachable under several names through hard links is stored once: under
+/// the path it was first recorded or seen under.
+#[derive(Clone, Default)]
+pub struct LinkedFiles {
+    primaries: Arc<std::sync::Mutex<HashMap<(u64, u64), PathBuf>>>,
+}
+
+impl LinkedFiles {
+    /// The path `path`'s file is stored under: another path it is linked
+    /// to, if that was seen first and still names the same file, and
+    /// otherwise `path` itself, which is then noted as the file's path.
+    pub fn primary_path(&self, path: &Path) -> PathBuf {
+        let Some((id, links)) = fingerprint::file_id(path) else { return path.to_path_buf() };
+        let mut primaries = self.primaries.lock().unwrap();
+        match primaries.get(&id) {
+            Some(primary) if primary == path => {}
+            Some(primary) if links > 1 && fingerprint::file_id(primary).is_some_and(|(other, _)| other == id) => {
+                return primary.clone();
+            }
+            _ => {
+                primaries.insert(id, path.to_path_buf());
+            }
+        }
+        path.to_path_buf()
+    }
+}
+
+/// One lock per debug capture file in use, held from the size check through
+/// the append so concurrent captures can't overrun `debug_capture_max_bytes`,
+/// and while the capture is moved or removed with its record. A lock is
+/// dropped once nothing holds or waits for it.
+#[derive(Clone, Default)]
+pub struct DebugCaptures {
+    locks: Arc<std::sync::Mutex<HashMap<PathBuf, Arc<tokio::sync::Mutex<()>>>>>,
+}
+
+impl DebugCaptures {
+    /// Waits for, then holds, the lock of `capture_path` until the returned
+    /// guard drops.
+    async fn lock(&self, capture_path: &Path) -> DebugCaptureLock {
+        let lock = self.locks.lock().unwrap_or_else(|e| e.into_inner())
+            .entry(capture_path.to_path_buf())
+            .or_default()
+            .clone();
+        DebugCaptureLock {
+            captures: self.clone(),
+            capture_path: capture_path.to_path_buf(),
+            guard: Some(lock.lock_owned().await),
+        }
+    }
+}
+
+struct DebugCaptureLock {
+    captures: DebugCaptures,
+    capture_path: PathBuf,
+    guard: Option<tokio::sync::OwnedMutexGuard<()>>,
+}
+
+impl Drop for DebugCaptureLock {
+    fn drop(&mut self) {
+        self.guard.take();
+        let mut locks = self.captures.locks.lock().unwrap_or_else(|e| e.into_inner());
+        // Anyone waiting holds a clone, so only the map's own means unused.
+        if locks.get(&self.capture_path).is_some_and(|lock| Arc::strong_count(lock) == 1) {
+            locks.remove(&self.capture_path);
+        }
+    }
+}
+
+/// Recorded paths by `case_key`, so that on a case-insensitive filesystem
+/// the record of a path differing only in case is found without comparing
+/// against every record. Kept up to date as records are added, removed and
+/// renamed, whatever the filesystem.
+#[derive(Clone, Default)]
+pub struct CaseKeys {
+    paths: Arc<std::sync::Mutex<HashMap<String, PathBuf>>>,
+}
+
+impl CaseKeys {
+    /// The recorded path `path` matches without regard to case, if any.
+    pub fn get(&self, path: &Path) -> Option<PathBuf> {
+        self.paths.lock().unwrap().get(&case_key(path)).cloned()
+    }
+    
+    /// Notes `path` as recorded, unless a path differing only in case
+    /// already is.
+    fn insert(&self, path: &Path) {
+        self.paths.lock().unwrap().entry(case_key(path)).or_insert_with(|| path.to_path_buf());
+    }
+    
+    /// Forgets `path`, if it is the recorded path for its key.
+    fn remove(&self, path: &Path) {
+        let mut paths = self.paths.lock().unwrap();
+        let key = case_key(path);
+        if paths.get(&key).is_some_and(|recorded| recorded == path) {
+            paths.remove(&key);
+        }
+    }
+    
+    /// Replaces every noted path with `paths`.
+    fn reset<'a>(&self, paths: impl IntoIterator<Item = &'a PathBuf>) {
+        let mut noted = self.paths.lock().unwrap();
+        noted.clear();
+        for path in paths {
+            noted.entry(case_key(path)).or_insert_with(|| path.clone());
+        }
+    }
+}
+
+/// Keeps one path per underlying file when hard links make the same file
+/// reachable under several names. `files` must be sorted; unless a file is
+/// already known under another path, the first path in that order is kept,
+/// so the same one wins on every scan.
+fn collapse_linked_paths(files: &mut Vec<PathBuf>, linked: &LinkedFiles) {
+    let mut duplicates = HashSet::new();
+    
+    for path in files.iter() {
+        let primary = linked.primary_path(path);
+        if primary != *path {
+            info!("{} is the same file as {}, storing it once under the latter", path.display(), primary.display());
+            duplicates.insert(path.clone());
+        }
+    }
+    
+    if !duplicates.is_empty() {
+        files.retain(|path| !duplicates.contains(path));
+    }
+}
+
+/// Assigns each file to one of `endpoints` so the endpoints end up with
+/// about the same total bytes: the largest files go first, each to the
+/// endpoint with the fewest bytes so far. Ties go to the earlier endpoint.
+pub fn assign_endpoints_by_size(mut files: Vec<(PathBuf, u64)>, endpoints: &[String]) -> HashMap<PathBuf, String> {
+    let mut assignments = HashMap::new();
+    if endpoints.is_empty() {
+        return assignments;
+    }
+    
+    files.sort_by(|(a_path, a_size), (b_path, b_size)| b_size.cmp(a_size).then_with(|| a_path.cmp(b_path)));
+    let mut loads = vec![0u64; endpoints.len()];
+    for (path, size) in files {
+        let (index, _) = loads.iter().enumerate().min_by_key(|(_, load)| **load).unwrap();
+        loads[index] += size;
+        assignments.insert(path, endpoints[index].clone());
+    }
+    assignments
+}
+
+/// Longest pause between passes while every status check is failing.
+const PURCHASE_CHECK_MAX_BACKOFF_SECS: u64 = 3600;
+
+/// Uploads and storage requests for files that aren't pinned.
+const UPLOAD_RETRY_POLICY: RetryPolicy = RetryPolicy {
+    max_retries: 3,
+    initial_delay: std::time::Duration::from_secs(1),
+    max_delay: Some(std::time::Duration::from_secs(30)),
+    jitter: 0.5,
+};
+
+/// Uploads and storage requests for pinned files: more attempts, closer
+/// together.
+const PINNED_RETRY_POLICY: RetryPolicy = RetryPolicy {
+    max_retries: 8,
+    initial_delay: std::time::Duration::from_millis(200),
+    max_delay: Some(std::time::Duration::from_secs(5)),
+    jitter: 0.5,
+};
+
+/// Sent to `webhook_url` when a pinned file fails to store or renew.
+#[derive(Debug, Clone, Serialize)]
+pub struct PinnedFailureAlert {
+    pub event: &'static str,
+    pub file_path: PathBuf,
+    pub original_cid: Option<String>,
+    pub purchase_id: Option<String>,
+    pub error: Option<String>,
+}
+
+/// How a purchase monitoring pass went.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PurchaseCheckOutcome {
+    /// At least one status check succeeded, or there was nothing to check.
+    Reachable,
+    /// Every status check failed; the node is most likely down.
+    AllFailed,
+}
+
+/// A stored record together with what the node currently says about its
+/// purchase.
+#[derive(Debug, Clone, Serialize)]
+pub struct RecordView {
+    pub record: FileRecord,
+    pub purchase: LivePurchase,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum LivePurchase {
+    /// The record has no purchase to ask about.
+    NoPurchase,
+    Status(PurchaseStatus),
+    /// The node couldn't be asked; holds the error.
+    Unavailable { error: String },
+}
+
+/// An upload and storage request a dry run would have made.
+#[derive(Debug, Clone, Serialize)]
+pub struct PlannedUpload {
+    pub file_path: PathBuf,
+    pub size: u64,
+    pub params: StorageParams,
+}
+
+/// Totals over the uploads a dry run has planned so far.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DryRunSummary {
+    pub files: usize,
+    pub total_bytes: u64,
+}
+
+/// Uploads in progress, each with a token that cancels it. Shared by every
+/// clone of the processor and safe to use from the watcher's own thread.
+#[derive(Clone, Default)]
+pub struct InFlightUploads {
+    tokens: Arc<std::sync::Mutex<HashMap<PathBuf, CancellationToken>>>,
+}
+
+impl InFlightUploads {
+    /// Registers an upload of `file_path` until the returned guard drops.
+    fn start(&self, file_path: &Path) -> InFlightUpload {
+        let token = CancellationToken::new();
+        self.tokens.lock().unwrap().insert(file_path.to_path_buf(), token.clone());
+        InFlightUpload { uploads: self.clone(), file_path: file_path.to_path_buf(), token }
+    }
+    
+    /// Cancels the upload of `file_path`, if one is in progress.
+    pub fn cancel(&self, file_path: &Path) -> bool {
+        match self.tokens.lock().unwrap().get(file_path) {
+            Some(token) => {
+                token.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+struct InFlightUpload {
+    uploads: InFlightUploads,
+    file_path: PathBuf,
+    token: CancellationToken,
+}
+
+impl Drop for InFlightUpload {
+    fn drop(&mut self) {
+        self.uploads.tokens.lock().unwrap().remove(&self.file_path);
+    }
+}
+
+/// Files with a `process_file` call under way. It expects their records to
+/// stay under their paths until it returns, so removing or moving those
+/// records waits for it. Only one call processes a path at a time, so that
+/// two calls can't both upload a file from the same `New` record.
+#[derive(Clone, Default)]
+pub struct ProcessingFiles {
+    counts: Arc<std::sync::Mutex<HashMap<PathBuf, usize>>>,
+    finished: Arc<tokio::sync::Notify>,
+}
+
+impl ProcessingFiles {
+    /// Waits until no other call is processing `file_path`, then registers
+    /// processing of it until the returned guard drops.
+    async fn start(&self, file_path: &Path) -> ProcessingFile {
+        loop {
+            let finished = self.finished.notified();
+            tokio::pin!(finished);
+            finished.as_mut().enable();
+            {
+                let mut counts = self.counts.lock().unwrap();
+                if !counts.contains_key(file_path) {
+                    counts.insert(file_path.to_path_buf(), 1);
+                    return ProcessingFile { files: self.clone(), file_path: file_path.to_path_buf() };
+                }
+            }
+            debug!("Waiting for the processing of {} under way to finish", file_path.display());
+            finished.await;
+        }
+    }
+    
+    /// Whether `path`, or a file under it, is being processed.
+    pub fn any_under(&self, path: &Path) -> bool {
+        self.counts.lock().unwrap().keys().any(|file_path| file_path.starts_with(path))
+    }
+    
+    /// Waits until neither `path` nor any file under it is being processed.
+    pub async fn wait_until_idle(&self, path: &Path) {
+        loop {
+            let finished = self.finished.notified();
+            tokio::pin!(finished);
+            finished.as_mut().enable();
+            if !self.any_under(path) {
+                return;
+            }
+            debug!("Waiting for processing under {} to finish", path.display());
+            finished.await;
+        }
+    }
+}
+
+struct ProcessingFile {
+    files: ProcessingFiles,
+    file_path: PathBuf,
+}
+
+impl Drop for ProcessingFile {
+    fn drop(&mut self) {
+        let mut counts = self.files.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.file_path) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.file_path);
+            }
+        }
+        drop(counts);
+        self.files.finished.notify_waiters();
+    }
+}
+
+/// What the initial scan did with one file.
+enum ScanOutcome {
+    Skipped,
+    Baselined,
+    Processed,
+    Failed(anyhow::Error),
+}
 
 pub struct FileProcessor {
     pub config: Arc<Config>,
     pub codex_client: Arc<CodexClient>,
     pub storage_manager: StorageManager,
     pub records: Arc<RwLock<HashMap<PathBuf, FileRecord>>>,
+    pub usage: Arc<UsageTracker>,
+    /// Set while processing is paused for lack of space in the output folder.
+    pub low_disk_paused: Arc<AtomicBool>,
+    /// Set while read-only for lack of tokens: when to next let a deferred
+    /// file through to probe whether storage requests succeed again.
+    pub read_only_probe_at: Arc<std::sync::Mutex<Option<std::time::Instant>>>,
+    pub path_filter: PathFilter,
+    pub pinned_files: PinnedFiles,
+    /// Whether the target folder's filesystem ignores case, so paths
+    /// differing only in case name the same file.
+    pub case_insensitive_paths: bool,
+    /// Cancelled on shutdown: no new files are started, and waits for
+    /// purchases to start end early.
+    pub shutdown: CancellationToken,
+    /// Uploads planned by `dry_run`, in the order files were seen.
+    pub dry_run_plan: Arc<RwLock<Vec<PlannedUpload>>>,
+    /// Cancelled when their file is deleted mid-upload.
+    pub uploads_in_flight: InFlightUploads,
+    /// Files being processed, which renames and delete cleanups wait for.
+    pub processing: ProcessingFiles,
+    pub linked_files: LinkedFiles,
+    /// Recorded paths by case key, for `record_path`.
+    pub case_keys: CaseKeys,
+    pub debug_captures: DebugCaptures,
 }
 
 impl FileProcessor {
     pub fn new(config: Arc<Config>, codex_client: Arc<CodexClient>) -> Self {
-        let storage_manager = StorageManager::new(
-            config.output_folder.clone(),
-            config.output_structure.clone(),
-        );
+        let storage_manager = StorageManager::from_config(&config);
+        
+        let usage = Arc::new(UsageTracker::new(&config.output_folder));
+        
+        // Config::validate has already rejected invalid patterns.
+        let path_filter = PathFilter::from_config(&config).unwrap_or_else(|e| {
+            error!("{}; ignoring include_patterns and exclude_patterns", e);
+            PathFilter::default()
+        });
+        
+        let pinned_files = PinnedFiles::from_config(&config).unwrap_or_else(|e| {
+            error!("{}; ignoring pinned_paths and pinned_cids", e);
+            PinnedFiles::default()
+        });
+        
+        let case_insensitive_paths = fingerprint::is_case_insensitive(&config.target_folder);
         
         Self {
             config,
             codex_client,
             storage_manager,
             records: Arc::new(RwLock::new(HashMap::new())),
+            usage,
+            low_disk_paused: Arc::new(AtomicBool::new(false)),
+            read_only_probe_at: Arc::new(std::sync::Mutex::new(None)),
+            path_filter,
+            pinned_files,
+            case_insensitive_paths,
+            shutdown: CancellationToken::new(),
+            dry_run_plan: Arc::new(RwLock::new(Vec::new())),
+            uploads_in_flight: InFlightUploads::default(),
+            processing: ProcessingFiles::default(),
+            linked_files: LinkedFiles::default(),
+            case_keys: CaseKeys::default(),
+            debug_captures: DebugCaptures::default(),
         }
     }
     
+    /// The key `file_path` is recorded under. On a case-insensitive
+    /// filesystem that is an existing record's path when it differs only in
+    /// case; otherwise `file_path` itself.
+    pub async fn record_path(&self, file_path: &Path) -> PathBuf {
+        if !self.case_insensitive_paths {
+            return file_path.to_path_buf();
+        }
+        
+        let records = self.records.read().await;
+        if records.contains_key(file_path) {
+            return file_path.to_path_buf();
+        }
+        self.case_keys.get(file_path)
+            .filter(|path| records.contains_key(path))
+            .unwrap_or_else(|| file_path.to_path_buf())
+    }
+    
+    /// A fresh record for `file_path`, noted in `case_keys` as it is about
+    /// to be recorded.
+    fn new_record(&self, file_path: &Path) -> FileRecord {
+        self.case_keys.insert(file_path);
+        self.storage_manager.create_new_record(file_path.to_path_buf())
+    }
+    
+    /// Moves the records of `from`, a file or a directory, to the same paths
+    /// under `to`, keeping their CIDs and purchases so the moved files aren't
+    /// uploaded again. Files under `from` still being processed are let
+    /// finish first. Returns how many records moved; none when nothing
+    /// under `from` was recorded.
+    pub async fn rename_records(&self, from: &Path, to: &Path) -> Result<usize> {
+        let from = self.record_path(from).await;
+        self.processing.wait_until_idle(&from).await;
+        let mut records = self.records.write().await;
+        let moved: Vec<PathBuf> = records.keys()
+            .filter(|path| path.starts_with(&from))
+            .cloned()
+            .collect();
+        
+        for old_path in &moved {
+            let rest = old_path.strip_prefix(&from).unwrap();
+            let new_path = if rest.as_os_str().is_empty() { to.to_path_buf() } else { to.join(rest) };
+            
+            let mut record = records.remove(old_path).unwrap();
+            record.file_path = new_path.clone();
+            record.updated_at = chrono::Utc::now();
+            self.storage_manager.save_record(&self.config.target_folder, &new_path, &record).await?;
+            self.storage_manager.remove_record(&self.config.target_folder, old_path).await?;
+            self.case_keys.remove(old_path);
+            self.case_keys.insert(&new_path);
+            self.move_debug_capture(old_path, Some(&new_path)).await;
+            if records.insert(new_path.clone(), record).is_some() {
+                warn!("{} was moved over {}, replacing the latter's record", old_path.display(), new_path.display());
+            }
+            info!("Moved record for {} to {}", old_path.display(), new_path.display());
+        }
+        
+        Ok(moved.len())
+    }
+    
+    /// Cancels the purchases of, and forgets, the records of `path` and of
+    /// anything under it whose file no longer exists. A record whose
+    /// purchase couldn't be cancelled is kept so the purchase isn't lost
+    /// track of. Waits for files under `path` still being processed, such
+    /// as one whose purchase has yet to start. Returns how many records
+    /// were removed.
+    pub async fn cleanup_deleted(&self, path: &Path) -> Result<usize> {
+        let path = self.record_path(path).await;
+        self.processing.wait_until_idle(&path).await;
+        let deleted: Vec<(PathBuf, FileRecord)> = self.records.read().await.iter()
+            .filter(|(record_path, _)| record_path.starts_with(&path) && !record_path.exists())
+            .map(|(record_path, record)| (record_path.clone(), record.clone()))
+            .collect();
+        
+        let mut removed = 0;
+        let mut failures = Vec::new();
+        for (file_path, record) in deleted {
+            if self.config.dry_run {
+                info!("Dry run: would clean up the record of deleted file {}", file_path.display());
+                continue;
+            }
+            if let Some(purchase_id) = record.purchase_id.as_ref().filter(|_| record.status != FileStatus::Expired) {
+                if let Err(e) = self.codex_client.cancel_purchase(purchase_id, record.codex_endpoint.as_deref()).await {
+                    failures.push(format!("{}: {}", file_path.display(), e));
+                    continue;
+                }
+            }
+            
+            self.storage_manager.remove_record(&self.config.target_folder, &file_path).await?;
+            self.records.write().await.remove(&file_path);
+            self.case_keys.remove(&file_path);
+            self.move_debug_capture(&file_path, None).await;
+            info!("Removed record of deleted file {}", file_path.display());
+            removed += 1;
+        }
+        
+        if !failures.is_empty() {
+            return Err(anyhow!("Failed to clean up {} deleted files, keeping their records: {}",
+                               failures.len(), failures.join("; ")));
+        }
+        Ok(removed)
+    }
+    
+    /// Whether `include_patterns` and `exclude_patterns` let `file_path`
+    /// through.
+    pub fn is_included(&self, file_path: &Path) -> bool {
+        let relative = file_path.strip_prefix(&self.config.target_folder).unwrap_or(file_path);
+        self.path_filter.allows(relative)
+    }
+    
     pub async fn initialize(&self) -> Result<()> {
         info!("Initializing file processor...");
         
-        let existing_records = self.storage_manager
+        self.low_disk_space_reason();
+        self.storage_manager.move_structured_records(&self.config.target_folder).await?;
+        self.load_records().await?;
+        if !self.config.dry_run {
+            self.reconcile_transient_records().await;
+        }
+        
+        info!("File processor initialized successfully");
+        Ok(())
+    }
+    
+    /// Loads the records in the output folder, without contacting Codex.
+    pub async fn load_records(&self) -> Result<()> {
+        self.storage_manager
+            .check_target_folder(&self.config.target_folder, self.config.rebase)
+            .await?;
+        
+        let mut existing_records = self.storage_manager
             .load_existing_records(&self.config.target_folder)
             .await?;
         
-        *self.records.write().await = existing_records;
+        if self.case_insensitive_paths {
+            info!("Target folder is on a case-insensitive filesystem; matching paths without regard to case");
+            collapse_case_collisions(&mut existing_records);
+        }
         
-        info!("File processor initialized successfully");
+        // Pins may have changed since the records were saved.
+        for record in existing_records.values_mut() {
+            record.pinned = self.is_pinned(record);
+        }
+        
+        // A file that gains another hard link stays stored under its
+        // recorded path.
+        let mut recorded: Vec<&PathBuf> = existing_records.keys().collect();
+        recorded.sort();
+        for path in recorded {
+            self.linked_files.primary_path(path);
+        }
+        
+        self.case_keys.reset(existing_records.keys());
+        *self.records.write().await = existing_records;
         Ok(())
     }
     
+    /// Whether `pinned_paths` or `pinned_cids` match the record.
+    fn is_pinned(&self, record: &FileRecord) -> bool {
+        let relative = record.file_path.strip_prefix(&self.config.target_folder).unwrap_or(&record.file_path);
+        let cids = record.original_cid.iter().chain(record.storage_cid.iter()).map(String::as_str);
+        self.pinned_files.matches(relative, cids)
+    }
+    
+    /// Logs a pinned file that was left `Failed` at error level and posts
+    /// an alert to `webhook_url`. Purchase monitoring retries it on every
+    /// pass.
+    async fn escalate_pinned_failure(&self, file_path: &Path) {
+        let alert = match self.records.read().await.get(file_path) {
+            Some(record) if record.pinned && record.status == FileStatus::Failed => PinnedFailureAlert {
+                event: "pinned_failure",
+                file_path: file_path.to_path_buf(),
+                original_cid: record.original_cid.clone(),
+                purchase_id: record.purchase_id.clone(),
+                error: record.error.clone(),
+            },
+            _ => return,
+        };
+        
+        error!("Pinned file {} failed to store: {}; retrying on every purchase monitoring pass",
+               file_path.display(), alert.error.as_deref().unwrap_or("unknown error"));
+        if let Some(url) = &self.config.webhook_url {
+            if let Err(e) = crate::monitor::post_webhook(url, &alert, "pinned failure alert").await {
+                error!("{}", e);
+            }
+        }
+    }
+    
+    /// Where `file_path`'s debug capture is written.
+    fn debug_capture_path(&self, file_path: &Path) -> PathBuf {
+        let relative = file_path.strip_prefix(&self.config.target_folder).unwrap_or(file_path);
+        let mut name = relative.as_os_str().to_os_string();
+        name.push(DEBUG_CAPTURE_SUFFIX);
+        self.config.output_folder.join(name)
+    }
+    
+    /// Moves `from`'s debug capture to `to`'s, or with no `to`, removes it,
+    /// so that a capture follows its record. Best effort, like capturing.
+    async fn move_debug_capture(&self, from: &Path, to: Option<&Path>) {
+        let from = self.debug_capture_path(from);
+        if !from.exists() {
+            return;
+        }
+        let _moving = self.debug_captures.lock(&from).await;
+        let result = match to.map(|to| self.debug_capture_path(to)) {
+            Some(to) => {
+                let _capturing = self.debug_captures.lock(&to).await;
+                async {
+                    if let Some(parent) = to.parent() {
+                        tokio::fs::create_dir_all(parent).await?;
+                    }
+                    tokio::fs::rename(&from, &to).await
+                }.await
+            }
+            None => tokio::fs::remove_file(&from).await,
+        };
+        if let Err(e) = result {
+            warn!("Failed to move or remove debug capture {}: {}", from.display(), e);
+        }
+    }
+    
+    /// With `debug_capture`, appends a raw Codex response body to the file's
+    /// capture, unless that has reached `debug_capture_max_bytes`.
+    pub(crate) async fn capture_codex_response(&self, file_path: &Path, call: &str, purchase_id: &str, body: &str) {
+        if !self.config.debug_capture {
+            return;
+        }
+        
+        let capture_path = self.debug_capture_path(file_path);
+        let entry = serde_json::json!({
+            "at": chrono::Utc::now(),
+            "call": call,
+            "purchase_id": purchase_id,
+            "body": serde_json::from_str::<serde_json::Value>(body)
+                .unwrap_or_else(|_| serde_json::Value::String(body.to_string())),
+        });
+        let line = format!("{}\n", entry);
+        
+        let _capturing = self.debug_captures.lock(&capture_path).await;
+        let written = tokio::fs::metadata(&capture_path).await.map(|metadata| metadata.len()).unwrap_or(0);
+        if written + line.len() as u64 > self.config.debug_capture_max_bytes {
+            debug!("Debug capture {} is full, not recording {}", capture_path.display(), call);
+            return;
+        }
+        
+        let result = async {
+            if let Some(parent) = capture_path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            let mut file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&capture_path)
+                .await?;
+            tokio::io::AsyncWriteExt::write_all(&mut file, line.as_bytes()).await?;
+            // Flushed so the next capture's size check sees this line.
+            tokio::io::AsyncWriteExt::flush(&mut file).await
+        }.await;
+        if let Err(e) = result {
+            warn!("Failed to write debug capture {}: {}", capture_path.display(), e);
+        }
+    }
+    
+    /// Settles records a crash left `Uploading` or `Creating`. A purchase the
+    /// node reports started is promoted to `Active`, one that ended without
+    /// starting is marked `Failed`, and a record with no purchase, or one
+    /// the node has never heard of, is reset to `New` so the scan uploads it
+    /// again. Records the node can't be asked about are left for purchase
+    /// monitoring.
+    ///
+    /// Any other record with a purchase whose purchase may have started
+    /// while FileHog was down (`Failed`, `Pending`, `WaitingLong` or
+    /// `Expired`) is promoted to `Active` if it did, and otherwise left as
+    /// it is: its status already says how its purchase ended. `Degraded`
+    /// records already have a started purchase, and `Unknown` ones are never
+    /// touched.
+    async fn reconcile_transient_records(&self) {
+        let transient: Vec<(PathBuf, Option<String>, Option<String>, bool)> = self.records.read().await
+            .iter()
+            .filter_map(|(path, record)| {
+                let interrupted = matches!(record.status, FileStatus::Uploading | FileStatus::Creating);
+                let settled = record.purchase_id.is_some() && matches!(record.status,
+                    FileStatus::Failed | FileStatus::Pending | FileStatus::WaitingLong | FileStatus::Expired);
+                (interrupted || settled)
+                    .then(|| (path.clone(), record.purchase_id.clone(), record.codex_endpoint.clone(), interrupted))
+            })
+            .collect();
+        if transient.is_empty() {
+            return;
+        }
+        info!("Reconciling {} records whose purchase may have changed while FileHog was down", transient.len());
+        
+        for (path, purchase_id, endpoint, interrupted) in transient {
+            let status = match &purchase_id {
+                Some(purchase_id) => match self.codex_client.get_purchase_status(purchase_id, endpoint.as_deref()).await {
+                    Ok(status) => {
+                        self.capture_codex_response(&path, "get_purchase_status", purchase_id, &status.raw_body).await;
+                        Some(status)
+                    }
+                    Err(e) if e.downcast_ref::<UnknownPurchase>().is_some() => None,
+                    Err(e) => {
+                        warn!("Could not reconcile {}: {}", path.display(), e);
+                        continue;
+                    }
+                },
+                None => None,
+            };
+            let started = status.as_ref().is_some_and(|status| status.has_started(self.config.min_filled_slots));
+            if !interrupted && !started {
+                continue;
+            }
+            
+            let mut records = self.records.write().await;
+            let Some(record) = records.get_mut(&path) else { continue };
+            match status {
+                None => {
+                    info!("Resetting {} to New, it has no purchase the node knows of", path.display());
+                    self.storage_manager.archive_record(record, "Interrupted before its purchase was created");
+                }
+                Some(status) if started => {
+                    info!("Purchase {} for {} started while FileHog was down", purchase_id.as_deref().unwrap_or_default(), path.display());
+                    record.filled_slots = status.filled_slots;
+                    self.storage_manager.mark_record_active(record);
+                }
+                Some(status) if matches!(status.state.as_str(), "cancelled" | "expired" | "failed") => {
+                    let message = format!("Purchase {} reached final state: {}", purchase_id.as_deref().unwrap_or_default(), status.state);
+                    self.storage_manager.update_record_status(record, FileStatus::Failed, Some(message));
+                }
+                Some(_) => continue,
+            }
+            if let Err(e) = self.storage_manager.save_record(&self.config.target_folder, &path, record).await {
+                error!("Failed to save reconciled record for {}: {}", path.display(), e);
+            }
+        }
+    }
+    
     pub async fn scan_target_folder(&self) -> Result<Vec<PathBuf>> {
         info!("Scanning target folder: {}", self.config.target_folder.display());
         
@@ -56,145 +822,613 @@ impl FileProcessor {
             let path = entry.path();
             
             if path.is_file() {
-                let metadata = path.metadata()
-                    .map_err(|e| anyhow!("Failed to get metadata for {}: {}", path.display(), e))?;
-                
-                let file_size = metadata.len();
-                
-                if file_size < 1024 * 1024 {
-                    warn!("Skipping file {} (too small: {} bytes)", path.display(), file_size);
+                if !self.is_included(path) {
+                    debug!("Skipping file {} (filtered by include/exclude patterns)", path.display());
                     continue;
                 }
-                
-                if file_size > 1024 * 1024 * 1024 {
-                    warn!("Skipping file {} (too large: {} bytes)", path.display(), file_size);
-                    continue;
+                match eligibility::check(&self.config, path)? {
+                    Eligibility::Eligible => files.push(path.to_path_buf()),
+                    reason => warn!("Skipping file {} ({})", path.display(), reason),
                 }
-                
-                files.push(path.to_path_buf());
             }
         }
         
+        if self.case_insensitive_paths {
+            for file in files.iter_mut() {
+                if let Some(known_path) = self.case_keys.get(file) {
+                    *file = known_path;
+                }
+            }
+        }
+        
+        // Path order matches a name-sorted walk and is stable across runs,
+        // which is what makes a scan checkpoint meaningful.
+        files.sort();
+        collapse_linked_paths(&mut files, &self.linked_files);
+        
         info!("Found {} eligible files", files.len());
         Ok(files)
     }
     
     pub async fn process_files(&self) -> Result<()> {
-        let files = self.scan_target_folder().await?;
+        let mut files = self.scan_target_folder().await?;
+        let mut baselined = 0;
+        let mut circuit = FailureCircuit::new(&self.config);
         
-        for file_path in files {
-            if let Err(e) = self.process_file(&file_path).await {
-                error!("Failed to process file {}: {}", file_path.display(), e);
-                
-                let mut records = self.records.write().await;
-                let record = records.entry(file_path.clone())
-                    .or_insert_with(|| self.storage_manager.create_new_record(file_path.clone()));
-                
-                self.storage_manager.update_record_status(record, FileStatus::Failed, Some(e.to_string()));
-                
-                if let Err(save_err) = self.storage_manager
-                    .save_record(&self.config.target_folder, &file_path, record)
-                    .await
-                {
-                    error!("Failed to save error record for {}: {}", file_path.display(), save_err);
+        if self.config.resume {
+            match self.load_scan_checkpoint().await? {
+                Some(checkpoint) => {
+                    let total = files.len();
+                    files.retain(|path| *path > checkpoint.last_path);
+                    info!("Resuming scan after {}: skipping {} files processed by the previous run",
+                          checkpoint.last_path.display(), total - files.len());
                 }
+                None => info!("No scan checkpoint found, processing every file"),
             }
         }
         
+        let assignments = if self.config.balance_by_size {
+            self.balance_by_size(&files).await
+        } else {
+            HashMap::new()
+        };
+        let assignments = &assignments;
+        
+        // Up to max_concurrent_uploads files are in flight at once, but
+        // results are taken in path order so the checkpoint only ever covers
+        // files that are done. On shutdown no further files are started.
+        let mut last_done = None;
+        let mut outcomes = futures::stream::iter(files.iter().cloned())
+            .take_while(|_| futures::future::ready(!self.shutdown.is_cancelled()))
+            .map(|file_path| async move {
+                let endpoint = assignments.get(&file_path).map(String::as_str);
+                let outcome = self.process_scanned_file(&file_path, endpoint).await;
+                (file_path, outcome)
+            })
+            .buffered(self.config.max_concurrent_uploads.max(1))
+            .enumerate();
+        
+        while let Some((index, (file_path, outcome))) = outcomes.next().await {
+            match outcome {
+                ScanOutcome::Skipped => {}
+                ScanOutcome::Baselined => baselined += 1,
+                ScanOutcome::Processed => circuit.record_success(),
+                ScanOutcome::Failed(e) => {
+                    if let Some(reason) = circuit.record_failure() {
+                        self.save_scan_checkpoint(&file_path).await;
+                        let message = format!("Aborting file processing: {}. Last error: {}", reason, e);
+                        if let Err(report_err) = write_crash_report(&self.config.output_folder, &message) {
+                            error!("Failed to write crash report: {}", report_err);
+                        }
+                        return Err(anyhow!(message));
+                    }
+                }
+            }
+            
+            if (index + 1) % SCAN_CHECKPOINT_INTERVAL == 0 {
+                self.save_scan_checkpoint(&file_path).await;
+            }
+            last_done = Some(file_path);
+        }
+        
+        if self.shutdown.is_cancelled() {
+            info!("Stopping the scan for shutdown");
+            if let Some(last_done) = &last_done {
+                self.save_scan_checkpoint(last_done).await;
+            }
+            return Ok(());
+        }
+        
+        if self.config.baseline {
+            info!("Recorded {} files as baseline without uploading", baselined);
+        }
+        
+        let checkpoint_file = self.config.output_folder.join(SCAN_CHECKPOINT_FILE_NAME);
+        if checkpoint_file.exists() && !self.config.dry_run {
+            tokio::fs::remove_file(&checkpoint_file).await
+                .map_err(|e| anyhow!("Failed to remove scan checkpoint {}: {}", checkpoint_file.display(), e))?;
+        }
+        
         Ok(())
     }
     
-    pub async fn process_file(&self, file_path: &Path) -> Result<()> {
+    /// Endpoints for the files of a scan that will be uploaded, balanced by
+    /// size across the healthy endpoints. Files that are already stored
+    /// don't count towards an endpoint's load.
+    async fn balance_by_size(&self, files: &[PathBuf]) -> HashMap<PathBuf, String> {
+        let endpoints = self.codex_client.healthy_endpoints();
+        if endpoints.len() < 2 {
+            return HashMap::new();
+        }
+        
+        let mut sized = Vec::new();
+        for file_path in files {
+            let uploads = self.config.force_reupload.is_some()
+                || self.would_upload(file_path).await.unwrap_or(true);
+            if !uploads {
+                continue;
+            }
+            match tokio::fs::metadata(file_path).await {
+                Ok(metadata) => sized.push((file_path.clone(), metadata.len())),
+                Err(e) => debug!("Not balancing {}: {}", file_path.display(), e),
+            }
+        }
+        
+        let sizes: HashMap<PathBuf, u64> = sized.iter().cloned().collect();
+        let assignments = assign_endpoints_by_size(sized, &endpoints);
+        let mut loads: HashMap<&str, (usize, u64)> = HashMap::new();
+        for (file_path, endpoint) in &assignments {
+            let load = loads.entry(endpoint.as_str()).or_default();
+            load.0 += 1;
+            load.1 += sizes[file_path];
+        }
+        for endpoint in &endpoints {
+            let (files, bytes) = loads.get(endpoint.as_str()).copied().unwrap_or_default();
+            info!("Assigned {} files ({} bytes) to endpoint {}", files, bytes, endpoint);
+        }
+        assignments
+    }
+    
+    /// Downloads what was just stored under `cid` and checks it hashes the
+    /// same as the local file. Returns the local file's SHA-256, reusing
+    /// `local_hash` when the fingerprint already has it.
+    async fn verify_upload(&self, file_path: &Path, cid: &str, local_hash: Option<String>) -> Result<String> {
+        let local_hash = match local_hash {
+            Some(hash) => hash,
+            None => fingerprint::sha256_file(file_path).await?,
+        };
+        
+        let downloaded = {
+            let client = self.codex_client.clone();
+            retry_with_backoff(
+                || client.download_file(cid),
+                &format!("download CID {} for verification", cid),
+                3,
+            ).await
+        }.map_err(|e| anyhow!("Failed to verify upload of {}: {}", file_path.display(), e))?;
+        
+        let remote_hash = fingerprint::sha256_bytes(&downloaded);
+        if remote_hash != local_hash {
+            return Err(anyhow!(
+                "Upload verification failed for {}: local SHA-256 {} but CID {} returned {} ({} bytes)",
+                file_path.display(), local_hash, cid, remote_hash, downloaded.len()
+            ));
+        }
+        
+        debug!("Verified upload of {} as CID {}", file_path.display(), cid);
+        Ok(local_hash)
+    }
+    
+    /// One file of the initial scan. A failure is recorded on the file's
+    /// record here, so each concurrent task keeps its own error.
+    async fn process_scanned_file(&self, file_path: &Path, endpoint: Option<&str>) -> ScanOutcome {
+        if let Err(e) = self.archive_for_force_reupload(file_path).await {
+            error!("Failed to archive record for forced re-upload of {}: {}", file_path.display(), e);
+            return ScanOutcome::Skipped;
+        }
+        
+        if self.config.baseline && !self.records.read().await.contains_key(file_path) {
+            return match self.record_baseline(file_path).await {
+                Ok(()) => ScanOutcome::Baselined,
+                Err(e) => {
+                    error!("Failed to record baseline for {}: {}", file_path.display(), e);
+                    ScanOutcome::Skipped
+                }
+            };
+        }
+        
+        match self.process_file_on(file_path, endpoint).await {
+            Ok(()) => ScanOutcome::Processed,
+            Err(e) => {
+                error!("Failed to process file {}: {}", file_path.display(), e);
+                self.record_failure(file_path, &e).await;
+                ScanOutcome::Failed(e)
+            }
+        }
+    }
+    
+    async fn load_scan_checkpoint(&self) -> Result<Option<ScanCheckpoint>> {
+        let checkpoint_file = self.config.output_folder.join(SCAN_CHECKPOINT_FILE_NAME);
+        if !checkpoint_file.exists() {
+            return Ok(None);
+        }
+        
+        let content = tokio::fs::read_to_string(&checkpoint_file).await
+            .map_err(|e| anyhow!("Failed to read scan checkpoint {}: {}", checkpoint_file.display(), e))?;
+        serde_json::from_str(&content)
+            .map(Some)
+            .map_err(|e| anyhow!("Failed to parse scan checkpoint {}: {}", checkpoint_file.display(), e))
+    }
+    
+    /// Best effort: a missed checkpoint only means more files are revisited
+    /// on `--resume`. A dry run leaves the checkpoint alone, since it
+    /// uploaded nothing a later `--resume` could skip.
+    async fn save_scan_checkpoint(&self, last_path: &Path) {
+        if self.config.dry_run {
+            return;
+        }
+        let checkpoint = ScanCheckpoint {
+            last_path: last_path.to_path_buf(),
+            updated_at: chrono::Utc::now(),
+        };
+        let checkpoint_file = self.config.output_folder.join(SCAN_CHECKPOINT_FILE_NAME);
+        let result = match serde_json::to_string_pretty(&checkpoint) {
+            Ok(content) => tokio::fs::write(&checkpoint_file, content).await.map_err(anyhow::Error::from),
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = result {
+            warn!("Failed to write scan checkpoint {}: {}", checkpoint_file.display(), e);
+        }
+    }
+    
+    /// Processes an explicit list of files, independently of the scan and the
+    /// watcher, up to `max_concurrent_uploads` at once, and returns each
+    /// file's resulting record or error in the order given. Files the scan
+    /// would skip are reported as errors. Paths naming the same file, listed
+    /// twice or linked, are processed one after the other, so the file is
+    /// stored once. After shutdown no further paths are started; each of
+    /// those is reported as an error too.
+    pub async fn process_paths(&self, paths: Vec<PathBuf>) -> Vec<(PathBuf, Result<FileRecord>)> {
+        let results = futures::stream::iter(paths)
+            .map(|path| async move {
+                if self.shutdown.is_cancelled() {
+                    let error = anyhow!("Skipping file {}: shut down before processing it", path.display());
+                    return (path, Err(error));
+                }
+                let result = self.process_path(&path).await;
+                (path, result)
+            })
+            .buffered(self.config.max_concurrent_uploads.max(1))
+            .collect()
+            .await;
+        self.flush_records().await;
+        results
+    }
+    
+    async fn process_path(&self, file_path: &Path) -> Result<FileRecord> {
+        if !file_path.starts_with(&self.config.target_folder) {
+            return Err(anyhow!("Skipping file {}: not under the target folder {}",
+                               file_path.display(), self.config.target_folder.display()));
+        }
+        if !self.is_included(file_path) {
+            return Err(anyhow!("Skipping file {}: filtered by include/exclude patterns", file_path.display()));
+        }
+        match eligibility::check(&self.config, file_path)? {
+            Eligibility::Eligible => {}
+            reason => return Err(anyhow!("Skipping file {}: {}", file_path.display(), reason)),
+        }
+        
+        // Resolved up front, so that the failure and the returned record are
+        // those of the path the file is processed under.
+        let primary = self.linked_files.primary_path(file_path);
+        let file_path = &self.record_path(&primary).await;
+        if let Err(e) = self.process_file(file_path).await {
+            self.record_failure(file_path, &e).await;
+            return Err(e);
+        }
+        
+        self.records.read().await
+            .get(file_path)
+            .cloned()
+            .ok_or_else(|| anyhow!("No record for {} after processing", file_path.display()))
+    }
+    
+    /// Marks a file `Failed` with the error that stopped it.
+    async fn record_failure(&self, file_path: &Path, e: &anyhow::Error) {
+        let mut records = self.records.write().await;
+        let record = records.entry(file_path.to_path_buf())
+            .or_insert_with(|| self.new_record(file_path));
+        
+        self.storage_manager.update_record_status(record, FileStatus::Failed, Some(e.to_string()));
+        
+        if let Err(save_err) = self.storage_manager
+            .save_record(&self.config.target_folder, file_path, record)
+            .await
+        {
+            error!("Failed to save error record for {}: {}", file_path.display(), save_err);
+        }
+    }
+    
+    /// With `--force-reupload`, archives the existing storage of a matching
+    /// file so that `process_file` uploads and purchases it again.
+    async fn archive_for_force_reupload(&self, file_path: &Path) -> Result<()> {
+        let Some(force) = &self.config.force_reupload else {
+            return Ok(());
+        };
+        let relative = file_path.strip_prefix(&self.config.target_folder).unwrap_or(file_path);
+        if !force.matches(relative) {
+            return Ok(());
+        }
+        
+        let mut records = self.records.write().await;
+        let Some(record) = records.get_mut(file_path) else {
+            return Ok(());
+        };
+        if record.original_cid.is_none() && record.purchase_id.is_none() && record.status == FileStatus::New {
+            return Ok(());
+        }
+        
+        info!("Forcing re-upload of {} (was {:?})", file_path.display(), record.status);
+        self.storage_manager.archive_record(record, "force re-upload");
+        self.storage_manager.save_record(&self.config.target_folder, file_path, record).await
+    }
+    
+    /// Records a file as already accounted for, so it is only uploaded once it
+    /// changes.
+    pub async fn record_baseline(&self, file_path: &Path) -> Result<()> {
+        let fingerprint = fingerprint::compute(file_path, self.config.change_detection).await?;
+        
         let mut records = self.records.write().await;
         let record = records.entry(file_path.to_path_buf())
-            .or_insert_with(|| self.storage_manager.create_new_record(file_path.to_path_buf()));
+            .or_insert_with(|| self.new_record(file_path));
+        self.storage_manager.update_record_fingerprint(record, &fingerprint);
+        self.storage_manager.update_record_status(record, FileStatus::Baseline, None);
+        self.storage_manager.save_record(&self.config.target_folder, file_path, record).await?;
         
-        if record.status == FileStatus::Active && !self.needs_renewal(record) {
-            debug!("File {} already has active storage", file_path.display());
+        debug!("Recorded baseline for {}", file_path.display());
+        Ok(())
+    }
+    
+    pub async fn process_file(&self, file_path: &Path) -> Result<()> {
+        self.process_file_on(file_path, None).await
+    }
+    
+    /// Processes `file_path`, uploading it to `endpoint` while that is
+    /// healthy; otherwise to the next endpoint, round robin.
+    async fn process_file_on(&self, file_path: &Path, endpoint: Option<&str>) -> Result<()> {
+        let primary = self.linked_files.primary_path(file_path);
+        if primary != file_path {
+            debug!("{} is the same file as {}; processing it under the latter", file_path.display(), primary.display());
+        }
+        let file_path = primary.as_path();
+        let attributes = vec![("file.path", file_path.display().to_string())];
+        let processing = self.processing.start(file_path).await;
+        let result = telemetry::traced("process_file", attributes, self.process_file_inner(file_path, endpoint)).await;
+        drop(processing);
+        self.escalate_pinned_failure(file_path).await;
+        let outcome = if result.is_ok() { "ok" } else { "error" };
+        self.codex_client.metrics().files_processed.with_label_values(&[outcome]).inc();
+        result
+    }
+    
+    async fn process_file_inner(&self, file_path: &Path, endpoint: Option<&str>) -> Result<()> {
+        let existing = {
+            let mut records = self.records.write().await;
+            records.entry(file_path.to_path_buf())
+                .or_insert_with(|| self.new_record(file_path))
+                .clone()
+        };
+        
+        if matches!(existing.status, FileStatus::Unknown(_)) {
+            debug!("Leaving {} alone: its record has a status this version doesn't know", file_path.display());
             return Ok(());
         }
         
-        drop(records);
+        if self.is_settled(&existing) {
+            let change = detect_change(&existing, file_path, self.config.change_detection).await?;
+            match self.confirm_change(&existing, file_path, change).await? {
+                ChangeStatus::Unchanged => {
+                    debug!("File {} is unchanged ({:?})", file_path.display(), existing.status);
+                    return Ok(());
+                }
+                ChangeStatus::Touched(fingerprint) => {
+                    debug!("File {} was touched but its contents are unchanged", file_path.display());
+                    let mut records = self.records.write().await;
+                    let Some(record) = records.get_mut(file_path) else { return Ok(()) };
+                    self.storage_manager.update_record_fingerprint(record, &fingerprint);
+                    self.storage_manager.save_record(&self.config.target_folder, file_path, record).await?;
+                    return Ok(());
+                }
+                ChangeStatus::Changed => {
+                    info!("File {} changed since it was stored", file_path.display());
+                }
+            }
+        }
+        
+        if self.config.dry_run {
+            return self.plan_upload(file_path, &existing).await;
+        }
+        
+        if let Some(reason) = self.deferral_reason(&existing) {
+            debug!("Deferring file {}: {}", file_path.display(), reason);
+            if existing.status == FileStatus::Active {
+                // Renewals stay active; purchase monitoring retries them.
+                return Ok(());
+            }
+            let mut records = self.records.write().await;
+            let Some(record) = records.get_mut(file_path) else { return Ok(()) };
+            self.storage_manager.update_record_status(record, FileStatus::Pending, Some(reason));
+            // The disk may be too full to write to; the pending record is kept
+            // in memory and retried either way.
+            if let Err(e) = self.storage_manager.save_record(&self.config.target_folder, file_path, record).await {
+                warn!("Failed to save deferred record for {}: {}", file_path.display(), e);
+            }
+            return Ok(());
+        }
         
         info!("Processing file: {}", file_path.display());
         
+        // Hashed whatever the mode, so a later size or mtime change can be
+        // checked against the contents before uploading again.
+        let mut fingerprint = fingerprint::compute(file_path, ChangeDetection::Sha256).await?;
+        let retry_policy = if self.is_pinned(&existing) { PINNED_RETRY_POLICY } else { UPLOAD_RETRY_POLICY };
+        
         let upload_result = {
             let client = self.codex_client.clone();
             let path = file_path.to_path_buf();
-            retry_with_backoff(
-                || client.upload_file(&path),
-                &format!("upload file {}", file_path.display()),
-                3,
-            ).await
+            let what = format!("upload file {}", file_path.display());
+            let in_flight = self.uploads_in_flight.start(file_path);
+            tokio::select! {
+                result = retry_with_policy(|| client.upload_file_to(&path, endpoint), &what, &retry_policy) => result,
+                _ = in_flight.token.cancelled() => {
+                    let message = "Upload cancelled because the file was deleted";
+                    let mut records = self.records.write().await;
+                    let Some(record) = records.get_mut(file_path) else { return Ok(()) };
+                    self.storage_manager.update_record_status(record, FileStatus::Failed, Some(message.to_string()));
+                    self.storage_manager.save_record(&self.config.target_folder, file_path, record).await?;
+                    info!("Cancelled upload of {}: the file was deleted", file_path.display());
+                    return Err(anyhow!("{}", message));
+                }
+            }
         };
         
-        let original_cid = match upload_result {
-            Ok(cid) => cid,
+        let upload = match upload_result {
+            Ok(upload) => upload,
+            // Every endpoint went unhealthy after the deferral check; wait for
+            // one to recover like any other deferral.
+            Err(e) if e.downcast_ref::<NoHealthyEndpoint>().is_some() => {
+                let mut records = self.records.write().await;
+                let Some(record) = records.get_mut(file_path) else { return Ok(()) };
+                self.storage_manager.update_record_status(record, FileStatus::Pending, Some(e.to_string()));
+                self.storage_manager.save_record(&self.config.target_folder, file_path, record).await?;
+                return Ok(());
+            }
             Err(e) => {
                 let mut records = self.records.write().await;
-                let record = records.get_mut(file_path).unwrap();
+                let Some(record) = records.get_mut(file_path) else { return Ok(()) };
                 self.storage_manager.update_record_status(record, FileStatus::Failed, Some(e.to_string()));
                 self.storage_manager.save_record(&self.config.target_folder, file_path, record).await?;
+                telemetry::record_failure("upload");
                 return Err(anyhow!("Upload failed: {}", e));
             }
         };
         
+        telemetry::record_upload(fingerprint.size);
+        if let Err(e) = self.usage.record_upload(fingerprint.size).await {
+            warn!("Failed to record upload usage for {}: {}", file_path.display(), e);
+        }
+        
+        if self.config.verify_after_upload {
+            match self.verify_upload(file_path, &upload.cid, fingerprint.content_hash.clone()).await {
+                Ok(hash) => fingerprint.content_hash = Some(hash),
+                Err(e) => {
+                    let mut records = self.records.write().await;
+                    let Some(record) = records.get_mut(file_path) else { return Ok(()) };
+                    self.storage_manager.update_record_status(record, FileStatus::Failed, Some(e.to_string()));
+                    self.storage_manager.save_record(&self.config.target_folder, file_path, record).await?;
+                    telemetry::record_failure("verify");
+                    return Err(e);
+                }
+            }
+        }
+        
         {
             let mut records = self.records.write().await;
-            let record = records.get_mut(file_path).unwrap();
-            self.storage_manager.update_record_upload(record, original_cid.clone(), "endpoint".to_string());
+            let Some(record) = records.get_mut(file_path) else { return Ok(()) };
+            self.storage_manager.update_record_upload(record, upload.cid.clone(), upload.endpoint.clone());
+            record.upload_filename = crate::codex::upload_filename(file_path);
+            record.pinned = self.is_pinned(record);
+            self.storage_manager.update_record_fingerprint(record, &fingerprint);
             self.storage_manager.save_record(&self.config.target_folder, file_path, record).await?;
         }
         
+        let params = self.storage_params_for(file_path, &upload.endpoint);
         let purchase_result = {
             let client = self.codex_client.clone();
-            let cid = original_cid.clone();
-            let params = self.config.storage_params.clone();
-            retry_with_backoff(
-                || client.create_storage_request(&cid, &params),
+            let params = params.clone();
+            retry_unless(
+                || client.create_storage_request(&upload.cid, &params, Some(&upload.endpoint)),
                 &format!("create storage request for {}", file_path.display()),
-                3,
+                &retry_policy,
+                |e| e.is::<InsufficientTokens>(),
             ).await
         };
         
         let purchase_response = match purchase_result {
-            Ok(response) => response,
+            Ok(response) => {
+                self.leave_read_only();
+                response
+            }
+            // Waits like any other deferral, so a funding gap doesn't leave
+            // failed records behind.
+            Err(e) if e.is::<InsufficientTokens>() => {
+                self.enter_read_only();
+                let mut records = self.records.write().await;
+                let Some(record) = records.get_mut(file_path) else { return Ok(()) };
+                self.storage_manager.update_record_status(record, FileStatus::Pending, Some(e.to_string()));
+                self.storage_manager.save_record(&self.config.target_folder, file_path, record).await?;
+                return Ok(());
+            }
             Err(e) => {
                 let mut records = self.records.write().await;
-                let record = records.get_mut(file_path).unwrap();
+                let Some(record) = records.get_mut(file_path) else { return Ok(()) };
                 self.storage_manager.update_record_status(record, FileStatus::Failed, Some(e.to_string()));
                 self.storage_manager.save_record(&self.config.target_folder, file_path, record).await?;
+                telemetry::record_failure("storage_request");
                 return Err(anyhow!("Storage request failed: {}", e));
             }
         };
         
+        telemetry::record_storage_request();
+        self.capture_codex_response(file_path, "create_storage_request", &purchase_response.purchase_id,
+                                    &purchase_response.raw_body).await;
+        if let Err(e) = self.usage.record_storage_request().await {
+            warn!("Failed to record storage request usage for {}: {}", file_path.display(), e);
+        }
+        
         {
             let mut records = self.records.write().await;
-            let record = records.get_mut(file_path).unwrap();
+            let Some(record) = records.get_mut(file_path) else { return Ok(()) };
             self.storage_manager.update_record_purchase(
                 record,
                 purchase_response.purchase_id.clone(),
                 purchase_response.request.content.cid.clone(),
             );
+            record.expiry_minutes = Some(params.expiry_minutes);
+            record.storage_params = Some(params.clone());
             self.storage_manager.save_record(&self.config.target_folder, file_path, record).await?;
         }
         
-        let timeout_secs = self.config.storage_params.expiry_minutes as u64 * 60;
-        let wait_result = self.codex_client
-            .wait_for_purchase_start(&purchase_response.purchase_id, timeout_secs)
-            .await;
+        let timeout_secs = params.expiry_minutes as u64 * 60;
+        let wait_started = chrono::Utc::now();
+        let wait_result = tokio::select! {
+            result = self.codex_client.wait_for_purchase_start(
+                &purchase_response.purchase_id, Some(&upload.endpoint), timeout_secs, self.config.min_filled_slots,
+            ) => result,
+            _ = self.shutdown.cancelled() => {
+                // Purchase monitoring keeps waiting for it on the next run.
+                let mut records = self.records.write().await;
+                let Some(record) = records.get_mut(file_path) else { return Ok(()) };
+                self.storage_manager.update_record_status(record, FileStatus::WaitingLong, None);
+                record.pending_since = Some(wait_started);
+                self.storage_manager.save_record(&self.config.target_folder, file_path, record).await?;
+                info!("Shutting down before purchase {} for {} started; purchase monitoring will keep waiting",
+                      purchase_response.purchase_id, file_path.display());
+                return Ok(());
+            }
+        };
         
         match wait_result {
-            Ok(_) => {
+            Ok(PurchaseWait::TimedOut { state }) if self.config.max_wait_for_start_minutes.is_some() => {
                 let mut records = self.records.write().await;
-                let record = records.get_mut(file_path).unwrap();
+                let Some(record) = records.get_mut(file_path) else { return Ok(()) };
+                self.storage_manager.update_record_status(record, FileStatus::WaitingLong, None);
+                record.pending_since = Some(wait_started);
+                self.storage_manager.save_record(&self.config.target_folder, file_path, record).await?;
+                info!("Purchase {} for {} is still {} after {} minutes; purchase monitoring will keep waiting",
+                      purchase_response.purchase_id, file_path.display(), state, params.expiry_minutes);
+            }
+            Ok(PurchaseWait::TimedOut { .. }) => {
+                let message = format!("Timeout waiting for purchase {} to start", purchase_response.purchase_id);
+                let mut records = self.records.write().await;
+                let Some(record) = records.get_mut(file_path) else { return Ok(()) };
+                self.storage_manager.update_record_status(record, FileStatus::Failed, Some(message.clone()));
+                self.storage_manager.save_record(&self.config.target_folder, file_path, record).await?;
+                return Err(anyhow!("Purchase failed to start: {}", message));
+            }
+            Ok(PurchaseWait::Started(status)) => {
+                self.capture_codex_response(file_path, "get_purchase_status", &purchase_response.purchase_id,
+                                            &status.raw_body).await;
+                let mut records = self.records.write().await;
+                let Some(record) = records.get_mut(file_path) else { return Ok(()) };
+                record.filled_slots = status.filled_slots;
                 self.storage_manager.mark_record_active(record);
                 self.storage_manager.save_record(&self.config.target_folder, file_path, record).await?;
                 info!("Successfully stored file: {}", file_path.display());
             }
             Err(e) => {
                 let mut records = self.records.write().await;
-                let record = records.get_mut(file_path).unwrap();
+                let Some(record) = records.get_mut(file_path) else { return Ok(()) };
                 self.storage_manager.update_record_status(record, FileStatus::Failed, Some(e.to_string()));
                 self.storage_manager.save_record(&self.config.target_folder, file_path, record).await?;
                 return Err(anyhow!("Purchase failed to start: {}", e));
@@ -204,59 +1438,706 @@ impl FileProcessor {
         Ok(())
     }
     
+    /// Whether `record` needs no processing unless its file changes.
+    fn is_settled(&self, record: &FileRecord) -> bool {
+        match record.status {
+            FileStatus::Active => !self.needs_renewal(record),
+            FileStatus::Baseline | FileStatus::WaitingLong => true,
+            FileStatus::DryRun => self.config.dry_run,
+            // Only a renewal or a re-upload from purchase monitoring replaces
+            // the purchase of a degraded file.
+            FileStatus::Degraded => !self.needs_renewal(record),
+            _ => false,
+        }
+    }
+    
+    /// For an active file, only a different content hash counts as a change:
+    /// a file touched or rewritten in place with the same contents is
+    /// `Touched`. Within `reprocess_cooldown_secs` of the file becoming
+    /// active, a record with no hash to compare is left for after the
+    /// cooldown. Later updates to the record, such as a status check, don't
+    /// restart it.
+    async fn confirm_change(&self, record: &FileRecord, file_path: &Path, change: ChangeStatus) -> Result<ChangeStatus> {
+        if change != ChangeStatus::Changed || record.status != FileStatus::Active {
+            return Ok(change);
+        }
+        
+        let Some(stored_hash) = &record.content_hash else {
+            let cooldown = chrono::Duration::seconds(self.config.reprocess_cooldown_secs as i64);
+            if chrono::Utc::now() < record.storage_start() + cooldown {
+                debug!("File {} changed within reprocess_cooldown_secs of being stored; checking it again afterwards",
+                       file_path.display());
+                return Ok(ChangeStatus::Unchanged);
+            }
+            return Ok(change);
+        };
+        // Any other mode only reports a change once the hashes differ.
+        if self.config.change_detection != ChangeDetection::SizeMtime {
+            return Ok(change);
+        }
+        
+        let fingerprint = fingerprint::compute(file_path, ChangeDetection::Sha256).await?;
+        if fingerprint.content_hash.as_ref() == Some(stored_hash) {
+            Ok(ChangeStatus::Touched(fingerprint))
+        } else {
+            Ok(change)
+        }
+    }
+    
+    /// Whether `process_file` would upload `file_path`, leaving aside
+    /// anything that would defer it.
+    pub async fn would_upload(&self, file_path: &Path) -> Result<bool> {
+        let record = match self.records.read().await.get(file_path) {
+            Some(record) => record.clone(),
+            None => return Ok(true),
+        };
+        if !self.is_settled(&record) {
+            return Ok(true);
+        }
+        Ok(detect_change(&record, file_path, self.config.change_detection).await? == ChangeStatus::Changed)
+    }
+    
+    /// Whether an already-recorded file should be handed back to `process_file`
+    /// because its contents changed after it was stored.
+    pub async fn has_changed_since_stored(&self, file_path: &Path) -> Result<bool> {
+        let record = match self.records.read().await.get(file_path) {
+            Some(record) if matches!(record.status, FileStatus::Active | FileStatus::Baseline) => record.clone(),
+            _ => return Ok(false),
+        };
+        
+        let change = detect_change(&record, file_path, self.config.change_detection).await?;
+        Ok(change == ChangeStatus::Changed)
+    }
+    
+    /// Why a file can't be uploaded and purchased right now, if it can't.
+    /// Storage that would lapse before a maintenance window ends is renewed
+    /// anyway.
+    fn deferral_reason(&self, record: &FileRecord) -> Option<String> {
+        if self.shutdown.is_cancelled() {
+            return Some("Deferred by shutdown".to_string());
+        }
+        
+        if let Some(reason) = self.low_disk_space_reason() {
+            return Some(reason);
+        }
+        
+        if !self.codex_client.has_healthy_endpoint() {
+            return Some("Deferred until a Codex endpoint is healthy again".to_string());
+        }
+        
+        if let Some(reason) = self.read_only_reason() {
+            return Some(reason);
+        }
+        
+        let now = chrono::Utc::now();
+        let window_end = self.config.maintenance_window_end(now)?;
+        
+        let duration = chrono::Duration::from_std(self.config.duration()).ok()?;
+        if record.expires_at(duration).is_some_and(|expires_at| expires_at <= window_end) {
+            warn!("Renewing {} during a maintenance window because it expires before the window ends",
+                  record.file_path.display());
+            return None;
+        }
+        
+        Some(format!("Deferred until maintenance window ends at {}", window_end.to_rfc3339()))
+    }
+    
+    /// Defers files while read-only for lack of tokens, except for one every
+    /// `read_only_probe_secs`, whose storage request shows whether the node
+    /// has been funded.
+    fn read_only_reason(&self) -> Option<String> {
+        let mut probe_at = self.read_only_probe_at.lock().unwrap();
+        let due = (*probe_at)?;
+        let now = std::time::Instant::now();
+        if now < due {
+            return Some("Deferred while the Codex node is out of tokens (read-only mode)".to_string());
+        }
+        *probe_at = Some(now + std::time::Duration::from_secs(self.config.read_only_probe_secs));
+        info!("Retrying a storage request to check whether the Codex node has tokens again");
+        None
+    }
+    
+    /// Stops new uploads and storage requests until a probe succeeds,
+    /// logging once on entering.
+    fn enter_read_only(&self) {
+        let mut probe_at = self.read_only_probe_at.lock().unwrap();
+        if probe_at.is_none() {
+            error!("Codex node is out of tokens; entering read-only mode. Active purchases are still \
+                    monitored, new files wait as Pending, and a storage request is retried every {}s",
+                   self.config.read_only_probe_secs);
+            *probe_at = Some(std::time::Instant::now() + std::time::Duration::from_secs(self.config.read_only_probe_secs));
+        }
+    }
+    
+    fn leave_read_only(&self) {
+        if self.read_only_probe_at.lock().unwrap().take().is_some() {
+            info!("Storage requests succeed again; leaving read-only mode");
+        }
+    }
+    
+    /// Checks free space in the output folder against `min_output_free_bytes`,
+    /// logging once when processing pauses and once when it resumes.
+    fn low_disk_space_reason(&self) -> Option<String> {
+        let min_free = self.config.min_output_free_bytes?;
+        let available = match fs2::available_space(&self.config.output_folder) {
+            Ok(available) => available,
+            Err(e) => {
+                warn!("Failed to check free space in {}: {}", self.config.output_folder.display(), e);
+                return None;
+            }
+        };
+        
+        if available < min_free {
+            if !self.low_disk_paused.swap(true, Ordering::Relaxed) {
+                error!("Output folder {} has only {} bytes free (min_output_free_bytes = {}); \
+                        pausing uploads until space is freed",
+                       self.config.output_folder.display(), available, min_free);
+            }
+            Some(format!("Deferred until the output folder has {} bytes free (has {})", min_free, available))
+        } else {
+            if self.low_disk_paused.swap(false, Ordering::Relaxed) {
+                info!("Output folder has {} bytes free again; resuming uploads", available);
+            }
+            None
+        }
+    }
+    
+    /// The record for `file_path` plus a fresh purchase status from the node.
+    /// Failing to reach the node is reported in the view rather than as an
+    /// error, so the local record is always returned when there is one.
+    pub async fn describe(&self, file_path: &Path) -> Result<RecordView> {
+        let record = self.records.read().await
+            .get(file_path)
+            .cloned()
+            .ok_or_else(|| anyhow!("No record for {}", file_path.display()))?;
+        
+        let purchase = match &record.purchase_id {
+            None => LivePurchase::NoPurchase,
+            Some(purchase_id) => match self.codex_client.get_purchase_status(purchase_id, record.codex_endpoint.as_deref()).await {
+                Ok(status) => LivePurchase::Status(status),
+                Err(e) => LivePurchase::Unavailable { error: e.to_string() },
+            },
+        };
+        
+        Ok(RecordView { record, purchase })
+    }
+    
+    /// Writes the whole in-memory records map to the output folder, as a
+    /// safety net for any update that didn't get its own `save_record`. The
+    /// read lock is held throughout so a concurrent per-record save can't be
+    /// overwritten with an older copy.
+    pub async fn persist_all_records(&self) -> Result<()> {
+        let records = self.records.read().await;
+        self.storage_manager
+            .save_all_records(&self.config.target_folder, &records)
+            .await
+            .map_err(|e| anyhow!("Failed to persist records: {}", e))?;
+        debug!("Persisted {} records", records.len());
+        Ok(())
+    }
+    
+    /// Writes out records whose saves are still held in memory, logging
+    /// rather than returning a failure, since the in-memory records stand.
+    pub async fn flush_records(&self) {
+        if let Err(e) = self.storage_manager.flush().await {
+            error!("Failed to flush records: {}", e);
+        }
+    }
+    
+    /// Hands every deferred file back to `process_file`, which defers it again
+    /// if whatever blocked it still applies.
+    pub async fn retry_pending(&self) {
+        self.codex_client.probe_unhealthy().await;
+        
+        let pending: Vec<PathBuf> = self.records.read().await.iter()
+            .filter(|(_, record)| record.status == FileStatus::Pending)
+            .map(|(path, _)| path.clone())
+            .collect();
+        
+        for file_path in pending {
+            if let Err(e) = self.process_file(&file_path).await {
+                error!("Failed to process deferred file {}: {}", file_path.display(), e);
+            }
+        }
+    }
+    
+    /// Logs the upload and storage request `process_file` would make for
+    /// `file_path` and adds it to `dry_run_plan`. A file with nothing stored
+    /// yet is recorded as `DryRun`; records of stored files are left alone.
+    async fn plan_upload(&self, file_path: &Path, existing: &FileRecord) -> Result<()> {
+        let fingerprint = fingerprint::compute(file_path, self.config.change_detection).await?;
+        let relative = file_path.strip_prefix(&self.config.target_folder).unwrap_or(file_path);
+        // The endpoint is only known once uploaded; assume the file's current one.
+        let params = self.config.storage_params_for(relative, existing.codex_endpoint.as_deref());
+        
+        info!("Dry run: would upload {} ({} bytes) and request storage for {}s with expiry {}s \
+               on {} nodes tolerating {} failures, price {}, collateral {}",
+              file_path.display(), fingerprint.size, params.duration_days as u64 * 24 * 60 * 60, params.expiry_minutes as u64 * 60,
+              params.nodes, params.tolerance, params.price, params.collateral);
+        
+        if existing.original_cid.is_none() && existing.purchase_id.is_none() {
+            let mut records = self.records.write().await;
+            let record = records.get_mut(file_path).unwrap();
+            self.storage_manager.update_record_fingerprint(record, &fingerprint);
+            self.storage_manager.update_record_status(record, FileStatus::DryRun, None);
+            self.storage_manager.save_record(&self.config.target_folder, file_path, record).await?;
+        }
+        
+        self.dry_run_plan.write().await.push(PlannedUpload {
+            file_path: file_path.to_path_buf(),
+            size: fingerprint.size,
+            params,
+        });
+        Ok(())
+    }
+    
+    /// Totals over the uploads planned by a dry run so far.
+    pub async fn dry_run_summary(&self) -> DryRunSummary {
+        let plan = self.dry_run_plan.read().await;
+        DryRunSummary {
+            files: plan.len(),
+            total_bytes: plan.iter().map(|planned| planned.size).sum(),
+        }
+    }
+    
+    fn storage_params_for(&self, file_path: &Path, endpoint: &str) -> StorageParams {
+        let relative = file_path.strip_prefix(&self.config.target_folder).unwrap_or(file_path);
+        self.config.storage_params_for(relative, Some(endpoint))
+    }
+    
     fn needs_renewal(&self, record: &FileRecord) -> bool {
-        let one_hour = chrono::Duration::hours(1);
-        self.storage_manager.needs_new_purchase(record, one_hour)
+        self.storage_manager.needs_new_purchase(record, self.config.renewal_lead())
     }
     
     pub async fn monitor_purchases(&self) -> Result<()> {
+        if self.config.dry_run {
+            info!("Dry run: purchase monitoring is off");
+            return Ok(());
+        }
         info!("Starting purchase monitoring...");
         
+        let interval = self.config.purchase_poll_secs;
+        let mut delay = interval;
         loop {
-            let purchases_to_check: Vec<(PathBuf, String)> = {
-                let records = self.records.read().await;
-                records.iter()
-                    .filter_map(|(path, record)| {
-                        if record.status == FileStatus::Active {
-                            record.purchase_id.as_ref().map(|id| (path.clone(), id.clone()))
-                        } else {
-                            None
-                        }
-                    })
-                    .collect()
-            };
-            
-            for (file_path, purchase_id) in purchases_to_check {
-                if let Err(e) = self.check_purchase_status(&file_path, &purchase_id).await {
-                    error!("Failed to check purchase status for {}: {}", file_path.display(), e);
+            match self.check_purchases_once().await {
+                PurchaseCheckOutcome::Reachable => {
+                    if delay != interval {
+                        info!("Purchase status checks are succeeding again, resuming normal monitoring");
+                    }
+                    delay = interval;
+                }
+                PurchaseCheckOutcome::AllFailed => {
+                    delay = (delay * 2).min(PURCHASE_CHECK_MAX_BACKOFF_SECS.max(interval));
+                    warn!("Codex node appears to be down, backing off purchase monitoring for {}s", delay);
                 }
             }
-            
-            tokio::time::sleep(std::time::Duration::from_secs(300)).await;
+            tokio::select! {
+                _ = tokio::time::sleep(std::time::Duration::from_secs(delay)) => {}
+                _ = self.shutdown.cancelled() => {
+                    info!("Stopping purchase monitoring");
+                    return Ok(());
+                }
+            }
+        }
+    }
+    
+    /// Runs one monitoring pass over every purchase that is active, or that
+    /// was left `Creating` longer than `process_file` would have waited for it.
+    /// When every status check fails, one summary is logged instead of an
+    /// error per purchase.
+    pub async fn check_purchases_once(&self) -> PurchaseCheckOutcome {
+        self.retry_failed_pinned().await;
+        
+        let purchases_to_check: Vec<(PathBuf, String)> = {
+            let records = self.records.read().await;
+            records.iter()
+                .filter_map(|(path, record)| {
+                    let expiry_minutes = record.expiry_minutes.unwrap_or(self.config.storage_params.expiry_minutes);
+                    let wait_window = chrono::Duration::minutes(expiry_minutes as i64);
+                    let abandoned = record.status == FileStatus::Creating
+                        && record.updated_at + wait_window < chrono::Utc::now();
+                    let polled = matches!(record.status, FileStatus::Active | FileStatus::Degraded | FileStatus::WaitingLong);
+                    if polled || abandoned {
+                        record.purchase_id.as_ref().map(|id| (path.clone(), id.clone()))
+                    } else {
+                        None
+                    }
+                })
+                .collect()
+        };
+        
+        // Paths with the same contents can share one purchase. Each purchase
+        // is checked and renewed once, through the first of its paths, and
+        // the others then follow that path's record.
+        let mut by_purchase: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+        for (file_path, purchase_id) in purchases_to_check {
+            by_purchase.entry(purchase_id).or_default().push(file_path);
+        }
+        
+        // Pinned purchases are checked, and renewed, first.
+        let mut by_purchase: Vec<(String, Vec<PathBuf>)> = by_purchase.into_iter().collect();
+        {
+            let records = self.records.read().await;
+            let pinned = |paths: &Vec<PathBuf>| paths.iter().any(|path| records.get(path).is_some_and(|record| record.pinned));
+            by_purchase.sort_by_key(|(_, paths)| !pinned(paths));
+        }
+        
+        let checked = by_purchase.len();
+        let mut status_failures = Vec::new();
+        for (purchase_id, mut paths) in by_purchase {
+            paths.sort();
+            let file_path = paths.remove(0);
+            let endpoint = self.records.read().await
+                .get(&file_path)
+                .and_then(|record| record.codex_endpoint.clone());
+            let status = match self.codex_client.get_purchase_status(&purchase_id, endpoint.as_deref()).await {
+                Ok(status) => {
+                    self.capture_codex_response(&file_path, "get_purchase_status", &purchase_id, &status.raw_body).await;
+                    status
+                }
+                Err(e) => {
+                    status_failures.push((file_path, e));
+                    continue;
+                }
+            };
+            if let Err(e) = self.check_purchase_status(&file_path, &purchase_id, status).await {
+                error!("Failed to check purchase status for {}: {}", file_path.display(), e);
+            }
+            if let Err(e) = self.follow_shared_purchase(&file_path, &paths).await {
+                error!("Failed to update records sharing purchase {}: {}", purchase_id, e);
+            }
+        }
+        
+        if checked > 0 && status_failures.len() == checked {
+            let (_, first_error) = &status_failures[0];
+            warn!("All {} purchase status checks failed, e.g.: {}", checked, first_error);
+            return PurchaseCheckOutcome::AllFailed;
+        }
+        for (file_path, e) in status_failures {
+            error!("Failed to check purchase status for {}: {}", file_path.display(), e);
+        }
+        PurchaseCheckOutcome::Reachable
+    }
+    
+    /// Hands pinned files left `Failed` back to `process_file`, so a failed
+    /// renewal is retried on every pass instead of being left to lapse.
+    async fn retry_failed_pinned(&self) {
+        let failed: Vec<PathBuf> = {
+            let records = self.records.read().await;
+            let mut failed: Vec<PathBuf> = records.iter()
+                .filter(|(_, record)| record.pinned && record.status == FileStatus::Failed)
+                .map(|(path, _)| path.clone())
+                .collect();
+            failed.sort();
+            failed
+        };
+        
+        for file_path in failed {
+            if self.shutdown.is_cancelled() {
+                return;
+            }
+            info!("Retrying pinned file {}", file_path.display());
+            if let Err(e) = self.process_file(&file_path).await {
+                error!("Retry of pinned file {} failed: {}", file_path.display(), e);
+            }
+        }
+    }
+    
+    /// Copies the purchase state of `primary`'s record to the records of
+    /// `sharing`, the other paths that were on the same purchase.
+    async fn follow_shared_purchase(&self, primary: &Path, sharing: &[PathBuf]) -> Result<()> {
+        if sharing.is_empty() {
+            return Ok(());
+        }
+        
+        let mut records = self.records.write().await;
+        let Some(source) = records.get(primary).cloned() else {
+            return Ok(());
+        };
+        for path in sharing {
+            if let Some(record) = records.get_mut(path) {
+                self.storage_manager.share_purchase(record, &source);
+                self.storage_manager.save_record(&self.config.target_folder, path, record).await?;
+            }
         }
+        Ok(())
     }
     
-    async fn check_purchase_status(&self, file_path: &Path, purchase_id: &str) -> Result<()> {
-        let status = self.codex_client.get_purchase_status(purchase_id).await?;
+    async fn check_purchase_status(&self, file_path: &Path, purchase_id: &str, status: PurchaseStatus) -> Result<()> {
+        if status.state == "started"
+            && !status.has_started(self.config.min_filled_slots)
+            && !self.handle_underfilled_purchase(file_path, purchase_id, &status).await?
+        {
+            return Ok(());
+        }
         
         match status.state.as_str() {
             "started" => {
-                // Still active, check if renewal is needed
-                let records = self.records.read().await;
-                if let Some(record) = records.get(file_path) {
+                let mut records = self.records.write().await;
+                if let Some(record) = records.get_mut(file_path) {
+                    let stored = matches!(record.status, FileStatus::Active | FileStatus::Degraded);
+                    if !stored || record.pending_since.is_some() {
+                        info!("Purchase {} for file {} has started", purchase_id, file_path.display());
+                        record.filled_slots = status.filled_slots;
+                        self.storage_manager.mark_record_active(record);
+                        self.storage_manager.save_record(&self.config.target_folder, file_path, record).await?;
+                    } else if record.filled_slots != status.filled_slots {
+                        record.filled_slots = status.filled_slots;
+                        self.storage_manager.save_record(&self.config.target_folder, file_path, record).await?;
+                    }
+                    
+                    // Still active, check if renewal is needed
                     if self.needs_renewal(record) {
                         drop(records);
                         info!("Purchase {} needs renewal for file {}", purchase_id, file_path.display());
                         self.process_file(file_path).await?;
+                    } else if self.verification_due(record) {
+                        drop(records);
+                        self.verify_retrievable(file_path, purchase_id).await?;
                     }
                 }
             }
             "failed" | "cancelled" | "expired" => {
                 info!("Purchase {} failed for file {}, creating new purchase", purchase_id, file_path.display());
+                self.release_waiting_record(file_path, &status.state).await?;
                 self.process_file(file_path).await?;
             }
             _ => {
-                debug!("Purchase {} in state: {}", purchase_id, status.state);
+                let waiting_long = self.records.read().await
+                    .get(file_path)
+                    .is_some_and(|record| record.status == FileStatus::WaitingLong);
+                if waiting_long {
+                    self.handle_waiting_long_purchase(file_path, purchase_id, &status.state).await?;
+                } else {
+                    self.handle_pending_purchase(file_path, purchase_id, &status.state).await?;
+                }
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// Whether a stored file is due for a scheduled retrievability check.
+    /// Degraded files are checked on every pass so recovery is noticed.
+    fn verification_due(&self, record: &FileRecord) -> bool {
+        let Some(days) = self.config.verify_cadence_days else {
+            return false;
+        };
+        if record.status == FileStatus::Degraded {
+            return true;
+        }
+        let last = record.last_verified_at.unwrap_or(record.created_at);
+        last + chrono::Duration::days(days as i64) <= chrono::Utc::now()
+    }
+    
+    /// Asks the endpoints for a stored file's CID. A file no endpoint has is
+    /// flagged `Degraded`, and re-uploaded if `reupload_degraded` is set; one
+    /// that turns up again goes back to `Active`. If no endpoint gives a
+    /// definite answer, the check is retried on the next pass.
+    async fn verify_retrievable(&self, file_path: &Path, purchase_id: &str) -> Result<()> {
+        let cid = {
+            let records = self.records.read().await;
+            let Some(record) = records.get(file_path) else {
+                return Ok(());
+            };
+            match record.storage_cid.as_ref().or(record.original_cid.as_ref()) {
+                Some(cid) => cid.clone(),
+                None => return Ok(()),
+            }
+        };
+        
+        let mut found = false;
+        let mut answered = false;
+        let mut reasons = Vec::new();
+        for endpoint in self.codex_client.endpoints() {
+            match self.codex_client.has_cid(endpoint, &cid).await {
+                Ok(true) => {
+                    found = true;
+                    break;
+                }
+                Ok(false) => {
+                    answered = true;
+                    reasons.push(format!("{}: not found", endpoint));
+                }
+                Err(e) => reasons.push(e.to_string()),
+            }
+        }
+        if !found && !answered {
+            warn!("Could not verify {} (CID {}): {}", file_path.display(), cid, reasons.join("; "));
+            return Ok(());
+        }
+        
+        let mut records = self.records.write().await;
+        let Some(record) = records.get_mut(file_path) else {
+            return Ok(());
+        };
+        record.last_verified_at = Some(chrono::Utc::now());
+        
+        if found {
+            if record.status == FileStatus::Degraded {
+                info!("{} is retrievable again, marking it active", file_path.display());
+                record.error = None;
+                self.storage_manager.mark_record_active(record);
+            }
+            debug!("Verified {} is retrievable as CID {}", file_path.display(), cid);
+            return self.storage_manager.save_record(&self.config.target_folder, file_path, record).await;
+        }
+        
+        let message = format!("CID {} could not be retrieved while purchase {} is active: {}",
+                              cid, purchase_id, reasons.join("; "));
+        if record.status != FileStatus::Degraded {
+            warn!("{} is degraded: {}", file_path.display(), message);
+            telemetry::record_failure("verify");
+        }
+        self.storage_manager.update_record_status(record, FileStatus::Degraded, Some(message));
+        self.storage_manager.save_record(&self.config.target_folder, file_path, record).await?;
+        
+        if self.config.reupload_degraded {
+            info!("Re-uploading degraded file {}", file_path.display());
+            self.storage_manager.archive_record(record, "CID could not be retrieved");
+            self.storage_manager.save_record(&self.config.target_folder, file_path, record).await?;
+            drop(records);
+            self.process_file(file_path).await?;
+        }
+        Ok(())
+    }
+    
+    /// A started purchase with fewer than `min_filled_slots` filled. An active
+    /// record stays active but is flagged as degraded, and `true` is returned
+    /// so it is still checked for renewal; anything else keeps waiting as if
+    /// the purchase hadn't started.
+    async fn handle_underfilled_purchase(&self, file_path: &Path, purchase_id: &str, status: &PurchaseStatus) -> Result<bool> {
+        let record_status = {
+            let mut records = self.records.write().await;
+            let Some(record) = records.get_mut(file_path) else {
+                return Ok(false);
+            };
+            if record.filled_slots != status.filled_slots {
+                record.filled_slots = status.filled_slots;
+                self.storage_manager.save_record(&self.config.target_folder, file_path, record).await?;
+            }
+            record.status.clone()
+        };
+        
+        let filled = status.filled_slots.unwrap_or(0);
+        let min = self.config.min_filled_slots.unwrap_or(0);
+        match record_status {
+            FileStatus::Active | FileStatus::Degraded => {
+                warn!("Purchase {} for {} is degraded: only {} of the required {} slots are filled",
+                      purchase_id, file_path.display(), filled, min);
+                return Ok(true);
+            }
+            FileStatus::WaitingLong => {
+                let state = format!("started with {} of {} slots", filled, min);
+                self.handle_waiting_long_purchase(file_path, purchase_id, &state).await?;
+            }
+            _ => {
+                let state = format!("started with {} of {} slots", filled, min);
+                self.handle_pending_purchase(file_path, purchase_id, &state).await?;
+            }
+        }
+        Ok(false)
+    }
+    
+    /// A `WaitingLong` record counts as settled in `process_file`, so it is
+    /// failed first when its purchase ends without starting.
+    async fn release_waiting_record(&self, file_path: &Path, state: &str) -> Result<()> {
+        let mut records = self.records.write().await;
+        if let Some(record) = records.get_mut(file_path) {
+            if record.status == FileStatus::WaitingLong {
+                self.storage_manager.update_record_status(record, FileStatus::Failed,
+                    Some(format!("Purchase reached final state {} without starting", state)));
+                self.storage_manager.save_record(&self.config.target_folder, file_path, record).await?;
+            }
+        }
+        Ok(())
+    }
+    
+    /// Fails a `WaitingLong` purchase once `max_wait_for_start_minutes` and
+    /// the purchase's own expiry window have passed since the wait for it
+    /// began.
+    async fn handle_waiting_long_purchase(&self, file_path: &Path, purchase_id: &str, state: &str) -> Result<()> {
+        let mut records = self.records.write().await;
+        let Some(record) = records.get_mut(file_path) else {
+            return Ok(());
+        };
+        let expiry_minutes = record.expiry_minutes.unwrap_or(self.config.storage_params.expiry_minutes);
+        let max_wait_minutes = self.config.max_wait_for_start_minutes
+            .map_or(expiry_minutes, |max_wait| max_wait.max(expiry_minutes));
+        let max_wait = chrono::Duration::minutes(max_wait_minutes as i64);
+        let waited = chrono::Utc::now() - record.pending_since.unwrap_or(record.updated_at);
+        
+        if waited < max_wait {
+            debug!("Purchase {} still {} after {} minutes", purchase_id, state, waited.num_minutes());
+            return Ok(());
+        }
+        
+        let message = format!("Purchase {} did not start within {} minutes (last state: {})",
+                              purchase_id, max_wait.num_minutes(), state);
+        warn!("{} for file {}", message, file_path.display());
+        self.storage_manager.update_record_status(record, FileStatus::Failed, Some(message));
+        self.storage_manager.save_record(&self.config.target_folder, file_path, record).await
+    }
+    
+    /// Tracks how long a purchase has sat in a non-terminal state and, past
+    /// `stuck_purchase_timeout_minutes`, fails or recreates it.
+    async fn handle_pending_purchase(&self, file_path: &Path, purchase_id: &str, state: &str) -> Result<()> {
+        let pending_for = {
+            let mut records = self.records.write().await;
+            let Some(record) = records.get_mut(file_path) else {
+                return Ok(());
+            };
+            
+            let since = match record.pending_since {
+                Some(since) => since,
+                None => {
+                    let now = chrono::Utc::now();
+                    record.pending_since = Some(now);
+                    self.storage_manager.save_record(&self.config.target_folder, file_path, record).await?;
+                    now
+                }
+            };
+            chrono::Utc::now() - since
+        };
+        
+        let Some(timeout_minutes) = self.config.stuck_purchase_timeout_minutes else {
+            debug!("Purchase {} in state: {}", purchase_id, state);
+            return Ok(());
+        };
+        
+        if pending_for < chrono::Duration::minutes(timeout_minutes as i64) {
+            debug!("Purchase {} in state {} for {} minutes", purchase_id, state, pending_for.num_minutes());
+            return Ok(());
+        }
+        
+        let message = format!("Purchase {} stuck in state {} for {} minutes",
+                              purchase_id, state, pending_for.num_minutes());
+        warn!("{} for file {}", message, file_path.display());
+        
+        match self.config.stuck_purchase_action {
+            StuckPurchaseAction::Fail => {
+                let mut records = self.records.write().await;
+                if let Some(record) = records.get_mut(file_path) {
+                    self.storage_manager.update_record_status(record, FileStatus::Failed, Some(message));
+                    self.storage_manager.save_record(&self.config.target_folder, file_path, record).await?;
+                }
+            }
+            StuckPurchaseAction::Recreate => {
+                info!("Recreating stuck purchase for file {}", file_path.display());
+                // Cancelled first, so the stuck purchase can't still fill
+                // alongside the new one. If that fails, the next pass tries again.
+                let endpoint = self.records.read().await.get(file_path).and_then(|record| record.codex_endpoint.clone());
+                self.codex_client.cancel_purchase(purchase_id, endpoint.as_deref()).await
+                    .map_err(|e| anyhow!("Failed to cancel stuck purchase {}: {}", purchase_id, e))?;
+                {
+                    let mut records = self.records.write().await;
+                    let Some(record) = records.get_mut(file_path) else {
+                        return Ok(());
+                    };
+                    self.storage_manager.archive_record(record, &message);
+                    self.storage_manager.save_record(&self.config.target_folder, file_path, record).await?;
+                }
+                self.process_file(file_path).await?;
             }
         }
         
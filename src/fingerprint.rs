@@ -0,0 +1,184 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use crate::config::ChangeDetection;
+use crate::storage::FileRecord;
+
+/// What a file looked like at the time it was stored.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileFingerprint {
+    pub size: u64,
+    pub modified: DateTime<Utc>,
+    pub content_hash: Option<String>,
+}
+
+/// Result of comparing a file on disk against its stored record.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChangeStatus {
+    Unchanged,
+    /// Size or mtime moved but the contents hash the same. The stored
+    /// fingerprint should be refreshed so the next check stays cheap.
+    Touched(FileFingerprint),
+    Changed,
+}
+
+pub fn size_and_mtime(path: &Path) -> Result<(u64, DateTime<Utc>)> {
+    let metadata = path.metadata()
+        .map_err(|e| anyhow!("Failed to get metadata for {}: {}", path.display(), e))?;
+    let modified = metadata.modified()
+        .map_err(|e| anyhow!("Failed to get modification time for {}: {}", path.display(), e))?;
+    Ok((metadata.len(), DateTime::<Utc>::from(modified)))
+}
+
+/// Device and inode of a file, and how many hard links it has, i.e. through
+/// how many paths it can be reached. `None` on platforms without inodes.
+#[cfg(unix)]
+pub fn file_id(path: &Path) -> Option<((u64, u64), u64)> {
+    use std::os::unix::fs::MetadataExt;
+    
+    let metadata = path.metadata().ok()?;
+    Some(((metadata.dev(), metadata.ino()), metadata.nlink()))
+}
+
+#[cfg(not(unix))]
+pub fn file_id(_path: &Path) -> Option<((u64, u64), u64)> {
+    None
+}
+
+/// Whether `dir` lives on a filesystem that ignores case in file names,
+/// found by looking the directory up again under a different case. Checks
+/// the nearest component of the path with letters in it; `false` when none
+/// has any or the directory can't be read.
+pub fn is_case_insensitive(dir: &Path) -> bool {
+    for candidate in dir.ancestors() {
+        let (Some(name), Some(parent)) = (candidate.file_name(), candidate.parent()) else {
+            continue;
+        };
+        let name = name.to_string_lossy();
+        let swapped: String = name.chars()
+            .map(|c| if c.is_lowercase() { c.to_uppercase().next().unwrap_or(c) } else { c.to_lowercase().next().unwrap_or(c) })
+            .collect();
+        if swapped == name {
+            continue;
+        }
+        return same_file(candidate, &parent.join(swapped));
+    }
+    false
+}
+
+#[cfg(unix)]
+fn same_file(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    
+    match (a.metadata(), b.metadata()) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn same_file(a: &Path, b: &Path) -> bool {
+    a.exists() && b.exists()
+}
+
+/// Files are hashed in chunks of this size. They aren't memory mapped: the
+/// files hashed are often still being written, and a file truncated under a
+/// map faults the whole process rather than failing one read.
+const HASH_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// SHA-256 of a file's contents, computed without buffering the whole file.
+pub async fn sha256_file(path: &Path) -> Result<String> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || hash_file_blocking(&path))
+        .await
+        .map_err(|e| anyhow!("Hashing task failed: {}", e))?
+}
+
+fn hash_file_blocking(path: &Path) -> Result<String> {
+    let mut file = File::open(path)
+        .map_err(|e| anyhow!("Failed to open file {} for hashing: {}", path.display(), e))?;
+    
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; HASH_CHUNK_SIZE];
+    loop {
+        let read = file.read(&mut buffer)
+            .map_err(|e| anyhow!("Failed to read file {} for hashing: {}", path.display(), e))?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    
+    Ok(hex_digest(hasher.finalize().as_slice()))
+}
+
+/// SHA-256 of an in-memory buffer, in the same form as `sha256_file`.
+pub fn sha256_bytes(bytes: &[u8]) -> String {
+    hex_digest(Sha256::digest(bytes).as_slice())
+}
+
+/// Lowercase hex of `bytes`.
+pub fn hex_digest(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Captures the fingerprint to store on a record, hashing only when the
+/// configured mode will ever compare hashes.
+pub async fn compute(path: &Path, mode: ChangeDetection) -> Result<FileFingerprint> {
+    let (size, modified) = size_and_mtime(path)?;
+    let content_hash = match mode {
+        ChangeDetection::SizeMtime => None,
+        ChangeDetection::Sha256 | ChangeDetection::Both => Some(sha256_file(path).await?),
+    };
+
+    Ok(FileFingerprint { size, modified, content_hash })
+}
+
+/// Records written before fingerprints existed are treated as unchanged so
+/// that upgrading does not trigger a full re-upload.
+pub async fn detect_change(record: &FileRecord, path: &Path, mode: ChangeDetection) -> Result<ChangeStatus> {
+    let (size, modified) = size_and_mtime(path)?;
+    let stat_matches = record.file_size == Some(size) && record.file_modified == Some(modified);
+
+    match mode {
+        ChangeDetection::SizeMtime => {
+            if record.file_size.is_none() || stat_matches {
+                Ok(ChangeStatus::Unchanged)
+            } else {
+                Ok(ChangeStatus::Changed)
+            }
+        }
+        ChangeDetection::Sha256 => {
+            let Some(stored_hash) = &record.content_hash else {
+                return Ok(ChangeStatus::Unchanged);
+            };
+            if sha256_file(path).await? == *stored_hash {
+                Ok(ChangeStatus::Unchanged)
+            } else {
+                Ok(ChangeStatus::Changed)
+            }
+        }
+        ChangeDetection::Both => {
+            if stat_matches {
+                return Ok(ChangeStatus::Unchanged);
+            }
+            let Some(stored_hash) = &record.content_hash else {
+                return Ok(if record.file_size.is_some() { ChangeStatus::Changed } else { ChangeStatus::Unchanged });
+            };
+            let current_hash = sha256_file(path).await?;
+            if current_hash == *stored_hash {
+                Ok(ChangeStatus::Touched(FileFingerprint {
+                    size,
+                    modified,
+                    content_hash: Some(current_hash),
+                }))
+            } else {
+                Ok(ChangeStatus::Changed)
+            }
+        }
+    }
+}
@@ -1,25 +1,166 @@
 pub mod config;
+pub mod commands;
 pub mod codex;
 pub mod file_processor;
 pub mod storage;
 pub mod monitor;
 pub mod error;
+pub mod fingerprint;
+pub mod usage;
+pub mod telemetry;
+pub mod eligibility;
+pub mod queue;
+pub mod metrics;
+pub mod event_log;
+pub mod sqlite_store;
+pub mod manifest;
+pub mod api;
+#[cfg(feature = "tui")]
+pub mod tui;
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::path::PathBuf;
+    use std::path::{Path, PathBuf};
+
+    struct MockRequest {
+        method: String,
+        path: String,
+        /// Header names are lowercased.
+        headers: std::collections::HashMap<String, String>,
+        body: String,
+    }
+
+    struct MockResponse {
+        status: u16,
+        content_type: &'static str,
+        body: String,
+    }
+
+    impl MockResponse {
+        fn new(status: u16, content_type: &'static str, body: impl Into<String>) -> Self {
+            Self { status, content_type, body: body.into() }
+        }
+    }
+
+    /// Serves each connection with `handler` and returns the base URL. Every
+    /// response closes its connection, so requests are handled one at a time.
+    async fn mock_server<F>(handler: F) -> String
+    where
+        F: Fn(MockRequest) -> MockResponse + Send + Sync + 'static,
+    {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handler = std::sync::Arc::new(handler);
+
+        tokio::spawn(async move {
+            loop {
+                let Ok((mut socket, _)) = listener.accept().await else { return };
+                let handler = handler.clone();
+                tokio::spawn(async move {
+                    let mut buf = Vec::new();
+                    let mut chunk = [0u8; 8192];
+                    let head_end = loop {
+                        let n = socket.read(&mut chunk).await.unwrap_or(0);
+                        if n == 0 {
+                            return;
+                        }
+                        buf.extend_from_slice(&chunk[..n]);
+                        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                            break pos + 4;
+                        }
+                    };
+                    let head = String::from_utf8_lossy(&buf[..head_end]).to_string();
+                    let content_length = head.lines()
+                        .find_map(|line| {
+                            let (name, value) = line.split_once(':')?;
+                            name.eq_ignore_ascii_case("content-length").then(|| value.trim().parse::<usize>().ok())?
+                        })
+                        .unwrap_or(0);
+                    while buf.len() < head_end + content_length {
+                        let n = socket.read(&mut chunk).await.unwrap_or(0);
+                        if n == 0 {
+                            break;
+                        }
+                        buf.extend_from_slice(&chunk[..n]);
+                    }
+                    let mut request_line = head.lines().next().unwrap_or_default().split_whitespace();
+                    let request = MockRequest {
+                        method: request_line.next().unwrap_or_default().to_string(),
+                        path: request_line.next().unwrap_or_default().to_string(),
+                        headers: head.lines()
+                            .skip(1)
+                            .filter_map(|line| line.split_once(':'))
+                            .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+                            .collect(),
+                        body: String::from_utf8_lossy(&buf[head_end..]).to_string(),
+                    };
+                    let response = handler(request);
+                    let raw = format!(
+                        "HTTP/1.1 {} Mock\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        response.status,
+                        response.content_type,
+                        response.body.len(),
+                        response.body
+                    );
+                    let _ = socket.write_all(raw.as_bytes()).await;
+                    let _ = socket.shutdown().await;
+                });
+            }
+        });
+
+        format!("http://{}", addr)
+    }
+
+    /// Answers as a Codex node where every upload and purchase succeeds:
+    /// uploads get a fixed CID, storage requests `purchase-1`, cancels
+    /// nothing, and every purchase has started.
+    fn codex_response(req: &MockRequest) -> MockResponse {
+        match req.path.as_str() {
+            "/api/codex/v1/data" => MockResponse::new(200, "text/plain", "zDvZRwzm2mK7tvDzKScRLapqGdgNTLyyEBvx1TQY37J2CvuzL7Gh"),
+            path if path.starts_with("/api/codex/v1/storage/request/") => MockResponse::new(200, "text/plain", "purchase-1"),
+            path if path.ends_with("/cancel") => MockResponse::new(200, "text/plain", ""),
+            _ => MockResponse::new(200, "application/json",
+                r#"{"state":"started","request":{"content":{"cid":"zCid"}}}"#),
+        }
+    }
+
+    /// A node answering with `codex_response`.
+    async fn happy_codex_mock() -> String {
+        mock_server(|req| codex_response(&req)).await
+    }
+
+    /// Like `happy_codex_mock`, also counting the uploads it receives.
+    async fn counting_codex_mock() -> (String, std::sync::Arc<std::sync::atomic::AtomicUsize>) {
+        let uploads = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counted = uploads.clone();
+        let server = mock_server(move |req| {
+            if req.path == "/api/codex/v1/data" {
+                counted.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            }
+            codex_response(&req)
+        }).await;
+        (server, uploads)
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("filehog-{}-{}", name, uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.canonicalize().unwrap()
+    }
 
     #[test]
     fn test_storage_params_default() {
         let params = config::StorageParams::default();
-        assert_eq!(params.price, 1000);
+        assert_eq!(params.price, config::TokenAmount::Integer(1000));
         assert_eq!(params.nodes, 10);
         assert_eq!(params.tolerance, 5);
-        assert_eq!(params.proof_probability, 100);
+        assert_eq!(params.proof_probability, config::ProofProbability::Integer(100));
         assert_eq!(params.duration_days, 6);
         assert_eq!(params.expiry_minutes, 60);
-        assert_eq!(params.collateral, 1);
+        assert_eq!(params.collateral, config::TokenAmount::Integer(1));
     }
 
     #[test]
@@ -30,6 +171,7 @@ mod tests {
             output_structure: config::OutputStructure::Structured,
             codex_endpoints: vec!["http://localhost:8080".to_string()],
             storage_params: config::StorageParams::default(),
+            ..Default::default()
         };
         
         assert!(config.validate().is_err());
@@ -43,6 +185,7 @@ mod tests {
             output_structure: config::OutputStructure::Structured,
             codex_endpoints: vec!["http://localhost:8080".to_string()],
             storage_params: config::StorageParams::default(),
+            ..Default::default()
         };
         
         config.storage_params.duration_days = 0;
@@ -57,12 +200,92 @@ mod tests {
             output_structure: config::OutputStructure::Structured,
             codex_endpoints: vec!["http://localhost:8080".to_string()],
             storage_params: config::StorageParams::default(),
+            ..Default::default()
         };
         
         config.storage_params.expiry_minutes = 10;
         assert!(config.validate().is_err());
     }
 
+    #[test]
+    fn test_config_validation_startup_connectivity_retries() {
+        let target = temp_dir("startup-retries-target");
+        let mut config = config::Config {
+            target_folder: target.clone(),
+            output_folder: target.with_extension("output"),
+            codex_endpoints: vec!["http://localhost:8080".to_string()],
+            startup_connectivity_retries: config::MAX_STARTUP_CONNECTIVITY_RETRIES,
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+
+        config.startup_connectivity_retries += 1;
+        assert!(config.validate().is_err());
+
+        // The wait between attempts stops doubling at the cap.
+        let policy = error::RetryPolicy {
+            max_retries: 20,
+            initial_delay: std::time::Duration::from_secs(5),
+            max_delay: Some(std::time::Duration::from_secs(60)),
+            jitter: 0.0,
+        };
+        assert_eq!(policy.delay(19), std::time::Duration::from_secs(60));
+
+        std::fs::remove_dir_all(&target).unwrap();
+    }
+
+    #[test]
+    fn test_config_validation_max_wait_for_start() {
+        let target = temp_dir("max-wait-target");
+        let mut config = config::Config {
+            target_folder: target.clone(),
+            output_folder: target.with_extension("output"),
+            codex_endpoints: vec!["http://localhost:8080".to_string()],
+            max_wait_for_start_minutes: Some(240),
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+
+        // An override with a longer expiry needs a longer wait too.
+        config.overrides = vec![config::PathOverride {
+            path: "videos/**".parse().unwrap(),
+            params: config::StorageParamsOverride { expiry_minutes: Some(480), ..Default::default() },
+        }];
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("videos/**") && err.contains("480"), "{}", err);
+
+        config.max_wait_for_start_minutes = Some(480);
+        assert!(config.validate().is_ok());
+
+        std::fs::remove_dir_all(&target).unwrap();
+    }
+
+    #[test]
+    fn test_config_validation_metrics_snapshot_path() {
+        let target = temp_dir("metrics-path-target");
+        let output = temp_dir("metrics-path-output");
+        let mut config = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            output_structure: config::OutputStructure::Structured,
+            codex_endpoints: vec!["http://localhost:8080".to_string()],
+            metrics_snapshot_path: Some(std::env::temp_dir().join("filehog-metrics.json")),
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+
+        config.metrics_snapshot_path = Some(target.join("metrics.json"));
+        assert!(config.validate().is_err());
+
+        config.metrics_snapshot_path = Some(output.join("metrics.json"));
+        assert!(config.validate().is_err());
+        config.output_structure = config::OutputStructure::Flattened;
+        assert!(config.validate().is_ok());
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
     #[test]
     fn test_file_record_creation() {
         let storage_manager = storage::StorageManager::new(
@@ -75,4 +298,4332 @@ mod tests {
         assert!(record.original_cid.is_none());
         assert!(record.purchase_id.is_none());
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_size_mtime_change_detection() {
+        let dir = temp_dir("change-detection");
+        let file = dir.join("data.bin");
+        std::fs::write(&file, vec![0u8; 1024]).unwrap();
+
+        let storage_manager = storage::StorageManager::new(dir.join("out"), config::OutputStructure::Structured);
+        let mut record = storage_manager.create_new_record(file.clone());
+        let mode = config::ChangeDetection::SizeMtime;
+
+        let print = fingerprint::compute(&file, mode).await.unwrap();
+        assert!(print.content_hash.is_none());
+        storage_manager.update_record_fingerprint(&mut record, &print);
+        assert_eq!(fingerprint::detect_change(&record, &file, mode).await.unwrap(), fingerprint::ChangeStatus::Unchanged);
+
+        std::fs::write(&file, vec![1u8; 2048]).unwrap();
+        assert_eq!(fingerprint::detect_change(&record, &file, mode).await.unwrap(), fingerprint::ChangeStatus::Changed);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_both_change_detection_ignores_mtime_only_touch() {
+        let dir = temp_dir("change-detection-both");
+        let file = dir.join("data.bin");
+        std::fs::write(&file, vec![7u8; 1024]).unwrap();
+
+        let storage_manager = storage::StorageManager::new(dir.join("out"), config::OutputStructure::Structured);
+        let mut record = storage_manager.create_new_record(file.clone());
+        let mode = config::ChangeDetection::Both;
+
+        let print = fingerprint::compute(&file, mode).await.unwrap();
+        storage_manager.update_record_fingerprint(&mut record, &print);
+
+        record.file_modified = Some(print.modified - chrono::Duration::seconds(60));
+        match fingerprint::detect_change(&record, &file, mode).await.unwrap() {
+            fingerprint::ChangeStatus::Touched(refreshed) => assert_eq!(refreshed, print),
+            other => panic!("expected touched, got {:?}", other),
+        }
+
+        std::fs::write(&file, vec![8u8; 1024]).unwrap();
+        record.file_modified = Some(print.modified - chrono::Duration::seconds(60));
+        assert_eq!(fingerprint::detect_change(&record, &file, mode).await.unwrap(), fingerprint::ChangeStatus::Changed);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_purchase_status_html_error_page() {
+        let page = format!("<html><body><h1>502 Bad Gateway</h1>{}</body></html>", "x".repeat(1000));
+        let bad_gateway = page.clone();
+        let endpoint = mock_server(move |req| {
+            assert_eq!(req.method, "GET");
+            assert_eq!(req.path, "/api/codex/v1/storage/purchases/abc");
+            MockResponse::new(502, "text/html", bad_gateway.clone())
+        }).await;
+        let client = codex::Client::new(vec![endpoint]);
+
+        let err = client.get_purchase_status("abc", None).await.unwrap_err().to_string();
+        assert!(err.contains("502"));
+        assert!(err.len() < page.len());
+
+        let endpoint = mock_server(move |_| MockResponse::new(200, "text/html", page.clone())).await;
+        let client = codex::Client::new(vec![endpoint]);
+
+        let err = client.get_purchase_status("abc", None).await.unwrap_err().to_string();
+        assert!(err.contains("text/html"), "{}", err);
+        assert!(err.contains("200"), "{}", err);
+    }
+
+    #[test]
+    fn test_renewal_strategies() {
+        let storage_manager = storage::StorageManager::new(
+            PathBuf::from("/tmp/output"),
+            config::OutputStructure::Structured,
+        );
+        let mut record = storage_manager.create_new_record(PathBuf::from("/tmp/test.txt"));
+        storage_manager.mark_record_active(&mut record);
+
+        let eager = config::Config {
+            renewal_strategy: config::RenewalStrategy::Eager,
+            renewal_buffer_minutes: 60,
+            ..Default::default()
+        };
+        let just_in_time = config::Config {
+            renewal_strategy: config::RenewalStrategy::JustInTime,
+            ..eager.clone()
+        };
+
+        // 30 minutes left on the purchase.
+        record.active_at = Some(chrono::Utc::now() - chrono::Duration::days(6) + chrono::Duration::minutes(30));
+        assert!(storage_manager.needs_new_purchase(&record, eager.renewal_lead()));
+        assert!(!storage_manager.needs_new_purchase(&record, just_in_time.renewal_lead()));
+
+        // Expired a minute ago.
+        record.active_at = Some(chrono::Utc::now() - chrono::Duration::days(6) - chrono::Duration::minutes(1));
+        assert!(storage_manager.needs_new_purchase(&record, eager.renewal_lead()));
+        assert!(storage_manager.needs_new_purchase(&record, just_in_time.renewal_lead()));
+    }
+
+    #[test]
+    fn test_proof_probability_formats() {
+        let parse = |value: &str| -> config::StorageParams {
+            toml::from_str(&format!(
+                "price = 1000\nnodes = 10\ntolerance = 5\nproof_probability = {}\nduration_days = 6\nexpiry_minutes = 60\ncollateral = 1\n",
+                value
+            )).unwrap()
+        };
+
+        assert_eq!(parse("100").proof_probability.to_string(), "100");
+        assert_eq!(parse("0.25").proof_probability.to_string(), "0.25");
+        assert_eq!(parse("\"0.25\"").proof_probability.to_string(), "0.25");
+
+        let dir = temp_dir("proof-probability");
+        let mut config = config::Config {
+            target_folder: dir.join("target"),
+            output_folder: dir.join("output"),
+            ..Default::default()
+        };
+        std::fs::create_dir_all(&config.target_folder).unwrap();
+        config.storage_params.proof_probability = config::ProofProbability::Text("0.25".to_string());
+        assert!(config.validate().is_ok());
+        config.storage_params.proof_probability = config::ProofProbability::Decimal(150.0);
+        assert!(config.validate().is_err());
+        config.storage_params.proof_probability = config::ProofProbability::Text("often".to_string());
+        assert!(config.validate().is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_baseline_scan_records_without_uploading() {
+        let dir = temp_dir("baseline");
+        let target = dir.join("target");
+        std::fs::create_dir_all(&target).unwrap();
+        let file = target.join("existing.bin");
+        std::fs::write(&file, vec![0u8; 1024 * 1024]).unwrap();
+
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: dir.join("output"),
+            // Nothing listens here, so any upload attempt would fail the record.
+            codex_endpoints: vec!["http://127.0.0.1:9".to_string()],
+            baseline: true,
+            ..Default::default()
+        };
+        let client = std::sync::Arc::new(codex::Client::new(config.codex_endpoints.clone()));
+        let processor = file_processor::FileProcessor::new(std::sync::Arc::new(config), client);
+
+        processor.process_files().await.unwrap();
+
+        let record = processor.records.read().await.get(&file).cloned().unwrap();
+        assert_eq!(record.status, storage::FileStatus::Baseline);
+        assert!(record.original_cid.is_none());
+        assert!(!processor.has_changed_since_stored(&file).await.unwrap());
+
+        std::fs::write(&file, vec![1u8; 2 * 1024 * 1024]).unwrap();
+        assert!(processor.has_changed_since_stored(&file).await.unwrap());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_migrate_structure_to_flattened() {
+        let dir = temp_dir("migrate");
+        let target = dir.join("target");
+        let output = dir.join("output");
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            output_structure: config::OutputStructure::Structured,
+            ..Default::default()
+        };
+
+        let structured = storage::StorageManager::new(output.clone(), config::OutputStructure::Structured);
+        for name in ["a.bin", "nested/b.bin"] {
+            let file = target.join(name);
+            let mut record = structured.create_new_record(file.clone());
+            structured.mark_record_active(&mut record);
+            structured.save_record(&target, &file, &record).await.unwrap();
+        }
+
+        usage::UsageTracker::new(&output).record_upload(1024).await.unwrap();
+
+        let migrated = commands::migrate_structure(&config, config::OutputStructure::Flattened, true).await.unwrap();
+        assert_eq!(migrated, 2);
+        assert!(output.join("files.json").exists());
+        assert!(!output.join("a.json").exists());
+        assert!(!output.join("nested/b.json").exists());
+
+        let flattened = storage::StorageManager::new(output.clone(), config::OutputStructure::Flattened);
+        let records = flattened.load_existing_records(&target).await.unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[&target.join("nested/b.bin")].status, storage::FileStatus::Active);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_usage_ledger_report() {
+        let dir = temp_dir("usage");
+        let path = dir.join(usage::USAGE_FILE_NAME);
+        let day = |d: &str| chrono::NaiveDate::parse_from_str(d, "%Y-%m-%d").unwrap();
+        let upload = |bytes| usage::DailyUsage { bytes_uploaded: bytes, uploads: 1, storage_requests: 1 };
+
+        let mut ledger = usage::UsageLedger::default();
+        ledger.add(day("2026-09-30"), upload(100));
+        ledger.add(day("2026-10-01"), upload(200));
+        ledger.add(day("2026-10-01"), upload(300));
+        ledger.add(day("2026-10-15"), upload(400));
+        ledger.save(&path).await.unwrap();
+
+        let ledger = usage::UsageLedger::load(&path).await.unwrap();
+        let daily = ledger.report(Some(day("2026-10-01")), usage::UsagePeriod::Day);
+        assert_eq!(daily.len(), 2);
+        assert_eq!(daily[0].period, "2026-10-01");
+        assert_eq!(daily[0].usage.bytes_uploaded, 500);
+        assert_eq!(daily[0].usage.uploads, 2);
+
+        let monthly = ledger.report(None, usage::UsagePeriod::Month);
+        assert_eq!(monthly.len(), 2);
+        assert_eq!(monthly[1].period, "2026-10");
+        assert_eq!(monthly[1].usage.bytes_uploaded, 900);
+        assert_eq!(monthly[1].usage.storage_requests, 3);
+
+        // A write cut short leaves only the temporary file torn.
+        std::fs::write(path.with_extension("json.tmp"), "{\"days\":{\"2026-").unwrap();
+        assert_eq!(usage::UsageLedger::load(&path).await.unwrap().days.len(), 3);
+        ledger.save(&path).await.unwrap();
+        assert!(!path.with_extension("json.tmp").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stuck_purchase_is_failed_after_timeout() {
+        let endpoint = mock_server(|_| {
+            MockResponse::new(200, "application/json", r#"{"state":"pending","request":{"content":{"cid":"zCid"}}}"#)
+        }).await;
+
+        let dir = temp_dir("stuck-purchase");
+        let target = dir.join("target");
+        let file = target.join("stuck.bin");
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: dir.join("output"),
+            codex_endpoints: vec![endpoint],
+            stuck_purchase_timeout_minutes: Some(60),
+            stuck_purchase_action: config::StuckPurchaseAction::Fail,
+            ..Default::default()
+        };
+        let client = std::sync::Arc::new(codex::Client::new(config.codex_endpoints.clone()));
+        let processor = file_processor::FileProcessor::new(std::sync::Arc::new(config), client);
+
+        let mut record = processor.storage_manager.create_new_record(file.clone());
+        processor.storage_manager.update_record_purchase(&mut record, "purchase-1".to_string(), "zCid".to_string());
+        processor.storage_manager.mark_record_active(&mut record);
+        processor.records.write().await.insert(file.clone(), record);
+
+        // First sighting only starts the clock.
+        processor.check_purchases_once().await;
+        let record = processor.records.read().await[&file].clone();
+        assert_eq!(record.status, storage::FileStatus::Active);
+        assert!(record.pending_since.is_some());
+
+        processor.records.write().await.get_mut(&file).unwrap().pending_since =
+            Some(chrono::Utc::now() - chrono::Duration::hours(2));
+        processor.check_purchases_once().await;
+        let record = processor.records.read().await[&file].clone();
+        assert_eq!(record.status, storage::FileStatus::Failed);
+        assert!(record.error.unwrap().contains("stuck in state pending"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_compressed_records_round_trip() {
+        let dir = temp_dir("compressed");
+        let target = dir.join("target");
+        let output = dir.join("output");
+        let file = target.join("nested/data.bin");
+
+        for structure in [config::OutputStructure::Structured, config::OutputStructure::Flattened] {
+            std::fs::create_dir_all(&output).unwrap();
+            let plain_config = config::Config {
+                target_folder: target.clone(),
+                output_folder: output.clone(),
+                output_structure: structure.clone(),
+                ..Default::default()
+            };
+            let compressed_config = config::Config { compress_records: true, ..plain_config.clone() };
+
+            // A record written before compression was enabled still loads, and
+            // the next save replaces it with the compressed form.
+            let plain = storage::StorageManager::from_config(&plain_config);
+            let record = plain.create_new_record(file.clone());
+            plain.save_record(&target, &file, &record).await.unwrap();
+
+            let compressed = storage::StorageManager::from_config(&compressed_config);
+            let mut records = compressed.load_existing_records(&target).await.unwrap();
+            assert_eq!(records.len(), 1);
+            let record = records.get_mut(&file).unwrap();
+            compressed.mark_record_active(record);
+            compressed.save_record(&target, &file, record).await.unwrap();
+
+            let (plain_file, gz_file) = match structure {
+                config::OutputStructure::Structured => (output.join("nested/data.json"), output.join("nested/data.json.gz")),
+                config::OutputStructure::Flattened => (output.join("files.json"), output.join("files.json.gz")),
+                config::OutputStructure::Sqlite => unreachable!("records in a database aren't compressed"),
+            };
+            assert!(!plain_file.exists());
+            assert!(gz_file.exists());
+
+            let reloaded = compressed.load_existing_records(&target).await.unwrap();
+            assert_eq!(reloaded[&file].status, storage::FileStatus::Active);
+
+            std::fs::remove_dir_all(&output).unwrap();
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_token_amounts_pass_through_verbatim() {
+        let params: config::StorageParams = toml::from_str(
+            "price = \"340282366920938463463374607431768211456\"\nnodes = 10\ntolerance = 5\nproof_probability = 100\nduration_days = 6\nexpiry_minutes = 60\ncollateral = \"0.5\"\n"
+        ).unwrap();
+        assert_eq!(params.price.to_string(), "340282366920938463463374607431768211456");
+        assert_eq!(params.collateral.to_string(), "0.5");
+        assert!(params.price.validate("Price").is_ok());
+        assert!(params.collateral.validate("Collateral").is_ok());
+
+        for bad in ["", "-1", "1.", "1e18", "12 tokens"] {
+            assert!(config::TokenAmount::Text(bad.to_string()).validate("Price").is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_expiring_within_window() {
+        assert_eq!(config::parse_duration("48h").unwrap(), std::time::Duration::from_secs(48 * 3600));
+        assert_eq!(config::parse_duration("7d").unwrap(), std::time::Duration::from_secs(7 * 86400));
+        assert!(config::parse_duration("soon").is_err());
+        assert_eq!(config::parse_duration("12").unwrap(), std::time::Duration::from_secs(12 * 3600));
+        assert!(config::parse_duration("999999999999999999d").unwrap_err().to_string().contains("too long"));
+
+        let storage_manager = storage::StorageManager::new(PathBuf::from("/tmp/output"), config::OutputStructure::Structured);
+        let now = chrono::Utc::now();
+        let duration = chrono::Duration::days(6);
+        let mut records = std::collections::HashMap::new();
+        for (name, age_hours, active) in [("soon.bin", 140, true), ("sooner.bin", 143, true), ("later.bin", 24, true), ("failed.bin", 143, false)] {
+            let path = PathBuf::from("/tmp/target").join(name);
+            let mut record = storage_manager.create_new_record(path.clone());
+            record.created_at = now - chrono::Duration::hours(age_hours);
+            if active {
+                storage_manager.mark_record_active(&mut record);
+                record.active_at = Some(record.created_at);
+            }
+            records.insert(path, record);
+        }
+
+        let expiring = commands::expiring_within(&records, duration, chrono::Duration::hours(48), now);
+        let names: Vec<_> = expiring.iter().map(|(path, _)| path.file_name().unwrap().to_str().unwrap()).collect();
+        assert_eq!(names, vec!["sooner.bin", "soon.bin"]);
+
+        // A window past the end of representable time covers every active record.
+        let forever = chrono::Duration::from_std(config::parse_duration("99999999999d").unwrap()).unwrap();
+        assert_eq!(commands::expiring_within(&records, duration, forever, now).len(), 3);
+    }
+
+    #[test]
+    fn test_config_profiles_merge_over_default() {
+        let content = r#"
+            [default]
+            target_folder = "/data/target"
+            output_folder = "/data/output"
+            output_structure = "structured"
+            codex_endpoints = ["http://localhost:8080"]
+
+            [default.storage_params]
+            price = 1000
+            nodes = 10
+            tolerance = 5
+            proof_probability = 100
+            duration_days = 6
+            expiry_minutes = 60
+            collateral = 1
+
+            [profiles.prod]
+            codex_endpoints = ["http://codex-prod:8080"]
+
+            [profiles.prod.storage_params]
+            nodes = 20
+        "#;
+
+        let default = config::Config::from_toml_str(content, None).unwrap();
+        assert_eq!(default.codex_endpoints, vec!["http://localhost:8080"]);
+        assert_eq!(default.storage_params.nodes, 10);
+
+        let prod = config::Config::from_toml_str(content, Some("prod")).unwrap();
+        assert_eq!(prod.codex_endpoints, vec!["http://codex-prod:8080"]);
+        assert_eq!(prod.storage_params.nodes, 20);
+        assert_eq!(prod.storage_params.tolerance, 5);
+        assert_eq!(prod.target_folder, PathBuf::from("/data/target"));
+
+        assert!(config::Config::from_toml_str(content, Some("staging")).is_err());
+
+        // Settings beside [default] are rejected rather than silently dropped.
+        let stray = format!("target_folder = \"/data/elsewhere\"\ncodex_endpoints = [\"http://codex-other:8080\"]\n{}", content);
+        let err = config::Config::from_toml_str(&stray, None).unwrap_err().to_string();
+        assert!(err.contains("outside [default]: codex_endpoints, target_folder"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_moved_target_folder_requires_rebase() {
+        let dir = temp_dir("rebase");
+        let old_target = dir.join("old-target");
+        let new_target = dir.join("new-target");
+        let output = dir.join("output");
+        std::fs::create_dir_all(&old_target).unwrap();
+        std::fs::create_dir_all(&new_target).unwrap();
+
+        let storage_manager = storage::StorageManager::new(output.clone(), config::OutputStructure::Structured);
+        storage_manager.check_target_folder(&old_target, false).await.unwrap();
+
+        let old_file = old_target.join("photos/cat.jpg");
+        let mut record = storage_manager.create_new_record(old_file.clone());
+        storage_manager.update_record_purchase(&mut record, "purchase-1".to_string(), "zCid".to_string());
+        storage_manager.save_record(&old_target, &old_file, &record).await.unwrap();
+
+        let err = storage_manager.check_target_folder(&new_target, false).await.unwrap_err();
+        assert!(err.to_string().contains("--rebase"));
+
+        storage_manager.check_target_folder(&new_target, true).await.unwrap();
+        storage_manager.check_target_folder(&new_target, false).await.unwrap();
+
+        let new_file = new_target.join("photos/cat.jpg");
+        let records = storage_manager.load_existing_records(&new_target).await.unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[&new_file].file_path, new_file);
+        assert_eq!(records[&new_file].purchase_id.as_deref(), Some("purchase-1"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_failure_circuit() {
+        let config = config::Config {
+            max_consecutive_failures: Some(3),
+            max_failure_rate: Some(0.5),
+            failure_rate_min_files: 10,
+            ..Default::default()
+        };
+
+        let mut consecutive = error::FailureCircuit::new(&config);
+        assert!(consecutive.record_failure().is_none());
+        assert!(consecutive.record_failure().is_none());
+        assert!(consecutive.record_failure().unwrap().contains("3 consecutive"));
+
+        // Scattered failures in a mostly successful run never trip it.
+        let mut scattered = error::FailureCircuit::new(&config);
+        for i in 0..100 {
+            if i % 5 == 0 {
+                assert!(scattered.record_failure().is_none());
+            } else {
+                scattered.record_success();
+            }
+        }
+
+        // A high rate only counts once enough files have been tried.
+        let mut rate = error::FailureCircuit::new(&config);
+        for _ in 0..4 {
+            assert!(rate.record_failure().is_none());
+            rate.record_success();
+        }
+        assert!(rate.record_failure().is_none());
+        assert!(rate.record_failure().unwrap().contains("6 of 10"));
+    }
+
+    #[tokio::test]
+    async fn test_sha256_file_in_chunks() {
+        use sha2::Digest;
+
+        let dir = temp_dir("hashing");
+        let small = dir.join("small.bin");
+        let large = dir.join("large.bin");
+        let small_content: Vec<u8> = (0..17).map(|i| (i % 251) as u8).collect();
+        let large_content: Vec<u8> = (0..3 * 1024 * 1024 + 5).map(|i| (i % 239) as u8).collect();
+        std::fs::write(&small, &small_content).unwrap();
+        std::fs::write(&large, &large_content).unwrap();
+
+        let hex = |bytes: &[u8]| -> String { sha2::Sha256::digest(bytes).iter().map(|b| format!("{:02x}", b)).collect() };
+        assert_eq!(fingerprint::sha256_file(&small).await.unwrap(), hex(&small_content));
+        assert_eq!(fingerprint::sha256_file(&large).await.unwrap(), hex(&large_content));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_window_defers_processing() {
+        use chrono::{TimeZone, Utc};
+
+        let daily: config::TimeRange = toml::from_str::<toml::Value>("start = \"23:00\"\nend = \"01:30\"")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let late = Utc.with_ymd_and_hms(2026, 3, 1, 23, 30, 0).unwrap();
+        let early = Utc.with_ymd_and_hms(2026, 3, 2, 0, 15, 0).unwrap();
+        let outside = Utc.with_ymd_and_hms(2026, 3, 2, 12, 0, 0).unwrap();
+        let end = Utc.with_ymd_and_hms(2026, 3, 2, 1, 30, 0).unwrap();
+        assert_eq!(daily.end_if_active(late), Some(end));
+        assert_eq!(daily.end_if_active(early), Some(end));
+        assert_eq!(daily.end_if_active(outside), None);
+
+        let target = temp_dir("maintenance-target");
+        let output = temp_dir("maintenance-output");
+        let file = target.join("a.txt");
+        std::fs::write(&file, "content").unwrap();
+
+        let now = Utc::now();
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            codex_endpoints: vec!["http://127.0.0.1:9".to_string()],
+            maintenance_windows: vec![config::TimeRange::Once {
+                start: now - chrono::Duration::hours(1),
+                end: now + chrono::Duration::hours(1),
+            }],
+            ..Default::default()
+        };
+        let client = std::sync::Arc::new(codex::Client::new(config.codex_endpoints.clone()));
+        let processor = file_processor::FileProcessor::new(std::sync::Arc::new(config), client);
+        processor.initialize().await.unwrap();
+
+        processor.process_file(&file).await.unwrap();
+        let record = processor.records.read().await.get(&file).cloned().unwrap();
+        assert_eq!(record.status, storage::FileStatus::Pending);
+        assert!(record.error.unwrap().contains("maintenance window"));
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_upload_rejects_bogus_cid() {
+        use config::CidValidation;
+
+        let v0 = "QmYwAPJzv5CZsnA625s3Xf2nemtYgPpHdWEz79ojWnPbdG";
+        let v1 = "zDvZRwzm2mK7tvDzKScRLapqGdgNTLyyEBvx1TQY37J2CvuzL7Gh";
+        assert!(codex::validate_cid(v0, CidValidation::Strict).is_ok());
+        assert!(codex::validate_cid(v1, CidValidation::Strict).is_ok());
+        assert!(codex::validate_cid("QmTooShort", CidValidation::Strict).is_err());
+        assert!(codex::validate_cid("x".repeat(40).as_str(), CidValidation::Strict).is_err());
+        assert!(codex::validate_cid("x".repeat(40).as_str(), CidValidation::Basic).is_ok());
+        assert!(codex::validate_cid("", CidValidation::Off).is_ok());
+        // Strict is opt-in, so an encoding it doesn't recognise still uploads.
+        assert_eq!(config::Config::default().cid_validation, CidValidation::Basic);
+        assert!(codex::validate_cid("u".repeat(40).as_str(), CidValidation::default()).is_ok());
+
+        let dir = temp_dir("bogus-cid");
+        let file = dir.join("a.bin");
+        std::fs::write(&file, vec![7u8; 1024 * 1024]).unwrap();
+
+        let endpoint = mock_server(|req| {
+            assert_eq!(req.path, "/api/codex/v1/data");
+            MockResponse::new(200, "text/plain", "Internal error: upstream node unavailable, try again later")
+        }).await;
+        let client = codex::Client::new(vec![endpoint]);
+
+        let err = client.upload_file(&file).await.unwrap_err().to_string();
+        assert!(err.contains("invalid CID"), "{}", err);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_path_override_expiry() {
+        let target = temp_dir("override-target");
+        let output = temp_dir("override-output");
+        let config = config::Config::from_toml_str(&format!(r#"
+            target_folder = "{}"
+            output_folder = "{}"
+            output_structure = "structured"
+            codex_endpoints = ["http://localhost:8080"]
+
+            [storage_params]
+            price = 1
+            nodes = 3
+            tolerance = 1
+            proof_probability = 100
+            duration_days = 1
+            expiry_minutes = 60
+            collateral = 1
+
+            [[overrides]]
+            path = "videos/**"
+            expiry_minutes = 240
+        "#, target.display(), output.display()), None).unwrap();
+        config.validate().unwrap();
+
+        assert_eq!(config.storage_params_for(Path::new("videos/2024/a.mp4"), None).expiry_minutes, 240);
+        assert_eq!(config.storage_params_for(Path::new("docs/a.pdf"), None).expiry_minutes, 60);
+        assert_eq!(config.storage_params_for(Path::new("a-videos/a.mp4"), None).expiry_minutes, 60);
+
+        let too_long = config::Config {
+            overrides: vec![config::PathOverride {
+                path: "videos/**".parse().unwrap(),
+                params: config::StorageParamsOverride { expiry_minutes: Some(2000), ..Default::default() },
+            }],
+            ..config
+        };
+        assert!(too_long.validate().unwrap_err().to_string().contains("videos/**"));
+
+        // An invalid glob fails when the config is parsed.
+        let invalid = config::Config::from_toml_str(r#"
+            target_folder = "/tmp/target"
+            codex_endpoints = ["http://localhost:8080"]
+
+            [[overrides]]
+            path = "videos/[a"
+            expiry_minutes = 240
+        "#, None).unwrap_err();
+        assert!(format!("{:#}", invalid).contains("Invalid path glob"), "{:#}", invalid);
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sharded_structured_records() {
+        let dir = temp_dir("sharded");
+        let target = dir.join("target");
+        let output = dir.join("output");
+        let files: Vec<PathBuf> = (0..20).map(|i| target.join(format!("file-{}.bin", i))).collect();
+
+        let unsharded_config = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            output_structure: config::OutputStructure::Structured,
+            ..Default::default()
+        };
+        let sharded_config = config::Config { shard_records: true, ..unsharded_config.clone() };
+
+        let unsharded = storage::StorageManager::from_config(&unsharded_config);
+        for file in &files {
+            unsharded.save_record(&target, file, &unsharded.create_new_record(file.clone())).await.unwrap();
+        }
+
+        // Turning sharding on still loads the existing records, without
+        // moving them.
+        let sharded = storage::StorageManager::from_config(&sharded_config);
+        let records = sharded.load_existing_records(&target).await.unwrap();
+        assert_eq!(records.len(), files.len());
+        assert!(files.iter().all(|file| records.contains_key(file)));
+        assert!(output.join("file-0.json").exists());
+
+        // Moving them is a separate step, which puts them into shard
+        // directories.
+        assert_eq!(sharded.move_structured_records(&target).await.unwrap(), files.len());
+        let top_level: Vec<_> = std::fs::read_dir(&output).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert!(top_level.iter().all(|path| path.is_dir()), "{:?}", top_level);
+        let record_files: Vec<PathBuf> = walkdir::WalkDir::new(&output).into_iter()
+            .map(|entry| entry.unwrap().into_path())
+            .filter(|path| path.is_file())
+            .collect();
+        assert_eq!(record_files.len(), files.len());
+        assert!(record_files.iter().all(|path| path.strip_prefix(&output).unwrap().components().count() == 3));
+
+        let reloaded = sharded.load_existing_records(&target).await.unwrap();
+        assert_eq!(reloaded.len(), files.len());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_force_reupload_archives_history() {
+        use clap::Parser;
+
+        let force = config::ForceReupload { pattern: Some("photos/**".parse().unwrap()) };
+        assert!(force.matches(Path::new("photos/2024/a.jpg")));
+        assert!(!force.matches(Path::new("docs/a.pdf")));
+        assert!(config::ForceReupload::default().matches(Path::new("docs/a.pdf")));
+        // An invalid pattern is rejected with the arguments.
+        assert!(config::Args::try_parse_from(["filehog", "--force-reupload", "--pattern", "photos/[a"]).is_err());
+
+        let storage_manager = storage::StorageManager::new(
+            PathBuf::from("/tmp/output"),
+            config::OutputStructure::Structured,
+        );
+        let mut record = storage_manager.create_new_record(PathBuf::from("/tmp/photos/a.jpg"));
+        storage_manager.update_record_upload(&mut record, "zOriginal".to_string(), "http://node".to_string());
+        storage_manager.update_record_purchase(&mut record, "purchase-1".to_string(), "zStorage".to_string());
+        storage_manager.mark_record_active(&mut record);
+
+        storage_manager.archive_record(&mut record, "force re-upload");
+        assert_eq!(record.status, storage::FileStatus::New);
+        assert!(record.original_cid.is_none() && record.purchase_id.is_none());
+        assert_eq!(record.history.len(), 1);
+        assert_eq!(record.history[0].purchase_id.as_deref(), Some("purchase-1"));
+        assert_eq!(record.history[0].status, storage::FileStatus::Active);
+    }
+
+    #[tokio::test]
+    async fn test_why_explains_verdict() {
+        let target = temp_dir("why-target");
+        let output = temp_dir("why-output");
+        std::fs::write(target.join("small.txt"), "tiny").unwrap();
+        std::fs::write(target.join("big.bin"), vec![0u8; 1024 * 1024]).unwrap();
+        std::fs::write(target.join("stored.bin"), vec![1u8; 1024 * 1024]).unwrap();
+
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            output_structure: config::OutputStructure::Structured,
+            ..Default::default()
+        };
+        let storage_manager = storage::StorageManager::from_config(&config);
+        let stored = target.join("stored.bin");
+        let mut record = storage_manager.create_new_record(stored.clone());
+        storage_manager.update_record_purchase(&mut record, "purchase-1".to_string(), "zCid".to_string());
+        storage_manager.mark_record_active(&mut record);
+        storage_manager.save_record(&target, &stored, &record).await.unwrap();
+
+        let small = commands::why(&config, Path::new("small.txt")).await.unwrap();
+        assert!(small.contains("too small: 4 bytes"), "{}", small);
+        let big = commands::why(&config, &target.join("big.bin")).await.unwrap();
+        assert!(big.contains("no record yet"), "{}", big);
+        let active = commands::why(&config, Path::new("stored.bin")).await.unwrap();
+        assert!(active.contains("already stored (purchase purchase-1)"), "{}", active);
+        let outside = commands::why(&config, &output).await.unwrap();
+        assert!(outside.contains("outside the target folder"), "{}", outside);
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_waiting_long_purchase_fails_after_max_wait() {
+        let endpoint = mock_server(|_| {
+            MockResponse::new(200, "application/json", r#"{"state":"submitted","request":{"content":{"cid":"zCid"}}}"#)
+        }).await;
+
+        let dir = temp_dir("waiting-long");
+        let target = dir.join("target");
+        let file = target.join("slow.bin");
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: dir.join("output"),
+            codex_endpoints: vec![endpoint],
+            max_wait_for_start_minutes: Some(240),
+            // Would fail the purchase on first sight if it applied here.
+            stuck_purchase_timeout_minutes: Some(1),
+            stuck_purchase_action: config::StuckPurchaseAction::Fail,
+            ..Default::default()
+        };
+        let client = std::sync::Arc::new(codex::Client::new(config.codex_endpoints.clone()));
+        let processor = file_processor::FileProcessor::new(std::sync::Arc::new(config), client);
+
+        let mut record = processor.storage_manager.create_new_record(file.clone());
+        processor.storage_manager.update_record_purchase(&mut record, "purchase-1".to_string(), "zCid".to_string());
+        processor.storage_manager.update_record_status(&mut record, storage::FileStatus::WaitingLong, None);
+        record.pending_since = Some(chrono::Utc::now() - chrono::Duration::hours(2));
+        processor.records.write().await.insert(file.clone(), record);
+
+        processor.check_purchases_once().await;
+        assert_eq!(processor.records.read().await[&file].status, storage::FileStatus::WaitingLong);
+
+        processor.records.write().await.get_mut(&file).unwrap().pending_since =
+            Some(chrono::Utc::now() - chrono::Duration::hours(5));
+        processor.check_purchases_once().await;
+        let record = processor.records.read().await[&file].clone();
+        assert_eq!(record.status, storage::FileStatus::Failed);
+        assert!(record.error.unwrap().contains("did not start within 240 minutes"));
+
+        // A purchase with a longer expiry waits at least that long.
+        let long_file = target.join("slow-long.bin");
+        let mut record = processor.storage_manager.create_new_record(long_file.clone());
+        processor.storage_manager.update_record_purchase(&mut record, "purchase-2".to_string(), "zCid".to_string());
+        processor.storage_manager.update_record_status(&mut record, storage::FileStatus::WaitingLong, None);
+        record.expiry_minutes = Some(480);
+        record.pending_since = Some(chrono::Utc::now() - chrono::Duration::hours(5));
+        processor.records.write().await.insert(long_file.clone(), record);
+
+        processor.check_purchases_once().await;
+        assert_eq!(processor.records.read().await[&long_file].status, storage::FileStatus::WaitingLong);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_audit_availability() {
+        let empty = mock_server(|_| MockResponse::new(404, "text/plain", "not found")).await;
+        let holder = mock_server(|req| {
+            if req.path == "/api/codex/v1/data/zKept/network/manifest" {
+                MockResponse::new(200, "application/json", "{}")
+            } else {
+                MockResponse::new(404, "text/plain", "not found")
+            }
+        }).await;
+
+        let dir = temp_dir("audit");
+        let target = dir.join("target");
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: dir.join("output"),
+            codex_endpoints: vec![empty, holder],
+            ..Default::default()
+        };
+        let storage_manager = storage::StorageManager::from_config(&config);
+        for (name, cid, active) in [("kept.bin", "zKept", true), ("lost.bin", "zLost", true), ("new.bin", "zNew", false)] {
+            let file = target.join(name);
+            let mut record = storage_manager.create_new_record(file.clone());
+            storage_manager.update_record_purchase(&mut record, format!("purchase-{}", name), cid.to_string());
+            if active {
+                storage_manager.mark_record_active(&mut record);
+            }
+            storage_manager.save_record(&target, &file, &record).await.unwrap();
+        }
+
+        let client = codex::Client::from_config(&config);
+        let report = commands::audit_availability(&config, &client, 100.0).await.unwrap();
+        assert_eq!((report.active, report.checked), (2, 2));
+        assert_eq!(report.unavailable.len(), 1);
+        assert_eq!(report.unavailable[0].cid, "zLost");
+        assert_eq!(report.unavailable[0].reasons.len(), 2);
+
+        let sampled = commands::audit_availability(&config, &client, 10.0).await.unwrap();
+        assert_eq!(sampled.checked, 1);
+        assert!(commands::audit_availability(&config, &client, 0.0).await.is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_low_disk_space_pauses_processing() {
+        let target = temp_dir("low-disk-target");
+        let output = temp_dir("low-disk-output");
+        let file = target.join("a.bin");
+        std::fs::write(&file, vec![0u8; 1024 * 1024]).unwrap();
+
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            codex_endpoints: vec!["http://127.0.0.1:9".to_string()],
+            min_output_free_bytes: Some(u64::MAX),
+            ..Default::default()
+        };
+        let client = std::sync::Arc::new(codex::Client::new(config.codex_endpoints.clone()));
+        let processor = file_processor::FileProcessor::new(std::sync::Arc::new(config), client);
+        processor.initialize().await.unwrap();
+
+        processor.process_file(&file).await.unwrap();
+        let record = processor.records.read().await[&file].clone();
+        assert_eq!(record.status, storage::FileStatus::Pending);
+        assert!(record.error.unwrap().contains("bytes free"));
+        assert!(processor.low_disk_paused.load(std::sync::atomic::Ordering::Relaxed));
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_process_paths_returns_per_file_results() {
+        let target = temp_dir("process-paths-target");
+        let output = temp_dir("process-paths-output");
+        let small = target.join("small.txt");
+        let settled = target.join("settled.bin");
+        let excluded = target.join("scratch.tmp");
+        let outside = output.join("outside.bin");
+        std::fs::write(&small, "tiny").unwrap();
+        std::fs::write(&settled, vec![0u8; 1024 * 1024]).unwrap();
+        std::fs::write(&excluded, vec![0u8; 1024 * 1024]).unwrap();
+        std::fs::write(&outside, vec![0u8; 1024 * 1024]).unwrap();
+
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            codex_endpoints: vec!["http://127.0.0.1:9".to_string()],
+            exclude_patterns: vec!["*.tmp".to_string()],
+            ..Default::default()
+        };
+        let client = std::sync::Arc::new(codex::Client::new(config.codex_endpoints.clone()));
+        let processor = file_processor::FileProcessor::new(std::sync::Arc::new(config), client);
+        processor.initialize().await.unwrap();
+        processor.record_baseline(&settled).await.unwrap();
+
+        let results = processor.process_paths(vec![small.clone(), settled.clone()]).await;
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].0, small);
+        assert!(results[0].1.as_ref().unwrap_err().to_string().contains("too small"));
+        assert_eq!(results[1].0, settled);
+        assert_eq!(results[1].1.as_ref().unwrap().status, storage::FileStatus::Baseline);
+
+        // Like the scan, skip files the patterns exclude and anything outside
+        // the target folder.
+        let results = processor.process_paths(vec![excluded.clone(), outside.clone()]).await;
+        assert!(results[0].1.as_ref().unwrap_err().to_string().contains("include/exclude patterns"));
+        assert!(results[1].1.as_ref().unwrap_err().to_string().contains("not under the target folder"));
+        assert!(!processor.records.read().await.contains_key(&excluded));
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_process_paths_stores_repeated_paths_once() {
+        let (server, uploads) = counting_codex_mock().await;
+
+        let target = temp_dir("process-paths-repeat-target");
+        let output = temp_dir("process-paths-repeat-output");
+        let file = target.join("data.bin");
+        std::fs::write(&file, vec![6u8; 1024 * 1024]).unwrap();
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            codex_endpoints: vec![server],
+            max_concurrent_uploads: 3,
+            ..Default::default()
+        };
+        let client = std::sync::Arc::new(codex::Client::from_config(&config));
+        let processor = file_processor::FileProcessor::new(std::sync::Arc::new(config), client);
+        processor.initialize().await.unwrap();
+
+        let results = processor.process_paths(vec![file.clone(), file.clone(), file.clone()]).await;
+        assert!(results.iter().all(|(_, result)| result.is_ok()));
+        assert_eq!(uploads.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        // After shutdown every path still gets a result.
+        processor.shutdown.cancel();
+        let results = processor.process_paths(vec![file.clone(), target.join("other.bin")]).await;
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(|(_, result)| result.as_ref().unwrap_err().to_string().contains("shut down")));
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[test]
+    fn test_duplicate_endpoints_are_merged() {
+        let config = config::Config {
+            codex_endpoints: vec![
+                "http://node-a:8080".to_string(),
+                "http://node-b:8080".to_string(),
+                "HTTP://Node-A:8080/".to_string(),
+            ],
+            ..Default::default()
+        };
+
+        let client = codex::Client::from_config(&config);
+        assert_eq!(client.endpoints(), ["http://node-a:8080", "http://node-b:8080"]);
+    }
+
+    #[tokio::test]
+    async fn test_min_filled_slots_before_active() {
+        let filled = std::sync::Arc::new(std::sync::atomic::AtomicU32::new(1));
+        let reported = filled.clone();
+        let endpoint = mock_server(move |_| {
+            let body = format!(
+                r#"{{"state":"started","filledSlots":{},"request":{{"content":{{"cid":"zCid"}}}}}}"#,
+                reported.load(std::sync::atomic::Ordering::Relaxed)
+            );
+            MockResponse::new(200, "application/json", body)
+        }).await;
+
+        let dir = temp_dir("filled-slots");
+        let target = dir.join("target");
+        let file = target.join("a.bin");
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: dir.join("output"),
+            codex_endpoints: vec![endpoint],
+            min_filled_slots: Some(3),
+            ..Default::default()
+        };
+        let client = std::sync::Arc::new(codex::Client::new(config.codex_endpoints.clone()));
+        let processor = file_processor::FileProcessor::new(std::sync::Arc::new(config), client);
+
+        let mut record = processor.storage_manager.create_new_record(file.clone());
+        processor.storage_manager.update_record_purchase(&mut record, "purchase-1".to_string(), "zCid".to_string());
+        record.updated_at = chrono::Utc::now() - chrono::Duration::hours(2);
+        processor.records.write().await.insert(file.clone(), record);
+
+        processor.check_purchases_once().await;
+        let record = processor.records.read().await[&file].clone();
+        assert_eq!(record.status, storage::FileStatus::Creating);
+        assert_eq!(record.filled_slots, Some(1));
+
+        filled.store(3, std::sync::atomic::Ordering::Relaxed);
+        processor.records.write().await.get_mut(&file).unwrap().updated_at = chrono::Utc::now() - chrono::Duration::hours(2);
+        processor.check_purchases_once().await;
+        let record = processor.records.read().await[&file].clone();
+        assert_eq!(record.status, storage::FileStatus::Active);
+        assert_eq!(record.filled_slots, Some(3));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_resume_skips_checkpointed_files() {
+        let dir = temp_dir("resume");
+        let target = dir.join("target");
+        let output = dir.join("output");
+        std::fs::create_dir_all(&target).unwrap();
+        std::fs::create_dir_all(&output).unwrap();
+        for name in ["a.bin", "b.bin", "c.bin"] {
+            std::fs::write(target.join(name), vec![0u8; 2 * 1024 * 1024]).unwrap();
+        }
+        let checkpoint = file_processor::ScanCheckpoint {
+            last_path: target.join("b.bin"),
+            updated_at: chrono::Utc::now(),
+        };
+        let checkpoint_file = output.join(file_processor::SCAN_CHECKPOINT_FILE_NAME);
+        std::fs::write(&checkpoint_file, serde_json::to_string(&checkpoint).unwrap()).unwrap();
+
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            codex_endpoints: vec!["http://127.0.0.1:9".to_string()],
+            baseline: true,
+            resume: true,
+            ..Default::default()
+        };
+        let client = std::sync::Arc::new(codex::Client::new(config.codex_endpoints.clone()));
+        let processor = file_processor::FileProcessor::new(std::sync::Arc::new(config), client);
+
+        processor.process_files().await.unwrap();
+
+        let records = processor.records.read().await;
+        assert!(!records.contains_key(&target.join("a.bin")));
+        assert!(!records.contains_key(&target.join("b.bin")));
+        assert_eq!(records.get(&target.join("c.bin")).unwrap().status, storage::FileStatus::Baseline);
+        assert!(!checkpoint_file.exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_summary_webhook() {
+        let storage = storage::StorageManager::new(PathBuf::from("output"), config::OutputStructure::Flattened);
+        let mut records = std::collections::HashMap::new();
+        for (name, status) in [
+            ("a.bin", storage::FileStatus::Active),
+            ("b.bin", storage::FileStatus::Failed),
+            ("c.bin", storage::FileStatus::Pending),
+            ("d.bin", storage::FileStatus::Active),
+        ] {
+            let mut record = storage.create_new_record(PathBuf::from(name));
+            record.status = status;
+            records.insert(PathBuf::from(name), record);
+        }
+
+        let summary = monitor::ShutdownSummary::from_records(&records);
+        assert_eq!(summary.total, 4);
+        assert_eq!(summary.counts.get("Active"), Some(&2));
+        assert_eq!(summary.failed, vec![PathBuf::from("b.bin")]);
+        assert_eq!(summary.pending, vec![PathBuf::from("c.bin")]);
+
+        let received = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = received.clone();
+        let endpoint = mock_server(move |req| {
+            sink.lock().unwrap().push((req.method, req.path, req.body));
+            MockResponse::new(204, "text/plain", "")
+        }).await;
+
+        monitor::send_shutdown_summary(&format!("{}/hook", endpoint), &summary).await.unwrap();
+
+        let received = received.lock().unwrap();
+        let (method, path, body) = &received[0];
+        assert_eq!(method, "POST");
+        assert_eq!(path, "/hook");
+        let body: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(body["counts"]["Failed"], 1);
+        assert_eq!(body["pending"][0], "c.bin");
+    }
+
+    #[test]
+    fn test_storage_param_flags_override_config_file() {
+        use clap::Parser;
+
+        let dir = temp_dir("param-flags");
+        let config_path = dir.join("config.toml");
+        std::fs::write(&config_path, r#"
+target_folder = "/data"
+output_folder = "/meta"
+codex_endpoints = ["http://localhost:8080"]
+output_structure = "flattened"
+
+[storage_params]
+price = 1000
+nodes = 5
+tolerance = 2
+proof_probability = 100
+duration_days = 30
+expiry_minutes = 60
+collateral = 1
+"#).unwrap();
+        let config_arg = config_path.to_str().unwrap();
+
+        let args = config::Args::try_parse_from([
+            "filehog", "--config", config_arg,
+            "--nodes", "3", "--price", "0.5", "--proof-probability", "0.25",
+        ]).unwrap();
+        let config = config::Config::from_args(&args).unwrap();
+        assert_eq!(config.storage_params.nodes, 3);
+        assert_eq!(config.storage_params.price, config::TokenAmount::Text("0.5".to_string()));
+        assert_eq!(config.storage_params.proof_probability, config::ProofProbability::Decimal(0.25));
+        assert_eq!(config.storage_params.tolerance, 2);
+        assert_eq!(config.storage_params.duration_days, 30);
+
+        let args = config::Args::try_parse_from(["filehog", "--config", config_arg]).unwrap();
+        assert_eq!(config::Config::from_args(&args).unwrap().storage_params.nodes, 5);
+
+        assert!(config::Args::try_parse_from(["filehog", "--price", "cheap"]).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_work_queue_replays_and_compacts() {
+        let dir = temp_dir("queue");
+        let queue_file = dir.join(queue::QUEUE_FILE_NAME);
+
+        let work = queue::WorkQueue::new(&dir);
+        work.push(Path::new("/data/a.bin")).await.unwrap();
+        work.push(Path::new("/data/b.bin")).await.unwrap();
+        work.push(Path::new("/data/a.bin")).await.unwrap();
+        work.complete(Path::new("/data/a.bin")).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&queue_file).unwrap().lines().count(), 3);
+
+        // A crash can leave a torn last line behind.
+        let mut content = std::fs::read_to_string(&queue_file).unwrap();
+        content.push_str("{\"path\":\"/data/c");
+        std::fs::write(&queue_file, content).unwrap();
+
+        let replayed = queue::WorkQueue::new(&dir);
+        assert_eq!(replayed.replay().await.unwrap(), 1);
+        assert_eq!(replayed.pending().await, vec![PathBuf::from("/data/b.bin")]);
+        assert_eq!(std::fs::read_to_string(&queue_file).unwrap().lines().count(), 1);
+
+        replayed.complete(Path::new("/data/b.bin")).await.unwrap();
+        assert!(replayed.pending().await.is_empty());
+        assert_eq!(std::fs::read_to_string(&queue_file).unwrap(), "");
+
+        // A file written again while it is processed stays queued, also
+        // across a restart.
+        replayed.push(Path::new("/data/d.bin")).await.unwrap();
+        assert_eq!(replayed.take().await, vec![PathBuf::from("/data/d.bin")]);
+        replayed.push(Path::new("/data/d.bin")).await.unwrap();
+        replayed.complete(Path::new("/data/d.bin")).await.unwrap();
+        assert_eq!(replayed.pending().await, vec![PathBuf::from("/data/d.bin")]);
+        assert_eq!(queue::WorkQueue::new(&dir).replay().await.unwrap(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_hard_linked_paths_are_scanned_once() {
+        let dir = temp_dir("hardlink");
+        let target = dir.join("target");
+        std::fs::create_dir_all(target.join("b")).unwrap();
+        std::fs::write(target.join("b/data.bin"), vec![0u8; 2 * 1024 * 1024]).unwrap();
+        std::fs::hard_link(target.join("b/data.bin"), target.join("a.bin")).unwrap();
+
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: dir.join("output"),
+            codex_endpoints: vec!["http://127.0.0.1:9".to_string()],
+            baseline: true,
+            ..Default::default()
+        };
+        let client = std::sync::Arc::new(codex::Client::new(config.codex_endpoints.clone()));
+        let processor = file_processor::FileProcessor::new(std::sync::Arc::new(config), client);
+
+        assert_eq!(processor.scan_target_folder().await.unwrap(), vec![target.join("a.bin")]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_oversized_status_response_is_rejected() {
+        let endpoint = mock_server(|req| {
+            let body = if req.path.ends_with("/big") {
+                format!(r#"{{"state":"started","request":{{"content":{{"cid":"{}"}}}}}}"#, "x".repeat(4096))
+            } else {
+                r#"{"state":"started","request":{"content":{"cid":"zQm"}}}"#.to_string()
+            };
+            MockResponse::new(200, "application/json", body)
+        }).await;
+        let config = config::Config {
+            codex_endpoints: vec![endpoint],
+            max_response_bytes: 1024,
+            ..Default::default()
+        };
+        let client = codex::Client::from_config(&config);
+
+        let err = client.get_purchase_status("big", None).await.unwrap_err();
+        assert!(err.to_string().contains("max_response_bytes"), "{}", err);
+        assert_eq!(client.get_purchase_status("small", None).await.unwrap().state, "started");
+    }
+
+    #[test]
+    fn test_classify_watched_paths() {
+        use eligibility::PathKind;
+
+        let dir = temp_dir("classify");
+        let file = dir.join("file.bin");
+        std::fs::write(&file, b"data").unwrap();
+
+        assert_eq!(eligibility::classify(&file).unwrap(), PathKind::File);
+        assert_eq!(eligibility::classify(&dir).unwrap(), PathKind::Directory);
+        assert_eq!(eligibility::classify(&dir.join("gone.bin")).unwrap(), PathKind::Vanished);
+
+        #[cfg(unix)]
+        {
+            let socket = dir.join("control.sock");
+            let _listener = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+            assert_eq!(eligibility::classify(&socket).unwrap(), PathKind::Special("socket"));
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_upload_sends_filename() {
+        let dir = temp_dir("upload-filename");
+        let file = dir.join("holiday \"2024\".bin");
+        std::fs::write(&file, vec![7u8; 1024 * 1024]).unwrap();
+
+        let received = std::sync::Arc::new(std::sync::Mutex::new(None));
+        let sink = received.clone();
+        let endpoint = mock_server(move |req| {
+            *sink.lock().unwrap() = req.headers.get("content-disposition").cloned();
+            MockResponse::new(200, "text/plain", "zDvZRwzm2mK7tvDzKScRLapqGdgNTLyyEBvx1TQY37J2CvuzL7Gh")
+        }).await;
+        let client = codex::Client::new(vec![endpoint]);
+
+        client.upload_file(&file).await.unwrap();
+
+        assert_eq!(codex::upload_filename(&file).as_deref(), Some("holiday _2024_.bin"));
+        assert_eq!(received.lock().unwrap().as_deref(), Some(r#"attachment; filename="holiday _2024_.bin""#));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_persist_all_records_saves_unsaved_changes() {
+        let dir = temp_dir("persist");
+        let target = dir.join("target");
+        std::fs::create_dir_all(&target).unwrap();
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: dir.join("output"),
+            ..Default::default()
+        };
+        let client = std::sync::Arc::new(codex::Client::new(vec!["http://127.0.0.1:9".to_string()]));
+        let processor = file_processor::FileProcessor::new(std::sync::Arc::new(config), client);
+        processor.initialize().await.unwrap();
+
+        let file = target.join("a.bin");
+        let mut record = processor.storage_manager.create_new_record(file.clone());
+        record.status = storage::FileStatus::Active;
+        processor.records.write().await.insert(file.clone(), record);
+
+        processor.persist_all_records().await.unwrap();
+
+        let loaded = processor.storage_manager.load_existing_records(&target).await.unwrap();
+        assert_eq!(loaded.get(&file).unwrap().status, storage::FileStatus::Active);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_diff_output_folders() {
+        let dir = temp_dir("diff");
+        let target = dir.join("target");
+        let output = dir.join("output");
+        let backup = dir.join("backup");
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            ..Default::default()
+        };
+        let backup_config = config::Config {
+            output_folder: backup.clone(),
+            output_structure: config::OutputStructure::Structured,
+            ..config.clone()
+        };
+
+        let mut records = std::collections::HashMap::new();
+        for name in ["same.bin", "changed.bin", "only-output.bin"] {
+            let mut record = storage::StorageManager::from_config(&config).create_new_record(target.join(name));
+            record.original_cid = Some(format!("cid-{}", name));
+            records.insert(target.join(name), record);
+        }
+        let mut backup_records = records.clone();
+        backup_records.remove(&target.join("only-output.bin"));
+        backup_records.get_mut(&target.join("changed.bin")).unwrap().status = storage::FileStatus::Active;
+        let extra = storage::StorageManager::from_config(&config).create_new_record(target.join("only-backup.bin"));
+        backup_records.insert(target.join("only-backup.bin"), extra);
+
+        std::fs::create_dir_all(&output).unwrap();
+        std::fs::create_dir_all(&backup).unwrap();
+        storage::StorageManager::from_config(&config).save_all_records(&target, &records).await.unwrap();
+        storage::StorageManager::from_config(&backup_config).save_all_records(&target, &backup_records).await.unwrap();
+
+        let diff = commands::diff_output_folders(&config, &backup).await.unwrap();
+        assert_eq!(diff.only_in_output, vec![target.join("only-output.bin")]);
+        assert_eq!(diff.only_in_other, vec![target.join("only-backup.bin")]);
+        assert_eq!(diff.changed.len(), 1);
+        assert_eq!(diff.changed[0].path, target.join("changed.bin"));
+        assert_eq!(diff.changed[0].fields[0].field, "status");
+        assert_eq!(diff.changed[0].fields[0].other.as_deref(), Some("Active"));
+
+        assert!(commands::diff_output_folders(&config, &output).await.unwrap().is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_purchase_check_detects_unreachable_node() {
+        let dir = temp_dir("node-down");
+        let target = dir.join("target");
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: dir.join("output"),
+            codex_endpoints: vec!["http://127.0.0.1:9".to_string()],
+            ..Default::default()
+        };
+        let client = std::sync::Arc::new(codex::Client::new(config.codex_endpoints.clone()));
+        let processor = file_processor::FileProcessor::new(std::sync::Arc::new(config), client);
+
+        assert_eq!(processor.check_purchases_once().await, file_processor::PurchaseCheckOutcome::Reachable);
+
+        for name in ["a.bin", "b.bin"] {
+            let file = target.join(name);
+            let mut record = processor.storage_manager.create_new_record(file.clone());
+            processor.storage_manager.update_record_purchase(&mut record, format!("purchase-{}", name), "zCid".to_string());
+            processor.storage_manager.mark_record_active(&mut record);
+            processor.records.write().await.insert(file, record);
+        }
+
+        assert_eq!(processor.check_purchases_once().await, file_processor::PurchaseCheckOutcome::AllFailed);
+        assert_eq!(processor.records.read().await[&target.join("a.bin")].status, storage::FileStatus::Active);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_describe_joins_record_and_live_status() {
+        let endpoint = mock_server(|req| {
+            assert_eq!(req.path, "/api/codex/v1/storage/purchases/purchase-1");
+            MockResponse::new(200, "application/json", r#"{"state":"started","request":{"content":{"cid":"zCid"}}}"#)
+        }).await;
+
+        let dir = temp_dir("describe");
+        let target = dir.join("target");
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: dir.join("output"),
+            codex_endpoints: vec![endpoint],
+            ..Default::default()
+        };
+        let client = std::sync::Arc::new(codex::Client::new(config.codex_endpoints.clone()));
+        let processor = file_processor::FileProcessor::new(std::sync::Arc::new(config), client);
+
+        let purchased = target.join("purchased.bin");
+        let mut record = processor.storage_manager.create_new_record(purchased.clone());
+        processor.storage_manager.update_record_purchase(&mut record, "purchase-1".to_string(), "zCid".to_string());
+        processor.records.write().await.insert(purchased.clone(), record);
+        let fresh = target.join("fresh.bin");
+        let record = processor.storage_manager.create_new_record(fresh.clone());
+        processor.records.write().await.insert(fresh.clone(), record);
+
+        let view = processor.describe(&purchased).await.unwrap();
+        assert_eq!(view.record.status, storage::FileStatus::Creating);
+        assert!(matches!(view.purchase, file_processor::LivePurchase::Status(ref status) if status.state == "started"));
+
+        let view = processor.describe(&fresh).await.unwrap();
+        assert!(matches!(view.purchase, file_processor::LivePurchase::NoPurchase));
+
+        assert!(processor.describe(&target.join("unknown.bin")).await.is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_no_healthy_endpoint_defers_processing() {
+        assert!(codex::Client::new(Vec::new()).get_purchase_status("purchase-1", None).await
+            .unwrap_err().downcast_ref::<codex::NoHealthyEndpoint>().is_some());
+
+        let endpoint = mock_server(|req| {
+            assert_eq!(req.path, "/api/codex/v1/debug/info");
+            MockResponse::new(200, "application/json", "{}")
+        }).await;
+
+        let dir = temp_dir("no-healthy-endpoint");
+        let target = dir.join("target");
+        std::fs::create_dir_all(&target).unwrap();
+        let file = target.join("a.bin");
+        std::fs::write(&file, vec![0u8; 1024 * 1024]).unwrap();
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: dir.join("output"),
+            codex_endpoints: vec![endpoint.clone()],
+            ..Default::default()
+        };
+        let client = std::sync::Arc::new(codex::Client::new(config.codex_endpoints.clone()));
+        let processor = file_processor::FileProcessor::new(std::sync::Arc::new(config), client.clone());
+
+        client.mark_unhealthy(&endpoint);
+        assert!(!client.has_healthy_endpoint());
+
+        processor.process_file(&file).await.unwrap();
+        let record = processor.records.read().await[&file].clone();
+        assert_eq!(record.status, storage::FileStatus::Pending);
+        assert!(record.original_cid.is_none());
+
+        assert_eq!(client.probe_unhealthy().await, 1);
+        assert!(client.has_healthy_endpoint());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_records_are_mirrored_to_extra_output_folders() {
+        let dir = temp_dir("mirror");
+        let target = dir.join("target");
+        let primary = dir.join("primary");
+        let mirror = dir.join("mirror");
+        let config = config::Config::from_toml_str(&format!(r#"
+            target_folder = "{}"
+            output_folder = ["{}", "{}"]
+            output_structure = "flattened"
+            codex_endpoints = ["http://localhost:8080"]
+
+            [storage_params]
+            price = 1
+            nodes = 3
+            tolerance = 1
+            proof_probability = 100
+            duration_days = 1
+            expiry_minutes = 60
+            collateral = 1
+        "#, target.display(), primary.display(), mirror.display()), None).unwrap();
+        assert_eq!(config.output_folder, primary);
+        assert_eq!(config.output_mirrors, vec![mirror.clone()]);
+
+        std::fs::create_dir_all(&primary).unwrap();
+        let storage_manager = storage::StorageManager::from_config(&config);
+        let file = target.join("a.bin");
+        let mut record = storage_manager.create_new_record(file.clone());
+        storage_manager.save_record(&target, &file, &record).await.unwrap();
+
+        let mirror_only = storage::StorageManager::new(mirror.clone(), config::OutputStructure::Flattened);
+        assert!(mirror_only.load_existing_records(&target).await.unwrap().contains_key(&file));
+
+        // A mirror written more recently than the primary wins on load.
+        record.status = storage::FileStatus::Active;
+        record.updated_at = chrono::Utc::now() + chrono::Duration::seconds(1);
+        mirror_only.save_record(&target, &file, &record).await.unwrap();
+        let loaded = storage_manager.load_existing_records(&target).await.unwrap();
+        assert_eq!(loaded[&file].status, storage::FileStatus::Active);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_storage_params_file_is_merged_under_inline_params() {
+        let dir = temp_dir("params-file");
+        std::fs::create_dir_all(dir.join("shared")).unwrap();
+        std::fs::write(dir.join("shared/economics.toml"), r#"
+            price = 500
+            nodes = 7
+            tolerance = 3
+            proof_probability = 100
+            duration_days = 30
+            expiry_minutes = 60
+            collateral = 2
+        "#).unwrap();
+        std::fs::write(dir.join("shared/economics.json"), r#"{"storage_params": {
+            "price": 900, "nodes": 4, "tolerance": 1, "proof_probability": 50,
+            "duration_days": 10, "expiry_minutes": 30, "collateral": 1
+        }}"#).unwrap();
+
+        let write_config = |params_file: &str, inline: &str| {
+            let path = dir.join("config.toml");
+            std::fs::write(&path, format!(r#"
+                target_folder = "/data"
+                output_folder = "/meta"
+                output_structure = "flattened"
+                codex_endpoints = ["http://localhost:8080"]
+                storage_params_file = "{}"
+                {}
+            "#, params_file, inline)).unwrap();
+            config::Config::from_file(&path, None).unwrap()
+        };
+
+        let config = write_config("shared/economics.toml", "[storage_params]\nnodes = 3");
+        assert_eq!(config.storage_params.nodes, 3);
+        assert_eq!(config.storage_params.price, config::TokenAmount::Integer(500));
+        assert_eq!(config.storage_params.duration_days, 30);
+        assert_eq!(config.storage_params_file, Some(dir.join("shared/economics.toml")));
+
+        let config = write_config("shared/economics.json", "");
+        assert_eq!(config.storage_params.nodes, 4);
+        assert_eq!(config.storage_params.price, config::TokenAmount::Integer(900));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_error_json_reports_chain_and_code() {
+        let error = anyhow::Error::new(codex::NoHealthyEndpoint).context("Upload failed");
+        let json = error::error_json(&error);
+        assert_eq!(json["error"], "Upload failed");
+        assert_eq!(json["context"], serde_json::json!(["No healthy Codex endpoint is available"]));
+        assert_eq!(json["code"], "no_healthy_endpoint");
+
+        let plain = error::error_json(&anyhow::anyhow!("Target folder does not exist"));
+        assert_eq!(plain["context"], serde_json::json!([]));
+        assert_eq!(plain["code"], "error");
+    }
+
+    #[test]
+    fn test_file_size_limits_are_configurable() {
+        let target = temp_dir("size-limits-target");
+        let file = target.join("large.bin");
+        std::fs::write(&file, vec![0u8; 2 * 1024 * 1024]).unwrap();
+
+        let mut config = config::Config {
+            target_folder: target.clone(),
+            output_folder: temp_dir("size-limits-output"),
+            max_file_size: 1024 * 1024,
+            ..Default::default()
+        };
+        assert_eq!(
+            eligibility::check(&config, &file).unwrap(),
+            eligibility::Eligibility::TooLarge(2 * 1024 * 1024, 1024 * 1024)
+        );
+
+        config.max_file_size = 4 * 1024 * 1024;
+        assert_eq!(eligibility::check(&config, &file).unwrap(), eligibility::Eligibility::Eligible);
+
+        config.min_file_size = config.max_file_size;
+        let err = config.validate().unwrap_err();
+        assert!(err.to_string().contains("min_file_size"), "{}", err);
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&config.output_folder).unwrap();
+    }
+
+    #[test]
+    fn test_include_and_exclude_patterns() {
+        let config = config::Config {
+            include_patterns: vec!["*.mp4".to_string()],
+            exclude_patterns: vec!["*.part".to_string()],
+            ..Default::default()
+        };
+        let filter = eligibility::PathFilter::from_config(&config).unwrap();
+        assert!(filter.allows(Path::new("movie.mp4")));
+        assert!(filter.allows(Path::new("shows/s01/e01.mp4")));
+        assert!(!filter.allows(Path::new("notes.txt")));
+        assert!(!filter.allows(Path::new("shows/e02.part")));
+
+        let both = config::Config {
+            include_patterns: vec!["*.mp4".to_string()],
+            exclude_patterns: vec!["downloads/*.mp4".to_string()],
+            ..Default::default()
+        };
+        let filter = eligibility::PathFilter::from_config(&both).unwrap();
+        assert!(filter.allows(Path::new("library/movie.mp4")));
+        assert!(!filter.allows(Path::new("downloads/movie.mp4")));
+
+        let unfiltered = eligibility::PathFilter::from_config(&config::Config::default()).unwrap();
+        assert!(unfiltered.allows(Path::new("anything.tmp")));
+    }
+
+    #[tokio::test]
+    async fn test_scan_applies_path_filters() {
+        let target = temp_dir("filter-scan-target");
+        let output = temp_dir("filter-scan-output");
+        std::fs::write(target.join("movie.mp4"), vec![0u8; 1024 * 1024]).unwrap();
+        std::fs::write(target.join("movie.mp4.part"), vec![0u8; 1024 * 1024]).unwrap();
+        std::fs::write(target.join("cover.jpg"), vec![0u8; 1024 * 1024]).unwrap();
+
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            output_structure: config::OutputStructure::Flattened,
+            include_patterns: vec!["*.mp4".to_string(), "*.part".to_string()],
+            exclude_patterns: vec!["*.part".to_string()],
+            ..Default::default()
+        };
+        let processor = file_processor::FileProcessor::new(
+            std::sync::Arc::new(config),
+            std::sync::Arc::new(codex::Client::new(vec!["http://127.0.0.1:9".to_string()])),
+        );
+        let files = processor.scan_target_folder().await.unwrap();
+        assert_eq!(files, vec![target.join("movie.mp4")]);
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[test]
+    fn test_watch_subpaths_limit_watch_roots() {
+        let target = temp_dir("watch-subpaths-target");
+        let mut config = config::Config {
+            target_folder: target.clone(),
+            output_folder: temp_dir("watch-subpaths-output"),
+            ..Default::default()
+        };
+        assert_eq!(config.watch_roots(), vec![target.clone()]);
+
+        config.watch_subpaths = vec![PathBuf::from("incoming"), PathBuf::from("photos/new")];
+        assert_eq!(config.watch_roots(), vec![target.join("incoming"), target.join("photos/new")]);
+
+        config.watch_subpaths = vec![PathBuf::from("../elsewhere")];
+        let err = config.validate().unwrap_err();
+        assert!(err.to_string().contains("watch_subpaths"), "{}", err);
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&config.output_folder).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shared_purchase_is_checked_once() {
+        let status_requests = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = status_requests.clone();
+        let endpoint = mock_server(move |req| {
+            assert_eq!(req.path, "/api/codex/v1/storage/purchases/purchase-shared");
+            counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            MockResponse::new(200, "application/json",
+                r#"{"state":"started","request":{"content":{"cid":"zShared"}}}"#)
+        }).await;
+
+        let dir = temp_dir("shared-purchase");
+        let target = dir.join("target");
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: dir.join("output"),
+            output_structure: config::OutputStructure::Flattened,
+            codex_endpoints: vec![endpoint],
+            ..Default::default()
+        };
+        std::fs::create_dir_all(&config.output_folder).unwrap();
+        let client = std::sync::Arc::new(codex::Client::new(config.codex_endpoints.clone()));
+        let processor = file_processor::FileProcessor::new(std::sync::Arc::new(config), client);
+
+        for name in ["a.bin", "copy-of-a.bin"] {
+            let file = target.join(name);
+            let mut record = processor.storage_manager.create_new_record(file.clone());
+            processor.storage_manager.update_record_purchase(&mut record, "purchase-shared".to_string(), "zShared".to_string());
+            record.updated_at = chrono::Utc::now() - chrono::Duration::days(1);
+            processor.records.write().await.insert(file, record);
+        }
+
+        assert_eq!(processor.check_purchases_once().await, file_processor::PurchaseCheckOutcome::Reachable);
+        assert_eq!(status_requests.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        let records = processor.records.read().await;
+        for name in ["a.bin", "copy-of-a.bin"] {
+            let record = &records[&target.join(name)];
+            assert_eq!(record.status, storage::FileStatus::Active, "{}", name);
+            assert_eq!(record.purchase_id.as_deref(), Some("purchase-shared"));
+        }
+        drop(records);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_process_files_respects_upload_concurrency() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let in_flight = std::sync::Arc::new(AtomicUsize::new(0));
+        let max_in_flight = std::sync::Arc::new(AtomicUsize::new(0));
+        let (current, peak) = (in_flight.clone(), max_in_flight.clone());
+        let endpoint = mock_server(move |req| {
+            if req.path == "/api/codex/v1/data" {
+                let now = current.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                std::thread::sleep(std::time::Duration::from_millis(200));
+                current.fetch_sub(1, Ordering::SeqCst);
+            }
+            codex_response(&req)
+        }).await;
+
+        let target = temp_dir("concurrency-target");
+        let output = temp_dir("concurrency-output");
+        for index in 0..6 {
+            std::fs::write(target.join(format!("file-{}.bin", index)), vec![index as u8; 1024 * 1024]).unwrap();
+        }
+
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            output_structure: config::OutputStructure::Flattened,
+            codex_endpoints: vec![endpoint],
+            max_concurrent_uploads: 2,
+            ..Default::default()
+        };
+        let client = std::sync::Arc::new(codex::Client::from_config(&config));
+        let processor = file_processor::FileProcessor::new(std::sync::Arc::new(config), client);
+        processor.initialize().await.unwrap();
+        processor.process_files().await.unwrap();
+
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), 2);
+        let records = processor.storage_manager.load_existing_records(&target).await.unwrap();
+        assert_eq!(records.len(), 6);
+        assert!(records.values().all(|record| record.status == storage::FileStatus::Active));
+
+        // Explicit paths share the same limit.
+        max_in_flight.store(0, Ordering::SeqCst);
+        let paths: Vec<PathBuf> = (6..10).map(|index| target.join(format!("file-{}.bin", index))).collect();
+        for (index, path) in paths.iter().enumerate() {
+            std::fs::write(path, vec![index as u8 + 6; 1024 * 1024]).unwrap();
+        }
+        let results = processor.process_paths(paths.clone()).await;
+        assert!(results.iter().all(|(_, result)| result.is_ok()));
+        assert_eq!(results.into_iter().map(|(path, _)| path).collect::<Vec<_>>(), paths);
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), 2);
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_metrics_snapshot_written_atomically() {
+        let dir = temp_dir("metrics-snapshot");
+        let storage_manager = storage::StorageManager::from_config(&config::Config {
+            output_folder: dir.clone(),
+            ..Default::default()
+        });
+        let mut records = std::collections::HashMap::new();
+        for (name, status, size) in [
+            ("a.bin", storage::FileStatus::Active, 3 * 1024 * 1024),
+            ("b.bin", storage::FileStatus::Active, 1024 * 1024),
+            ("c.bin", storage::FileStatus::Failed, 1024 * 1024),
+        ] {
+            let mut record = storage_manager.create_new_record(dir.join(name));
+            record.status = status;
+            record.file_size = Some(size);
+            records.insert(dir.join(name), record);
+        }
+
+        let snapshot = metrics::MetricsSnapshot::from_records(&records);
+        assert_eq!(snapshot.total, 3);
+        assert_eq!(snapshot.counts["Active"], 2);
+        assert_eq!(snapshot.bytes_stored, 4 * 1024 * 1024);
+        assert_eq!(snapshot.failures, 1);
+
+        let path = dir.join("metrics.json");
+        snapshot.write(&path).await.unwrap();
+        let written: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(written["counts"]["Failed"], 1);
+        assert!(!dir.join("metrics.json.tmp").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_verify_after_upload_detects_mismatch() {
+        let cid = "zDvZRwzm2mK7tvDzKScRLapqGdgNTLyyEBvx1TQY37J2CvuzL7Gh";
+        let endpoint = mock_server(move |req| {
+            if req.method == "POST" && req.path == "/api/codex/v1/data" {
+                MockResponse::new(200, "text/plain", cid)
+            } else if req.path == format!("/api/codex/v1/data/{}", cid) {
+                MockResponse::new(200, "application/octet-stream", "not what was uploaded")
+            } else {
+                panic!("unexpected request {}", req.path)
+            }
+        }).await;
+
+        let target = temp_dir("verify-target");
+        let output = temp_dir("verify-output");
+        let file = target.join("a.bin");
+        std::fs::write(&file, vec![3u8; 1024 * 1024]).unwrap();
+
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            output_structure: config::OutputStructure::Flattened,
+            codex_endpoints: vec![endpoint.clone()],
+            verify_after_upload: true,
+            ..Default::default()
+        };
+        let client = std::sync::Arc::new(codex::Client::from_config(&config));
+        let downloaded = client.download_file(cid).await.unwrap();
+        assert_eq!(downloaded, b"not what was uploaded");
+
+        let processor = file_processor::FileProcessor::new(std::sync::Arc::new(config), client);
+        processor.initialize().await.unwrap();
+        let err = processor.process_file(&file).await.unwrap_err();
+        assert!(err.to_string().contains("verification failed"), "{}", err);
+
+        let record = &processor.records.read().await[&file];
+        assert_eq!(record.status, storage::FileStatus::Failed);
+        assert_eq!(record.purchase_id, None);
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_auth_token_sent_with_requests() {
+        let endpoint = mock_server(|req| {
+            if req.headers.get("authorization").map(String::as_str) != Some("Bearer secret-token") {
+                return MockResponse::new(401, "text/plain", "unauthorized");
+            }
+            match req.path.as_str() {
+                "/api/codex/v1/debug/info" => MockResponse::new(200, "application/json", "{}"),
+                _ => codex_response(&req),
+            }
+        }).await;
+
+        let dir = temp_dir("auth-token");
+        let file = dir.join("a.bin");
+        std::fs::write(&file, vec![1u8; 1024 * 1024]).unwrap();
+
+        let anonymous = codex::Client::new(vec![endpoint.clone()]);
+        assert!(anonymous.check_connectivity().await.is_err());
+
+        let config = config::Config {
+            codex_endpoints: vec![endpoint],
+            auth_token: Some("secret-token".to_string()),
+            ..Default::default()
+        };
+        let client = codex::Client::from_config(&config);
+        client.check_connectivity().await.unwrap();
+        let upload = client.upload_file(&file).await.unwrap();
+        let purchase = client.create_storage_request(&upload.cid, &config.storage_params, Some(&upload.endpoint)).await.unwrap();
+        let status = client.get_purchase_status(&purchase.purchase_id, Some(&upload.endpoint)).await.unwrap();
+        assert_eq!(status.state, "started");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_case_insensitive_paths_share_a_record() {
+        let target = temp_dir("case-target");
+        let output = temp_dir("case-output");
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            output_structure: config::OutputStructure::Structured,
+            ..Default::default()
+        };
+        let storage_manager = storage::StorageManager::from_config(&config);
+        let mut older = storage_manager.create_new_record(target.join("foo.bin"));
+        older.updated_at = chrono::Utc::now() - chrono::Duration::hours(1);
+        storage_manager.save_record(&target, &target.join("foo.bin"), &older).await.unwrap();
+        let mut newer = storage_manager.create_new_record(target.join("Foo.bin"));
+        storage_manager.update_record_purchase(&mut newer, "purchase-1".to_string(), "zCid".to_string());
+        storage_manager.mark_record_active(&mut newer);
+        storage_manager.save_record(&target, &target.join("Foo.bin"), &newer).await.unwrap();
+
+        let client = std::sync::Arc::new(codex::Client::new(vec!["http://127.0.0.1:9".to_string()]));
+        let mut processor = file_processor::FileProcessor::new(std::sync::Arc::new(config), client);
+        // The temp dir is case-sensitive here; pretend otherwise.
+        processor.case_insensitive_paths = true;
+        processor.initialize().await.unwrap();
+
+        let records = processor.records.read().await.clone();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[&target.join("Foo.bin")].purchase_id.as_deref(), Some("purchase-1"));
+        assert_eq!(processor.record_path(&target.join("FOO.BIN")).await, target.join("Foo.bin"));
+        assert_eq!(processor.record_path(&target.join("bar.bin")).await, target.join("bar.bin"));
+
+        // A renamed record is found under its new name only.
+        processor.rename_records(&target.join("foo.bin"), &target.join("Bar.bin")).await.unwrap();
+        assert_eq!(processor.record_path(&target.join("BAR.BIN")).await, target.join("Bar.bin"));
+        assert_eq!(processor.record_path(&target.join("FOO.BIN")).await, target.join("FOO.BIN"));
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_scheduled_verification_flags_degraded_files() {
+        let available = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let flag = available.clone();
+        let endpoint = mock_server(move |req| {
+            if req.path.ends_with("/network/manifest") {
+                if flag.load(std::sync::atomic::Ordering::SeqCst) {
+                    MockResponse::new(200, "application/json", "{}")
+                } else {
+                    MockResponse::new(404, "text/plain", "not found")
+                }
+            } else {
+                MockResponse::new(200, "application/json",
+                    r#"{"state":"started","request":{"content":{"cid":"zCid"}}}"#)
+            }
+        }).await;
+
+        let dir = temp_dir("verify-cadence");
+        let target = dir.join("target");
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: dir.join("output"),
+            output_structure: config::OutputStructure::Flattened,
+            codex_endpoints: vec![endpoint],
+            verify_cadence_days: Some(7),
+            ..Default::default()
+        };
+        std::fs::create_dir_all(&config.output_folder).unwrap();
+        let client = std::sync::Arc::new(codex::Client::new(config.codex_endpoints.clone()));
+        let processor = file_processor::FileProcessor::new(std::sync::Arc::new(config), client);
+
+        let file = target.join("a.bin");
+        let mut record = processor.storage_manager.create_new_record(file.clone());
+        processor.storage_manager.update_record_purchase(&mut record, "purchase-1".to_string(), "zCid".to_string());
+        processor.storage_manager.mark_record_active(&mut record);
+        record.last_verified_at = Some(chrono::Utc::now() - chrono::Duration::days(8));
+        processor.records.write().await.insert(file.clone(), record);
+
+        processor.check_purchases_once().await;
+        {
+            let records = processor.records.read().await;
+            assert_eq!(records[&file].status, storage::FileStatus::Degraded);
+            assert!(records[&file].last_verified_at.unwrap() > chrono::Utc::now() - chrono::Duration::minutes(1));
+        }
+
+        available.store(true, std::sync::atomic::Ordering::SeqCst);
+        processor.check_purchases_once().await;
+        {
+            let records = processor.records.read().await;
+            assert_eq!(records[&file].status, storage::FileStatus::Active);
+            assert_eq!(records[&file].error, None);
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_purchase_follows_upload_endpoint() {
+        let seen = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut endpoints = Vec::new();
+        for name in ["first", "second"] {
+            let seen = seen.clone();
+            endpoints.push(mock_server(move |req| {
+                seen.lock().unwrap().push((name, req.path.clone()));
+                codex_response(&req)
+            }).await);
+        }
+
+        let target = temp_dir("endpoint-target");
+        let output = temp_dir("endpoint-output");
+        let file = target.join("a.bin");
+        std::fs::write(&file, vec![5u8; 1024 * 1024]).unwrap();
+
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            output_structure: config::OutputStructure::Flattened,
+            codex_endpoints: endpoints.clone(),
+            ..Default::default()
+        };
+        let client = std::sync::Arc::new(codex::Client::from_config(&config));
+        let processor = file_processor::FileProcessor::new(std::sync::Arc::new(config), client);
+        processor.initialize().await.unwrap();
+        processor.process_file(&file).await.unwrap();
+        processor.records.write().await.get_mut(&file).unwrap().updated_at = chrono::Utc::now() - chrono::Duration::hours(2);
+        processor.check_purchases_once().await;
+
+        let seen = seen.lock().unwrap().clone();
+        let uploader = seen.iter().find(|(_, path)| path == "/api/codex/v1/data").unwrap().0;
+        let expected = &endpoints[if uploader == "first" { 0 } else { 1 }];
+        let record = processor.records.read().await[&file].clone();
+        assert_eq!(record.status, storage::FileStatus::Active);
+        assert_eq!(record.codex_endpoint.as_ref(), Some(expected));
+        assert!(seen.iter().filter(|(_, path)| path.contains("/storage/")).count() >= 2);
+        assert!(seen.iter().all(|(name, _)| *name == uploader), "{:?}", seen);
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[test]
+    fn test_watch_estimate_counts_directories() {
+        let target = temp_dir("watch-estimate");
+        for dir in ["photos/2024", "cache/thumbs", "docs"] {
+            std::fs::create_dir_all(target.join(dir)).unwrap();
+        }
+        std::fs::write(target.join("photos/a.jpg"), b"x").unwrap();
+
+        let mut config = config::Config {
+            target_folder: target.clone(),
+            exclude_patterns: vec!["cache".to_string()],
+            ..Default::default()
+        };
+        let estimate = commands::estimate_watches(&config).unwrap();
+        assert_eq!(estimate.watches, 6);
+        assert_eq!(estimate.excluded, 2);
+
+        config.watch_subpaths = vec![PathBuf::from("photos")];
+        let estimate = commands::estimate_watches(&config).unwrap();
+        assert_eq!(estimate.roots, vec![target.join("photos")]);
+        assert_eq!(estimate.watches, 2);
+        assert_eq!(estimate.excluded, 0);
+
+        std::fs::remove_dir_all(&target).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_failing_endpoint_is_taken_out_of_rotation() {
+        let cid = "zDvZRwzm2mK7tvDzKScRLapqGdgNTLyyEBvx1TQY37J2CvuzL7Gh";
+        let broken_hits = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let hits = broken_hits.clone();
+        let broken = mock_server(move |_| {
+            hits.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            MockResponse::new(500, "text/plain", "internal error")
+        }).await;
+        let working = mock_server(move |_| MockResponse::new(200, "text/plain", cid)).await;
+
+        let dir = temp_dir("failover");
+        let file = dir.join("a.bin");
+        std::fs::write(&file, vec![7u8; 1024 * 1024]).unwrap();
+
+        let config = config::Config {
+            codex_endpoints: vec![broken.clone(), working.clone()],
+            endpoint_failure_threshold: 2,
+            ..Default::default()
+        };
+        let client = codex::Client::from_config(&config);
+        let mut failures = 0;
+        for _ in 0..4 {
+            if client.upload_file(&file).await.is_err() {
+                failures += 1;
+            }
+        }
+        assert_eq!(failures, 2);
+        assert_eq!(broken_hits.load(std::sync::atomic::Ordering::SeqCst), 2);
+
+        for _ in 0..3 {
+            assert_eq!(client.upload_file(&file).await.unwrap().endpoint, working);
+        }
+        assert_eq!(client.probe_unhealthy().await, 0);
+        assert_eq!(broken_hits.load(std::sync::atomic::Ordering::SeqCst), 3);
+
+        client.mark_unhealthy(&working);
+        let err = client.upload_file(&file).await.unwrap_err();
+        assert!(err.downcast_ref::<codex::NoHealthyEndpoint>().is_some(), "{}", err);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_finishes_file_in_progress() {
+        let shutdown = tokio_util::sync::CancellationToken::new();
+        let signal = shutdown.clone();
+        let started = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let is_started = started.clone();
+        let endpoint = mock_server(move |req| {
+            match req.path.as_str() {
+                "/api/codex/v1/data" => {
+                    // Shutdown is requested while the first file is uploading.
+                    signal.cancel();
+                    MockResponse::new(200, "text/plain", "zDvZRwzm2mK7tvDzKScRLapqGdgNTLyyEBvx1TQY37J2CvuzL7Gh")
+                }
+                "/api/codex/v1/storage/purchases/purchase-1" if !is_started.load(std::sync::atomic::Ordering::SeqCst) =>
+                    MockResponse::new(200, "application/json", r#"{"state":"submitted","request":{"content":{"cid":"zCid"}}}"#),
+                _ => codex_response(&req),
+            }
+        }).await;
+
+        let target = temp_dir("shutdown-target");
+        let output = temp_dir("shutdown-output");
+        for name in ["a.bin", "b.bin", "c.bin"] {
+            std::fs::write(target.join(name), vec![9u8; 1024 * 1024]).unwrap();
+        }
+
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            output_structure: config::OutputStructure::Flattened,
+            codex_endpoints: vec![endpoint],
+            ..Default::default()
+        };
+        let client = std::sync::Arc::new(codex::Client::from_config(&config));
+        let mut processor = file_processor::FileProcessor::new(std::sync::Arc::new(config), client);
+        processor.shutdown = shutdown;
+        processor.initialize().await.unwrap();
+        processor.process_files().await.unwrap();
+
+        // No record is left in a transient state; the one whose purchase was
+        // pending waits for it as WaitingLong.
+        let records = processor.storage_manager.load_existing_records(&target).await.unwrap();
+        assert_eq!(records.len(), 1);
+        let record = &records[&target.join("a.bin")];
+        assert_eq!(record.status, storage::FileStatus::WaitingLong);
+        assert_eq!(record.purchase_id.as_deref(), Some("purchase-1"));
+        assert!(record.pending_since.is_some());
+        assert!(processor.records.read().await.values().all(|record| !matches!(record.status,
+            storage::FileStatus::New | storage::FileStatus::Uploading | storage::FileStatus::Creating)));
+        assert!(output.join(file_processor::SCAN_CHECKPOINT_FILE_NAME).exists());
+
+        // The next run's purchase monitoring picks the purchase up.
+        let restarted = file_processor::FileProcessor::new(processor.config.clone(), processor.codex_client.clone());
+        restarted.initialize().await.unwrap();
+        restarted.check_purchases_once().await;
+        assert_eq!(restarted.records.read().await[&target.join("a.bin")].status, storage::FileStatus::WaitingLong);
+        started.store(true, std::sync::atomic::Ordering::SeqCst);
+        restarted.check_purchases_once().await;
+        assert_eq!(restarted.records.read().await[&target.join("a.bin")].status, storage::FileStatus::Active);
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[test]
+    fn test_display_timezone_formats_reports() {
+        let at = chrono::DateTime::parse_from_rfc3339("2024-07-01T12:00:00Z").unwrap().with_timezone(&chrono::Utc);
+        let mut config = config::Config::default();
+        assert_eq!(commands::format_timestamp(at, config.display_tz()), "2024-07-01 12:00:00 UTC");
+
+        config.display_timezone = "Europe/Berlin".to_string();
+        assert_eq!(commands::format_timestamp(at, config.display_tz()), "2024-07-01 14:00:00 CEST");
+
+        config.display_timezone = "Mars/Olympus".to_string();
+        assert!(config.validate().unwrap_err().to_string().contains("display_timezone"));
+    }
+
+    #[tokio::test]
+    async fn test_shadow_config_reports_cost_and_renewal_changes() {
+        let target = temp_dir("shadow-target");
+        let output = temp_dir("shadow-output");
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            output_structure: config::OutputStructure::Flattened,
+            ..Default::default()
+        };
+        let storage_manager = storage::StorageManager::from_config(&config);
+        let mut active = storage_manager.create_new_record(target.join("a.bin"));
+        active.file_size = Some(1024 * 1024);
+        storage_manager.update_record_purchase(&mut active, "purchase-1".to_string(), "zCid".to_string());
+        storage_manager.mark_record_active(&mut active);
+        storage_manager.save_record(&target, &target.join("a.bin"), &active).await.unwrap();
+        let mut baseline = storage_manager.create_new_record(target.join("b.bin"));
+        baseline.status = storage::FileStatus::Baseline;
+        storage_manager.save_record(&target, &target.join("b.bin"), &baseline).await.unwrap();
+
+        let mut shadow = config.clone();
+        shadow.storage_params.price = config::TokenAmount::Integer(2000);
+        shadow.renewal_buffer_minutes = config.renewal_buffer_minutes + 60;
+
+        let report = commands::shadow_compare(&config, &shadow).await.unwrap();
+        assert_eq!(report.records, 1);
+        assert_eq!(report.changed_params.len(), 1);
+        assert_eq!(report.changed_params[0].fields[0].field, "price");
+        assert_eq!(report.changed_renewals.len(), 1);
+        assert_eq!(report.changed_renewals[0].current - report.changed_renewals[0].shadow, chrono::Duration::hours(1));
+        assert!(report.shadow_cost_per_day > report.current_cost_per_day);
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_startup_reconciles_transient_records() {
+        let endpoint = mock_server(|req| match req.path.as_str() {
+            "/api/codex/v1/storage/purchases/purchase-1" => MockResponse::new(200, "application/json",
+                r#"{"state":"started","request":{"content":{"cid":"zCid"}}}"#),
+            _ => MockResponse::new(404, "text/plain", "purchase not found"),
+        }).await;
+
+        let target = temp_dir("reconcile-target");
+        let output = temp_dir("reconcile-output");
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            output_structure: config::OutputStructure::Flattened,
+            codex_endpoints: vec![endpoint],
+            ..Default::default()
+        };
+        let storage_manager = storage::StorageManager::from_config(&config);
+        let seeds = [
+            ("started.bin", Some("purchase-1"), None),
+            ("unknown.bin", Some("purchase-2"), None),
+            ("uploading.bin", None, None),
+            ("failed-started.bin", Some("purchase-1"), Some(storage::FileStatus::Failed)),
+            ("pending-started.bin", Some("purchase-1"), Some(storage::FileStatus::Pending)),
+            ("failed-unknown.bin", Some("purchase-2"), Some(storage::FileStatus::Failed)),
+        ];
+        for (name, purchase_id, status) in seeds {
+            let mut record = storage_manager.create_new_record(target.join(name));
+            storage_manager.update_record_upload(&mut record, "zCid".to_string(), config.codex_endpoints[0].clone());
+            if let Some(purchase_id) = purchase_id {
+                storage_manager.update_record_purchase(&mut record, purchase_id.to_string(), "zCid".to_string());
+            }
+            if let Some(status) = status {
+                storage_manager.update_record_status(&mut record, status, Some("interrupted".to_string()));
+            }
+            storage_manager.save_record(&target, &target.join(name), &record).await.unwrap();
+        }
+
+        let client = std::sync::Arc::new(codex::Client::from_config(&config));
+        let processor = file_processor::FileProcessor::new(std::sync::Arc::new(config), client);
+        processor.initialize().await.unwrap();
+
+        let records = processor.storage_manager.load_existing_records(&target).await.unwrap();
+        assert_eq!(records[&target.join("started.bin")].status, storage::FileStatus::Active);
+        let unknown = &records[&target.join("unknown.bin")];
+        assert_eq!(unknown.status, storage::FileStatus::New);
+        assert_eq!(unknown.purchase_id, None);
+        assert_eq!(unknown.history.len(), 1);
+        assert_eq!(records[&target.join("uploading.bin")].status, storage::FileStatus::New);
+        // A settled record is promoted if its purchase started meanwhile,
+        // and otherwise kept as it was.
+        assert_eq!(records[&target.join("failed-started.bin")].status, storage::FileStatus::Active);
+        assert_eq!(records[&target.join("pending-started.bin")].status, storage::FileStatus::Active);
+        let failed = &records[&target.join("failed-unknown.bin")];
+        assert_eq!(failed.status, storage::FileStatus::Failed);
+        assert_eq!(failed.purchase_id.as_deref(), Some("purchase-2"));
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_debug_capture_records_raw_responses() {
+        let endpoint = mock_server(|req| match req.path.as_str() {
+            "/api/codex/v1/data" => MockResponse::new(200, "text/plain", "zDvZRwzm2mK7tvDzKScRLapqGdgNTLyyEBvx1TQY37J2CvuzL7Gh"),
+            path if path.starts_with("/api/codex/v1/storage/request/") => MockResponse::new(200, "text/plain", "purchase-1\n"),
+            _ => MockResponse::new(200, "application/json",
+                r#"{"state":"started","filledSlots":3,"request":{"content":{"cid":"zCid"}}}"#),
+        }).await;
+
+        let target = temp_dir("capture-target");
+        let output = temp_dir("capture-output");
+        std::fs::create_dir_all(target.join("sub")).unwrap();
+        let file = target.join("sub/a.bin");
+        std::fs::write(&file, vec![4u8; 1024 * 1024]).unwrap();
+
+        let mut config = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            output_structure: config::OutputStructure::Structured,
+            codex_endpoints: vec![endpoint],
+            debug_capture: true,
+            ..Default::default()
+        };
+        config.debug_capture_max_bytes = 300;
+        let client = std::sync::Arc::new(codex::Client::from_config(&config));
+        let processor = file_processor::FileProcessor::new(std::sync::Arc::new(config), client);
+        processor.initialize().await.unwrap();
+        processor.process_file(&file).await.unwrap();
+
+        let capture_path = output.join(format!("sub/a.bin{}", file_processor::DEBUG_CAPTURE_SUFFIX));
+        let content = std::fs::read_to_string(&capture_path).unwrap();
+        let lines: Vec<serde_json::Value> = content.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["call"], "create_storage_request");
+        assert_eq!(lines[0]["body"], "purchase-1\n");
+        assert_eq!(lines[1]["call"], "get_purchase_status");
+        assert_eq!(lines[1]["body"]["filledSlots"], 3);
+
+        // The capture stops growing at debug_capture_max_bytes.
+        processor.records.write().await.get_mut(&file).unwrap().updated_at = chrono::Utc::now() - chrono::Duration::hours(2);
+        processor.check_purchases_once().await;
+        assert_eq!(std::fs::read_to_string(&capture_path).unwrap(), content);
+
+        // Concurrent captures for one file share the limit.
+        std::fs::remove_file(&capture_path).unwrap();
+        futures::future::join_all((0..20).map(|_| {
+            processor.capture_codex_response(&file, "get_purchase_status", "purchase-1", r#"{"state":"started"}"#)
+        })).await;
+        let content = std::fs::read_to_string(&capture_path).unwrap();
+        assert!(content.len() <= 300, "{}", content.len());
+        assert!(content.lines().all(|line| serde_json::from_str::<serde_json::Value>(line).is_ok()));
+
+        // The capture follows its record when renamed, and goes with it.
+        let moved = target.join("sub/b.bin");
+        let moved_capture = output.join(format!("sub/b.bin{}", file_processor::DEBUG_CAPTURE_SUFFIX));
+        processor.rename_records(&file, &moved).await.unwrap();
+        assert!(!capture_path.exists());
+        assert_eq!(std::fs::read_to_string(&moved_capture).unwrap(), content);
+        assert_eq!(processor.cleanup_deleted(&moved).await.unwrap(), 1);
+        assert!(!moved_capture.exists());
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[test]
+    fn test_retry_policy_delays_are_jittered_and_capped() {
+        let policy = error::RetryPolicy {
+            max_retries: 10,
+            initial_delay: std::time::Duration::from_secs(1),
+            max_delay: Some(std::time::Duration::from_secs(20)),
+            jitter: 0.5,
+        };
+        for attempt in 0..10 {
+            let base = 2f64.powi(attempt as i32);
+            for _ in 0..50 {
+                let delay = policy.delay(attempt).as_secs_f64();
+                assert!(delay <= 20.0, "attempt {} waited {}s", attempt, delay);
+                assert!(delay >= (base * 0.5).min(20.0), "attempt {} waited {}s", attempt, delay);
+                assert!(delay <= base * 1.5, "attempt {} waited {}s", attempt, delay);
+            }
+        }
+
+        let exact = error::RetryPolicy { max_delay: None, jitter: 0.0, ..policy };
+        assert_eq!(exact.delay(4), std::time::Duration::from_secs(16));
+        assert_eq!(exact.delay(40), std::time::Duration::from_secs(1 << 40));
+    }
+
+    #[tokio::test]
+    async fn test_endpoint_params_apply_to_pinned_endpoint() {
+        let cheap = mock_server(|req| match req.path.as_str() {
+            path if path.starts_with("/api/codex/v1/storage/request/") => {
+                assert!(req.body.contains("\"pricePerBytePerSecond\":\"10\""), "{}", req.body);
+                MockResponse::new(200, "text/plain", "purchase-1")
+            }
+            _ => codex_response(&req),
+        }).await;
+
+        let target = temp_dir("endpoint-params-target");
+        let output = temp_dir("endpoint-params-output");
+        let file = target.join("a.bin");
+        std::fs::write(&file, vec![6u8; 1024 * 1024]).unwrap();
+
+        let mut config = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            output_structure: config::OutputStructure::Flattened,
+            codex_endpoints: vec![cheap.clone()],
+            ..Default::default()
+        };
+        config.endpoint_params.insert(format!("{}/", cheap), config::StorageParamsOverride {
+            price: Some(config::TokenAmount::Integer(10)),
+            ..Default::default()
+        });
+        assert_eq!(config.storage_params_for(Path::new("a.bin"), None).price, config::TokenAmount::Integer(1000));
+
+        let client = std::sync::Arc::new(codex::Client::from_config(&config));
+        let processor = file_processor::FileProcessor::new(std::sync::Arc::new(config), client);
+        processor.initialize().await.unwrap();
+        processor.process_file(&file).await.unwrap();
+
+        let records = processor.storage_manager.load_existing_records(&target).await.unwrap();
+        let record = &records[&file];
+        assert_eq!(record.status, storage::FileStatus::Active);
+        assert_eq!(record.storage_params.as_ref().unwrap().price, config::TokenAmount::Integer(10));
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_event_log_records_status_transitions() {
+        let server = happy_codex_mock().await;
+
+        let target = temp_dir("event-log-target");
+        let output = temp_dir("event-log-output");
+        let events = temp_dir("event-log-events").join("events.jsonl");
+        let file = target.join("a.bin");
+        std::fs::write(&file, vec![8u8; 1024 * 1024]).unwrap();
+
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            output_structure: config::OutputStructure::Flattened,
+            codex_endpoints: vec![server],
+            event_log_path: Some(events.clone()),
+            ..Default::default()
+        };
+        let client = std::sync::Arc::new(codex::Client::from_config(&config));
+        let processor = file_processor::FileProcessor::new(std::sync::Arc::new(config), client);
+        processor.initialize().await.unwrap();
+        processor.process_file(&file).await.unwrap();
+
+        let lines: Vec<serde_json::Value> = std::fs::read_to_string(&events).unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let transitions: Vec<(&str, &str)> = lines.iter()
+            .map(|event| (event["old_status"].as_str().unwrap(), event["new_status"].as_str().unwrap()))
+            .collect();
+        assert_eq!(transitions, vec![("New", "Uploading"), ("Uploading", "Creating"), ("Creating", "Active")]);
+        for event in &lines {
+            assert_eq!(event["file_path"], file.to_str().unwrap());
+            assert_eq!(event["cid"], "zDvZRwzm2mK7tvDzKScRLapqGdgNTLyyEBvx1TQY37J2CvuzL7Gh");
+            assert!(event["error"].is_null());
+            assert!(event["timestamp"].is_string());
+        }
+        assert!(lines[0]["purchase_id"].is_null());
+        assert_eq!(lines[2]["purchase_id"], "purchase-1");
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&output).unwrap();
+        std::fs::remove_dir_all(events.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_dry_run_makes_no_requests() {
+        let requests = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counted = requests.clone();
+        let server = mock_server(move |_| {
+            counted.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            MockResponse::new(500, "text/plain", "unexpected")
+        }).await;
+
+        let target = temp_dir("dry-run-target");
+        let output = temp_dir("dry-run-output");
+        std::fs::write(target.join("a.bin"), vec![1u8; 1024 * 1024]).unwrap();
+        std::fs::write(target.join("b.bin"), vec![2u8; 2 * 1024 * 1024]).unwrap();
+
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            output_structure: config::OutputStructure::Flattened,
+            codex_endpoints: vec![server],
+            dry_run: true,
+            ..Default::default()
+        };
+        let client = std::sync::Arc::new(codex::Client::from_config(&config));
+        let processor = file_processor::FileProcessor::new(std::sync::Arc::new(config), client);
+        processor.initialize().await.unwrap();
+        processor.process_files().await.unwrap();
+        processor.monitor_purchases().await.unwrap();
+
+        assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 0);
+        assert_eq!(processor.dry_run_summary().await, file_processor::DryRunSummary {
+            files: 2,
+            total_bytes: 3 * 1024 * 1024,
+        });
+        let plan = processor.dry_run_plan.read().await;
+        assert_eq!(plan[0].file_path, target.join("a.bin"));
+        assert_eq!(plan[0].params.duration_days, 6);
+
+        let records = processor.storage_manager.load_existing_records(&target).await.unwrap();
+        assert_eq!(records[&target.join("a.bin")].status, storage::FileStatus::DryRun);
+        assert!(records[&target.join("b.bin")].original_cid.is_none());
+
+        // Seen again within the same dry run, an unchanged file isn't planned twice.
+        drop(plan);
+        processor.process_file(&target.join("a.bin")).await.unwrap();
+        assert_eq!(processor.dry_run_summary().await.files, 2);
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_plan_estimates_backlog() {
+        let target = temp_dir("plan-target");
+        let output = temp_dir("plan-output");
+        std::fs::write(target.join("a.bin"), vec![1u8; 1024 * 1024]).unwrap();
+        std::fs::write(target.join("b.bin"), vec![2u8; 2 * 1024 * 1024]).unwrap();
+        std::fs::write(target.join("c.bin"), vec![3u8; 3 * 1024 * 1024]).unwrap();
+
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            output_structure: config::OutputStructure::Flattened,
+            // Nothing listens here; planning must not contact Codex.
+            codex_endpoints: vec!["http://127.0.0.1:9".to_string()],
+            max_concurrent_uploads: 2,
+            ..Default::default()
+        };
+        let client = std::sync::Arc::new(codex::Client::from_config(&config));
+        let processor = file_processor::FileProcessor::new(std::sync::Arc::new(config.clone()), client);
+        processor.record_baseline(&target.join("a.bin")).await.unwrap();
+
+        let plan = commands::plan_uploads(&config, 1024 * 1024, std::time::Duration::from_secs(60)).await.unwrap();
+        assert_eq!(plan.files, 2);
+        assert_eq!(plan.total_bytes, 5 * 1024 * 1024);
+        assert_eq!(plan.upload_time, std::time::Duration::from_secs(5));
+        // Two files, two at a time: one round of purchase overhead.
+        assert_eq!(plan.purchase_time, std::time::Duration::from_secs(60));
+        assert_eq!(plan.eta(), std::time::Duration::from_secs(65));
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_records_with_unknown_fields_load() {
+        let target = temp_dir("unknown-fields-target");
+        let structured = temp_dir("unknown-fields-structured");
+        let flattened = temp_dir("unknown-fields-flattened");
+
+        // A record written by an older version, without any of the optional
+        // fields, plus fields from a newer version this one doesn't know.
+        let record = serde_json::json!({
+            "file_path": target.join("a.bin"),
+            "original_cid": "zDvZRwzm2mK7tvDzKScRLapqGdgNTLyyEBvx1TQY37J2CvuzL7Gh",
+            "storage_cid": null,
+            "purchase_id": "purchase-1",
+            "created_at": "2025-01-01T00:00:00Z",
+            "updated_at": "2025-01-01T00:00:00Z",
+            "codex_endpoint": null,
+            "status": "Active",
+            "error": null,
+            "replicas": [{"node": "codex-b", "slot": 3}],
+            "retention_class": "archive",
+        });
+        std::fs::write(structured.join("a.json"), serde_json::to_string(&record).unwrap()).unwrap();
+        let mut flattened_record = record.clone();
+        flattened_record["relative_path"] = serde_json::json!("a.bin");
+        std::fs::write(flattened.join("files.json"), serde_json::to_string(&vec![flattened_record]).unwrap()).unwrap();
+
+        for (folder, structure) in [
+            (&structured, config::OutputStructure::Structured),
+            (&flattened, config::OutputStructure::Flattened),
+        ] {
+            let storage = storage::StorageManager::new(folder.clone(), structure);
+            let records = storage.load_existing_records(&target).await.unwrap();
+            let loaded = &records[&target.join("a.bin")];
+            assert_eq!(loaded.status, storage::FileStatus::Active);
+            assert_eq!(loaded.purchase_id.as_deref(), Some("purchase-1"));
+            assert!(loaded.history.is_empty());
+            assert!(loaded.storage_params.is_none());
+        }
+
+        for dir in [&target, &structured, &flattened] {
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_environment_overrides_config_file() {
+        use clap::Parser;
+
+        let dir = temp_dir("env-overrides");
+        let config_path = dir.join("config.toml");
+        std::fs::write(&config_path, r#"
+target_folder = "/data/files"
+output_folder = "/data/output"
+codex_endpoints = ["http://localhost:8080"]
+output_structure = "flattened"
+
+[storage_params]
+price = 1000
+nodes = 5
+tolerance = 2
+proof_probability = 100
+duration_days = 30
+expiry_minutes = 60
+collateral = 1
+"#).unwrap();
+
+        let env: std::collections::HashMap<&str, &str> = [
+            ("FILEHOG_CODEX_ENDPOINTS", "http://codex-a:8080, http://codex-b:8080,"),
+            ("FILEHOG_PRICE", "2500"),
+            ("FILEHOG_NODES", "8"),
+            ("FILEHOG_DURATION_DAYS", "14"),
+            ("FILEHOG_OUTPUT_FOLDER", "/mnt/output"),
+        ].into_iter().collect();
+        let var = |name: &str| env.get(name).map(|value| value.to_string());
+
+        let args = config::Args::try_parse_from(["filehog", "--config", config_path.to_str().unwrap(), "--nodes", "3"]).unwrap();
+        let config = config::Config::from_args_and_env(&args, var).unwrap();
+        assert_eq!(config.codex_endpoints, vec!["http://codex-a:8080", "http://codex-b:8080"]);
+        assert_eq!(config.storage_params.price, config::TokenAmount::Integer(2500));
+        assert_eq!(config.storage_params.duration_days, 14);
+        assert_eq!(config.output_folder, PathBuf::from("/mnt/output"));
+        // Unset variables keep the file's value, and flags beat the environment.
+        assert_eq!(config.storage_params.tolerance, 2);
+        assert_eq!(config.target_folder, PathBuf::from("/data/files"));
+        assert_eq!(config.storage_params.nodes, 3);
+
+        let bad = |name: &str| (name == "FILEHOG_NODES").then(|| "many".to_string());
+        let args = config::Args::try_parse_from(["filehog", "--config", config_path.to_str().unwrap()]).unwrap();
+        let error = config::Config::from_args_and_env(&args, bad).unwrap_err().to_string();
+        assert!(error.contains("FILEHOG_NODES") && error.contains("command-line flags"), "{}", error);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_records_round_trip() {
+        let target = temp_dir("sqlite-target");
+        let output = temp_dir("sqlite-output");
+        let storage = storage::StorageManager::new(output.clone(), config::OutputStructure::Sqlite);
+        assert!(storage.load_existing_records(&target).await.unwrap().is_empty());
+
+        let mut first = storage.create_new_record(target.join("photos/a.jpg"));
+        storage.update_record_upload(&mut first, "zCidA".to_string(), "http://codex-a:8080".to_string());
+        storage.update_record_purchase(&mut first, "purchase-a".to_string(), "zStorageA".to_string());
+        storage.mark_record_active(&mut first);
+        first.file_size = Some(5_000_000_000);
+        first.expiry_minutes = Some(90);
+        first.storage_params = Some(config::StorageParams::default());
+        storage.archive_record(&mut first, "changed");
+        storage.update_record_upload(&mut first, "zCidA2".to_string(), "http://codex-a:8080".to_string());
+        let second = storage.create_new_record(target.join("b.bin"));
+
+        let mut records = std::collections::HashMap::new();
+        records.insert(first.file_path.clone(), first.clone());
+        records.insert(second.file_path.clone(), second.clone());
+        storage.save_all_records(&target, &records).await.unwrap();
+
+        let loaded = storage.load_existing_records(&target).await.unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(serde_json::to_value(&loaded[&first.file_path]).unwrap(), serde_json::to_value(&first).unwrap());
+        assert_eq!(serde_json::to_value(&loaded[&second.file_path]).unwrap(), serde_json::to_value(&second).unwrap());
+
+        // Record every row written from here on.
+        let connection = rusqlite::Connection::open(output.join(sqlite_store::SQLITE_FILE_NAME)).unwrap();
+        connection.execute_batch("
+            CREATE TABLE writes (relative_path TEXT);
+            CREATE TRIGGER record_inserts AFTER INSERT ON records BEGIN INSERT INTO writes VALUES (NEW.relative_path); END;
+            CREATE TRIGGER record_updates AFTER UPDATE ON records BEGIN INSERT INTO writes VALUES (NEW.relative_path); END;
+        ").unwrap();
+
+        let mut second = second;
+        storage.update_record_status(&mut second, storage::FileStatus::Failed, Some("upload failed".to_string()));
+        storage.save_record(&target, &second.file_path, &second).await.unwrap();
+
+        let writes: Vec<String> = connection.prepare("SELECT relative_path FROM writes").unwrap()
+            .query_map([], |row| row.get(0)).unwrap()
+            .collect::<Result<_, _>>().unwrap();
+        assert_eq!(writes, vec!["b.bin"]);
+
+        let loaded = storage.load_existing_records(&target).await.unwrap();
+        assert_eq!(loaded[&second.file_path].status, storage::FileStatus::Failed);
+        assert_eq!(loaded[&first.file_path].original_cid.as_deref(), Some("zCidA2"));
+
+        storage.remove_all_records(&target, &records).await.unwrap();
+        assert!(storage.load_existing_records(&target).await.unwrap().is_empty());
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_migrate_structure_to_sqlite() {
+        let target = temp_dir("sqlite-migrate-target");
+        let output = temp_dir("sqlite-migrate-output");
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            output_structure: config::OutputStructure::Flattened,
+            ..Default::default()
+        };
+
+        let flattened = storage::StorageManager::from_config(&config);
+        for name in ["a.bin", "nested/b.bin"] {
+            let record = flattened.create_new_record(target.join(name));
+            flattened.save_record(&target, &record.file_path, &record).await.unwrap();
+        }
+
+        let migrated = commands::migrate_structure(&config, config::OutputStructure::Sqlite, true).await.unwrap();
+        assert_eq!(migrated, 2);
+        assert!(!output.join("files.json").exists());
+
+        let sqlite = storage::StorageManager::for_structure(&config, config::OutputStructure::Sqlite);
+        let records = sqlite.load_existing_records(&target).await.unwrap();
+        assert!(records.contains_key(&target.join("nested/b.bin")));
+        assert_eq!(records.len(), 2);
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pinned_file_retries_harder() {
+        let requests = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counted = requests.clone();
+        let server = mock_server(move |req| match req.path.as_str() {
+            path if path.starts_with("/api/codex/v1/storage/request/") => {
+                // Fails more often than an unpinned file is retried.
+                if counted.fetch_add(1, std::sync::atomic::Ordering::SeqCst) < 4 {
+                    MockResponse::new(500, "text/plain", "market busy")
+                } else {
+                    MockResponse::new(200, "text/plain", "purchase-1")
+                }
+            }
+            _ => codex_response(&req),
+        }).await;
+
+        let target = temp_dir("pinned-target");
+        let output = temp_dir("pinned-output");
+        std::fs::create_dir_all(target.join("critical")).unwrap();
+        let file = target.join("critical/ledger.bin");
+        std::fs::write(&file, vec![4u8; 1024 * 1024]).unwrap();
+
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            output_structure: config::OutputStructure::Flattened,
+            codex_endpoints: vec![server],
+            endpoint_failure_threshold: 10,
+            pinned_paths: vec!["critical/**".to_string()],
+            ..Default::default()
+        };
+        let client = std::sync::Arc::new(codex::Client::from_config(&config));
+        let processor = file_processor::FileProcessor::new(std::sync::Arc::new(config), client);
+        processor.initialize().await.unwrap();
+        processor.process_file(&file).await.unwrap();
+
+        assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 5);
+        let records = processor.storage_manager.load_existing_records(&target).await.unwrap();
+        assert_eq!(records[&file].status, storage::FileStatus::Active);
+        assert!(records[&file].pinned);
+
+        let mut failed = records[&file].clone();
+        processor.storage_manager.update_record_status(&mut failed, storage::FileStatus::Failed, Some("renewal failed".to_string()));
+        let snapshot = metrics::MetricsSnapshot::from_records(&[(file.clone(), failed)].into_iter().collect());
+        assert_eq!(snapshot.pinned_failures, 1);
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_balance_by_size_evens_out_endpoint_bytes() {
+        async fn counting_server(uploaded: std::sync::Arc<std::sync::atomic::AtomicU64>) -> String {
+            mock_server(move |req| match req.path.as_str() {
+                "/api/codex/v1/data" => {
+                    let length: u64 = req.headers["content-length"].parse().unwrap();
+                    uploaded.fetch_add(length, std::sync::atomic::Ordering::SeqCst);
+                    MockResponse::new(200, "text/plain", "zDvZRwzm2mK7tvDzKScRLapqGdgNTLyyEBvx1TQY37J2CvuzL7Gh")
+                }
+                _ => codex_response(&req),
+            }).await
+        }
+
+        let first_bytes = std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0));
+        let second_bytes = std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0));
+        let first = counting_server(first_bytes.clone()).await;
+        let second = counting_server(second_bytes.clone()).await;
+
+        let target = temp_dir("balance-target");
+        let output = temp_dir("balance-output");
+        // Round robin in path order would put 6 MiB on one endpoint and 4 MiB
+        // on the other.
+        for (name, mib) in [("a.bin", 4), ("b.bin", 3), ("c.bin", 2), ("d.bin", 1)] {
+            std::fs::write(target.join(name), vec![1u8; mib * 1024 * 1024]).unwrap();
+        }
+
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            codex_endpoints: vec![first, second],
+            balance_by_size: true,
+            ..Default::default()
+        };
+        let client = std::sync::Arc::new(codex::Client::from_config(&config));
+        let processor = file_processor::FileProcessor::new(std::sync::Arc::new(config), client);
+        processor.initialize().await.unwrap();
+        processor.process_files().await.unwrap();
+
+        let five_mib = 5 * 1024 * 1024;
+        assert_eq!(first_bytes.load(std::sync::atomic::Ordering::SeqCst), five_mib);
+        assert_eq!(second_bytes.load(std::sync::atomic::Ordering::SeqCst), five_mib);
+
+        let assignments = file_processor::assign_endpoints_by_size(
+            vec![(target.join("a.bin"), 10), (target.join("b.bin"), 10)],
+            &["http://one".to_string()],
+        );
+        assert!(assignments.values().all(|endpoint| endpoint == "http://one"));
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flattened_saves_parse_record_file_once() {
+        let target = temp_dir("flattened-index-target");
+        let output = temp_dir("flattened-index-output");
+        let manager = storage::StorageManager::new(output.clone(), config::OutputStructure::Flattened);
+
+        let existing = storage::FlattenedRecord {
+            relative_path: "existing.bin".to_string(),
+            record: manager.create_new_record(target.join("existing.bin")),
+        };
+        std::fs::write(output.join("files.json"), serde_json::to_string(&vec![existing]).unwrap()).unwrap();
+
+        for index in 0..1000 {
+            let file = target.join(format!("dir{}/file{}.bin", index % 10, index));
+            let mut record = manager.create_new_record(file.clone());
+            manager.mark_record_active(&mut record);
+            manager.save_record(&target, &file, &record).await.unwrap();
+        }
+        manager.flush().await.unwrap();
+        assert_eq!(manager.flattened_parse_count().await, 1);
+        assert_eq!(manager.clone().flattened_parse_count().await, 1);
+
+        // Another spelling of the folder shares the index rather than writing
+        // the file from its own.
+        let respelled = storage::StorageManager::new(output.join("."), config::OutputStructure::Flattened);
+        assert_eq!(respelled.flattened_parse_count().await, 1);
+
+        let content = std::fs::read_to_string(output.join("files.json")).unwrap();
+        let written: Vec<storage::FlattenedRecord> = serde_json::from_str(&content).unwrap();
+        assert_eq!(written.len(), 1001);
+        assert!(written.windows(2).all(|pair| pair[0].relative_path < pair[1].relative_path));
+        assert!(!output.join("files.json.tmp").exists());
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[cfg(feature = "tui")]
+    #[test]
+    fn test_tui_dashboard_renders_records() {
+        let target = std::path::PathBuf::from("/data");
+        let manager = storage::StorageManager::new(std::path::PathBuf::from("/out"), config::OutputStructure::Structured);
+        let mut records = std::collections::HashMap::new();
+        for (name, minutes_ago, active) in [("old.bin", 10, true), ("new.bin", 1, false), ("mid.bin", 5, true)] {
+            let file = target.join(name);
+            let mut record = manager.create_new_record(file.clone());
+            if active {
+                manager.update_record_upload(&mut record, format!("zCid{}", name), "http://node".to_string());
+                manager.mark_record_active(&mut record);
+            }
+            record.updated_at = chrono::Utc::now() - chrono::Duration::minutes(minutes_ago);
+            records.insert(file, record);
+        }
+
+        let mut view = tui::DashboardView::from_records(&records, &target, 2);
+        assert_eq!(view.total, 3);
+        assert_eq!(view.counts["Active"], 2);
+        let recent: Vec<&str> = view.recent.iter().map(|file| file.relative_path.as_str()).collect();
+        assert_eq!(recent, ["new.bin", "mid.bin"]);
+
+        view.endpoints = vec![("http://up".to_string(), true), ("http://down".to_string(), false)];
+        view.bytes_per_sec = 2.0 * 1024.0 * 1024.0;
+        let mut terminal = ratatui::Terminal::new(ratatui::backend::TestBackend::new(100, 20)).unwrap();
+        terminal.draw(|frame| tui::render(frame, &view, chrono_tz::UTC)).unwrap();
+        let screen: String = terminal.backend().buffer().content().iter().map(|cell| cell.symbol()).collect();
+        for expected in ["3 files", "Active 2", "down http://down", "2.0 MiB/s", "mid.bin", "zCidmid.bin"] {
+            assert!(screen.contains(expected), "missing {:?}", expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_restore_downloads_active_files() {
+        let server = mock_server(|req| match req.path.as_str() {
+            "/api/codex/v1/data/zTop" => MockResponse::new(200, "application/octet-stream", "top contents"),
+            "/api/codex/v1/data/zNested" => MockResponse::new(200, "application/octet-stream", "nested contents"),
+            _ => MockResponse::new(404, "text/plain", "not found"),
+        }).await;
+
+        let dir = temp_dir("restore");
+        let target = dir.join("target");
+        let restore_dir = dir.join("restored");
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: dir.join("output"),
+            codex_endpoints: vec![server],
+            ..Default::default()
+        };
+        let storage_manager = storage::StorageManager::from_config(&config);
+        for (name, cid, active) in [
+            ("top.bin", Some("zTop"), true),
+            ("a/b/nested.bin", Some("zNested"), true),
+            ("no-cid.bin", None, true),
+            ("failed.bin", Some("zFailed"), false),
+        ] {
+            let file = target.join(name);
+            let mut record = storage_manager.create_new_record(file.clone());
+            if let Some(cid) = cid {
+                storage_manager.update_record_purchase(&mut record, format!("purchase-{}", name), cid.to_string());
+            }
+            if active {
+                storage_manager.mark_record_active(&mut record);
+            }
+            storage_manager.save_record(&target, &file, &record).await.unwrap();
+        }
+
+        let client = codex::Client::from_config(&config);
+        let report = commands::restore_files(&config, &client, &restore_dir, None).await.unwrap();
+        assert_eq!(report.restored, [std::path::PathBuf::from("a/b/nested.bin"), std::path::PathBuf::from("top.bin")]);
+        assert_eq!(report.skipped, [std::path::PathBuf::from("no-cid.bin")]);
+        assert!(report.failed.is_empty());
+        assert_eq!(std::fs::read(restore_dir.join("top.bin")).unwrap(), b"top contents");
+        assert_eq!(std::fs::read(restore_dir.join("a/b/nested.bin")).unwrap(), b"nested contents");
+        assert!(!restore_dir.join("failed.bin").exists());
+
+        let only_dir = dir.join("only");
+        let report = commands::restore_files(&config, &client, &only_dir, Some(std::path::Path::new("top.bin"))).await.unwrap();
+        assert_eq!(report.restored.len(), 1);
+        assert!(only_dir.join("top.bin").exists());
+        assert!(!only_dir.join("a").exists());
+        assert!(commands::restore_files(&config, &client, &only_dir, Some(std::path::Path::new("failed.bin"))).await.is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_deleting_file_cancels_upload() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let upload_started = std::sync::Arc::new(AtomicBool::new(false));
+        let started = upload_started.clone();
+        let endpoint = mock_server(move |req| {
+            if req.path == "/api/codex/v1/data" {
+                started.store(true, Ordering::SeqCst);
+                std::thread::sleep(std::time::Duration::from_secs(3));
+                MockResponse::new(200, "text/plain", "zDvZRwzm2mK7tvDzKScRLapqGdgNTLyyEBvx1TQY37J2CvuzL7Gh")
+            } else {
+                MockResponse::new(404, "text/plain", "not found")
+            }
+        }).await;
+
+        let target = temp_dir("cancel-upload-target");
+        let output = temp_dir("cancel-upload-output");
+        let file = target.join("large.bin");
+        std::fs::write(&file, vec![3u8; 1024 * 1024]).unwrap();
+
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            codex_endpoints: vec![endpoint],
+            ..Default::default()
+        };
+        let client = std::sync::Arc::new(codex::Client::from_config(&config));
+        let processor = file_processor::FileProcessor::new(std::sync::Arc::new(config), client);
+        processor.initialize().await.unwrap();
+        assert!(!processor.uploads_in_flight.cancel(&file));
+
+        let uploading = processor.clone();
+        let uploaded_file = file.clone();
+        let upload = tokio::spawn(async move { uploading.process_file(&uploaded_file).await });
+        while !upload_started.load(Ordering::SeqCst) {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        let deleted_at = std::time::Instant::now();
+        std::fs::remove_file(&file).unwrap();
+        assert!(processor.uploads_in_flight.cancel(&file));
+        let err = upload.await.unwrap().unwrap_err();
+        assert!(deleted_at.elapsed() < std::time::Duration::from_secs(2));
+        assert!(err.to_string().contains("deleted"));
+
+        let record = processor.records.read().await[&file].clone();
+        assert_eq!(record.status, storage::FileStatus::Failed);
+        assert!(record.original_cid.is_none());
+        assert!(!processor.uploads_in_flight.cancel(&file));
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reprocess_cooldown_ignores_touches() {
+        let (server, uploads) = counting_codex_mock().await;
+
+        let target = temp_dir("cooldown-target");
+        let output = temp_dir("cooldown-output");
+        let file = target.join("edited.bin");
+        let content = vec![6u8; 1024 * 1024];
+        std::fs::write(&file, &content).unwrap();
+        let touch = |seconds: u64| {
+            let modified = std::time::SystemTime::now() + std::time::Duration::from_secs(seconds);
+            std::fs::File::options().write(true).open(&file).unwrap().set_modified(modified).unwrap();
+        };
+
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            codex_endpoints: vec![server],
+            reprocess_cooldown_secs: 3600,
+            ..Default::default()
+        };
+        let client = std::sync::Arc::new(codex::Client::from_config(&config));
+        let processor = file_processor::FileProcessor::new(std::sync::Arc::new(config), client);
+        processor.initialize().await.unwrap();
+        processor.process_file(&file).await.unwrap();
+        assert_eq!(uploads.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        // Without a stored hash, as on records from older versions, a change
+        // waits for the cooldown to pass.
+        processor.records.write().await.get_mut(&file).unwrap().content_hash = None;
+        touch(60);
+        processor.process_file(&file).await.unwrap();
+        assert_eq!(uploads.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        // With one, a rewrite with the same contents is only a touch.
+        processor.records.write().await.get_mut(&file).unwrap().content_hash = Some(fingerprint::sha256_bytes(&content));
+        touch(120);
+        processor.process_file(&file).await.unwrap();
+        assert_eq!(uploads.load(std::sync::atomic::Ordering::SeqCst), 1);
+        let record = processor.records.read().await[&file].clone();
+        assert_eq!(record.status, storage::FileStatus::Active);
+        assert_eq!(record.content_hash, Some(fingerprint::sha256_bytes(&content)));
+
+        std::fs::write(&file, vec![7u8; 1024 * 1024]).unwrap();
+        touch(180);
+        processor.process_file(&file).await.unwrap();
+        assert_eq!(uploads.load(std::sync::atomic::Ordering::SeqCst), 2);
+
+        // The cooldown runs from when storage started, not from the last
+        // time anything touched the record.
+        {
+            let mut records = processor.records.write().await;
+            let record = records.get_mut(&file).unwrap();
+            record.content_hash = None;
+            record.active_at = Some(chrono::Utc::now() - chrono::Duration::hours(2));
+            record.updated_at = chrono::Utc::now();
+        }
+        std::fs::write(&file, vec![8u8; 1024 * 1024]).unwrap();
+        touch(240);
+        processor.process_file(&file).await.unwrap();
+        assert_eq!(uploads.load(std::sync::atomic::Ordering::SeqCst), 3);
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_moved_file_keeps_its_record() {
+        let (server, uploads) = counting_codex_mock().await;
+
+        let target = temp_dir("rename-target");
+        let output = temp_dir("rename-output");
+        let old_path = target.join("inbox/report.bin");
+        let new_path = target.join("archive/2024/report.bin");
+        std::fs::create_dir_all(old_path.parent().unwrap()).unwrap();
+        std::fs::create_dir_all(new_path.parent().unwrap()).unwrap();
+        std::fs::write(&old_path, vec![8u8; 1024 * 1024]).unwrap();
+
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            output_structure: config::OutputStructure::Structured,
+            codex_endpoints: vec![server],
+            ..Default::default()
+        };
+        let client = std::sync::Arc::new(codex::Client::from_config(&config));
+        let processor = file_processor::FileProcessor::new(std::sync::Arc::new(config), client);
+        processor.initialize().await.unwrap();
+        processor.process_file(&old_path).await.unwrap();
+        let stored = processor.records.read().await[&old_path].clone();
+
+        std::fs::rename(&old_path, &new_path).unwrap();
+        assert_eq!(processor.rename_records(&old_path, &new_path).await.unwrap(), 1);
+
+        let moved = processor.records.read().await[&new_path].clone();
+        assert!(!processor.records.read().await.contains_key(&old_path));
+        assert_eq!(moved.file_path, new_path);
+        assert_eq!(moved.original_cid, stored.original_cid);
+        assert_eq!(moved.purchase_id, stored.purchase_id);
+        assert_eq!(moved.status, storage::FileStatus::Active);
+        assert!(!output.join("inbox/report.json").exists());
+        assert!(output.join("archive/2024/report.json").exists());
+
+        processor.process_file(&new_path).await.unwrap();
+        assert_eq!(uploads.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        // Moving a directory moves the records beneath it.
+        std::fs::rename(target.join("archive"), target.join("old")).unwrap();
+        assert_eq!(processor.rename_records(&target.join("archive"), &target.join("old")).await.unwrap(), 1);
+        let records = processor.storage_manager.load_existing_records(&target).await.unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[&target.join("old/2024/report.bin")].purchase_id, stored.purchase_id);
+        assert_eq!(processor.rename_records(&target.join("missing.bin"), &target.join("other.bin")).await.unwrap(), 0);
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_signed_manifest_lists_stored_files() {
+        let dir = temp_dir("manifest");
+        let target = dir.join("target");
+        std::fs::create_dir_all(target.join("docs")).unwrap();
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: dir.join("output"),
+            ..Default::default()
+        };
+        let storage_manager = storage::StorageManager::from_config(&config);
+        for (name, contents, active) in [
+            ("docs/report.pdf", "report", true),
+            ("changed.bin", "original", true),
+            ("pending.bin", "pending", false),
+        ] {
+            let file = target.join(name);
+            std::fs::write(&file, contents).unwrap();
+            let mut record = storage_manager.create_new_record(file.clone());
+            record.file_size = Some(contents.len() as u64);
+            record.content_hash = Some(fingerprint::sha256_bytes(contents.as_bytes()));
+            storage_manager.update_record_purchase(&mut record, format!("purchase-{}", name), format!("z{}", name));
+            if active {
+                storage_manager.mark_record_active(&mut record);
+            }
+            storage_manager.save_record(&target, &file, &record).await.unwrap();
+        }
+        std::fs::write(target.join("changed.bin"), "modified").unwrap();
+
+        let built = manifest::build_manifest(&config).await.unwrap();
+        assert_eq!(built.record_count, 3);
+        assert_eq!(built.file_count, 1);
+        assert_eq!(built.total_bytes, 6);
+        let entry = &built.files[0];
+        assert_eq!(entry.path, std::path::PathBuf::from("docs/report.pdf"));
+        assert_eq!(entry.sha256, fingerprint::sha256_bytes(b"report"));
+        assert_eq!(entry.purchase_id.as_deref(), Some("purchase-docs/report.pdf"));
+        assert_eq!(entry.storage_expiry - entry.storage_start, chrono::Duration::from_std(config.duration()).unwrap());
+        assert_eq!(built.unverified.len(), 1);
+        assert_eq!(built.unverified[0].path, std::path::PathBuf::from("changed.bin"));
+        assert_eq!(built.not_stored.len(), 1);
+        assert_eq!(built.not_stored[0].status, storage::FileStatus::Creating);
+
+        let key_file = dir.join("signing.key");
+        std::fs::write(&key_file, format!("{}\n", "7".repeat(64))).unwrap();
+        let key = manifest::read_signing_key(&key_file).unwrap();
+        let document = serde_json::to_string_pretty(&manifest::sign_manifest(built.clone(), Some(&key)).unwrap()).unwrap();
+        let public_key = manifest::verify_manifest(&document).unwrap();
+        assert_eq!(public_key, fingerprint::hex_digest(key.verifying_key().as_bytes()));
+
+        let tampered = document.replace("\"size\": 6", "\"size\": 7");
+        assert_ne!(tampered, document);
+        assert!(manifest::verify_manifest(&tampered).is_err());
+        let unsigned = serde_json::to_string(&manifest::sign_manifest(built, None).unwrap()).unwrap();
+        assert!(manifest::verify_manifest(&unsigned).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_on_delete_removes_record() {
+        let cancelled = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = cancelled.clone();
+        let server = mock_server(move |req| match req.path.as_str() {
+            path if path.ends_with("/cancel") => {
+                seen.lock().unwrap().push(path.to_string());
+                MockResponse::new(200, "text/plain", "")
+            }
+            _ => codex_response(&req),
+        }).await;
+
+        let target = temp_dir("cleanup-target");
+        let output = temp_dir("cleanup-output");
+        let deleted = target.join("deleted.bin");
+        let kept = target.join("kept.bin");
+        std::fs::write(&deleted, vec![1u8; 1024 * 1024]).unwrap();
+        std::fs::write(&kept, vec![2u8; 1024 * 1024]).unwrap();
+
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            output_structure: config::OutputStructure::Structured,
+            codex_endpoints: vec![server],
+            cleanup_on_delete: true,
+            ..Default::default()
+        };
+        let client = std::sync::Arc::new(codex::Client::from_config(&config));
+        let processor = file_processor::FileProcessor::new(std::sync::Arc::new(config), client);
+        processor.initialize().await.unwrap();
+        processor.process_file(&deleted).await.unwrap();
+        processor.process_file(&kept).await.unwrap();
+        assert!(output.join("deleted.json").exists());
+
+        std::fs::remove_file(&deleted).unwrap();
+        assert_eq!(processor.cleanup_deleted(&deleted).await.unwrap(), 1);
+        assert_eq!(*cancelled.lock().unwrap(), ["/api/codex/v1/storage/purchases/purchase-1/cancel"]);
+        assert!(!processor.records.read().await.contains_key(&deleted));
+        assert!(!output.join("deleted.json").exists());
+
+        // A file that is back by the time cleanup runs keeps its record.
+        assert_eq!(processor.cleanup_deleted(&kept).await.unwrap(), 0);
+        assert!(processor.records.read().await.contains_key(&kept));
+        assert!(output.join("kept.json").exists());
+        let records = processor.storage_manager.load_existing_records(&target).await.unwrap();
+        assert_eq!(records.keys().collect::<Vec<_>>(), [&kept]);
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_colliding_structured_records_both_survive() {
+        let dir = temp_dir("record-collision");
+        let target = dir.join("target");
+        let output = dir.join("output");
+        let mut config = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            output_structure: config::OutputStructure::Structured,
+            ..Default::default()
+        };
+        let storage_manager = storage::StorageManager::from_config(&config);
+
+        // Record files drop the original extension, so both map to docs/report.json.
+        let text = target.join("docs/report.txt");
+        let binary = target.join("docs/report.bin");
+        for (file, purchase) in [(&text, "purchase-text"), (&binary, "purchase-binary")] {
+            let mut record = storage_manager.create_new_record(file.clone());
+            storage_manager.update_record_purchase(&mut record, purchase.to_string(), "zCid".to_string());
+            storage_manager.save_record(&target, file, &record).await.unwrap();
+        }
+        // Saving again finds each record where it was written.
+        let mut record = storage_manager.create_new_record(binary.clone());
+        storage_manager.update_record_purchase(&mut record, "purchase-binary-2".to_string(), "zCid".to_string());
+        storage_manager.save_record(&target, &binary, &record).await.unwrap();
+
+        let mut names: Vec<String> = std::fs::read_dir(output.join("docs")).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["report.json", "report~1.json"]);
+        let records = storage_manager.load_existing_records(&target).await.unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[&text].purchase_id.as_deref(), Some("purchase-text"));
+        assert_eq!(records[&binary].purchase_id.as_deref(), Some("purchase-binary-2"));
+
+        // Removing the first record moves the suffixed one into its place.
+        storage_manager.remove_record(&target, &text).await.unwrap();
+        assert!(!output.join("docs/report~1.json").exists());
+        let records = storage_manager.load_existing_records(&target).await.unwrap();
+        assert_eq!(records.keys().collect::<Vec<_>>(), [&binary]);
+
+        config.record_collision = config::RecordCollision::Error;
+        let strict = storage::StorageManager::from_config(&config);
+        let csv = target.join("docs/report.csv");
+        assert!(strict.save_record(&target, &csv, &strict.create_new_record(csv.clone())).await.is_err());
+        assert_eq!(strict.load_existing_records(&target).await.unwrap()[&binary].purchase_id.as_deref(), Some("purchase-binary-2"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    async fn hashed_upload_fixture(name: &str) -> (
+        file_processor::FileProcessor,
+        std::sync::Arc<std::sync::atomic::AtomicUsize>,
+        PathBuf,
+        PathBuf,
+    ) {
+        let (server, uploads) = counting_codex_mock().await;
+
+        let target = temp_dir(&format!("{}-target", name));
+        let file = target.join("notes.bin");
+        std::fs::write(&file, vec![3u8; 1024 * 1024]).unwrap();
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: temp_dir(&format!("{}-output", name)),
+            codex_endpoints: vec![server],
+            ..Default::default()
+        };
+        let client = std::sync::Arc::new(codex::Client::from_config(&config));
+        let processor = file_processor::FileProcessor::new(std::sync::Arc::new(config), client);
+        processor.initialize().await.unwrap();
+        processor.process_file(&file).await.unwrap();
+        assert_eq!(uploads.load(std::sync::atomic::Ordering::SeqCst), 1);
+        (processor, uploads, target, file)
+    }
+
+    fn set_mtime(file: &Path, seconds_from_now: u64) {
+        let modified = std::time::SystemTime::now() + std::time::Duration::from_secs(seconds_from_now);
+        std::fs::File::options().write(true).open(file).unwrap().set_modified(modified).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_touched_file_with_matching_hash_is_not_reuploaded() {
+        let (processor, uploads, target, file) = hashed_upload_fixture("hash-touched").await;
+        let stored = processor.records.read().await[&file].clone();
+        assert_eq!(stored.content_hash, Some(fingerprint::sha256_bytes(&vec![3u8; 1024 * 1024])));
+
+        // The default size_mtime mode sees a change, but the contents hash the same.
+        std::fs::write(&file, vec![3u8; 1024 * 1024]).unwrap();
+        set_mtime(&file, 60);
+        processor.process_file(&file).await.unwrap();
+
+        assert_eq!(uploads.load(std::sync::atomic::Ordering::SeqCst), 1);
+        let record = processor.records.read().await[&file].clone();
+        assert_eq!(record.status, storage::FileStatus::Active);
+        assert_eq!(record.purchase_id, stored.purchase_id);
+        assert_ne!(record.file_modified, stored.file_modified);
+        assert_eq!(record.content_hash, stored.content_hash);
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&processor.config.output_folder).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_changed_content_is_reuploaded() {
+        let (processor, uploads, target, file) = hashed_upload_fixture("hash-changed").await;
+
+        // Same size, new contents.
+        std::fs::write(&file, vec![4u8; 1024 * 1024]).unwrap();
+        set_mtime(&file, 60);
+        processor.process_file(&file).await.unwrap();
+
+        assert_eq!(uploads.load(std::sync::atomic::Ordering::SeqCst), 2);
+        let record = processor.records.read().await[&file].clone();
+        assert_eq!(record.status, storage::FileStatus::Active);
+        assert_eq!(record.content_hash, Some(fingerprint::sha256_bytes(&vec![4u8; 1024 * 1024])));
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&processor.config.output_folder).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_debouncer_coalesces_rapid_modifies() {
+        use std::io::Write;
+
+        let dir = temp_dir("debounce");
+        let file = dir.join("growing.bin");
+        std::fs::write(&file, b"start").unwrap();
+        let window = std::time::Duration::from_millis(200);
+        let mut debouncer = monitor::Debouncer::new(window);
+        let append = |bytes: &[u8]| std::fs::File::options().append(true).open(&file).unwrap().write_all(bytes).unwrap();
+
+        for _ in 0..5 {
+            append(b"chunk");
+            debouncer.touch(file.clone());
+            tokio::time::sleep(std::time::Duration::from_millis(30)).await;
+            assert!(debouncer.take_due().is_empty());
+        }
+        tokio::time::sleep_until(debouncer.next_due().unwrap()).await;
+        assert_eq!(debouncer.take_due(), std::slice::from_ref(&file));
+        assert!(debouncer.is_empty());
+
+        // Growing without an event still holds the file back a window.
+        debouncer.touch(file.clone());
+        append(b"more");
+        tokio::time::sleep(window).await;
+        assert!(debouncer.take_due().is_empty());
+        tokio::time::sleep_until(debouncer.next_due().unwrap()).await;
+        assert_eq!(debouncer.take_due(), std::slice::from_ref(&file));
+
+        assert!(!monitor::Debouncer::new(std::time::Duration::ZERO).is_enabled());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rebuild_from_journal_restores_lost_records() {
+        let server = happy_codex_mock().await;
+
+        let target = temp_dir("journal-target");
+        let output = temp_dir("journal-output");
+        let journal = temp_dir("journal-events").join("events.jsonl");
+        let file = target.join("a.bin");
+        std::fs::write(&file, vec![8u8; 1024 * 1024]).unwrap();
+
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            output_structure: config::OutputStructure::Structured,
+            codex_endpoints: vec![server],
+            event_log_path: Some(journal.clone()),
+            overrides: vec![config::PathOverride {
+                path: "*.bin".parse().unwrap(),
+                params: config::StorageParamsOverride { expiry_minutes: Some(240), ..Default::default() },
+            }],
+            pinned_paths: vec!["a.bin".to_string()],
+            ..Default::default()
+        };
+        let client = std::sync::Arc::new(codex::Client::from_config(&config));
+        let processor = file_processor::FileProcessor::new(std::sync::Arc::new(config.clone()), client);
+        processor.initialize().await.unwrap();
+        processor.process_file(&file).await.unwrap();
+        let stored = processor.records.read().await[&file].clone();
+        assert_eq!(stored.storage_params.as_ref().unwrap().expiry_minutes, 240);
+
+        // A line from before events carried the record fields, and one cut
+        // short by a crash.
+        let old = target.join("old.bin");
+        let mut log = std::fs::read_to_string(&journal).unwrap();
+        log.push_str(&format!(
+            "{{\"timestamp\":\"2025-01-01T12:00:00Z\",\"file_path\":{:?},\"old_status\":\"Creating\",\"new_status\":\"Active\",\"cid\":\"zOld\",\"purchase_id\":\"purchase-old\",\"error\":null}}\n",
+            old.to_str().unwrap()));
+        log.push_str("{\"timestamp\":\"2025-01-0");
+        std::fs::write(&journal, log).unwrap();
+
+        std::fs::remove_dir_all(&output).unwrap();
+        let report = commands::rebuild_from_journal(&config, &journal, false).await.unwrap();
+        assert_eq!(report.rebuilt, 2);
+        assert_eq!(report.events, 4);
+        assert_eq!(report.unreadable_lines, 1);
+
+        let records = storage::StorageManager::from_config(&config).load_existing_records(&target).await.unwrap();
+        let rebuilt = &records[&file];
+        assert_eq!(rebuilt.status, storage::FileStatus::Active);
+        assert_eq!(rebuilt.original_cid, stored.original_cid);
+        assert_eq!(rebuilt.storage_cid, stored.storage_cid);
+        assert_eq!(rebuilt.purchase_id, stored.purchase_id);
+        assert_eq!(rebuilt.created_at, stored.created_at);
+        assert_eq!(rebuilt.file_size, stored.file_size);
+        assert_eq!(rebuilt.content_hash, stored.content_hash);
+        assert_eq!(rebuilt.storage_params.as_ref().unwrap().expiry_minutes, 240);
+        assert_eq!(rebuilt.expiry_minutes, stored.expiry_minutes);
+        assert_eq!(rebuilt.pending_since, stored.pending_since);
+        assert_eq!(rebuilt.history, stored.history);
+        assert!(rebuilt.pinned);
+        assert_eq!(records[&old].purchase_id.as_deref(), Some("purchase-old"));
+        assert_eq!(records[&old].status, storage::FileStatus::Active);
+
+        // Existing records are only overwritten on request.
+        assert!(commands::rebuild_from_journal(&config, &journal, false).await.is_err());
+        assert_eq!(commands::rebuild_from_journal(&config, &journal, true).await.unwrap().rebuilt, 2);
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&output).unwrap();
+        std::fs::remove_dir_all(journal.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_http_version_is_applied() {
+        let server = mock_server(|_| MockResponse::new(200, "application/octet-stream", "contents")).await;
+        let client_for = |http_version| codex::Client::from_config(&config::Config {
+            codex_endpoints: vec![server.clone()],
+            http_version,
+            ..Default::default()
+        });
+
+        for version in [config::HttpVersion::Auto, config::HttpVersion::Http1] {
+            assert_eq!(client_for(version).download_file("zCid").await.unwrap(), b"contents");
+        }
+        // The mock node only speaks HTTP/1.1, so a client insisting on HTTP/2 fails.
+        let http2 = client_for(config::HttpVersion::Http2);
+        let download = tokio::time::timeout(std::time::Duration::from_secs(10), http2.download_file("zCid"));
+        assert!(download.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_metrics_endpoint_counts_processed_files() {
+        let (processor, _, target, _) = hashed_upload_fixture("prometheus").await;
+        let shutdown = tokio_util::sync::CancellationToken::new();
+        let (addr, server) = metrics::serve_metrics(
+            "127.0.0.1:0".parse().unwrap(),
+            processor.codex_client.metrics().clone(),
+            processor.records.clone(),
+            shutdown.clone(),
+        ).unwrap();
+        let scrape = || async move {
+            reqwest::get(format!("http://{}/metrics", addr)).await.unwrap().text().await.unwrap()
+        };
+
+        let before = scrape().await;
+        assert!(before.contains(r#"filehog_uploads_total{result="succeeded"} 1"#), "{}", before);
+        assert!(before.contains(r#"filehog_files_processed_total{result="ok"} 1"#), "{}", before);
+
+        let other = target.join("other.bin");
+        std::fs::write(&other, vec![4u8; 1024 * 1024]).unwrap();
+        processor.process_file(&other).await.unwrap();
+
+        let after = scrape().await;
+        assert!(after.contains(r#"filehog_uploads_total{result="succeeded"} 2"#), "{}", after);
+        assert!(after.contains(r#"filehog_files_processed_total{result="ok"} 2"#), "{}", after);
+        assert!(after.contains(r#"filehog_storage_requests_total{result="created"} 2"#), "{}", after);
+        assert!(after.contains("filehog_purchases_active 2"), "{}", after);
+        assert!(after.contains("filehog_upload_duration_seconds_count 2"), "{}", after);
+
+        let missing = reqwest::get(format!("http://{}/other", addr)).await.unwrap();
+        assert_eq!(missing.status(), reqwest::StatusCode::NOT_FOUND);
+
+        shutdown.cancel();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_status_api_serves_records() {
+        let (processor, _, _, _) = hashed_upload_fixture("status-api").await;
+        let shutdown = tokio_util::sync::CancellationToken::new();
+        let (addr, server) = api::serve_api(
+            "127.0.0.1:0".parse().unwrap(),
+            processor.records.clone(),
+            processor.config.target_folder.clone(),
+            shutdown.clone(),
+        ).unwrap();
+        let get = |path: &'static str| async move {
+            reqwest::get(format!("http://{}{}", addr, path)).await.unwrap()
+        };
+
+        let summary: serde_json::Value = get("/summary").await.json().await.unwrap();
+        assert_eq!(summary["total"], 1);
+        assert_eq!(summary["counts"]["Active"], 1);
+
+        let files: serde_json::Value = get("/files").await.json().await.unwrap();
+        assert_eq!(files["notes.bin"]["status"], "Active");
+        let file: serde_json::Value = get("/files/notes.bin").await.json().await.unwrap();
+        assert_eq!(file["original_cid"], "zDvZRwzm2mK7tvDzKScRLapqGdgNTLyyEBvx1TQY37J2CvuzL7Gh");
+        assert_eq!(get("/files/missing.bin").await.status(), reqwest::StatusCode::NOT_FOUND);
+
+        shutdown.cancel();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_out_of_tokens_defers_files_until_funded() {
+        use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+        let funded = std::sync::Arc::new(AtomicBool::new(false));
+        let uploads = std::sync::Arc::new(AtomicUsize::new(0));
+        let requests = std::sync::Arc::new(AtomicUsize::new(0));
+        let (server_funded, server_uploads, server_requests) = (funded.clone(), uploads.clone(), requests.clone());
+        let server = mock_server(move |req| match req.path.as_str() {
+            "/api/codex/v1/data" => {
+                server_uploads.fetch_add(1, Ordering::SeqCst);
+                MockResponse::new(200, "text/plain", "zDvZRwzm2mK7tvDzKScRLapqGdgNTLyyEBvx1TQY37J2CvuzL7Gh")
+            }
+            path if path.starts_with("/api/codex/v1/storage/request/") => {
+                server_requests.fetch_add(1, Ordering::SeqCst);
+                if server_funded.load(Ordering::SeqCst) {
+                    MockResponse::new(200, "text/plain", "purchase-1")
+                } else {
+                    MockResponse::new(402, "text/plain", "insufficient funds")
+                }
+            }
+            _ => codex_response(&req),
+        }).await;
+
+        let target = temp_dir("read-only-target");
+        let output = temp_dir("read-only-output");
+        let first = target.join("first.bin");
+        let second = target.join("second.bin");
+        std::fs::write(&first, vec![5u8; 1024 * 1024]).unwrap();
+        std::fs::write(&second, vec![6u8; 1024 * 1024]).unwrap();
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            codex_endpoints: vec![server],
+            read_only_probe_secs: 3600,
+            ..Default::default()
+        };
+        let client = std::sync::Arc::new(codex::Client::from_config(&config));
+        let processor = file_processor::FileProcessor::new(std::sync::Arc::new(config), client);
+        processor.initialize().await.unwrap();
+
+        // A 402 isn't retried, and the file waits instead of failing.
+        processor.process_file(&first).await.unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        assert_eq!(processor.records.read().await[&first].status, storage::FileStatus::Pending);
+        assert!(processor.read_only_probe_at.lock().unwrap().is_some());
+
+        // Read-only: nothing more is uploaded until the next probe.
+        processor.process_file(&second).await.unwrap();
+        assert_eq!(uploads.load(Ordering::SeqCst), 1);
+        let deferred = processor.records.read().await[&second].clone();
+        assert_eq!(deferred.status, storage::FileStatus::Pending);
+        assert!(deferred.error.unwrap().contains("read-only"));
+
+        funded.store(true, Ordering::SeqCst);
+        *processor.read_only_probe_at.lock().unwrap() = Some(std::time::Instant::now());
+        processor.retry_pending().await;
+        assert!(processor.read_only_probe_at.lock().unwrap().is_none());
+        let records = processor.records.read().await;
+        assert_eq!(records[&first].status, storage::FileStatus::Active);
+        assert_eq!(records[&second].status, storage::FileStatus::Active);
+        drop(records);
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_path_override_storage_params_reach_storage_request() {
+        let bodies = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = bodies.clone();
+        let server = mock_server(move |req| match req.path.as_str() {
+            path if path.starts_with("/api/codex/v1/storage/request/") => {
+                recorded.lock().unwrap().push(serde_json::from_str::<serde_json::Value>(&req.body).unwrap());
+                MockResponse::new(200, "text/plain", "purchase-1")
+            }
+            _ => codex_response(&req),
+        }).await;
+
+        let target = temp_dir("param-override-target");
+        let output = temp_dir("param-override-output");
+        std::fs::create_dir_all(target.join("photos/2024")).unwrap();
+        std::fs::create_dir_all(target.join("scratch")).unwrap();
+        let photo = target.join("photos/2024/beach.jpg");
+        let scratch = target.join("scratch/tmp.bin");
+        std::fs::write(&photo, vec![7u8; 1024 * 1024]).unwrap();
+        std::fs::write(&scratch, vec![8u8; 1024 * 1024]).unwrap();
+        let config = config::Config::from_toml_str(&format!(r#"
+            target_folder = "{}"
+            output_folder = "{}"
+            output_structure = "structured"
+            codex_endpoints = ["{}"]
+
+            [storage_params]
+            price = 1
+            nodes = 3
+            tolerance = 1
+            proof_probability = 100
+            duration_days = 1
+            expiry_minutes = 60
+            collateral = 1
+
+            [[overrides]]
+            path = "photos/**"
+            nodes = 5
+            tolerance = 2
+            price = 2
+        "#, target.display(), output.display(), server), None).unwrap();
+        config.validate().unwrap();
+
+        let client = std::sync::Arc::new(codex::Client::from_config(&config));
+        let processor = file_processor::FileProcessor::new(std::sync::Arc::new(config), client);
+        processor.initialize().await.unwrap();
+        processor.process_file(&photo).await.unwrap();
+        processor.process_file(&scratch).await.unwrap();
+
+        let bodies = bodies.lock().unwrap().clone();
+        assert_eq!((bodies[0]["nodes"].clone(), bodies[0]["tolerance"].clone()), (5.into(), 2.into()));
+        assert_eq!(bodies[0]["pricePerBytePerSecond"], "2");
+        assert_eq!((bodies[1]["nodes"].clone(), bodies[1]["tolerance"].clone()), (3.into(), 1.into()));
+        assert_eq!(bodies[1]["pricePerBytePerSecond"], "1");
+        assert_eq!(processor.records.read().await[&photo].storage_params.as_ref().unwrap().nodes, 5);
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_status_lists_filtered_records() {
+        assert_eq!(config::parse_file_status("active").unwrap(), storage::FileStatus::Active);
+        assert_eq!(config::parse_file_status("WaitingLong").unwrap(), storage::FileStatus::WaitingLong);
+        assert!(config::parse_file_status("stored").is_err());
+
+        let target = temp_dir("status-target");
+        let output = temp_dir("status-output");
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            ..Default::default()
+        };
+        let storage_manager = storage::StorageManager::from_config(&config);
+        let now = chrono::Utc::now();
+        let mut records = std::collections::HashMap::new();
+        for (name, active) in [("photos/a.jpg", true), ("failed.bin", false), ("b.txt", true)] {
+            let path = target.join(name);
+            let mut record = storage_manager.create_new_record(path.clone());
+            record.created_at = now - chrono::Duration::hours(25);
+            if active {
+                record.original_cid = Some(format!("zCid-{}", name));
+                record.purchase_id = Some("purchase-1".to_string());
+                storage_manager.mark_record_active(&mut record);
+                record.active_at = Some(record.created_at);
+            } else {
+                storage_manager.update_record_status(&mut record, storage::FileStatus::Failed, Some("boom".to_string()));
+            }
+            records.insert(path, record);
+        }
+        storage_manager.save_all_records(&target, &records).await.unwrap();
+
+        let loaded = storage_manager.load_existing_records(&target).await.unwrap();
+        let duration = chrono::Duration::from_std(config.duration()).unwrap();
+        let active = commands::status_rows(&loaded, &target, duration, Some(&storage::FileStatus::Active), now);
+        let paths: Vec<_> = active.iter().map(|row| row.path.clone()).collect();
+        assert_eq!(paths, vec![PathBuf::from("b.txt"), PathBuf::from("photos/a.jpg")]);
+        assert_eq!(active[0].age_seconds, 25 * 3600);
+        assert_eq!(active[0].expires_in_seconds, Some((duration - chrono::Duration::hours(25)).num_seconds()));
+
+        let table = commands::format_status_table(&active);
+        assert!(table.contains("zCid-photos/a.jpg"), "{}", table);
+        assert!(table.contains("1d 1h 0m"), "{}", table);
+        assert!(!table.contains("failed.bin"), "{}", table);
+        assert!(table.ends_with("2 records\n"), "{}", table);
+
+        let all = commands::status_rows(&loaded, &target, duration, None, now);
+        let failed = all.iter().find(|row| row.path == Path::new("failed.bin")).unwrap();
+        assert_eq!((failed.status.clone(), failed.expires_at), (storage::FileStatus::Failed, None));
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_purchase_start_poll_interval_is_configurable() {
+        let checks = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counted = checks.clone();
+        let server = mock_server(move |_| {
+            counted.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            MockResponse::new(200, "application/json", r#"{"state":"submitted","request":{"content":{"cid":"zCid"}}}"#)
+        }).await;
+        let config = config::Config {
+            codex_endpoints: vec![server],
+            purchase_poll_secs: 2,
+            purchase_start_poll_secs: 1,
+            periodic_scan_secs: 1,
+            ..Default::default()
+        };
+        let client = codex::Client::from_config(&config);
+        assert_eq!(client.purchase_start_poll(), std::time::Duration::from_secs(1));
+
+        // Polled every second rather than every five until the wait times out.
+        let started = std::time::Instant::now();
+        let wait = client.wait_for_purchase_start("purchase-1", None, 2, None).await.unwrap();
+        assert!(matches!(wait, codex::PurchaseWait::TimedOut { .. }));
+        assert!(checks.load(std::sync::atomic::Ordering::SeqCst) >= 3);
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+
+        let target = temp_dir("intervals-target");
+        let output = temp_dir("intervals-output");
+        let zero = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            periodic_scan_secs: 0,
+            ..config
+        };
+        assert!(zero.validate().unwrap_err().to_string().contains("periodic_scan_secs"));
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[test]
+    fn test_renewal_follows_configured_duration_from_activation() {
+        let manager_for = |duration_days| storage::StorageManager::from_config(&config::Config {
+            storage_params: config::StorageParams { duration_days, ..Default::default() },
+            ..Default::default()
+        });
+        let lead = chrono::Duration::minutes(60);
+        let now = chrono::Utc::now();
+
+        let short = manager_for(2);
+        let mut record = short.create_new_record(PathBuf::from("/tmp/short.bin"));
+        record.created_at = now - chrono::Duration::days(10);
+        short.update_record_purchase(&mut record, "purchase-1".to_string(), "zCid".to_string());
+        short.mark_record_active(&mut record);
+        assert!(record.active_at.is_some_and(|at| at >= now));
+        // Measured from activation, not from when the file was first seen.
+        assert!(!short.needs_new_purchase(&record, lead));
+        record.active_at = Some(now - chrono::Duration::days(2) + chrono::Duration::minutes(30));
+        assert!(short.needs_new_purchase(&record, lead));
+        record.active_at = Some(now - chrono::Duration::hours(46));
+        assert!(!short.needs_new_purchase(&record, lead));
+
+        let long = manager_for(30);
+        let mut record = long.create_new_record(PathBuf::from("/tmp/long.bin"));
+        long.mark_record_active(&mut record);
+        record.active_at = Some(now - chrono::Duration::days(7));
+        assert!(!long.needs_new_purchase(&record, lead));
+        record.active_at = Some(now - chrono::Duration::days(30) + chrono::Duration::minutes(30));
+        assert!(long.needs_new_purchase(&record, lead));
+        assert_eq!(record.expires_at(chrono::Duration::days(30)), Some(record.active_at.unwrap() + chrono::Duration::days(30)));
+
+        // The duration the purchase was requested with wins over the configured one.
+        record.storage_params = Some(config::StorageParams { duration_days: 60, ..Default::default() });
+        assert!(!long.needs_new_purchase(&record, lead));
+
+        // Older records without active_at count from created_at.
+        record.storage_params = None;
+        record.active_at = None;
+        record.created_at = now - chrono::Duration::days(31);
+        assert!(long.needs_new_purchase(&record, lead));
+
+        // A new purchase starts the clock again once it is active.
+        long.update_record_purchase(&mut record, "purchase-2".to_string(), "zCid".to_string());
+        assert_eq!(record.active_at, None);
+    }
+
+    #[tokio::test]
+    async fn test_purchase_timestamps_follow_record_lifecycle() {
+        let output = temp_dir("timestamps-output");
+        let storage_manager = storage::StorageManager::new(output.clone(), config::OutputStructure::Flattened);
+        let mut record = storage_manager.create_new_record(PathBuf::from("/tmp/target/a.bin"));
+        assert_eq!((record.uploaded_at, record.purchased_at, record.active_at), (None, None, None));
+
+        storage_manager.update_record_upload(&mut record, "zCid".to_string(), "http://localhost:8080".to_string());
+        let uploaded_at = record.uploaded_at.unwrap();
+        assert_eq!((record.purchased_at, record.active_at), (None, None));
+
+        storage_manager.update_record_purchase(&mut record, "purchase-1".to_string(), "zStorage".to_string());
+        let purchased_at = record.purchased_at.unwrap();
+        assert!(purchased_at >= uploaded_at);
+        assert_eq!(record.active_at, None);
+
+        storage_manager.mark_record_active(&mut record);
+        let active_at = record.active_at.unwrap();
+        assert!(active_at >= purchased_at);
+        // Seen started again by monitoring: the purchase started when first seen.
+        storage_manager.mark_record_active(&mut record);
+        assert_eq!(record.active_at, Some(active_at));
+
+        // Records written before these fields existed load with them unset.
+        let mut old = serde_json::to_value(&record).unwrap();
+        for field in ["uploaded_at", "purchased_at", "active_at"] {
+            old.as_object_mut().unwrap().remove(field);
+        }
+        let old: storage::FileRecord = serde_json::from_value(old).unwrap();
+        assert_eq!((old.uploaded_at, old.purchased_at, old.active_at), (None, None, None));
+        assert_eq!(old.expires_at(chrono::Duration::days(6)), Some(old.created_at + chrono::Duration::days(6)));
+
+        // They survive a save and load.
+        let records = std::collections::HashMap::from([(record.file_path.clone(), record.clone())]);
+        storage_manager.save_all_records(Path::new("/tmp/target"), &records).await.unwrap();
+        let loaded = storage_manager.load_existing_records(Path::new("/tmp/target")).await.unwrap();
+        let loaded = &loaded[&record.file_path];
+        assert_eq!((loaded.uploaded_at, loaded.purchased_at, loaded.active_at),
+                   (Some(uploaded_at), Some(purchased_at), Some(active_at)));
+
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_upload_streams_file_from_disk() {
+        const SIZE: usize = 16 * 1024 * 1024;
+        let dir = temp_dir("streamed-upload");
+        let file = dir.join("large.bin");
+        std::fs::write(&file, "0123456789abcdef".repeat(SIZE / 16)).unwrap();
+
+        // The body is a stream over the file, not a buffer of its contents.
+        let (body, size) = codex::file_body(&file).await.unwrap();
+        assert_eq!(size, SIZE as u64);
+        assert!(body.as_bytes().is_none());
+
+        let endpoint = mock_server(|req| {
+            assert_eq!(req.path, "/api/codex/v1/data");
+            assert_eq!(req.headers.get("content-length").map(String::as_str), Some(SIZE.to_string().as_str()));
+            assert!(!req.headers.contains_key("transfer-encoding"));
+            assert_eq!(req.body.len(), SIZE);
+            assert!(req.body.starts_with("0123456789abcdef0123"));
+            MockResponse::new(200, "text/plain", "zDvZRwzm2mK7tvDzKScRLapqGdgNTLyyEBvx1TQY37J2CvuzL7Gh")
+        }).await;
+        let client = codex::Client::new(vec![endpoint]);
+
+        let upload = client.upload_file(&file).await.unwrap();
+        assert_eq!(upload.cid, "zDvZRwzm2mK7tvDzKScRLapqGdgNTLyyEBvx1TQY37J2CvuzL7Gh");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_requests_to_a_hung_node_time_out() {
+        // Accepts connections and reads requests, but never answers them.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let Ok((mut socket, _)) = listener.accept().await else { return };
+                tokio::spawn(async move {
+                    use tokio::io::AsyncReadExt;
+                    let mut buf = [0u8; 8192];
+                    while socket.read(&mut buf).await.unwrap_or(0) > 0 {}
+                });
+            }
+        });
+        let config = config::Config {
+            codex_endpoints: vec![format!("http://{}", addr)],
+            request_timeout_secs: 1,
+            upload_timeout_secs: 2,
+            ..Default::default()
+        };
+        let client = codex::Client::from_config(&config);
+
+        let started = std::time::Instant::now();
+        let err = client.get_purchase_status("purchase-1", None).await.unwrap_err();
+        assert!(started.elapsed() < std::time::Duration::from_secs(2));
+        assert!(err.is::<codex::RequestTimedOut>(), "{:#}", err);
+        assert_eq!(error::error_code(&err), "timeout");
+        assert!(err.to_string().starts_with("Failed to get purchase status"), "{}", err);
+
+        // Uploads get their own, longer timeout.
+        let dir = temp_dir("hung-node");
+        let file = dir.join("a.bin");
+        std::fs::write(&file, vec![7u8; 1024 * 1024]).unwrap();
+        let started = std::time::Instant::now();
+        let err = client.upload_file(&file).await.unwrap_err();
+        assert!(started.elapsed() >= std::time::Duration::from_secs(2));
+        assert!(err.is::<codex::RequestTimedOut>(), "{:#}", err);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Waits until the record of `file` has status `status`.
+    async fn wait_for_status(processor: &file_processor::FileProcessor, file: &Path, status: storage::FileStatus) {
+        for _ in 0..100 {
+            if processor.records.read().await.get(file).is_some_and(|record| record.status == status) {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        panic!("{} never reached {:?}", file.display(), status);
+    }
+
+    #[tokio::test]
+    async fn test_delete_cleanup_waits_for_pending_purchase() {
+        let started = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let cancelled = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let (is_started, seen) = (started.clone(), cancelled.clone());
+        let server = mock_server(move |req| match req.path.as_str() {
+            path if path.ends_with("/cancel") => {
+                seen.lock().unwrap().push(path.to_string());
+                MockResponse::new(200, "text/plain", "")
+            }
+            "/api/codex/v1/storage/purchases/purchase-1" if !is_started.load(std::sync::atomic::Ordering::SeqCst) =>
+                MockResponse::new(200, "application/json", r#"{"state":"submitted","request":{"content":{"cid":"zCid"}}}"#),
+            _ => codex_response(&req),
+        }).await;
+
+        let target = temp_dir("delete-pending-target");
+        let output = temp_dir("delete-pending-output");
+        let file = target.join("a.bin");
+        std::fs::write(&file, vec![1u8; 1024 * 1024]).unwrap();
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            codex_endpoints: vec![server],
+            cleanup_on_delete: true,
+            purchase_start_poll_secs: 1,
+            ..Default::default()
+        };
+        let client = std::sync::Arc::new(codex::Client::from_config(&config));
+        let processor = file_processor::FileProcessor::new(std::sync::Arc::new(config), client);
+        processor.initialize().await.unwrap();
+
+        let processing = tokio::spawn({
+            let (processor, file) = (processor.clone(), file.clone());
+            async move { processor.process_file(&file).await }
+        });
+        wait_for_status(&processor, &file, storage::FileStatus::Creating).await;
+
+        // Deleted while its purchase is pending: the cleanup waits for it.
+        std::fs::remove_file(&file).unwrap();
+        let cleanup = tokio::spawn({
+            let (processor, file) = (processor.clone(), file.clone());
+            async move { processor.cleanup_deleted(&file).await }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        assert!(!cleanup.is_finished());
+        assert!(processor.records.read().await.contains_key(&file));
+        assert!(cancelled.lock().unwrap().is_empty());
+
+        started.store(true, std::sync::atomic::Ordering::SeqCst);
+        processing.await.unwrap().unwrap();
+        assert_eq!(cleanup.await.unwrap().unwrap(), 1);
+        assert_eq!(*cancelled.lock().unwrap(), ["/api/codex/v1/storage/purchases/purchase-1/cancel"]);
+        assert!(!processor.records.read().await.contains_key(&file));
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rename_waits_for_pending_purchase() {
+        let started = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let is_started = started.clone();
+        let server = mock_server(move |req| match req.path.as_str() {
+            "/api/codex/v1/storage/purchases/purchase-1" if !is_started.load(std::sync::atomic::Ordering::SeqCst) =>
+                MockResponse::new(200, "application/json", r#"{"state":"submitted","request":{"content":{"cid":"zCid"}}}"#),
+            _ => codex_response(&req),
+        }).await;
+
+        let target = temp_dir("rename-pending-target");
+        let output = temp_dir("rename-pending-output");
+        let old_path = target.join("a.bin");
+        let new_path = target.join("b.bin");
+        std::fs::write(&old_path, vec![1u8; 1024 * 1024]).unwrap();
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            codex_endpoints: vec![server],
+            purchase_start_poll_secs: 1,
+            ..Default::default()
+        };
+        let client = std::sync::Arc::new(codex::Client::from_config(&config));
+        let processor = file_processor::FileProcessor::new(std::sync::Arc::new(config), client);
+        processor.initialize().await.unwrap();
+
+        let processing = tokio::spawn({
+            let (processor, file) = (processor.clone(), old_path.clone());
+            async move { processor.process_file(&file).await }
+        });
+        wait_for_status(&processor, &old_path, storage::FileStatus::Creating).await;
+
+        // Moved while its purchase is pending: the record moves once it started.
+        std::fs::rename(&old_path, &new_path).unwrap();
+        let rename = tokio::spawn({
+            let (processor, from, to) = (processor.clone(), old_path.clone(), new_path.clone());
+            async move { processor.rename_records(&from, &to).await }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        assert!(!rename.is_finished());
+        assert!(processor.records.read().await.contains_key(&old_path));
+
+        started.store(true, std::sync::atomic::Ordering::SeqCst);
+        processing.await.unwrap().unwrap();
+        assert_eq!(rename.await.unwrap().unwrap(), 1);
+        let records = processor.records.read().await;
+        assert!(!records.contains_key(&old_path));
+        assert_eq!(records[&new_path].status, storage::FileStatus::Active);
+        assert_eq!(records[&new_path].purchase_id.as_deref(), Some("purchase-1"));
+        drop(records);
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_structured_records_avoid_bookkeeping_file_names() {
+        let server = happy_codex_mock().await;
+
+        let target = temp_dir("reserved-names-target");
+        let output = temp_dir("reserved-names-output");
+        let usage_file = target.join("usage.txt");
+        let checkpoint_file = target.join("scan-checkpoint.dat");
+        std::fs::write(&usage_file, vec![1u8; 1024 * 1024]).unwrap();
+        std::fs::write(&checkpoint_file, vec![2u8; 1024 * 1024]).unwrap();
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            output_structure: config::OutputStructure::Structured,
+            codex_endpoints: vec![server],
+            ..Default::default()
+        };
+        let client = std::sync::Arc::new(codex::Client::from_config(&config));
+        let processor = file_processor::FileProcessor::new(std::sync::Arc::new(config), client);
+        processor.initialize().await.unwrap();
+        processor.process_file(&usage_file).await.unwrap();
+        processor.process_file(&checkpoint_file).await.unwrap();
+
+        // The usage ledger survives, and the records sit beside it.
+        let ledger = usage::UsageLedger::load(&output.join(usage::USAGE_FILE_NAME)).await.unwrap();
+        assert_eq!(ledger.days.values().map(|day| day.uploads).sum::<u64>(), 2);
+        assert!(output.join("usage~1.json").exists());
+        assert!(output.join("scan-checkpoint~1.json").exists());
+        assert!(!output.join(file_processor::SCAN_CHECKPOINT_FILE_NAME).exists());
+
+        let records = processor.storage_manager.load_existing_records(&target).await.unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[&usage_file].status, storage::FileStatus::Active);
+
+        processor.storage_manager.remove_record(&target, &usage_file).await.unwrap();
+        assert!(!output.join("usage~1.json").exists());
+        assert!(output.join(usage::USAGE_FILE_NAME).exists());
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_hard_link_seen_by_watcher_keeps_stored_path() {
+        let (server, uploads) = counting_codex_mock().await;
+
+        let target = temp_dir("hardlink-watch-target");
+        let output = temp_dir("hardlink-watch-output");
+        let stored = target.join("b.bin");
+        std::fs::write(&stored, vec![4u8; 1024 * 1024]).unwrap();
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            codex_endpoints: vec![server],
+            ..Default::default()
+        };
+        let client = std::sync::Arc::new(codex::Client::from_config(&config));
+        let processor = file_processor::FileProcessor::new(std::sync::Arc::new(config), client);
+        processor.initialize().await.unwrap();
+        processor.process_files().await.unwrap();
+
+        // Linked under a name that sorts first, after the file was stored.
+        let link = target.join("a.bin");
+        std::fs::hard_link(&stored, &link).unwrap();
+        let monitor = monitor::Monitor::new(processor.clone());
+        let event = notify::Event::new(notify::EventKind::Create(notify::event::CreateKind::File)).add_path(link.clone());
+        monitor.handle_file_event(event, &mut monitor::Debouncer::new(std::time::Duration::ZERO)).await.unwrap();
+        processor.process_file(&link).await.unwrap();
+
+        assert_eq!(uploads.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert_eq!(processor.records.read().await.keys().collect::<Vec<_>>(), [&stored]);
+
+        // A restart keeps the recorded path rather than the first in order.
+        let restarted = file_processor::FileProcessor::new(processor.config.clone(), processor.codex_client.clone());
+        restarted.initialize().await.unwrap();
+        assert_eq!(restarted.scan_target_folder().await.unwrap(), vec![stored.clone()]);
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_process_paths_stores_hard_links_once() {
+        let (server, uploads) = counting_codex_mock().await;
+
+        let target = temp_dir("hardlink-paths-target");
+        let output = temp_dir("hardlink-paths-output");
+        let stored = target.join("b.bin");
+        let link = target.join("a.bin");
+        std::fs::write(&stored, vec![5u8; 1024 * 1024]).unwrap();
+        std::fs::hard_link(&stored, &link).unwrap();
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            codex_endpoints: vec![server],
+            ..Default::default()
+        };
+        let client = std::sync::Arc::new(codex::Client::from_config(&config));
+        let processor = file_processor::FileProcessor::new(std::sync::Arc::new(config), client);
+        processor.initialize().await.unwrap();
+
+        // Both names report the record the file is stored under.
+        for path in [&stored, &link] {
+            let results = processor.process_paths(vec![path.clone()]).await;
+            assert_eq!(results[0].1.as_ref().unwrap().file_path, stored);
+        }
+        assert_eq!(uploads.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert_eq!(processor.records.read().await.keys().collect::<Vec<_>>(), [&stored]);
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_records_with_unknown_status_load() {
+        let target = temp_dir("unknown-status-target");
+        let output = temp_dir("unknown-status-output");
+        std::fs::write(target.join("a.bin"), vec![1u8; 1024 * 1024]).unwrap();
+
+        // Written by a newer version with a status this one doesn't know,
+        // next to a record it does know.
+        let record = |name: &str, status: &str| serde_json::json!({
+            "relative_path": name,
+            "file_path": target.join(name),
+            "original_cid": "zDvZRwzm2mK7tvDzKScRLapqGdgNTLyyEBvx1TQY37J2CvuzL7Gh",
+            "storage_cid": null,
+            "purchase_id": "purchase-1",
+            "created_at": "2025-01-01T00:00:00Z",
+            "updated_at": "2025-01-01T00:00:00Z",
+            "codex_endpoint": null,
+            "status": status,
+            "error": null,
+        });
+        let mut archived = record("a.bin", "Archived");
+        archived["archived_to"] = serde_json::json!("cold-tier");
+        let records = vec![archived, record("b.bin", "Active")];
+        std::fs::write(output.join("files.json"), serde_json::to_string(&records).unwrap()).unwrap();
+
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            output_structure: config::OutputStructure::Flattened,
+            codex_endpoints: vec!["http://127.0.0.1:9".to_string()],
+            ..Default::default()
+        };
+        let client = std::sync::Arc::new(codex::Client::from_config(&config));
+        let processor = file_processor::FileProcessor::new(std::sync::Arc::new(config), client);
+        processor.load_records().await.unwrap();
+        let unknown = storage::FileStatus::Unknown("Archived".to_string());
+        assert_eq!(processor.records.read().await[&target.join("a.bin")].status, unknown);
+        assert_eq!(processor.records.read().await[&target.join("b.bin")].status, storage::FileStatus::Active);
+
+        // Left alone rather than uploaded to the unreachable endpoint.
+        processor.process_file(&target.join("a.bin")).await.unwrap();
+        assert_eq!(processor.records.read().await[&target.join("a.bin")].status, unknown);
+
+        // Saved back with the status and fields the newer version wrote.
+        processor.persist_all_records().await.unwrap();
+        let saved: Vec<serde_json::Value> = serde_json::from_str(&std::fs::read_to_string(output.join("files.json")).unwrap()).unwrap();
+        let saved = saved.iter().find(|record| record["relative_path"] == "a.bin").unwrap();
+        assert_eq!(saved["status"], "Archived");
+        assert_eq!(saved["archived_to"], "cold-tier");
+
+        let reloaded = file_processor::FileProcessor::new(processor.config.clone(), processor.codex_client.clone());
+        reloaded.load_records().await.unwrap();
+        assert_eq!(reloaded.records.read().await[&target.join("a.bin")].status, unknown);
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fatal_errors_report_their_cause() {
+        let dir = temp_dir("fatal-codes");
+
+        let missing = config::Config::from_file(&dir.join("missing.toml"), None).unwrap_err();
+        assert_eq!(error::error_code(&missing), "io");
+        let broken = dir.join("broken.toml");
+        std::fs::write(&broken, "target_folder = [").unwrap();
+        let json = error::error_json(&config::Config::from_file(&broken, None).unwrap_err());
+        assert_eq!(json["code"], "parse");
+        assert_eq!(json["error"], "Failed to parse config file");
+        assert_eq!(json["context"].as_array().unwrap().len(), 1);
+
+        // A corrupt record stops startup with a parse error.
+        let target = dir.join("target");
+        let output = dir.join("output");
+        std::fs::create_dir_all(&target).unwrap();
+        std::fs::create_dir_all(&output).unwrap();
+        std::fs::write(output.join("a.json"), "{ not json").unwrap();
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            output_structure: config::OutputStructure::Structured,
+            codex_endpoints: vec!["http://127.0.0.1:9".to_string()],
+            ..Default::default()
+        };
+        let client = std::sync::Arc::new(codex::Client::from_config(&config));
+        let processor = file_processor::FileProcessor::new(std::sync::Arc::new(config), client.clone());
+        let err = processor.initialize().await.unwrap_err();
+        assert_eq!(error::error_code(&err), "parse");
+        assert!(format!("{:#}", err).starts_with(&format!("Failed to parse record from {}: ", output.join("a.json").display())));
+
+        let err = client.check_connectivity().await.unwrap_err();
+        assert_eq!(error::error_code(&err), "network");
+        assert!(err.to_string().starts_with("Failed to connect to endpoint http://127.0.0.1:9: "), "{}", err);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_endpoint_and_path_override_combinations_are_validated() {
+        let target = temp_dir("override-combination-target");
+        let output = temp_dir("override-combination-output");
+        let mut config = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            codex_endpoints: vec!["http://localhost:8080".to_string()],
+            overrides: vec![config::PathOverride {
+                path: "videos/**".parse().unwrap(),
+                params: config::StorageParamsOverride { expiry_minutes: Some(2000), ..Default::default() },
+            }],
+            ..Default::default()
+        };
+        config.validate().unwrap();
+
+        // Each is fine alone, but a video uploaded through the endpoint
+        // would expire after its storage ended.
+        config.endpoint_params.insert("http://localhost:8080".to_string(), config::StorageParamsOverride {
+            duration_days: Some(1),
+            ..Default::default()
+        });
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("videos/**") && err.contains("endpoint http://localhost:8080"), "{}", err);
+
+        config.endpoint_params.clear();
+        config.overrides[0].params.proof_probability = Some(config::ProofProbability::Integer(200));
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("Proof probability for override \"videos/**\""), "{}", err);
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_rapid_writes_through_monitor_upload_once() {
+        let (server, uploads) = counting_codex_mock().await;
+        let target = temp_dir("debounce-monitor-target");
+        let output = temp_dir("debounce-monitor-output");
+        let events = temp_dir("debounce-monitor-events").join("events.jsonl");
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            codex_endpoints: vec![server],
+            debounce_secs: 1,
+            // Without debouncing, every close of the file would be processed.
+            trigger_on_close_write: true,
+            grace_rescan_seconds: 0,
+            periodic_scan_secs: 3600,
+            event_log_path: Some(events.clone()),
+            ..Default::default()
+        };
+        let client = std::sync::Arc::new(codex::Client::from_config(&config));
+        let processor = file_processor::FileProcessor::new(std::sync::Arc::new(config), client);
+        let monitor = monitor::Monitor::new(processor.clone());
+        let running = tokio::spawn(async move { monitor.run().await });
+        // Give the watcher time to start after the (empty) initial scan.
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+
+        let file = target.join("a.bin");
+        for i in 1..=5 {
+            std::fs::write(&file, vec![4u8; 1024 * 1024 + i]).unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        wait_for_status(&processor, &file, storage::FileStatus::Active).await;
+        // Long enough for a second debounce window to have fired.
+        tokio::time::sleep(std::time::Duration::from_millis(1500)).await;
+
+        assert_eq!(uploads.load(std::sync::atomic::Ordering::SeqCst), 1);
+        let processed = std::fs::read_to_string(&events).unwrap()
+            .lines()
+            .filter(|line| line.contains("\"new_status\":\"Uploading\""))
+            .count();
+        assert_eq!(processed, 1);
+
+        processor.shutdown.cancel();
+        running.await.unwrap().unwrap();
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&output).unwrap();
+        std::fs::remove_dir_all(events.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stuck_purchase_is_cancelled_and_recreated() {
+        let cancelled = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = cancelled.clone();
+        let server = mock_server(move |req| match req.path.as_str() {
+            path if path.starts_with("/api/codex/v1/storage/request/") => MockResponse::new(200, "text/plain", "purchase-2"),
+            path if path.ends_with("/cancel") => {
+                seen.lock().unwrap().push(path.to_string());
+                MockResponse::new(200, "text/plain", "")
+            }
+            // The first purchase never leaves pending.
+            "/api/codex/v1/storage/purchases/purchase-1" =>
+                MockResponse::new(200, "application/json", r#"{"state":"pending","request":{"content":{"cid":"zCid"}}}"#),
+            _ => codex_response(&req),
+        }).await;
+
+        let target = temp_dir("stuck-recreate-target");
+        let output = temp_dir("stuck-recreate-output");
+        let file = target.join("stuck.bin");
+        std::fs::write(&file, vec![2u8; 1024 * 1024]).unwrap();
+        let config = config::Config {
+            target_folder: target.clone(),
+            output_folder: output.clone(),
+            codex_endpoints: vec![server],
+            stuck_purchase_timeout_minutes: Some(60),
+            ..Default::default()
+        };
+        assert_eq!(config.stuck_purchase_action, config::StuckPurchaseAction::Recreate);
+        let client = std::sync::Arc::new(codex::Client::from_config(&config));
+        let processor = file_processor::FileProcessor::new(std::sync::Arc::new(config), client);
+        processor.initialize().await.unwrap();
+
+        let mut record = processor.storage_manager.create_new_record(file.clone());
+        processor.storage_manager.update_record_fingerprint(&mut record,
+            &fingerprint::compute(&file, config::ChangeDetection::SizeMtime).await.unwrap());
+        processor.storage_manager.update_record_purchase(&mut record, "purchase-1".to_string(), "zCid".to_string());
+        processor.storage_manager.mark_record_active(&mut record);
+        record.pending_since = Some(chrono::Utc::now() - chrono::Duration::hours(2));
+        processor.records.write().await.insert(file.clone(), record);
+
+        processor.check_purchases_once().await;
+        assert_eq!(*cancelled.lock().unwrap(), vec!["/api/codex/v1/storage/purchases/purchase-1/cancel".to_string()]);
+        let record = processor.records.read().await[&file].clone();
+        assert_eq!(record.status, storage::FileStatus::Active);
+        assert_eq!(record.purchase_id.as_deref(), Some("purchase-2"));
+        assert_eq!(record.pending_since, None);
+        assert_eq!(record.history.len(), 1);
+        assert_eq!(record.history[0].purchase_id.as_deref(), Some("purchase-1"));
+        assert!(record.history[0].reason.contains("stuck in state pending"));
+
+        std::fs::remove_dir_all(&target).unwrap();
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+}
@@ -1,38 +1,139 @@
-mod config;
-mod codex;
-mod file_processor;
-mod storage;
-mod monitor;
-mod error;
-
-use anyhow::Result;
-use config::Config;
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use codex_filehog::{codex, commands, config::{Args, Config, ForceReupload}, error::{self, retry_with_delay}, file_processor, monitor, telemetry::Telemetry};
 use log::info;
+use std::io::{BufRead, IsTerminal, Write};
 use std::sync::Arc;
+use std::time::Duration;
+
+/// Longest wait between startup connectivity checks, unless
+/// `startup_connectivity_delay_seconds` is longer.
+const STARTUP_CONNECTIVITY_MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// Asks before a `--force-reupload` run, since it pays for new storage for
+/// every matching file.
+fn confirm_force_reupload(force: &ForceReupload) -> Result<()> {
+    if !std::io::stdin().is_terminal() {
+        return Err(anyhow!("--force-reupload needs confirmation; pass --yes when running non-interactively"));
+    }
+    
+    let scope = match &force.pattern {
+        Some(pattern) => format!("every file matching {:?}", pattern.as_str()),
+        None => "every file in the target folder".to_string(),
+    };
+    print!("This will upload and purchase storage again for {}, including files that are already active. Continue? [y/N] ", scope);
+    std::io::stdout().flush()?;
+    
+    let mut answer = String::new();
+    std::io::stdin().lock().read_line(&mut answer)?;
+    if matches!(answer.trim().to_lowercase().as_str(), "y" | "yes") {
+        Ok(())
+    } else {
+        Err(anyhow!("Force re-upload cancelled"))
+    }
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    env_logger::init();
+    let args = Args::parse();
+    // With --tui, logging starts once the output folder is known.
+    if !args.tui {
+        env_logger::init();
+    }
+    
+    if !args.json_errors {
+        return run(&args).await;
+    }
     
-    let config = Config::load()?;
+    error::enable_json_errors();
+    if let Err(e) = run(&args).await {
+        error::report_fatal(&e);
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+async fn run(args: &Args) -> Result<()> {
+    let config = Config::from_args(args)?;
     config.validate()?;
     
+    if args.tui {
+        start_tui_logging(&config)?;
+    }
+    
+    if let Some(command) = &args.command {
+        return commands::run(command, &config).await;
+    }
+    
+    if let Some(shadow_path) = &args.shadow_config {
+        let shadow = Config::from_file(shadow_path, args.profile.as_deref())?;
+        shadow.validate()?;
+        let report = commands::shadow_compare(&config, &shadow).await?;
+        commands::print_shadow_report(&report, config.display_tz());
+        return Ok(());
+    }
+    
+    if let Some(force) = &config.force_reupload {
+        if !args.yes {
+            confirm_force_reupload(force)?;
+        }
+    }
+    
+    let telemetry = Telemetry::init(&config)?;
+    
     info!("Starting FileHog with config: target={}, output={}", 
           config.target_folder.display(), config.output_folder.display());
     
-    let codex_client = Arc::new(codex::Client::new(config.codex_endpoints.clone()));
+    let codex_client = Arc::new(codex::Client::from_config(&config));
     
-    codex_client.check_connectivity().await?;
-    info!("All Codex endpoints are reachable");
+    if config.dry_run {
+        info!("Dry run: nothing will be uploaded or purchased");
+    } else {
+        retry_with_delay(
+            || codex_client.check_connectivity(),
+            "Codex connectivity check",
+            config.startup_connectivity_retries,
+            Duration::from_secs(config.startup_connectivity_delay_seconds),
+            STARTUP_CONNECTIVITY_MAX_DELAY,
+        ).await?;
+        info!("All Codex endpoints are reachable");
+    }
     
     let file_processor = file_processor::FileProcessor::new(
         Arc::new(config),
         codex_client.clone()
     );
     
+    #[cfg(feature = "tui")]
+    let dashboard = args.tui.then(|| codex_filehog::tui::Dashboard::new(&file_processor).spawn());
+    let shutdown = file_processor.shutdown.clone();
+    
     let monitor = monitor::Monitor::new(file_processor);
     
-    monitor.run().await?;
+    let result = monitor.run().await;
+    // The dashboard closes on shutdown, also when the monitor stopped on
+    // its own.
+    shutdown.cancel();
+    #[cfg(feature = "tui")]
+    if let Some(dashboard) = dashboard {
+        match dashboard.await {
+            Ok(Err(e)) => eprintln!("Dashboard failed: {}", e),
+            Err(e) => eprintln!("Dashboard failed: {}", e),
+            Ok(Ok(())) => {}
+        }
+    }
+    telemetry.shutdown();
     
+    result
+}
+
+#[cfg(feature = "tui")]
+fn start_tui_logging(config: &Config) -> Result<()> {
+    codex_filehog::tui::log_to_file(&config.output_folder)?;
     Ok(())
 }
+
+#[cfg(not(feature = "tui"))]
+fn start_tui_logging(_config: &Config) -> Result<()> {
+    Err(anyhow!("--tui needs FileHog built with the tui feature"))
+}
@@ -0,0 +1,238 @@
+//! Inventory of stored content for audits: every stored file with its size,
+//! SHA-256, CID, purchase and storage period, optionally signed with an
+//! ed25519 key so later tampering can be detected.
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::fingerprint::{self, hex_digest};
+use crate::storage::{FileStatus, StorageManager};
+
+/// Name written in `ManifestSignature::algorithm`.
+pub const SIGNATURE_ALGORITHM: &str = "ed25519";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Manifest {
+    pub generated_at: DateTime<Utc>,
+    pub target_folder: PathBuf,
+    /// Records of every status, so the inventory accounts for all of them.
+    pub record_count: usize,
+    pub file_count: usize,
+    pub total_bytes: u64,
+    /// Stored files whose content was hashed and matches their record,
+    /// sorted by path.
+    pub files: Vec<ManifestEntry>,
+    /// Stored files that could not be vouched for, such as ones deleted or
+    /// changed on disk since they were stored.
+    pub unverified: Vec<UnverifiedFile>,
+    /// Records that are not stored, with their status.
+    pub not_stored: Vec<UnstoredFile>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ManifestEntry {
+    /// Relative to the target folder.
+    pub path: PathBuf,
+    pub size: u64,
+    pub sha256: String,
+    pub cid: Option<String>,
+    pub storage_cid: Option<String>,
+    pub purchase_id: Option<String>,
+    pub status: FileStatus,
+    pub storage_start: DateTime<Utc>,
+    pub storage_expiry: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UnverifiedFile {
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UnstoredFile {
+    pub path: PathBuf,
+    pub status: FileStatus,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ManifestSignature {
+    pub algorithm: String,
+    /// Hex of the ed25519 public key that verifies `signature`.
+    pub public_key: String,
+    /// Hex of the signature over `signed_bytes` of the manifest.
+    pub signature: String,
+}
+
+/// The document `manifest` writes: the manifest and, with `--sign`, its
+/// signature.
+#[derive(Debug, Clone, Serialize)]
+pub struct SignedManifest {
+    pub manifest: Manifest,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signature: Option<ManifestSignature>,
+}
+
+/// Builds the manifest of every record in the output folder, hashing the
+/// local copy of each stored file.
+pub async fn build_manifest(config: &Config) -> Result<Manifest> {
+    let records = StorageManager::from_config(config)
+        .load_existing_records(&config.target_folder)
+        .await?;
+    let duration = chrono::Duration::from_std(config.duration())?;
+
+    let mut sorted: Vec<_> = records.iter().collect();
+    sorted.sort_by_key(|(path, _)| *path);
+
+    let mut manifest = Manifest {
+        generated_at: Utc::now(),
+        target_folder: config.target_folder.clone(),
+        record_count: records.len(),
+        file_count: 0,
+        total_bytes: 0,
+        files: Vec::new(),
+        unverified: Vec::new(),
+        not_stored: Vec::new(),
+    };
+    for (path, record) in sorted {
+        let relative = path.strip_prefix(&config.target_folder)
+            .map_err(|e| anyhow!("Failed to get relative path for {}: {}", path.display(), e))?
+            .to_path_buf();
+        let Some(storage_expiry) = record.expires_at(duration) else {
+            manifest.not_stored.push(UnstoredFile { path: relative, status: record.status.clone() });
+            continue;
+        };
+
+        let (size, _) = match fingerprint::size_and_mtime(path) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                manifest.unverified.push(UnverifiedFile { path: relative, reason: e.to_string() });
+                continue;
+            }
+        };
+        if let Some(stored) = record.file_size.filter(|stored| *stored != size) {
+            manifest.unverified.push(UnverifiedFile {
+                path: relative,
+                reason: format!("size is {} bytes but {} were stored", size, stored),
+            });
+            continue;
+        }
+        let sha256 = match fingerprint::sha256_file(path).await {
+            Ok(sha256) => sha256,
+            Err(e) => {
+                manifest.unverified.push(UnverifiedFile { path: relative, reason: e.to_string() });
+                continue;
+            }
+        };
+        if record.content_hash.as_ref().is_some_and(|stored| *stored != sha256) {
+            manifest.unverified.push(UnverifiedFile {
+                path: relative,
+                reason: "content changed since it was stored".to_string(),
+            });
+            continue;
+        }
+
+        manifest.file_count += 1;
+        manifest.total_bytes += size;
+        manifest.files.push(ManifestEntry {
+            path: relative,
+            size,
+            sha256,
+            cid: record.original_cid.clone(),
+            storage_cid: record.storage_cid.clone(),
+            purchase_id: record.purchase_id.clone(),
+            status: record.status.clone(),
+            storage_start: record.storage_start(),
+            storage_expiry,
+        });
+    }
+
+    Ok(manifest)
+}
+
+/// The bytes a signature covers: the manifest as compact JSON with object
+/// keys sorted, which a verifier can reproduce from the written document.
+pub fn signed_bytes(manifest: &serde_json::Value) -> Result<Vec<u8>> {
+    serde_json::to_vec(manifest).map_err(|e| anyhow!("Failed to serialize manifest: {}", e))
+}
+
+/// Reads an ed25519 secret key written as 64 hex characters.
+pub fn read_signing_key(path: &Path) -> Result<SigningKey> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| anyhow!("Failed to read signing key {}: {}", path.display(), e))?;
+    let bytes = decode_hex(text.trim())
+        .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+        .ok_or_else(|| anyhow!("Failed to parse signing key {}: expected 64 hex characters", path.display()))?;
+    Ok(SigningKey::from_bytes(&bytes))
+}
+
+/// Wraps `manifest` for writing, signed with `key` if given.
+pub fn sign_manifest(manifest: Manifest, key: Option<&SigningKey>) -> Result<SignedManifest> {
+    let signature = match key {
+        Some(key) => {
+            let value = serde_json::to_value(&manifest)
+                .map_err(|e| anyhow!("Failed to serialize manifest: {}", e))?;
+            let signature = key.sign(&signed_bytes(&value)?);
+            Some(ManifestSignature {
+                algorithm: SIGNATURE_ALGORITHM.to_string(),
+                public_key: hex_digest(key.verifying_key().as_bytes()),
+                signature: hex_digest(&signature.to_bytes()),
+            })
+        }
+        None => None,
+    };
+    Ok(SignedManifest { manifest, signature })
+}
+
+/// Checks the signature of a manifest document as written by `manifest
+/// --sign`, returning the hex public key it was signed with.
+pub fn verify_manifest(document: &str) -> Result<String> {
+    let document: serde_json::Value = serde_json::from_str(document)
+        .map_err(|e| anyhow!("Failed to parse manifest: {}", e))?;
+    let manifest = document.get("manifest")
+        .ok_or_else(|| anyhow!("Failed to verify manifest: it has no manifest object"))?;
+    let signature = document.get("signature")
+        .ok_or_else(|| anyhow!("Failed to verify manifest: it is not signed"))?;
+    let field = |name: &str| signature.get(name).and_then(|value| value.as_str())
+        .ok_or_else(|| anyhow!("Failed to verify manifest: signature has no {}", name));
+
+    if field("algorithm")? != SIGNATURE_ALGORITHM {
+        return Err(anyhow!("Failed to verify manifest: unsupported signature algorithm {}", field("algorithm")?));
+    }
+    let public_key = field("public_key")?;
+    let key = decode_hex(public_key)
+        .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+        .and_then(|bytes| VerifyingKey::from_bytes(&bytes).ok())
+        .ok_or_else(|| anyhow!("Failed to verify manifest: invalid public key"))?;
+    let signature = decode_hex(field("signature")?)
+        .and_then(|bytes| <[u8; 64]>::try_from(bytes).ok())
+        .map(|bytes| Signature::from_bytes(&bytes))
+        .ok_or_else(|| anyhow!("Failed to verify manifest: invalid signature"))?;
+
+    key.verify(&signed_bytes(manifest)?, &signature)
+        .map_err(|_| anyhow!("Manifest signature does not match its contents"))?;
+    Ok(public_key.to_string())
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|index| text.get(index..index + 2).and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+        .collect()
+}
+
+pub fn print_summary(manifest: &Manifest) {
+    for file in &manifest.unverified {
+        eprintln!("UNVERIFIED {}: {}", file.path.display(), file.reason);
+    }
+    eprintln!("Listed {} stored files ({} bytes) of {} records, {} unverified, {} not stored",
+              manifest.file_count, manifest.total_bytes, manifest.record_count,
+              manifest.unverified.len(), manifest.not_stored.len());
+}
@@ -0,0 +1,201 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use log::{error, info};
+use prometheus::{Encoder, Histogram, HistogramOpts, IntCounterVec, IntGauge, Opts, Registry, TextEncoder, TEXT_FORMAT};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs;
+use tokio::sync::RwLock;
+use tokio_util::sync::CancellationToken;
+
+use crate::storage::{FileRecord, FileStatus};
+
+/// Point-in-time metrics written to `metrics_snapshot_path`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MetricsSnapshot {
+    pub generated_at: DateTime<Utc>,
+    pub total: usize,
+    /// Record count per status, keyed by the status name.
+    pub counts: BTreeMap<String, usize>,
+    /// Size of every file with active storage, as last fingerprinted.
+    pub bytes_stored: u64,
+    pub failures: usize,
+    /// Pinned files left `Failed`, which should always be zero.
+    pub pinned_failures: usize,
+}
+
+impl MetricsSnapshot {
+    pub fn from_records(records: &HashMap<PathBuf, FileRecord>) -> Self {
+        let mut counts = BTreeMap::new();
+        let mut bytes_stored = 0;
+        let mut failures = 0;
+        let mut pinned_failures = 0;
+        for record in records.values() {
+            *counts.entry(format!("{:?}", record.status)).or_default() += 1;
+            match record.status {
+                FileStatus::Active => bytes_stored += record.file_size.unwrap_or(0),
+                FileStatus::Failed => {
+                    failures += 1;
+                    if record.pinned {
+                        pinned_failures += 1;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Self {
+            generated_at: Utc::now(),
+            total: records.len(),
+            counts,
+            bytes_stored,
+            failures,
+            pinned_failures,
+        }
+    }
+
+    /// Writes the snapshot as JSON through a temporary file in the same
+    /// directory, so a reader sees either the old snapshot or the new one.
+    pub async fn write(&self, path: &Path) -> Result<()> {
+        let content = serde_json::to_string_pretty(self)
+            .map_err(|e| anyhow!("Failed to serialize metrics snapshot: {}", e))?;
+
+        let mut temp_name = path.file_name()
+            .ok_or_else(|| anyhow!("Metrics snapshot path {} has no file name", path.display()))?
+            .to_os_string();
+        temp_name.push(".tmp");
+        let temp_path = path.with_file_name(temp_name);
+
+        fs::write(&temp_path, content).await
+            .map_err(|e| anyhow!("Failed to write metrics snapshot {}: {}", temp_path.display(), e))?;
+        fs::rename(&temp_path, path).await
+            .map_err(|e| anyhow!("Failed to replace metrics snapshot {}: {}", path.display(), e))
+    }
+}
+
+/// Counters served in the Prometheus text format on `/metrics` when
+/// `metrics_addr` is set. Clones share the same counters.
+#[derive(Clone)]
+pub struct PrometheusMetrics {
+    registry: Registry,
+    /// `process_file` calls, by `result`: `ok` or `error`.
+    pub files_processed: IntCounterVec,
+    /// Uploads to Codex, by `result`: `succeeded` or `failed`.
+    pub uploads: IntCounterVec,
+    /// Storage requests, by `result`: `created` or `failed`.
+    pub storage_requests: IntCounterVec,
+    pub purchases_active: IntGauge,
+    /// Seconds each successful upload took.
+    pub upload_duration: Histogram,
+}
+
+impl PrometheusMetrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+        let files_processed = IntCounterVec::new(
+            Opts::new("filehog_files_processed_total", "Files processed, by result"), &["result"]).unwrap();
+        let uploads = IntCounterVec::new(
+            Opts::new("filehog_uploads_total", "Uploads to Codex, by result"), &["result"]).unwrap();
+        let storage_requests = IntCounterVec::new(
+            Opts::new("filehog_storage_requests_total", "Storage requests, by result"), &["result"]).unwrap();
+        let purchases_active = IntGauge::new("filehog_purchases_active", "Files with an active purchase").unwrap();
+        let upload_duration = Histogram::with_opts(
+            HistogramOpts::new("filehog_upload_duration_seconds", "Time taken by successful uploads")
+                .buckets(vec![0.1, 0.5, 1.0, 5.0, 15.0, 60.0, 300.0, 900.0, 3600.0])).unwrap();
+
+        // The names above are fixed and distinct, so registering can't fail.
+        registry.register(Box::new(files_processed.clone())).unwrap();
+        registry.register(Box::new(uploads.clone())).unwrap();
+        registry.register(Box::new(storage_requests.clone())).unwrap();
+        registry.register(Box::new(purchases_active.clone())).unwrap();
+        registry.register(Box::new(upload_duration.clone())).unwrap();
+
+        Self { registry, files_processed, uploads, storage_requests, purchases_active, upload_duration }
+    }
+
+    /// Every metric in the Prometheus text format.
+    pub fn render(&self) -> Result<String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)
+            .map_err(|e| anyhow!("Failed to encode metrics: {}", e))?;
+        String::from_utf8(buffer).map_err(|e| anyhow!("Failed to encode metrics: {}", e))
+    }
+}
+
+impl std::fmt::Debug for PrometheusMetrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PrometheusMetrics").finish_non_exhaustive()
+    }
+}
+
+impl Default for PrometheusMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Serves `metrics` on `/metrics` at `addr` until `shutdown` is cancelled,
+/// refreshing the active purchase count from `records` on each scrape.
+/// Returns the bound address, useful when `addr` asks for any free port.
+pub fn serve_metrics(
+    addr: SocketAddr,
+    metrics: PrometheusMetrics,
+    records: Arc<RwLock<HashMap<PathBuf, FileRecord>>>,
+    shutdown: CancellationToken,
+) -> Result<(SocketAddr, tokio::task::JoinHandle<()>)> {
+    let make_service = make_service_fn(move |_| {
+        let metrics = metrics.clone();
+        let records = records.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let metrics = metrics.clone();
+                let records = records.clone();
+                async move { Ok::<_, Infallible>(metrics_response(request, &metrics, &records).await) }
+            }))
+        }
+    });
+
+    let server = Server::try_bind(&addr)
+        .map_err(|e| anyhow!("Failed to bind metrics server to {}: {}", addr, e))?
+        .serve(make_service);
+    let bound = server.local_addr();
+    let handle = tokio::spawn(async move {
+        if let Err(e) = server.with_graceful_shutdown(shutdown.cancelled_owned()).await {
+            error!("Metrics server failed: {}", e);
+        }
+    });
+    info!("Serving Prometheus metrics on http://{}/metrics", bound);
+    Ok((bound, handle))
+}
+
+async fn metrics_response(
+    request: Request<Body>,
+    metrics: &PrometheusMetrics,
+    records: &RwLock<HashMap<PathBuf, FileRecord>>,
+) -> Response<Body> {
+    let respond = |status: StatusCode, content_type: &str, body: String| Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, content_type)
+        .body(Body::from(body))
+        .unwrap();
+
+    if request.method() != Method::GET || request.uri().path() != "/metrics" {
+        return respond(StatusCode::NOT_FOUND, "text/plain", "Not found\n".to_string());
+    }
+
+    let active = records.read().await.values()
+        .filter(|record| record.status == FileStatus::Active)
+        .count();
+    metrics.purchases_active.set(active as i64);
+    match metrics.render() {
+        Ok(body) => respond(StatusCode::OK, TEXT_FORMAT, body),
+        Err(e) => respond(StatusCode::INTERNAL_SERVER_ERROR, "text/plain", format!("{}\n", e)),
+    }
+}
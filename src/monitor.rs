@@ -1,20 +1,154 @@
 use anyhow::{anyhow, Result};
 use log::{info, error, debug, warn};
+use notify::event::{AccessKind, AccessMode, ModifyKind, RenameMode};
 use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
 use tokio::time::{sleep, Duration};
 
+use crate::eligibility::{self, Eligibility, PathKind};
+use crate::error::crash_with_error;
 use crate::file_processor::FileProcessor;
-use crate::storage::StorageManager;
+use crate::metrics::MetricsSnapshot;
+use crate::queue::WorkQueue;
+use crate::storage::{FileRecord, FileStatus};
+
+/// A continuous stream of events postpones the grace re-scan by at most this
+/// many grace periods.
+const GRACE_RESCAN_MAX_DELAY_FACTOR: u32 = 6;
+
+/// With `cleanup_on_delete`, a deleted file's record is only cleaned up if
+/// the file is still gone this long after the deletion, so one that is
+/// deleted and written again, as some editors save, keeps its record.
+const DELETE_CLEANUP_DELAY: Duration = Duration::from_secs(5);
+
+/// How long a webhook call gets before FileHog gives up on it.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where records stood when the monitor stopped.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ShutdownSummary {
+    pub total: usize,
+    /// Record count per status, keyed by the status name.
+    pub counts: BTreeMap<String, usize>,
+    pub failed: Vec<PathBuf>,
+    pub pending: Vec<PathBuf>,
+}
+
+impl ShutdownSummary {
+    pub fn from_records(records: &HashMap<PathBuf, FileRecord>) -> Self {
+        let mut summary = Self { total: records.len(), ..Default::default() };
+        for (path, record) in records {
+            *summary.counts.entry(format!("{:?}", record.status)).or_default() += 1;
+            match record.status {
+                FileStatus::Failed => summary.failed.push(path.clone()),
+                FileStatus::Pending => summary.pending.push(path.clone()),
+                _ => {}
+            }
+        }
+        summary.failed.sort();
+        summary.pending.sort();
+        summary
+    }
+}
+
+/// POSTs the summary as JSON to `url`.
+pub async fn send_shutdown_summary(url: &str, summary: &ShutdownSummary) -> Result<()> {
+    post_webhook(url, summary, "shutdown summary").await
+}
+
+/// POSTs `body` as JSON to `url`; `what` names it in errors.
+pub async fn post_webhook(url: &str, body: &impl Serialize, what: &str) -> Result<()> {
+    let response = reqwest::Client::new()
+        .post(url)
+        .timeout(WEBHOOK_TIMEOUT)
+        .json(body)
+        .send()
+        .await
+        .map_err(|e| anyhow!("Failed to send {} to {}: {}", what, url, e))?;
+    
+    if !response.status().is_success() {
+        return Err(anyhow!("Webhook {} returned {} for {}", url, response.status(), what));
+    }
+    Ok(())
+}
+
+/// Coalesces events for written files per path, releasing a path once it
+/// has had no events for a whole window and its size held steady.
+pub struct Debouncer {
+    window: Duration,
+    /// When each path last had an event, and its size then.
+    pending: HashMap<PathBuf, (tokio::time::Instant, Option<u64>)>,
+}
+
+impl Debouncer {
+    pub fn new(window: Duration) -> Self {
+        Self { window, pending: HashMap::new() }
+    }
+    
+    /// Whether events are debounced at all; with a zero window they are
+    /// handled as they arrive.
+    pub fn is_enabled(&self) -> bool {
+        !self.window.is_zero()
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+    
+    /// Notes an event for `path`, restarting its window.
+    pub fn touch(&mut self, path: PathBuf) {
+        let size = path.metadata().ok().map(|metadata| metadata.len());
+        self.pending.insert(path, (tokio::time::Instant::now(), size));
+    }
+    
+    /// When the earliest pending window ends.
+    pub fn next_due(&self) -> Option<tokio::time::Instant> {
+        self.pending.values().map(|(at, _)| *at + self.window).min()
+    }
+    
+    /// Removes and returns the paths whose window has ended, sorted. A path
+    /// whose size changed during its window, without an event saying so,
+    /// gets another window; one that vanished is dropped.
+    pub fn take_due(&mut self) -> Vec<PathBuf> {
+        let now = tokio::time::Instant::now();
+        let ended: Vec<PathBuf> = self.pending.iter()
+            .filter(|(_, (at, _))| *at + self.window <= now)
+            .map(|(path, _)| path.clone())
+            .collect();
+        
+        let mut due = Vec::new();
+        for path in ended {
+            let (_, size) = self.pending.remove(&path).unwrap();
+            match path.metadata().ok().map(|metadata| metadata.len()) {
+                None => debug!("File {} vanished while its events were debounced", path.display()),
+                Some(current) if Some(current) != size => {
+                    debug!("File {} is still growing; waiting for it to settle", path.display());
+                    self.pending.insert(path, (now, Some(current)));
+                }
+                Some(_) => due.push(path),
+            }
+        }
+        due.sort();
+        due
+    }
+}
 
 pub struct Monitor {
     file_processor: FileProcessor,
+    /// With `persistent_queue`, detected files go through this instead of
+    /// being processed inline.
+    queue: Option<Arc<WorkQueue>>,
 }
 
 impl Monitor {
     pub fn new(file_processor: FileProcessor) -> Self {
-        Self { file_processor }
+        let queue = file_processor.config.persistent_queue
+            .then(|| Arc::new(WorkQueue::new(&file_processor.config.output_folder)));
+        Self { file_processor, queue }
     }
     
     pub async fn run(&self) -> Result<()> {
@@ -22,18 +156,96 @@ impl Monitor {
         
         self.file_processor.initialize().await?;
         
+        // Stopped once the monitor has finished, after any final work.
+        let metrics_stop = tokio_util::sync::CancellationToken::new();
+        let metrics_server = match self.file_processor.config.metrics_addr {
+            Some(addr) => Some(crate::metrics::serve_metrics(
+                addr,
+                self.file_processor.codex_client.metrics().clone(),
+                self.file_processor.records.clone(),
+                metrics_stop.clone(),
+            )?.1),
+            None => None,
+        };
+        // Stopped by Ctrl+C along with everything else, so a client can't
+        // hold the monitor open.
+        let api_stop = self.file_processor.shutdown.child_token();
+        let api_server = match self.file_processor.config.api_addr {
+            Some(addr) => Some(crate::api::serve_api(
+                addr,
+                self.file_processor.records.clone(),
+                self.file_processor.config.target_folder.clone(),
+                api_stop.clone(),
+            )?.1),
+            None => None,
+        };
+        let result = self.run_initialized().await;
+        metrics_stop.cancel();
+        api_stop.cancel();
+        for server in [metrics_server, api_server].into_iter().flatten() {
+            let _ = server.await;
+        }
+        result
+    }
+    
+    async fn run_initialized(&self) -> Result<()> {
+        if let Some(queue) = &self.queue {
+            queue.replay().await?;
+        }
+        
+        let shutdown = self.file_processor.shutdown.clone();
+        let signalled = shutdown.clone();
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                info!("Received shutdown signal, finishing files in progress");
+                signalled.cancel();
+            }
+        });
+        
         info!("Processing existing files...");
-        self.file_processor.process_files().await?;
+        let scanned = self.within_shutdown_grace(self.file_processor.process_files()).await;
+        self.file_processor.flush_records().await;
+        if let Some(Err(e)) = scanned {
+            crash_with_error(&e);
+        }
+        
+        if self.file_processor.config.dry_run {
+            let summary = self.file_processor.dry_run_summary().await;
+            info!("Dry run: the initial scan would upload {} files, {} bytes in total",
+                  summary.files, summary.total_bytes);
+        }
+        
+        if shutdown.is_cancelled() {
+            if let Err(e) = self.file_processor.persist_all_records().await {
+                error!("{}", e);
+            }
+            self.report_shutdown_summary().await;
+            info!("FileHog monitor stopped");
+            return Ok(());
+        }
         
         let (tx, mut rx) = mpsc::channel(100);
         
-        let target_folder = self.file_processor.config.target_folder.clone();
         let watcher_tx = tx.clone();
+        let uploads_in_flight = self.file_processor.uploads_in_flight.clone();
+        // Uploads are registered under the recorded path, which may differ in
+        // case from the path the event names.
+        let case_keys = self.file_processor.case_insensitive_paths.then(|| self.file_processor.case_keys.clone());
         
         let mut watcher = RecommendedWatcher::new(
             move |res: Result<Event, notify::Error>| {
                 match res {
                     Ok(event) => {
+                        // Cancelled here rather than in the event loop, which
+                        // may itself be busy uploading the deleted file.
+                        if let EventKind::Remove(_) = event.kind {
+                            for path in &event.paths {
+                                let recorded = case_keys.as_ref().and_then(|case_keys| case_keys.get(path));
+                                if uploads_in_flight.cancel(recorded.as_deref().unwrap_or(path)) {
+                                    info!("File {} was deleted mid-upload; cancelling the upload", path.display());
+                                }
+                            }
+                        }
                         if let Err(e) = watcher_tx.try_send(event) {
                             error!("Failed to send file event: {}", e);
                         }
@@ -44,99 +256,217 @@ impl Monitor {
             notify::Config::default(),
         ).map_err(|e| anyhow!("Failed to create file watcher: {}", e))?;
         
-        watcher.watch(&target_folder, RecursiveMode::Recursive)
-            .map_err(|e| anyhow!("Failed to watch target folder: {}", e))?;
+        for root in self.file_processor.config.watch_roots() {
+            watcher.watch(&root, RecursiveMode::Recursive)
+                .map_err(|e| anyhow!("Failed to watch {}: {}", root.display(), e))?;
+            info!("File watcher started for: {}", root.display());
+        }
         
-        info!("File watcher started for: {}", target_folder.display());
+        if self.file_processor.config.trigger_on_close_write && !self.uses_close_write() {
+            warn!("trigger_on_close_write is only supported on Linux; falling back to size-stability checks");
+        }
         
         let file_processor = Arc::new(self.file_processor.clone());
         let monitor_processor = file_processor.clone();
         
-        let monitor_handle = tokio::spawn(async move {
+        let mut monitor_handle = tokio::spawn(async move {
             if let Err(e) = monitor_processor.monitor_purchases().await {
                 error!("Purchase monitoring failed: {}", e);
             }
         });
         
-        let mut file_check_interval = tokio::time::interval(Duration::from_secs(30));
+        let (persist_stop, persist_stopped) = watch::channel(false);
+        let persist_handle = tokio::spawn(persist_periodically(file_processor.clone(), persist_stopped));
+        let metrics_handle = tokio::spawn(write_metrics_periodically(file_processor.clone(), persist_stop.subscribe()));
+        
+        let mut queue_handle = self.queue.clone().map(|queue| {
+            let queue_processor = file_processor.clone();
+            tokio::spawn(drain_queue(queue, queue_processor))
+        });
+        
+        let mut file_check_interval = tokio::time::interval(Duration::from_secs(self.file_processor.config.periodic_scan_secs));
+        
+        // Directories touched by the current burst of events, re-scanned once
+        // the burst has been quiet for a grace period.
+        let grace = Duration::from_secs(self.file_processor.config.grace_rescan_seconds);
+        let mut touched_dirs: HashSet<PathBuf> = HashSet::new();
+        let mut burst_started = tokio::time::Instant::now();
+        let grace_timer = sleep(grace);
+        tokio::pin!(grace_timer);
+        
+        let mut debouncer = Debouncer::new(Duration::from_secs(self.file_processor.config.debounce_secs));
+        let debounce_timer = sleep(Duration::ZERO);
+        tokio::pin!(debounce_timer);
         
         info!("FileHog monitor is running. Press Ctrl+C to stop.");
         
         loop {
             tokio::select! {
                 Some(event) = rx.recv() => {
-                    if let Err(e) = self.handle_file_event(event).await {
-                        error!("Failed to handle file event: {}", e);
+                    if !grace.is_zero() {
+                        let now = tokio::time::Instant::now();
+                        if touched_dirs.is_empty() {
+                            burst_started = now;
+                        }
+                        touched_dirs.extend(event_dirs(&event));
+                        let latest = burst_started + grace * GRACE_RESCAN_MAX_DELAY_FACTOR;
+                        grace_timer.as_mut().reset((now + grace).min(latest));
+                    }
+                    match self.within_shutdown_grace(self.handle_file_event(event, &mut debouncer)).await {
+                        Some(Err(e)) => error!("Failed to handle file event: {}", e),
+                        Some(Ok(())) => {}
+                        None => break,
+                    }
+                    if let Some(due) = debouncer.next_due() {
+                        debounce_timer.as_mut().reset(due);
                     }
                 }
-                _ = file_check_interval.tick() => {
-                    if let Err(e) = self.periodic_check().await {
-                        error!("Periodic check failed: {}", e);
+                _ = &mut debounce_timer, if !debouncer.is_empty() => {
+                    let due = debouncer.take_due();
+                    let handled = self.within_shutdown_grace(async {
+                        for path in due {
+                            // The debouncer has already seen the size hold.
+                            self.handle_written_file(&path, false).await;
+                        }
+                    }).await;
+                    if handled.is_none() {
+                        break;
+                    }
+                    if let Some(due) = debouncer.next_due() {
+                        debounce_timer.as_mut().reset(due);
+                    }
+                }
+                _ = &mut grace_timer, if !touched_dirs.is_empty() => {
+                    if self.within_shutdown_grace(self.grace_rescan(std::mem::take(&mut touched_dirs))).await.is_none() {
+                        break;
                     }
                 }
-                _ = tokio::signal::ctrl_c() => {
-                    info!("Received shutdown signal");
-                    break;
+                _ = file_check_interval.tick() => {
+                    match self.within_shutdown_grace(self.periodic_check()).await {
+                        Some(Err(e)) => error!("Periodic check failed: {}", e),
+                        Some(Ok(())) => {}
+                        None => break,
+                    }
                 }
+                _ = shutdown.cancelled() => break,
             }
         }
         
-        monitor_handle.abort();
+        // No new file events from here on; let the purchase monitor and the
+        // queue finish the files they are on.
+        drop(watcher);
+        let finished = self.within_shutdown_grace(async {
+            let _ = (&mut monitor_handle).await;
+            if let Some(queue_handle) = &mut queue_handle {
+                let _ = queue_handle.await;
+            }
+        }).await;
+        if finished.is_none() {
+            monitor_handle.abort();
+            if let Some(queue_handle) = &queue_handle {
+                // Anything interrupted here is still in the queue file for next time.
+                queue_handle.abort();
+            }
+        }
+        // Let the persist task finish any write in progress and save once more.
+        let _ = persist_stop.send(true);
+        if let Err(e) = persist_handle.await {
+            error!("Record persistence task failed: {}", e);
+        }
+        if let Err(e) = metrics_handle.await {
+            error!("Metrics snapshot task failed: {}", e);
+        }
+        self.file_processor.flush_records().await;
+        self.report_shutdown_summary().await;
         info!("FileHog monitor stopped");
         Ok(())
     }
     
-    async fn handle_file_event(&self, event: Event) -> Result<()> {
+    /// Runs `work` to completion, unless shutdown has been requested and it
+    /// is still running `shutdown_grace_secs` later, in which case it is
+    /// dropped and `None` returned.
+    async fn within_shutdown_grace<T>(&self, work: impl std::future::Future<Output = T>) -> Option<T> {
+        let grace = Duration::from_secs(self.file_processor.config.shutdown_grace_secs);
+        let shutdown = &self.file_processor.shutdown;
+        tokio::select! {
+            result = work => Some(result),
+            _ = async { shutdown.cancelled().await; sleep(grace).await } => {
+                warn!("Work in progress didn't finish within {}s of shutdown; stopping it", grace.as_secs());
+                None
+            }
+        }
+    }
+    
+    async fn report_shutdown_summary(&self) {
+        let summary = ShutdownSummary::from_records(&*self.file_processor.records.read().await);
+        
+        let counts: Vec<String> = summary.counts.iter()
+            .map(|(status, count)| format!("{} {}", count, status))
+            .collect();
+        info!("Shutdown summary: {} records ({})", summary.total, counts.join(", "));
+        for path in &summary.failed {
+            warn!("Left Failed: {}", path.display());
+        }
+        for path in &summary.pending {
+            warn!("Left Pending: {}", path.display());
+        }
+        
+        if let Some(url) = self.file_processor.config.webhook_url.as_ref().filter(|_| !self.file_processor.config.dry_run) {
+            if let Err(e) = send_shutdown_summary(url, &summary).await {
+                error!("{}", e);
+            }
+        }
+    }
+    
+    pub(crate) async fn handle_file_event(&self, event: Event, debouncer: &mut Debouncer) -> Result<()> {
+        let close_write = self.uses_close_write();
+        
         match event.kind {
+            EventKind::Access(AccessKind::Close(AccessMode::Write)) if close_write => {
+                for path in event.paths {
+                    if !is_regular_file(&path) {
+                        continue;
+                    }
+                    if debouncer.is_enabled() {
+                        debouncer.touch(path);
+                    } else {
+                        self.handle_written_file(&path, false).await;
+                    }
+                }
+            }
+            // A move within the watched tree keeps the records of what moved.
+            // Other rename events carry one path and fall through below, so a
+            // file moved in is treated as potentially new.
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if event.paths.len() == 2 => {
+                let (from, to) = (&event.paths[0], &event.paths[1]);
+                match self.file_processor.rename_records(from, to).await {
+                    Ok(0) if is_regular_file(to) => self.handle_written_file(to, !close_write).await,
+                    Ok(_) => {}
+                    Err(e) => error!("Failed to move records from {} to {}: {}", from.display(), to.display(), e),
+                }
+            }
+            // With close-write events available, a file is only picked up once
+            // its writer closes it, so creates and data writes are ignored.
+            EventKind::Create(_) | EventKind::Modify(ModifyKind::Data(_)) | EventKind::Modify(ModifyKind::Any)
+                if close_write => {}
             EventKind::Create(_) | EventKind::Modify(_) => {
                 for path in event.paths {
-                    if path.is_file() {
-                        let metadata = match path.metadata() {
-                            Ok(m) => m,
-                            Err(e) => {
-                                warn!("Failed to get metadata for {}: {}", path.display(), e);
-                                continue;
-                            }
-                        };
-                        
-                        let file_size = metadata.len();
-                        
-                        if file_size < 1024 * 1024 {
-                            debug!("Ignoring small file: {} ({} bytes)", path.display(), file_size);
-                            continue;
-                        }
-                        
-                        if file_size > 1024 * 1024 * 1024 {
-                            warn!("Ignoring large file: {} ({} bytes)", path.display(), file_size);
-                            continue;
-                        }
-                        
-                        info!("New file detected: {}", path.display());
-                        
-                        sleep(Duration::from_secs(1)).await;
-                        
-                        let new_metadata = match path.metadata() {
-                            Ok(m) => m,
-                            Err(_e) => {
-                                warn!("File disappeared before processing: {}", path.display());
-                                continue;
-                            }
-                        };
-                        
-                        if new_metadata.len() != file_size {
-                            debug!("File {} still being written, skipping for now", path.display());
-                            continue;
-                        }
-                        
-                        if let Err(e) = self.file_processor.process_file(&path).await {
-                            error!("Failed to process new file {}: {}", path.display(), e);
-                        }
+                    if !is_regular_file(&path) {
+                        continue;
+                    }
+                    if debouncer.is_enabled() {
+                        debouncer.touch(path);
+                    } else {
+                        self.handle_written_file(&path, !close_write).await;
                     }
                 }
             }
             EventKind::Remove(_) => {
                 for path in event.paths {
                     info!("File removed: {}", path.display());
+                    if self.file_processor.config.cleanup_on_delete {
+                        tokio::spawn(cleanup_after_delay(self.file_processor.clone(), path));
+                    }
                 }
             }
             _ => {}
@@ -145,25 +475,145 @@ impl Monitor {
         Ok(())
     }
     
-    async fn periodic_check(&self) -> Result<()> {
-        debug!("Performing periodic check...");
+    /// Close-write events come from inotify, so they are only relied on when
+    /// running on Linux.
+    fn uses_close_write(&self) -> bool {
+        self.file_processor.config.trigger_on_close_write && cfg!(target_os = "linux")
+    }
+    
+    /// Processes a created or written file. Without a close-write signal, the
+    /// file is only processed if its size holds steady for a second.
+    async fn handle_written_file(&self, path: &Path, wait_for_stable_size: bool) {
+        if !self.file_processor.is_included(path) {
+            debug!("Ignoring file {}: filtered by include/exclude patterns", path.display());
+            return;
+        }
         
-        let files = self.file_processor.scan_target_folder().await?;
-        let mut new_files = Vec::new();
+        match eligibility::check(&self.file_processor.config, path) {
+            Ok(Eligibility::Eligible) => {}
+            Ok(reason @ (Eligibility::TooSmall(..) | Eligibility::NotAFile)) => {
+                debug!("Ignoring file {}: {}", path.display(), reason);
+                return;
+            }
+            Ok(reason) => {
+                warn!("Ignoring file {}: {}", path.display(), reason);
+                return;
+            }
+            Err(_) if !path.exists() => {
+                debug!("File {} vanished before it could be checked", path.display());
+                return;
+            }
+            Err(e) => {
+                warn!("{}", e);
+                return;
+            }
+        }
+        
+        let file_size = match path.metadata() {
+            Ok(m) => m.len(),
+            Err(_) if !path.exists() => {
+                debug!("File {} vanished before it could be checked", path.display());
+                return;
+            }
+            Err(e) => {
+                warn!("Failed to get metadata for {}: {}", path.display(), e);
+                return;
+            }
+        };
+        
+        info!("New file detected: {}", path.display());
         
-        {
-            let records = self.file_processor.records.read().await;
-            for file_path in files {
-                if !records.contains_key(&file_path) {
-                    new_files.push(file_path);
+        if wait_for_stable_size {
+            sleep(Duration::from_secs(1)).await;
+            
+            let new_metadata = match path.metadata() {
+                Ok(m) => m,
+                Err(_e) => {
+                    debug!("File disappeared before processing: {}", path.display());
+                    return;
                 }
+            };
+            
+            if new_metadata.len() != file_size {
+                debug!("File {} still being written, skipping for now", path.display());
+                return;
             }
         }
         
+        let path = &self.file_processor.record_path(path).await;
+        if let Some(queue) = &self.queue {
+            if let Err(e) = queue.push(path).await {
+                error!("Failed to queue new file {}: {}", path.display(), e);
+            }
+        } else if let Err(e) = self.file_processor.process_file(path).await {
+            error!("Failed to process new file {}: {}", path.display(), e);
+        }
+    }
+    
+    /// Looks for files in `dirs` that the watcher should have reported but
+    /// that have no up-to-date record, e.g. because events were dropped under
+    /// load. Only the directories themselves are listed, not their subtrees.
+    async fn grace_rescan(&self, dirs: HashSet<PathBuf>) {
+        let mut candidates = Vec::new();
+        let target_folder = &self.file_processor.config.target_folder;
+        for dir in dirs.iter().filter(|dir| dir.starts_with(target_folder)) {
+            let entries = match std::fs::read_dir(dir) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    debug!("Skipping grace re-scan of {}: {}", dir.display(), e);
+                    continue;
+                }
+            };
+            candidates.extend(entries.flatten().map(|entry| entry.path()).filter(|path| path.is_file()));
+        }
+        
+        let stragglers = self.new_or_changed(candidates).await;
+        if stragglers.is_empty() {
+            return;
+        }
+        
+        info!("Grace re-scan of {} directories found {} files the watcher missed", dirs.len(), stragglers.len());
+        for path in stragglers {
+            self.handle_written_file(&path, true).await;
+        }
+    }
+    
+    /// The files in `files` that have no record or have changed since they
+    /// were stored.
+    async fn new_or_changed(&self, files: Vec<PathBuf>) -> Vec<PathBuf> {
+        let mut new_files = Vec::new();
+        
+        for file_path in files {
+            let is_known = self.file_processor.records.read().await.contains_key(&file_path);
+            if !is_known {
+                new_files.push(file_path);
+                continue;
+            }
+            
+            match self.file_processor.has_changed_since_stored(&file_path).await {
+                Ok(true) => new_files.push(file_path),
+                Ok(false) => {}
+                Err(e) => warn!("Failed to check {} for changes: {}", file_path.display(), e),
+            }
+        }
+        
+        new_files
+    }
+    
+    async fn periodic_check(&self) -> Result<()> {
+        debug!("Performing periodic check...");
+        
+        self.file_processor.retry_pending().await;
+        
+        let files = self.file_processor.scan_target_folder().await?;
+        let new_files = self.new_or_changed(files).await;
+        
         if !new_files.is_empty() {
-            info!("Found {} new files during periodic check", new_files.len());
+            info!("Found {} new or changed files during periodic check", new_files.len());
             for file_path in new_files {
-                if let Err(e) = self.file_processor.process_file(&file_path).await {
+                if let Some(queue) = &self.queue {
+                    queue.push(&file_path).await?;
+                } else if let Err(e) = self.file_processor.process_file(&file_path).await {
                     error!("Failed to process file {}: {}", file_path.display(), e);
                 }
             }
@@ -173,10 +623,147 @@ impl Monitor {
     }
 }
 
+/// Directories a grace re-scan should list for `event`: the parent of each
+/// path, and the path itself when it is a directory, since files created
+/// inside a new directory are the events most easily missed.
+fn event_dirs(event: &Event) -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    for path in &event.paths {
+        if path.is_dir() {
+            dirs.push(path.clone());
+        }
+        if let Some(parent) = path.parent() {
+            dirs.push(parent.to_path_buf());
+        }
+    }
+    dirs
+}
+
+/// Whether a path from a file event should be handled as a file. Anything
+/// else is expected in a watched tree and only logged at debug level.
+fn is_regular_file(path: &Path) -> bool {
+    match eligibility::classify(path) {
+        Ok(PathKind::File) => true,
+        Ok(PathKind::Directory) => false,
+        Ok(PathKind::Special(kind)) => {
+            debug!("Ignoring {} {}", kind, path.display());
+            false
+        }
+        Ok(PathKind::Vanished) => {
+            debug!("Ignoring event for {}, which no longer exists", path.display());
+            false
+        }
+        Err(e) => {
+            warn!("{}", e);
+            false
+        }
+    }
+}
+
+/// Cleans up after the deletion of `path` once `DELETE_CLEANUP_DELAY` has
+/// passed, unless it has been recreated by then.
+async fn cleanup_after_delay(file_processor: FileProcessor, path: PathBuf) {
+    sleep(DELETE_CLEANUP_DELAY).await;
+    if path.exists() {
+        debug!("{} was recreated after being deleted; keeping its record", path.display());
+        return;
+    }
+    match file_processor.cleanup_deleted(&path).await {
+        Ok(0) => {}
+        Ok(removed) => info!("Cleaned up {} records under deleted {}", removed, path.display()),
+        Err(e) => error!("{}", e),
+    }
+}
+
+/// Saves every record each `persist_interval_minutes`, and once more when
+/// `stop` is set. Does nothing when the interval is 0.
+async fn persist_periodically(file_processor: Arc<FileProcessor>, mut stop: watch::Receiver<bool>) {
+    let minutes = file_processor.config.persist_interval_minutes;
+    if minutes == 0 {
+        return;
+    }
+    
+    let period = Duration::from_secs(minutes as u64 * 60);
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = stop.changed() => {
+                if let Err(e) = file_processor.persist_all_records().await {
+                    error!("{}", e);
+                }
+                return;
+            }
+        }
+        
+        if let Err(e) = file_processor.persist_all_records().await {
+            error!("{}", e);
+        }
+    }
+}
+
+/// Writes a metrics snapshot to `metrics_snapshot_path` every
+/// `metrics_snapshot_interval_seconds`, and a last one when `stop` fires.
+async fn write_metrics_periodically(file_processor: Arc<FileProcessor>, mut stop: watch::Receiver<bool>) {
+    let Some(path) = file_processor.config.metrics_snapshot_path.clone() else {
+        return;
+    };
+    
+    let period = Duration::from_secs(file_processor.config.metrics_snapshot_interval_seconds);
+    let mut interval = tokio::time::interval(period);
+    
+    loop {
+        let stopping = tokio::select! {
+            _ = interval.tick() => false,
+            _ = stop.changed() => true,
+        };
+        
+        let snapshot = MetricsSnapshot::from_records(&*file_processor.records.read().await);
+        if let Err(e) = snapshot.write(&path).await {
+            warn!("{}", e);
+        }
+        if stopping {
+            return;
+        }
+    }
+}
+
+/// Processes queued files as they arrive, dequeuing each once it has been
+/// handled, whether or not it succeeded, unless shutdown stopped it.
+async fn drain_queue(queue: Arc<WorkQueue>, file_processor: Arc<FileProcessor>) {
+    let shutdown = file_processor.shutdown.clone();
+    while !shutdown.is_cancelled() {
+        let batch = queue.take().await;
+        if batch.is_empty() {
+            tokio::select! {
+                _ = queue.notified() => {}
+                _ = shutdown.cancelled() => {}
+            }
+            continue;
+        }
+        
+        for (path, result) in file_processor.process_paths(batch).await {
+            if let Err(e) = result {
+                // Left queued for the next start, which also retries a file
+                // that failed while shutting down.
+                if shutdown.is_cancelled() {
+                    continue;
+                }
+                error!("Failed to process queued file {}: {}", path.display(), e);
+            }
+            if let Err(e) = queue.complete(&path).await {
+                error!("{}", e);
+            }
+        }
+    }
+}
+
 impl Clone for Monitor {
     fn clone(&self) -> Self {
         Self {
             file_processor: self.file_processor.clone(),
+            queue: self.queue.clone(),
         }
     }
 }
@@ -186,11 +773,21 @@ impl Clone for FileProcessor {
         Self {
             config: self.config.clone(),
             codex_client: self.codex_client.clone(),
-            storage_manager: StorageManager::new(
-                self.config.output_folder.clone(),
-                self.config.output_structure.clone(),
-            ),
+            storage_manager: self.storage_manager.clone(),
             records: self.records.clone(),
+            usage: self.usage.clone(),
+            path_filter: self.path_filter.clone(),
+            pinned_files: self.pinned_files.clone(),
+            case_insensitive_paths: self.case_insensitive_paths,
+            shutdown: self.shutdown.clone(),
+            dry_run_plan: self.dry_run_plan.clone(),
+            uploads_in_flight: self.uploads_in_flight.clone(),
+            processing: self.processing.clone(),
+            linked_files: self.linked_files.clone(),
+            case_keys: self.case_keys.clone(),
+            debug_captures: self.debug_captures.clone(),
+            low_disk_paused: self.low_disk_paused.clone(),
+            read_only_probe_at: self.read_only_probe_at.clone(),
         }
     }
 }
\ No newline at end of file
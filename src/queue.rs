@@ -0,0 +1,186 @@
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{Mutex, Notify};
+
+pub const QUEUE_FILE_NAME: &str = "queue.jsonl";
+
+/// Completed entries tolerated in the queue file before it is rewritten.
+const COMPACT_AFTER_COMPLETED: usize = 1000;
+
+/// One line of the queue file. A path is queued by an entry without `done`
+/// and dequeued by a later entry with it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct QueueEntry {
+    path: PathBuf,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    done: bool,
+}
+
+#[derive(Default)]
+struct QueueState {
+    /// Files waiting to be handed out by `take`.
+    pending: VecDeque<PathBuf>,
+    /// Files handed out by `take` and not yet completed. A file pushed again
+    /// meanwhile is also in `pending`, so the newer change is processed too.
+    in_flight: HashSet<PathBuf>,
+    completed_since_compaction: usize,
+}
+
+/// Files waiting to be processed, persisted as an append-only `queue.jsonl`
+/// in the output folder so work detected just before a crash is picked up
+/// again on the next start.
+pub struct WorkQueue {
+    path: PathBuf,
+    state: Mutex<QueueState>,
+    notify: Notify,
+}
+
+impl WorkQueue {
+    pub fn new(output_folder: &Path) -> Self {
+        Self {
+            path: output_folder.join(QUEUE_FILE_NAME),
+            state: Mutex::new(QueueState::default()),
+            notify: Notify::new(),
+        }
+    }
+
+    /// Loads whatever a previous run left queued and compacts the file.
+    /// Returns the number of files still pending.
+    pub async fn replay(&self) -> Result<usize> {
+        let mut state = self.state.lock().await;
+        state.pending.clear();
+        state.in_flight.clear();
+
+        if self.path.exists() {
+            let content = fs::read_to_string(&self.path).await
+                .map_err(|e| anyhow!("Failed to read work queue {}: {}", self.path.display(), e))?;
+            for (index, line) in content.lines().enumerate() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                // A crash mid-append can leave a torn last line; skip it.
+                let entry: QueueEntry = match serde_json::from_str(line) {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        warn!("Skipping unreadable line {} of {}: {}", index + 1, self.path.display(), e);
+                        continue;
+                    }
+                };
+                if entry.done {
+                    state.pending.retain(|path| *path != entry.path);
+                } else if !state.pending.contains(&entry.path) {
+                    state.pending.push_back(entry.path);
+                }
+            }
+        }
+
+        self.compact(&mut state).await?;
+
+        let pending = state.pending.len();
+        if pending > 0 {
+            info!("Replaying {} queued files from {}", pending, self.path.display());
+            self.notify.notify_one();
+        }
+        Ok(pending)
+    }
+
+    /// Queues a file unless it is already waiting. A file being processed
+    /// is queued again, since it may have changed after processing began.
+    pub async fn push(&self, path: &Path) -> Result<()> {
+        let mut state = self.state.lock().await;
+        if state.pending.iter().any(|queued| queued == path) {
+            return Ok(());
+        }
+
+        self.append(&QueueEntry { path: path.to_path_buf(), done: false }).await?;
+        state.pending.push_back(path.to_path_buf());
+        self.notify.notify_one();
+        Ok(())
+    }
+
+    /// Files currently waiting, oldest first. They stay queued until passed
+    /// to `complete`.
+    pub async fn pending(&self) -> Vec<PathBuf> {
+        self.state.lock().await.pending.iter().cloned().collect()
+    }
+
+    /// Hands out every waiting file for processing, oldest first. They stay
+    /// queued until passed to `complete`, but are no longer waiting, so
+    /// pushing one again queues it anew.
+    pub async fn take(&self) -> Vec<PathBuf> {
+        let mut state = self.state.lock().await;
+        let batch: Vec<PathBuf> = state.pending.drain(..).collect();
+        state.in_flight.extend(batch.iter().cloned());
+        batch
+    }
+
+    /// Removes a processed file from the queue, compacting the file once
+    /// enough completed entries have built up or the queue runs empty. A
+    /// file pushed again while it was being processed stays queued.
+    pub async fn complete(&self, path: &Path) -> Result<()> {
+        let mut state = self.state.lock().await;
+        if state.in_flight.remove(path) && state.pending.iter().any(|queued| queued == path) {
+            return Ok(());
+        }
+        self.append(&QueueEntry { path: path.to_path_buf(), done: true }).await?;
+        state.pending.retain(|queued| queued != path);
+        state.completed_since_compaction += 1;
+
+        let empty = state.pending.is_empty() && state.in_flight.is_empty();
+        if empty || state.completed_since_compaction >= COMPACT_AFTER_COMPLETED {
+            self.compact(&mut state).await?;
+        }
+        Ok(())
+    }
+
+    /// Waits until something is queued.
+    pub async fn notified(&self) {
+        self.notify.notified().await
+    }
+
+    async fn append(&self, entry: &QueueEntry) -> Result<()> {
+        let mut line = serde_json::to_string(entry)
+            .map_err(|e| anyhow!("Failed to serialize queue entry: {}", e))?;
+        line.push('\n');
+
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await
+            .map_err(|e| anyhow!("Failed to open work queue {}: {}", self.path.display(), e))?;
+        // Flushed before returning, so the next append or read sees this
+        // line, and synced so it survives a crash.
+        file.write_all(line.as_bytes()).await
+            .map_err(|e| anyhow!("Failed to append to work queue {}: {}", self.path.display(), e))?;
+        file.flush().await
+            .map_err(|e| anyhow!("Failed to append to work queue {}: {}", self.path.display(), e))?;
+        file.sync_data().await
+            .map_err(|e| anyhow!("Failed to sync work queue {}: {}", self.path.display(), e))
+    }
+
+    /// Rewrites the queue file with only the entries not yet completed.
+    async fn compact(&self, state: &mut QueueState) -> Result<()> {
+        let mut content = String::new();
+        for path in state.in_flight.iter().filter(|path| !state.pending.contains(path)).chain(&state.pending) {
+            let entry = QueueEntry { path: path.clone(), done: false };
+            content.push_str(&serde_json::to_string(&entry)
+                .map_err(|e| anyhow!("Failed to serialize queue entry: {}", e))?);
+            content.push('\n');
+        }
+
+        let temp_path = self.path.with_extension("jsonl.tmp");
+        fs::write(&temp_path, content).await
+            .map_err(|e| anyhow!("Failed to write work queue {}: {}", temp_path.display(), e))?;
+        fs::rename(&temp_path, &self.path).await
+            .map_err(|e| anyhow!("Failed to replace work queue {}: {}", self.path.display(), e))?;
+
+        state.completed_since_compaction = 0;
+        Ok(())
+    }
+}
@@ -0,0 +1,232 @@
+use anyhow::{anyhow, Result};
+use rusqlite::{params_from_iter, types::Value, Connection, Row};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::storage::{FileRecord, FileStatus};
+
+/// Name of the database the `sqlite` output structure keeps its records in.
+pub const SQLITE_FILE_NAME: &str = "records.sqlite";
+
+/// Columns of the records table after `relative_path`, one per `FileRecord`
+/// field. Columns missing from an older database are added when it is
+/// opened, so a field added here only needs its column listed.
+const COLUMNS: &[(&str, &str)] = &[
+    ("file_path", "TEXT NOT NULL"),
+    ("original_cid", "TEXT"),
+    ("storage_cid", "TEXT"),
+    ("purchase_id", "TEXT"),
+    ("created_at", "TEXT NOT NULL"),
+    ("updated_at", "TEXT NOT NULL"),
+    ("codex_endpoint", "TEXT"),
+    ("status", "TEXT NOT NULL"),
+    ("error", "TEXT"),
+    ("file_size", "INTEGER"),
+    ("file_modified", "TEXT"),
+    ("content_hash", "TEXT"),
+    ("pending_since", "TEXT"),
+    ("expiry_minutes", "INTEGER"),
+    ("history", "TEXT"),
+    ("filled_slots", "INTEGER"),
+    ("upload_filename", "TEXT"),
+    ("last_verified_at", "TEXT"),
+    ("storage_params", "TEXT"),
+    ("pinned", "INTEGER"),
+    ("uploaded_at", "TEXT"),
+    ("purchased_at", "TEXT"),
+    ("active_at", "TEXT"),
+];
+
+/// Records stored one row per file, keyed by the path relative to the target
+/// folder, so saving a record only writes its own row. Calls block; run them
+/// off the async runtime.
+pub struct SqliteStore {
+    connection: Connection,
+}
+
+impl SqliteStore {
+    /// Opens the database in `output_folder`, creating it and any missing
+    /// columns as needed.
+    pub fn open(output_folder: &Path) -> Result<Self> {
+        let path = output_folder.join(SQLITE_FILE_NAME);
+        let connection = Connection::open(&path)
+            .map_err(|e| anyhow!("Failed to open record database {}: {}", path.display(), e))?;
+        // Waits out another writer, such as a mirror sync, instead of failing.
+        connection.busy_timeout(std::time::Duration::from_secs(30))
+            .map_err(|e| anyhow!("Failed to configure record database {}: {}", path.display(), e))?;
+
+        let store = Self { connection };
+        store.migrate()
+            .map_err(|e| anyhow!("Failed to prepare record database {}: {}", path.display(), e))?;
+        Ok(store)
+    }
+
+    /// Whether `output_folder` holds a record database.
+    pub fn exists(output_folder: &Path) -> bool {
+        output_folder.join(SQLITE_FILE_NAME).exists()
+    }
+
+    fn migrate(&self) -> rusqlite::Result<()> {
+        let columns: Vec<String> = COLUMNS.iter().map(|(name, kind)| format!("{} {}", name, kind)).collect();
+        self.connection.execute(
+            &format!("CREATE TABLE IF NOT EXISTS records (relative_path TEXT PRIMARY KEY, {})", columns.join(", ")),
+            [],
+        )?;
+
+        let existing: Vec<String> = self.connection.prepare("SELECT name FROM pragma_table_info('records')")?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        for (name, kind) in COLUMNS {
+            if !existing.iter().any(|column| column == name) {
+                // A NOT NULL column can't be added to existing rows without a default.
+                let kind = kind.trim_end_matches(" NOT NULL");
+                self.connection.execute(&format!("ALTER TABLE records ADD COLUMN {} {}", name, kind), [])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Every record, keyed by its path under `target_folder`.
+    pub fn load(&self, target_folder: &Path) -> Result<HashMap<PathBuf, FileRecord>> {
+        let names: Vec<&str> = COLUMNS.iter().map(|(name, _)| *name).collect();
+        let mut statement = self.connection
+            .prepare(&format!("SELECT relative_path, {} FROM records", names.join(", ")))
+            .map_err(|e| anyhow!("Failed to read record database: {}", e))?;
+        let rows = statement.query_map([], |row| Ok((row.get::<_, String>(0)?, read_record(row))))
+            .map_err(|e| anyhow!("Failed to read record database: {}", e))?;
+
+        let mut records = HashMap::new();
+        for row in rows {
+            let (relative_path, record) = row.map_err(|e| anyhow!("Failed to read record database: {}", e))?;
+            let record = record.map_err(|e| anyhow!("Failed to parse record for {}: {}", relative_path, e))?;
+            records.insert(target_folder.join(&relative_path), record);
+        }
+        Ok(records)
+    }
+
+    /// Inserts or replaces the row for `relative_path`, leaving every other
+    /// row untouched.
+    pub fn upsert(&self, relative_path: &str, record: &FileRecord) -> Result<()> {
+        self.connection.execute(&upsert_sql(), params_from_iter(record_values(relative_path, record)?))
+            .map_err(|e| anyhow!("Failed to save record for {}: {}", relative_path, e))?;
+        Ok(())
+    }
+
+    /// Upserts every record in one transaction.
+    pub fn upsert_all<'a>(&mut self, records: impl IntoIterator<Item = (String, &'a FileRecord)>) -> Result<()> {
+        let transaction = self.connection.transaction()
+            .map_err(|e| anyhow!("Failed to start record database transaction: {}", e))?;
+        {
+            let mut statement = transaction.prepare(&upsert_sql())
+                .map_err(|e| anyhow!("Failed to prepare record upsert: {}", e))?;
+            for (relative_path, record) in records {
+                statement.execute(params_from_iter(record_values(&relative_path, record)?))
+                    .map_err(|e| anyhow!("Failed to save record for {}: {}", relative_path, e))?;
+            }
+        }
+        transaction.commit()
+            .map_err(|e| anyhow!("Failed to commit records: {}", e))
+    }
+
+    pub fn remove(&self, relative_path: &str) -> Result<()> {
+        self.connection.execute("DELETE FROM records WHERE relative_path = ?1", [relative_path])
+            .map_err(|e| anyhow!("Failed to remove record for {}: {}", relative_path, e))?;
+        Ok(())
+    }
+}
+
+fn upsert_sql() -> String {
+    let names: Vec<&str> = COLUMNS.iter().map(|(name, _)| *name).collect();
+    let placeholders: Vec<String> = (1..=COLUMNS.len() + 1).map(|index| format!("?{}", index)).collect();
+    let updates: Vec<String> = names.iter().map(|name| format!("{} = excluded.{}", name, name)).collect();
+    format!(
+        "INSERT INTO records (relative_path, {}) VALUES ({}) ON CONFLICT(relative_path) DO UPDATE SET {}",
+        names.join(", "), placeholders.join(", "), updates.join(", ")
+    )
+}
+
+/// Column values for `record`, in `COLUMNS` order after `relative_path`.
+fn record_values(relative_path: &str, record: &FileRecord) -> Result<Vec<Value>> {
+    let text = |value: Option<String>| value.map_or(Value::Null, Value::Text);
+    let integer = |value: Option<i64>| value.map_or(Value::Null, Value::Integer);
+    let timestamp = |value: Option<chrono::DateTime<chrono::Utc>>| text(value.map(|at| at.to_rfc3339()));
+    let json = |value: serde_json::Result<String>| value
+        .map(Value::Text)
+        .map_err(|e| anyhow!("Failed to serialize record for {}: {}", relative_path, e));
+
+    let status = match serde_json::to_value(&record.status) {
+        Ok(serde_json::Value::String(status)) => status,
+        _ => return Err(anyhow!("Failed to serialize status of record for {}", relative_path)),
+    };
+    let file_size = record.file_size
+        .map(|size| i64::try_from(size).map_err(|_| anyhow!("File size {} of {} is too large to store", size, relative_path)))
+        .transpose()?;
+
+    Ok(vec![
+        Value::Text(relative_path.to_string()),
+        Value::Text(record.file_path.to_string_lossy().into_owned()),
+        text(record.original_cid.clone()),
+        text(record.storage_cid.clone()),
+        text(record.purchase_id.clone()),
+        Value::Text(record.created_at.to_rfc3339()),
+        Value::Text(record.updated_at.to_rfc3339()),
+        text(record.codex_endpoint.clone()),
+        Value::Text(status),
+        text(record.error.clone()),
+        integer(file_size),
+        timestamp(record.file_modified),
+        text(record.content_hash.clone()),
+        timestamp(record.pending_since),
+        integer(record.expiry_minutes.map(i64::from)),
+        json(serde_json::to_string(&record.history))?,
+        integer(record.filled_slots.map(i64::from)),
+        text(record.upload_filename.clone()),
+        timestamp(record.last_verified_at),
+        match &record.storage_params {
+            Some(params) => json(serde_json::to_string(params))?,
+            None => Value::Null,
+        },
+        Value::Integer(record.pinned.into()),
+        timestamp(record.uploaded_at),
+        timestamp(record.purchased_at),
+        timestamp(record.active_at),
+    ])
+}
+
+/// Reads the `COLUMNS` after `relative_path` back into a record.
+fn read_record(row: &Row) -> Result<FileRecord> {
+    let status: String = row.get("status")?;
+    let status: FileStatus = serde_json::from_value(serde_json::Value::String(status))?;
+    let history: Option<String> = row.get("history")?;
+    let storage_params: Option<String> = row.get("storage_params")?;
+    let file_size: Option<i64> = row.get("file_size")?;
+
+    Ok(FileRecord {
+        file_path: PathBuf::from(row.get::<_, String>("file_path")?),
+        original_cid: row.get("original_cid")?,
+        storage_cid: row.get("storage_cid")?,
+        purchase_id: row.get("purchase_id")?,
+        created_at: row.get("created_at")?,
+        updated_at: row.get("updated_at")?,
+        codex_endpoint: row.get("codex_endpoint")?,
+        status,
+        error: row.get("error")?,
+        file_size: file_size.map(|size| size as u64),
+        file_modified: row.get("file_modified")?,
+        content_hash: row.get("content_hash")?,
+        pending_since: row.get("pending_since")?,
+        expiry_minutes: row.get("expiry_minutes")?,
+        history: history.map(|history| serde_json::from_str(&history)).transpose()?.unwrap_or_default(),
+        filled_slots: row.get("filled_slots")?,
+        upload_filename: row.get("upload_filename")?,
+        last_verified_at: row.get("last_verified_at")?,
+        storage_params: storage_params.map(|params| serde_json::from_str(&params)).transpose()?,
+        pinned: row.get::<_, Option<bool>>("pinned")?.unwrap_or(false),
+        uploaded_at: row.get("uploaded_at")?,
+        purchased_at: row.get("purchased_at")?,
+        active_at: row.get("active_at")?,
+        // Columns added by a newer version aren't read, and are left as they
+        // are by the upsert, which only sets the columns listed here.
+        extra: serde_json::Map::new(),
+    })
+}
@@ -1,12 +1,91 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use tokio::fs;
-use log::{info, error, debug};
+use log::{info, debug, warn};
 use walkdir::WalkDir;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use sha2::{Digest, Sha256};
+use std::io::{Read, Write};
+use std::sync::{Arc, OnceLock, Weak};
+
+use crate::sqlite_store::SqliteStore;
+
+/// Name of the single record file used by the flattened structure.
+const FLATTENED_FILE_NAME: &str = "files.json";
+
+/// How long saved flattened records may wait in memory before they are
+/// written out. Saves within that time share one write.
+const FLATTENED_FLUSH_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// The flattened record file of one output folder, parsed once and then
+/// updated in memory, so a save doesn't re-read and rewrite the whole file.
+/// Every live manager writing the folder shares it, which also serializes
+/// their updates; it is dropped with the last of them.
+#[derive(Default)]
+struct FlattenedIndex {
+    /// Records by path relative to the target folder, or `None` until the
+    /// file is first read.
+    records: Option<HashMap<String, FlattenedRecord>>,
+    /// Whether `records` has changes the file doesn't.
+    dirty: bool,
+    /// Whether a delayed write is already on its way.
+    flush_scheduled: bool,
+    /// Times the file was parsed.
+    parses: usize,
+}
+
+type SharedFlattenedIndex = Arc<tokio::sync::Mutex<FlattenedIndex>>;
+
+/// The flattened indexes a manager and its clones hold for the output
+/// folder and its mirrors, by folder as configured.
+type FlattenedIndexes = Arc<std::sync::Mutex<HashMap<PathBuf, SharedFlattenedIndex>>>;
+
+/// The indexes held by live managers, by canonical folder, so that managers
+/// spelling the same folder differently find the same one.
+static LIVE_FLATTENED_INDEXES: OnceLock<std::sync::Mutex<HashMap<PathBuf, Weak<tokio::sync::Mutex<FlattenedIndex>>>>> = OnceLock::new();
+
+/// The index another live manager holds for `folder`, or a new one.
+fn live_flattened_index(folder: &Path) -> SharedFlattenedIndex {
+    let key = folder.canonicalize().unwrap_or_else(|_| folder.to_path_buf());
+    let mut indexes = LIVE_FLATTENED_INDEXES.get_or_init(Default::default).lock().unwrap();
+    indexes.retain(|_, index| index.strong_count() > 0);
+    if let Some(index) = indexes.get(&key).and_then(Weak::upgrade) {
+        return index;
+    }
+    let index = SharedFlattenedIndex::default();
+    indexes.insert(key, Arc::downgrade(&index));
+    index
+}
+
+/// Records which target folder an output folder belongs to.
+pub const METADATA_FILE_NAME: &str = "filehog-meta.json";
+
+/// Files FileHog keeps at the root of the output folder that are not
+/// structured records. A structured record that would take one of these
+/// names, such as that of `usage.txt`, gets a `~N` suffixed name instead.
+const RESERVED_OUTPUT_FILES: &[&str] = &[
+    FLATTENED_FILE_NAME,
+    METADATA_FILE_NAME,
+    crate::usage::USAGE_FILE_NAME,
+    crate::file_processor::SCAN_CHECKPOINT_FILE_NAME,
+    crate::queue::QUEUE_FILE_NAME,
+];
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutputMetadata {
+    pub target_folder: PathBuf,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// One file's record. Records outlive the binary that wrote them, so unknown
+/// fields are kept in `extra` and written back rather than denied, and every
+/// field added after the first release is an `Option` or `#[serde(default)]`:
+/// older and newer versions can then read each other's records.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileRecord {
     pub file_path: PathBuf,
@@ -18,9 +97,89 @@ pub struct FileRecord {
     pub codex_endpoint: Option<String>,
     pub status: FileStatus,
     pub error: Option<String>,
+    #[serde(default)]
+    pub file_size: Option<u64>,
+    #[serde(default)]
+    pub file_modified: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub content_hash: Option<String>,
+    /// When the current purchase was first seen in a non-terminal state that
+    /// wasn't `started`.
+    #[serde(default)]
+    pub pending_since: Option<DateTime<Utc>>,
+    /// How long the current storage request was left open for hosts to accept
+    /// it. Older records fall back to the configured `expiry_minutes`.
+    #[serde(default)]
+    pub expiry_minutes: Option<u32>,
+    /// Uploads and purchases this record was previously tracking, oldest
+    /// first.
+    #[serde(default)]
+    pub history: Vec<HistoryEntry>,
+    /// Slots of the current purchase hosts had filled when last checked, for
+    /// nodes that report it.
+    #[serde(default)]
+    pub filled_slots: Option<u32>,
+    /// Filename sent to the node with the upload.
+    #[serde(default)]
+    pub upload_filename: Option<String>,
+    /// When a scheduled verification last found the CID retrievable, or
+    /// failed to.
+    #[serde(default)]
+    pub last_verified_at: Option<DateTime<Utc>>,
+    /// Storage params the current purchase was requested with.
+    #[serde(default)]
+    pub storage_params: Option<crate::config::StorageParams>,
+    /// Matched by `pinned_paths` or `pinned_cids` when last processed.
+    #[serde(default)]
+    pub pinned: bool,
+    /// When the current CID was uploaded.
+    #[serde(default)]
+    pub uploaded_at: Option<DateTime<Utc>>,
+    /// When the current purchase was requested.
+    #[serde(default)]
+    pub purchased_at: Option<DateTime<Utc>>,
+    /// When the current purchase was first seen started. Records written
+    /// before this was kept count from `created_at` instead.
+    #[serde(default)]
+    pub active_at: Option<DateTime<Utc>>,
+    /// Fields written by a newer version that this one doesn't know, kept so
+    /// that saving the record doesn't drop them.
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct HistoryEntry {
+    pub original_cid: Option<String>,
+    pub storage_cid: Option<String>,
+    pub purchase_id: Option<String>,
+    pub status: FileStatus,
+    pub archived_at: DateTime<Utc>,
+    pub reason: String,
+}
+
+impl FileRecord {
+    /// When the current purchase ends, for records with active storage: the
+    /// duration it was requested with, or `duration` if that wasn't stored,
+    /// from when it started.
+    pub fn expires_at(&self, duration: chrono::Duration) -> Option<DateTime<Utc>> {
+        match self.status {
+            FileStatus::Active | FileStatus::Degraded => {
+                let duration = self.storage_params.as_ref()
+                    .map_or(duration, |params| chrono::Duration::days(params.duration_days.into()));
+                Some(self.storage_start() + duration)
+            }
+            _ => None,
+        }
+    }
+    
+    /// When the current purchase started, as far as the record knows.
+    pub fn storage_start(&self) -> DateTime<Utc> {
+        self.active_at.unwrap_or(self.created_at)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq)]
 pub enum FileStatus {
     New,
     Uploading,
@@ -28,6 +187,66 @@ pub enum FileStatus {
     Active,
     Failed,
     Expired,
+    /// Recorded by a `--baseline` scan without being uploaded. Only uploaded
+    /// once the file changes.
+    Baseline,
+    /// Processing was deferred and will be retried; `error` says why.
+    Pending,
+    /// The purchase didn't start within the expiry window, or before FileHog
+    /// shut down; purchase monitoring keeps polling it until both
+    /// `max_wait_for_start_minutes` and the purchase's `expiry_minutes` have
+    /// passed since `pending_since`.
+    WaitingLong,
+    /// The purchase is still reported as started, but the last scheduled
+    /// verification couldn't retrieve the CID from any endpoint.
+    Degraded,
+    /// Seen by a `--dry-run` scan, which logged the upload and storage
+    /// request it would have made. Processed for real by the next run
+    /// without `--dry-run`.
+    DryRun,
+    /// A status written by a newer version that this one doesn't know, by the
+    /// name it was written with, which is also what it is saved as. The record
+    /// is loaded, but left alone by processing and purchase monitoring.
+    #[serde(untagged)]
+    Unknown(String),
+}
+
+/// Statuses print as their variant name, which is what status filters, counts
+/// and tables match on; every unknown status prints as `Unknown`.
+impl std::fmt::Debug for FileStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            FileStatus::New => "New",
+            FileStatus::Uploading => "Uploading",
+            FileStatus::Creating => "Creating",
+            FileStatus::Active => "Active",
+            FileStatus::Failed => "Failed",
+            FileStatus::Expired => "Expired",
+            FileStatus::Baseline => "Baseline",
+            FileStatus::Pending => "Pending",
+            FileStatus::WaitingLong => "WaitingLong",
+            FileStatus::Degraded => "Degraded",
+            FileStatus::DryRun => "DryRun",
+            FileStatus::Unknown(_) => "Unknown",
+        })
+    }
+}
+
+impl FileStatus {
+    pub const ALL: [FileStatus; 12] = [
+        FileStatus::New,
+        FileStatus::Uploading,
+        FileStatus::Creating,
+        FileStatus::Active,
+        FileStatus::Failed,
+        FileStatus::Expired,
+        FileStatus::Baseline,
+        FileStatus::Pending,
+        FileStatus::WaitingLong,
+        FileStatus::Degraded,
+        FileStatus::DryRun,
+        FileStatus::Unknown(String::new()),
+    ];
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -37,9 +256,29 @@ pub struct FlattenedRecord {
     pub record: FileRecord,
 }
 
+/// Extension appended to record files when `compress_records` is on.
+const COMPRESSED_EXTENSION: &str = "gz";
+
+/// Directory levels added in front of structured records by `shard_records`.
+/// Each level is one byte of the path hash, so at most 256 entries wide.
+const SHARD_LEVELS: usize = 2;
+
+#[derive(Clone)]
 pub struct StorageManager {
     output_folder: PathBuf,
     output_structure: crate::config::OutputStructure,
+    compress: bool,
+    shard: bool,
+    collision: crate::config::RecordCollision,
+    /// Further output folders every save is copied to, best effort.
+    mirrors: Vec<PathBuf>,
+    /// Where status transitions are logged, from `event_log_path`.
+    event_log: Option<crate::event_log::EventLog>,
+    /// The configured storage duration, for records that don't store the
+    /// params they were purchased with.
+    duration: chrono::Duration,
+    /// Shared with clones and mirror managers; see `FlattenedIndex`.
+    flattened_indexes: FlattenedIndexes,
 }
 
 impl StorageManager {
@@ -47,10 +286,166 @@ impl StorageManager {
         Self {
             output_folder,
             output_structure,
+            compress: false,
+            shard: false,
+            collision: crate::config::RecordCollision::default(),
+            mirrors: Vec::new(),
+            event_log: None,
+            duration: chrono::Duration::days(crate::config::StorageParams::default().duration_days.into()),
+            flattened_indexes: FlattenedIndexes::default(),
+        }
+    }
+    
+    pub fn from_config(config: &crate::config::Config) -> Self {
+        Self::for_structure(config, config.output_structure.clone())
+    }
+    
+    /// Like `from_config`, but reading and writing `output_structure` instead
+    /// of the configured one.
+    pub fn for_structure(config: &crate::config::Config, output_structure: crate::config::OutputStructure) -> Self {
+        Self {
+            output_folder: config.output_folder.clone(),
+            output_structure,
+            compress: config.compress_records,
+            shard: config.shard_records,
+            collision: config.record_collision,
+            mirrors: config.output_mirrors.clone(),
+            event_log: config.event_log_path.as_deref().map(crate::event_log::EventLog::shared),
+            duration: chrono::Duration::days(config.storage_params.duration_days.into()),
+            flattened_indexes: FlattenedIndexes::default(),
+        }
+    }
+    
+    /// A manager for one mirror folder, writing the same structure as this one.
+    fn for_mirror(&self, folder: &Path) -> Self {
+        Self {
+            output_folder: folder.to_path_buf(),
+            output_structure: self.output_structure.clone(),
+            compress: self.compress,
+            shard: self.shard,
+            collision: self.collision,
+            mirrors: Vec::new(),
+            event_log: None,
+            duration: self.duration,
+            flattened_indexes: self.flattened_indexes.clone(),
+        }
+    }
+    
+    /// The index of the flattened records in this manager's output folder.
+    fn flattened_index(&self) -> SharedFlattenedIndex {
+        let mut indexes = self.flattened_indexes.lock().unwrap();
+        indexes.entry(self.output_folder.clone())
+            .or_insert_with(|| live_flattened_index(&self.output_folder))
+            .clone()
+    }
+    
+    /// Checks that the output folder's records were written for
+    /// `target_folder`. With `rebase`, records written for a different target
+    /// are re-pointed at this one instead of failing.
+    pub async fn check_target_folder(&self, target_folder: &Path, rebase: bool) -> Result<()> {
+        let canonical_target = target_folder.canonicalize()
+            .with_context(|| format!("Failed to resolve target folder {}", target_folder.display()))?;
+        let metadata_file = self.output_folder.join(METADATA_FILE_NAME);
+        
+        if metadata_file.exists() {
+            let content = fs::read_to_string(&metadata_file).await
+                .with_context(|| format!("Failed to read {}", metadata_file.display()))?;
+            let metadata: OutputMetadata = serde_json::from_str(&content)
+                .with_context(|| format!("Failed to parse {}", metadata_file.display()))?;
+            
+            if metadata.target_folder == canonical_target {
+                return Ok(());
+            }
+            
+            if !rebase {
+                return Err(anyhow!(
+                    "Output folder {} holds records for target folder {}, but the configured target folder is {}. \
+                     Pass --rebase to re-point the existing records at the new target folder",
+                    self.output_folder.display(),
+                    metadata.target_folder.display(),
+                    canonical_target.display()
+                ));
+            }
+            
+            self.rebase_records(&metadata.target_folder, &canonical_target, target_folder).await?;
+        }
+        
+        let metadata = OutputMetadata {
+            target_folder: canonical_target,
+            updated_at: Utc::now(),
+        };
+        let content = serde_json::to_string_pretty(&metadata)
+            .map_err(|e| anyhow!("Failed to serialize output metadata: {}", e))?;
+        fs::create_dir_all(&self.output_folder).await
+            .map_err(|e| anyhow!("Failed to create output folder {}: {}", self.output_folder.display(), e))?;
+        fs::write(&metadata_file, content).await
+            .map_err(|e| anyhow!("Failed to write {}: {}", metadata_file.display(), e))?;
+        
+        Ok(())
+    }
+    
+    async fn rebase_records(&self, old_target: &Path, canonical_target: &Path, target_folder: &Path) -> Result<()> {
+        let old_records = self.load_existing_records(old_target).await?;
+        let mut rebased = HashMap::with_capacity(old_records.len());
+        
+        for (old_path, mut record) in old_records.clone() {
+            let relative_path = old_path.strip_prefix(old_target)
+                .map_err(|e| anyhow!("Failed to get relative path: {}", e))?;
+            let new_path = target_folder.join(relative_path);
+            record.file_path = new_path.clone();
+            rebased.insert(new_path, record);
         }
+        
+        self.remove_all_records(old_target, &old_records).await?;
+        self.save_all_records(target_folder, &rebased).await?;
+        
+        info!("Rebased {} records from {} to {}", rebased.len(), old_target.display(), canonical_target.display());
+        Ok(())
     }
     
+    /// Loads the records from the output folder, or with mirrors, from
+    /// whichever readable copy was updated most recently (the primary on a
+    /// tie). Mirrors that can't be read are skipped with a warning.
     pub async fn load_existing_records(&self, target_folder: &Path) -> Result<HashMap<PathBuf, FileRecord>> {
+        let primary = self.load_records_here(target_folder).await;
+        if self.mirrors.is_empty() {
+            return primary;
+        }
+        
+        let newest = |records: &HashMap<PathBuf, FileRecord>| records.values().map(|record| record.updated_at).max();
+        let mut chosen = match primary {
+            Ok(records) => Some((self.output_folder.clone(), records)),
+            Err(e) => {
+                warn!("Failed to load records from {}, trying mirrors: {}", self.output_folder.display(), e);
+                None
+            }
+        };
+        
+        for folder in &self.mirrors {
+            if !folder.exists() {
+                continue;
+            }
+            match self.for_mirror(folder).load_records_here(target_folder).await {
+                Ok(records) => {
+                    let is_newer = chosen.as_ref().is_none_or(|(_, current)| newest(&records) > newest(current));
+                    if is_newer {
+                        chosen = Some((folder.clone(), records));
+                    }
+                }
+                Err(e) => warn!("Failed to load records from mirror {}: {}", folder.display(), e),
+            }
+        }
+        
+        let (folder, records) = chosen
+            .ok_or_else(|| anyhow!("Failed to load records from {} or any mirror", self.output_folder.display()))?;
+        if folder != self.output_folder {
+            warn!("Using records from mirror {}, which are newer than those in {}",
+                  folder.display(), self.output_folder.display());
+        }
+        Ok(records)
+    }
+    
+    async fn load_records_here(&self, target_folder: &Path) -> Result<HashMap<PathBuf, FileRecord>> {
         let mut records = HashMap::new();
         
         match self.output_structure {
@@ -60,60 +455,261 @@ impl StorageManager {
             crate::config::OutputStructure::Structured => {
                 self.load_structured_records(&mut records, target_folder).await?;
             }
+            crate::config::OutputStructure::Sqlite => {
+                if SqliteStore::exists(&self.output_folder) {
+                    let target_folder = target_folder.to_path_buf();
+                    records = self.with_sqlite(move |store| store.load(&target_folder)).await?;
+                }
+            }
         }
         
         info!("Loaded {} existing file records", records.len());
         Ok(records)
     }
     
+    /// Reads the flattened record file afresh, after writing out any saves
+    /// still held in memory, and refreshes the shared index from it.
     async fn load_flattened_records(&self, records: &mut HashMap<PathBuf, FileRecord>, target_folder: &Path) -> Result<()> {
-        let flattened_file = self.output_folder.join("files.json");
+        let index = self.flattened_index();
+        let mut index = index.lock().await;
+        if index.dirty {
+            self.write_flattened_index(&mut index).await?;
+        }
         
-        if !flattened_file.exists() {
-            return Ok(());
+        let flattened_records = self.read_flattened_file(&mut index).await?;
+        for flattened in flattened_records.values() {
+            let full_path = target_folder.join(&flattened.relative_path);
+            records.insert(full_path, flattened.record.clone());
         }
+        index.records = Some(flattened_records);
         
-        let content = fs::read_to_string(&flattened_file).await
-            .map_err(|e| anyhow!("Failed to read flattened records file: {}", e))?;
+        Ok(())
+    }
+    
+    /// Parses the flattened record file, which is empty when missing.
+    async fn read_flattened_file(&self, index: &mut FlattenedIndex) -> Result<HashMap<String, FlattenedRecord>> {
+        // Prefer the configured format, but still read the other one so that
+        // toggling `compress_records` doesn't lose the existing records.
+        let plain = self.output_folder.join(FLATTENED_FILE_NAME);
+        let compressed = Self::compressed_path(&plain);
+        let candidates = if self.compress { [compressed, plain] } else { [plain, compressed] };
         
+        let Some(flattened_file) = candidates.into_iter().find(|path| path.exists()) else {
+            return Ok(HashMap::new());
+        };
+        
+        let content = Self::read_record_file(&flattened_file).await
+            .context("Failed to read flattened records file")?;
+        
+        index.parses += 1;
         let flattened_records: Vec<FlattenedRecord> = serde_json::from_str(&content)
-            .map_err(|e| anyhow!("Failed to parse flattened records: {}", e))?;
+            .context("Failed to parse flattened records")?;
         
-        for flattened in flattened_records {
-            let full_path = target_folder.join(&flattened.relative_path);
-            records.insert(full_path, flattened.record);
+        Ok(flattened_records.into_iter()
+            .map(|flattened| (flattened.relative_path.clone(), flattened))
+            .collect())
+    }
+    
+    /// Writes the index to the flattened record file, sorted by path.
+    async fn write_flattened_index(&self, index: &mut FlattenedIndex) -> Result<()> {
+        let mut flattened_records: Vec<&FlattenedRecord> = index.records.iter().flat_map(|records| records.values()).collect();
+        flattened_records.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+        
+        let content = serde_json::to_string_pretty(&flattened_records)
+            .map_err(|e| anyhow!("Failed to serialize flattened records: {}", e))?;
+        
+        self.write_record_file(&self.output_folder.join(FLATTENED_FILE_NAME), content).await
+            .map_err(|e| anyhow!("Failed to write flattened records: {}", e))?;
+        index.dirty = false;
+        Ok(())
+    }
+    
+    /// Writes out flattened records saved since the last write, here and in
+    /// every mirror. Call it before exiting and after a batch of saves;
+    /// otherwise they are written at most `FLATTENED_FLUSH_DELAY` later.
+    pub async fn flush(&self) -> Result<()> {
+        if self.output_structure != crate::config::OutputStructure::Flattened {
+            return Ok(());
         }
         
+        self.flush_here().await?;
+        for folder in &self.mirrors {
+            if let Err(e) = self.for_mirror(folder).flush_here().await {
+                warn!("Failed to flush mirrored records to {}: {}", folder.display(), e);
+            }
+        }
         Ok(())
     }
     
+    async fn flush_here(&self) -> Result<()> {
+        let index = self.flattened_index();
+        let mut index = index.lock().await;
+        if index.dirty {
+            self.write_flattened_index(&mut index).await?;
+        }
+        Ok(())
+    }
+    
+    /// How many times the flattened record file in the output folder has
+    /// been parsed since its index was created.
+    pub async fn flattened_parse_count(&self) -> usize {
+        self.flattened_index().lock().await.parses
+    }
+    
     async fn load_structured_records(&self, records: &mut HashMap<PathBuf, FileRecord>, target_folder: &Path) -> Result<()> {
-        let walker = WalkDir::new(&self.output_folder);
+        for (path, relative_output_path, record) in self.structured_record_files().await? {
+            // The record file name drops the original extension, so prefer
+            // the stored path whenever it belongs under this target. That also
+            // finds records left in the other layout after toggling
+            // `shard_records`, which loading leaves where they are; see
+            // `move_structured_records`.
+            let original_path = if record.file_path.starts_with(target_folder) {
+                record.file_path.clone()
+            } else {
+                self.output_path_to_original_path(&relative_output_path, target_folder)?
+            };
+            
+            // A record in each layout: the one saved last is current.
+            match records.get(&original_path) {
+                Some(loaded) if loaded.updated_at >= record.updated_at => {
+                    debug!("Ignoring older record file {} for {}", path.display(), original_path.display());
+                }
+                _ => {
+                    records.insert(original_path, record);
+                }
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// Every structured record file in the output folder, with its path
+    /// relative to the folder without any compression extension, and the
+    /// record it holds.
+    async fn structured_record_files(&self) -> Result<Vec<(PathBuf, PathBuf, FileRecord)>> {
+        let entries = WalkDir::new(&self.output_folder)
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| anyhow!("Failed to read output directory: {}", e))?;
+        let mut files = Vec::new();
         
-        for entry in walker {
-            let entry = entry.map_err(|e| anyhow!("Failed to read output directory: {}", e))?;
+        for entry in entries {
             let path = entry.path();
             
-            if path.is_file() && path.extension().map_or(false, |ext| ext == "json") {
-                let relative_output_path = path.strip_prefix(&self.output_folder)
+            if !path.is_file() {
+                continue;
+            }
+            
+            let uncompressed_path = Self::strip_compressed_extension(path);
+            
+            if uncompressed_path.extension().is_some_and(|ext| ext == "json") {
+                let relative_output_path = uncompressed_path.strip_prefix(&self.output_folder)
                     .map_err(|e| anyhow!("Failed to get relative path: {}", e))?;
                 
-                let original_path = self.output_path_to_original_path(relative_output_path, target_folder)?;
+                if RESERVED_OUTPUT_FILES.iter().any(|name| relative_output_path == Path::new(name)) {
+                    continue;
+                }
                 
-                let content = fs::read_to_string(path).await
-                    .map_err(|e| anyhow!("Failed to read record file {}: {}", path.display(), e))?;
+                let content = Self::read_record_file(path).await
+                    .with_context(|| format!("Failed to read record file {}", path.display()))?;
                 
                 let record: FileRecord = serde_json::from_str(&content)
-                    .map_err(|e| anyhow!("Failed to parse record from {}: {}", path.display(), e))?;
+                    .with_context(|| format!("Failed to parse record from {}", path.display()))?;
                 
-                records.insert(original_path, record);
+                files.push((path.to_path_buf(), relative_output_path.to_path_buf(), record));
             }
         }
         
-        Ok(())
+        Ok(files)
+    }
+    
+    /// Moves structured records left in the other layout after toggling
+    /// `shard_records` to where this layout expects them, here and in every
+    /// mirror. Where a record was since saved in this layout too, the older
+    /// of the two is removed. Loading leaves records where they are, so
+    /// this is only done when FileHog starts watching. Returns how many
+    /// record files moved in the output folder.
+    pub async fn move_structured_records(&self, target_folder: &Path) -> Result<usize> {
+        if self.output_structure != crate::config::OutputStructure::Structured || !self.output_folder.exists() {
+            return Ok(0);
+        }
+        
+        let moved = self.move_structured_records_here(target_folder).await?;
+        for folder in &self.mirrors {
+            if !folder.exists() {
+                continue;
+            }
+            if let Err(e) = self.for_mirror(folder).move_structured_records_here(target_folder).await {
+                warn!("Failed to move mirrored records in {}: {}", folder.display(), e);
+            }
+        }
+        Ok(moved)
+    }
+    
+    async fn move_structured_records_here(&self, target_folder: &Path) -> Result<usize> {
+        let mut moved = 0;
+        
+        for (path, _, record) in self.structured_record_files().await? {
+            let uncompressed_path = Self::strip_compressed_extension(&path);
+            let Ok(expected) = self.structured_record_path(target_folder, &record.file_path) else {
+                continue;
+            };
+            if expected == uncompressed_path || Self::is_collision_path(&uncompressed_path, &expected) {
+                continue;
+            }
+            
+            let destination = self.structured_record_slot(target_folder, &record.file_path).await?;
+            if Self::structured_record_exists(&destination) {
+                let current = Self::read_structured_record(&destination).await;
+                if current.is_some_and(|current| current.updated_at >= record.updated_at) {
+                    Self::remove_record_file(&uncompressed_path).await?;
+                    continue;
+                }
+                Self::remove_record_file(&destination).await?;
+            }
+            Self::move_record_file(&path, &uncompressed_path, &destination).await?;
+            moved += 1;
+        }
+        
+        if moved > 0 {
+            info!("Moved {} record files in {} into the {} layout",
+                  moved, self.output_folder.display(), if self.shard { "sharded" } else { "unsharded" });
+        }
+        
+        Ok(moved)
+    }
+    
+    /// Moves a record file found at `path` (whose uncompressed form is
+    /// `uncompressed_path`) to `expected`, keeping any compression suffix.
+    async fn move_record_file(path: &Path, uncompressed_path: &Path, expected: &Path) -> Result<()> {
+        let destination = if path == uncompressed_path { expected.to_path_buf() } else { Self::compressed_path(expected) };
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent).await
+                .map_err(|e| anyhow!("Failed to create output directory {}: {}", parent.display(), e))?;
+        }
+        fs::rename(path, &destination).await
+            .map_err(|e| anyhow!("Failed to move record {} to {}: {}", path.display(), destination.display(), e))
     }
     
+    /// Saves to the output folder, then to each mirror. Only a failure in the
+    /// output folder is an error; mirror failures are logged.
     pub async fn save_record(&self, target_folder: &Path, file_path: &Path, record: &FileRecord) -> Result<()> {
+        self.save_record_here(target_folder, file_path, record).await?;
+        
+        for folder in &self.mirrors {
+            let mirror = self.for_mirror(folder);
+            let result = match fs::create_dir_all(folder).await {
+                Ok(()) => mirror.save_record_here(target_folder, file_path, record).await,
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = result {
+                warn!("Failed to mirror record for {} to {}: {}", file_path.display(), folder.display(), e);
+            }
+        }
+        Ok(())
+    }
+    
+    async fn save_record_here(&self, target_folder: &Path, file_path: &Path, record: &FileRecord) -> Result<()> {
         match self.output_structure {
             crate::config::OutputStructure::Flattened => {
                 self.save_flattened_record(target_folder, file_path, record).await
@@ -121,54 +717,410 @@ impl StorageManager {
             crate::config::OutputStructure::Structured => {
                 self.save_structured_record(target_folder, file_path, record).await
             }
+            crate::config::OutputStructure::Sqlite => {
+                let relative_path = Self::relative_path(target_folder, file_path)?;
+                let record = record.clone();
+                self.with_sqlite(move |store| store.upsert(&relative_path, &record)).await?;
+                debug!("Saved record for {} to the record database", file_path.display());
+                Ok(())
+            }
         }
     }
     
+    /// Runs `work` against the record database in the output folder, on a
+    /// blocking thread.
+    async fn with_sqlite<T: Send + 'static>(
+        &self,
+        work: impl FnOnce(&mut SqliteStore) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let folder = self.output_folder.clone();
+        tokio::task::spawn_blocking(move || work(&mut SqliteStore::open(&folder)?))
+            .await
+            .map_err(|e| anyhow!("Record database task failed: {}", e))?
+    }
+    
     async fn save_flattened_record(&self, target_folder: &Path, file_path: &Path, new_record: &FileRecord) -> Result<()> {
-        let flattened_file = self.output_folder.join("files.json");
+        let relative_path = Self::relative_path(target_folder, file_path)?;
+        self.update_flattened_index(|records| {
+            records.insert(relative_path.clone(), FlattenedRecord {
+                relative_path,
+                record: new_record.clone(),
+            });
+        }).await?;
         
-        let mut records = if flattened_file.exists() {
-            let content = fs::read_to_string(&flattened_file).await
-                .map_err(|e| anyhow!("Failed to read existing flattened file: {}", e))?;
-            serde_json::from_str::<Vec<FlattenedRecord>>(&content)
-                .map_err(|e| anyhow!("Failed to parse existing flattened file: {}", e))?
-        } else {
-            Vec::new()
-        };
+        debug!("Saved flattened record for {}", file_path.display());
+        Ok(())
+    }
+    
+    /// Applies `change` to the shared index and schedules a write of the
+    /// flattened file, unless one is already scheduled.
+    async fn update_flattened_index(&self, change: impl FnOnce(&mut HashMap<String, FlattenedRecord>)) -> Result<()> {
+        let shared = self.flattened_index();
+        let mut index = shared.lock().await;
         
-        let relative_path = file_path.strip_prefix(target_folder)
-            .map_err(|e| anyhow!("Failed to get relative path: {}", e))?
-            .to_string_lossy()
-            .to_string();
+        if index.records.is_none() {
+            let records = self.read_flattened_file(&mut index).await?;
+            index.records = Some(records);
+        }
+        change(index.records.as_mut().unwrap());
+        index.dirty = true;
         
-        let flattened_record = FlattenedRecord {
-            relative_path: relative_path.clone(),
-            record: new_record.clone(),
-        };
+        if !index.flush_scheduled {
+            index.flush_scheduled = true;
+            // Writing only needs the folder and the format.
+            let writer = self.for_mirror(&self.output_folder);
+            let shared = shared.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(FLATTENED_FLUSH_DELAY).await;
+                let mut index = shared.lock().await;
+                index.flush_scheduled = false;
+                if index.dirty {
+                    if let Err(e) = writer.write_flattened_index(&mut index).await {
+                        warn!("Failed to write flattened records in {}: {}", writer.output_folder.display(), e);
+                    }
+                }
+            });
+        }
+        Ok(())
+    }
+    
+    /// Removes one file's record, here and in every mirror. Only a failure
+    /// in the output folder is an error, as in `save_record`.
+    pub async fn remove_record(&self, target_folder: &Path, file_path: &Path) -> Result<()> {
+        self.remove_record_here(target_folder, file_path).await?;
         
-        let existing_index = records.iter().position(|r| r.relative_path == relative_path);
+        for folder in &self.mirrors {
+            if let Err(e) = self.for_mirror(folder).remove_record_here(target_folder, file_path).await {
+                warn!("Failed to remove mirrored record for {} from {}: {}", file_path.display(), folder.display(), e);
+            }
+        }
+        Ok(())
+    }
+    
+    async fn remove_record_here(&self, target_folder: &Path, file_path: &Path) -> Result<()> {
+        let relative_path = Self::relative_path(target_folder, file_path)?;
+        match self.output_structure {
+            crate::config::OutputStructure::Flattened => {
+                self.update_flattened_index(|records| {
+                    records.remove(&relative_path);
+                }).await?;
+            }
+            crate::config::OutputStructure::Structured => {
+                self.remove_structured_record(target_folder, file_path).await?;
+            }
+            crate::config::OutputStructure::Sqlite => {
+                if SqliteStore::exists(&self.output_folder) {
+                    self.with_sqlite(move |store| store.remove(&relative_path)).await?;
+                }
+            }
+        }
+        debug!("Removed record for {}", file_path.display());
+        Ok(())
+    }
+    
+    /// Writes every record in one pass. Unlike repeated `save_record` calls,
+    /// the flattened file is only written once. Mirrors are written the same
+    /// way as in `save_record`.
+    pub async fn save_all_records(&self, target_folder: &Path, records: &HashMap<PathBuf, FileRecord>) -> Result<()> {
+        self.save_all_records_here(target_folder, records).await?;
         
-        if let Some(index) = existing_index {
-            records[index] = flattened_record;
-        } else {
-            records.push(flattened_record);
+        for folder in &self.mirrors {
+            let mirror = self.for_mirror(folder);
+            let result = match fs::create_dir_all(folder).await {
+                Ok(()) => mirror.save_all_records_here(target_folder, records).await,
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = result {
+                warn!("Failed to mirror records to {}: {}", folder.display(), e);
+            }
+        }
+        Ok(())
+    }
+    
+    async fn save_all_records_here(&self, target_folder: &Path, records: &HashMap<PathBuf, FileRecord>) -> Result<()> {
+        match self.output_structure {
+            crate::config::OutputStructure::Flattened => {
+                let mut flattened_records = HashMap::with_capacity(records.len());
+                for (file_path, record) in records {
+                    let relative_path = Self::relative_path(target_folder, file_path)?;
+                    flattened_records.insert(relative_path.clone(), FlattenedRecord {
+                        relative_path,
+                        record: record.clone(),
+                    });
+                }
+                
+                let index = self.flattened_index();
+                let mut index = index.lock().await;
+                index.records = Some(flattened_records);
+                self.write_flattened_index(&mut index).await?;
+            }
+            crate::config::OutputStructure::Structured => {
+                for (file_path, record) in records {
+                    self.save_structured_record(target_folder, file_path, record).await?;
+                }
+            }
+            crate::config::OutputStructure::Sqlite => {
+                let rows = records.iter()
+                    .map(|(file_path, record)| Ok((Self::relative_path(target_folder, file_path)?, record.clone())))
+                    .collect::<Result<Vec<_>>>()?;
+                self.with_sqlite(move |store| {
+                    store.upsert_all(rows.iter().map(|(relative_path, record)| (relative_path.clone(), record)))
+                }).await?;
+            }
         }
         
-        let content = serde_json::to_string_pretty(&records)
-            .map_err(|e| anyhow!("Failed to serialize flattened records: {}", e))?;
+        Ok(())
+    }
+    
+    /// Deletes the on-disk records for the given files in this structure.
+    pub async fn remove_all_records(&self, target_folder: &Path, records: &HashMap<PathBuf, FileRecord>) -> Result<()> {
+        match self.output_structure {
+            crate::config::OutputStructure::Flattened => {
+                let index = self.flattened_index();
+                let mut index = index.lock().await;
+                Self::remove_record_file(&self.output_folder.join(FLATTENED_FILE_NAME)).await?;
+                index.records = Some(HashMap::new());
+                index.dirty = false;
+            }
+            crate::config::OutputStructure::Structured => {
+                for file_path in records.keys() {
+                    self.remove_structured_record(target_folder, file_path).await?;
+                }
+            }
+            crate::config::OutputStructure::Sqlite => {
+                let relative_paths = records.keys()
+                    .map(|file_path| Self::relative_path(target_folder, file_path))
+                    .collect::<Result<Vec<_>>>()?;
+                self.with_sqlite(move |store| {
+                    relative_paths.iter().try_for_each(|relative_path| store.remove(relative_path))
+                }).await?;
+            }
+        }
         
-        fs::write(&flattened_file, content).await
-            .map_err(|e| anyhow!("Failed to write flattened records: {}", e))?;
+        Ok(())
+    }
+    
+    fn relative_path(target_folder: &Path, file_path: &Path) -> Result<String> {
+        Ok(file_path.strip_prefix(target_folder)
+            .map_err(|e| anyhow!("Failed to get relative path: {}", e))?
+            .to_string_lossy()
+            .to_string())
+    }
+    
+    fn compressed_path(path: &Path) -> PathBuf {
+        let mut compressed = path.as_os_str().to_owned();
+        compressed.push(".");
+        compressed.push(COMPRESSED_EXTENSION);
+        PathBuf::from(compressed)
+    }
+    
+    fn strip_compressed_extension(path: &Path) -> PathBuf {
+        if path.extension().is_some_and(|ext| ext == COMPRESSED_EXTENSION) {
+            path.with_extension("")
+        } else {
+            path.to_path_buf()
+        }
+    }
+    
+    async fn read_record_file(path: &Path) -> Result<String> {
+        let bytes = fs::read(path).await?;
+        if path.extension().is_some_and(|ext| ext == COMPRESSED_EXTENSION) {
+            let mut content = String::new();
+            GzDecoder::new(bytes.as_slice()).read_to_string(&mut content)?;
+            Ok(content)
+        } else {
+            Ok(String::from_utf8(bytes)?)
+        }
+    }
+    
+    /// Writes `content` to `path`, gzipped to `<path>.gz` when compression is
+    /// on, and removes the copy in the other format so it can't shadow this one.
+    async fn write_record_file(&self, path: &Path, content: String) -> Result<()> {
+        let compressed = Self::compressed_path(path);
+        let (target, stale) = if self.compress { (compressed, path.to_path_buf()) } else { (path.to_path_buf(), compressed) };
         
-        debug!("Saved flattened record for {}", file_path.display());
+        let bytes = if self.compress {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(content.as_bytes())?;
+            encoder.finish()?
+        } else {
+            content.into_bytes()
+        };
+        
+        // Written beside the target and renamed over it, so a crash mid-write
+        // leaves the previous version intact.
+        let mut temporary = target.as_os_str().to_owned();
+        temporary.push(".tmp");
+        let temporary = PathBuf::from(temporary);
+        fs::write(&temporary, bytes).await?;
+        fs::rename(&temporary, &target).await?;
+        if stale.exists() {
+            fs::remove_file(&stale).await?;
+        }
         Ok(())
     }
     
-    async fn save_structured_record(&self, target_folder: &Path, file_path: &Path, record: &FileRecord) -> Result<()> {
+    /// Removes both the plain and the compressed form of a record file.
+    async fn remove_record_file(path: &Path) -> Result<()> {
+        for candidate in [path.to_path_buf(), Self::compressed_path(path)] {
+            if candidate.exists() {
+                fs::remove_file(&candidate).await
+                    .map_err(|e| anyhow!("Failed to remove {}: {}", candidate.display(), e))?;
+            }
+        }
+        Ok(())
+    }
+    
+    /// Where a file's structured record lives, before any compression extension.
+    fn structured_record_path(&self, target_folder: &Path, file_path: &Path) -> Result<PathBuf> {
         let relative_path = file_path.strip_prefix(target_folder)
             .map_err(|e| anyhow!("Failed to get relative path: {}", e))?;
         
-        let output_path = self.output_folder.join(relative_path).with_extension("json");
+        let record_path = if self.shard {
+            Self::shard_prefix(relative_path).join(relative_path)
+        } else {
+            relative_path.to_path_buf()
+        };
+        Ok(self.output_folder.join(record_path).with_extension("json"))
+    }
+    
+    /// Where `file_path`'s structured record should be written: the first of
+    /// its record path and the `~N` suffixed paths beside it that is free or
+    /// already holds its record. Suffixes are kept contiguous, see
+    /// `remove_structured_record`, so the first free one ends the search.
+    /// With `RecordCollision::Error`, finding another file's record at the
+    /// record path is an error instead.
+    async fn structured_record_slot(&self, target_folder: &Path, file_path: &Path) -> Result<PathBuf> {
+        let base = self.structured_record_path(target_folder, file_path)?;
+        for suffix in 0.. {
+            let candidate = Self::collision_path(&base, suffix);
+            if self.is_reserved(&candidate) {
+                continue;
+            }
+            match Self::structured_record_owner(&candidate).await {
+                None => return Ok(candidate),
+                Some(owner) if Self::owns_record(target_folder, file_path, &owner) => return Ok(candidate),
+                Some(owner) if self.collision == crate::config::RecordCollision::Error => {
+                    return Err(anyhow!("Failed to save record for {}: {} holds the record of {}",
+                                       file_path.display(), candidate.display(), owner.display()));
+                }
+                Some(owner) => {
+                    debug!("{} holds the record of {}; trying the next name for {}",
+                           candidate.display(), owner.display(), file_path.display());
+                }
+            }
+        }
+        unreachable!("suffixes are unbounded")
+    }
+    
+    /// Removes `file_path`'s structured record, if it has one, moving the
+    /// record with the highest suffix into its place so that no gap is left
+    /// for a later search to stop at.
+    async fn remove_structured_record(&self, target_folder: &Path, file_path: &Path) -> Result<()> {
+        let base = self.structured_record_path(target_folder, file_path)?;
+        for suffix in 0.. {
+            let candidate = Self::collision_path(&base, suffix);
+            if self.is_reserved(&candidate) {
+                continue;
+            }
+            match Self::structured_record_owner(&candidate).await {
+                None => return Ok(()),
+                Some(owner) if Self::owns_record(target_folder, file_path, &owner) => {
+                    Self::remove_record_file(&candidate).await?;
+                    let mut last = suffix;
+                    while Self::structured_record_exists(&Self::collision_path(&base, last + 1)) {
+                        last += 1;
+                    }
+                    if last > suffix {
+                        let from = Self::collision_path(&base, last);
+                        for (from, to) in [(from.clone(), candidate.clone()), (Self::compressed_path(&from), Self::compressed_path(&candidate))] {
+                            if from.exists() {
+                                fs::rename(&from, &to).await
+                                    .map_err(|e| anyhow!("Failed to move record {} to {}: {}", from.display(), to.display(), e))?;
+                            }
+                        }
+                    }
+                    return Ok(());
+                }
+                Some(_) => {}
+            }
+        }
+        unreachable!("suffixes are unbounded")
+    }
+    
+    /// Whether `record_path` is one of `RESERVED_OUTPUT_FILES`.
+    fn is_reserved(&self, record_path: &Path) -> bool {
+        RESERVED_OUTPUT_FILES.iter().any(|name| record_path == self.output_folder.join(name))
+    }
+    
+    fn structured_record_exists(record_path: &Path) -> bool {
+        record_path.exists() || Self::compressed_path(record_path).exists()
+    }
+    
+    /// The file whose record is stored at `record_path`, in either format.
+    /// `None` if there is no record there; an empty path if it can't be read,
+    /// so that it is overwritten as before collisions were checked.
+    async fn structured_record_owner(record_path: &Path) -> Option<PathBuf> {
+        if !Self::structured_record_exists(record_path) {
+            return None;
+        }
+        let owner = Self::read_structured_record(record_path).await
+            .map(|record| record.file_path)
+            .unwrap_or_default();
+        Some(owner)
+    }
+    
+    /// The record stored at `record_path`, in either format, if it can be read.
+    async fn read_structured_record(record_path: &Path) -> Option<FileRecord> {
+        let path = [record_path.to_path_buf(), Self::compressed_path(record_path)]
+            .into_iter()
+            .find(|candidate| candidate.exists())?;
+        Self::read_record_file(&path).await.ok()
+            .and_then(|content| serde_json::from_str::<FileRecord>(&content).ok())
+    }
+    
+    /// Whether a record stored for `owner` may be taken as `file_path`'s. A
+    /// record whose path isn't under the target folder, such as one written
+    /// before the target folder was spelled differently, can't be told apart
+    /// and is taken as the same file's.
+    fn owns_record(target_folder: &Path, file_path: &Path, owner: &Path) -> bool {
+        owner == file_path || !owner.starts_with(target_folder)
+    }
+    
+    /// `dir/name.json` for 0, otherwise `dir/name~<suffix>.json`.
+    fn collision_path(base: &Path, suffix: usize) -> PathBuf {
+        if suffix == 0 {
+            return base.to_path_buf();
+        }
+        let stem = base.file_stem().unwrap_or_default().to_string_lossy();
+        base.with_file_name(format!("{}~{}.json", stem, suffix))
+    }
+    
+    /// Whether `path` is one of the suffixed paths `collision_path` gives for
+    /// `base`.
+    fn is_collision_path(path: &Path, base: &Path) -> bool {
+        let (Some(stem), Some(base_stem)) = (path.file_stem(), base.file_stem()) else {
+            return false;
+        };
+        path.parent() == base.parent()
+            && path.extension() == base.extension()
+            && stem.to_string_lossy()
+                .strip_prefix(&format!("{}~", base_stem.to_string_lossy()))
+                .and_then(|suffix| suffix.parse::<usize>().ok())
+                .is_some_and(|suffix| suffix > 0)
+    }
+    
+    /// `ab/cd` from the first bytes of the SHA-256 of the relative path.
+    fn shard_prefix(relative_path: &Path) -> PathBuf {
+        let digest = Sha256::digest(relative_path.to_string_lossy().as_bytes());
+        digest.iter()
+            .take(SHARD_LEVELS)
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+    
+    async fn save_structured_record(&self, target_folder: &Path, file_path: &Path, record: &FileRecord) -> Result<()> {
+        let output_path = self.structured_record_slot(target_folder, file_path).await?;
         
         if let Some(parent) = output_path.parent() {
             fs::create_dir_all(parent).await
@@ -178,7 +1130,7 @@ impl StorageManager {
         let content = serde_json::to_string_pretty(record)
             .map_err(|e| anyhow!("Failed to serialize record: {}", e))?;
         
-        fs::write(&output_path, content).await
+        self.write_record_file(&output_path, content).await
             .map_err(|e| anyhow!("Failed to write record to {}: {}", output_path.display(), e))?;
         
         debug!("Saved structured record for {} to {}", file_path.display(), output_path.display());
@@ -186,6 +1138,11 @@ impl StorageManager {
     }
     
     fn output_path_to_original_path(&self, output_path: &Path, target_folder: &Path) -> Result<PathBuf> {
+        let output_path: PathBuf = if self.shard {
+            output_path.components().skip(SHARD_LEVELS).collect()
+        } else {
+            output_path.to_path_buf()
+        };
         let without_extension = output_path.with_extension("");
         Ok(target_folder.join(without_extension))
     }
@@ -202,41 +1159,138 @@ impl StorageManager {
             codex_endpoint: None,
             status: FileStatus::New,
             error: None,
+            file_size: None,
+            file_modified: None,
+            content_hash: None,
+            pending_since: None,
+            expiry_minutes: None,
+            history: Vec::new(),
+            filled_slots: None,
+            upload_filename: None,
+            last_verified_at: None,
+            storage_params: None,
+            pinned: false,
+            uploaded_at: None,
+            purchased_at: None,
+            active_at: None,
+            extra: serde_json::Map::new(),
+        }
+    }
+    
+    /// Logs the move from `old_status` to the record's current status to the
+    /// event log, if one is configured and the status changed.
+    fn log_transition(&self, record: &FileRecord, old_status: &FileStatus) {
+        if let Some(event_log) = &self.event_log {
+            if *old_status != record.status {
+                event_log.record_transition(record, old_status);
+            }
         }
     }
     
     pub fn update_record_status(&self, record: &mut FileRecord, status: FileStatus, error: Option<String>) {
-        record.status = status;
+        let old_status = std::mem::replace(&mut record.status, status);
         record.error = error;
         record.updated_at = Utc::now();
+        self.log_transition(record, &old_status);
     }
     
     pub fn update_record_upload(&self, record: &mut FileRecord, cid: String, endpoint: String) {
+        let now = Utc::now();
         record.original_cid = Some(cid);
         record.codex_endpoint = Some(endpoint);
-        record.status = FileStatus::Uploading;
-        record.updated_at = Utc::now();
+        record.uploaded_at = Some(now);
+        record.purchased_at = None;
+        record.active_at = None;
+        let old_status = std::mem::replace(&mut record.status, FileStatus::Uploading);
+        record.updated_at = now;
+        self.log_transition(record, &old_status);
     }
     
     pub fn update_record_purchase(&self, record: &mut FileRecord, purchase_id: String, storage_cid: String) {
         record.purchase_id = Some(purchase_id);
         record.storage_cid = Some(storage_cid);
-        record.status = FileStatus::Creating;
+        let now = Utc::now();
+        record.pending_since = None;
+        record.purchased_at = Some(now);
+        record.active_at = None;
+        let old_status = std::mem::replace(&mut record.status, FileStatus::Creating);
+        record.updated_at = now;
+        self.log_transition(record, &old_status);
+    }
+    
+    pub fn update_record_fingerprint(&self, record: &mut FileRecord, fingerprint: &crate::fingerprint::FileFingerprint) {
+        record.file_size = Some(fingerprint.size);
+        record.file_modified = Some(fingerprint.modified);
+        record.content_hash = fingerprint.content_hash.clone();
         record.updated_at = Utc::now();
     }
     
+    /// Moves the record's CIDs and purchase into its history and resets it to
+    /// `New`, so the file is stored again from scratch.
+    pub fn archive_record(&self, record: &mut FileRecord, reason: &str) {
+        let now = Utc::now();
+        record.history.push(HistoryEntry {
+            original_cid: record.original_cid.take(),
+            storage_cid: record.storage_cid.take(),
+            purchase_id: record.purchase_id.take(),
+            status: record.status.clone(),
+            archived_at: now,
+            reason: reason.to_string(),
+        });
+        let old_status = std::mem::replace(&mut record.status, FileStatus::New);
+        record.error = None;
+        record.pending_since = None;
+        record.expiry_minutes = None;
+        record.filled_slots = None;
+        record.storage_params = None;
+        record.uploaded_at = None;
+        record.purchased_at = None;
+        record.active_at = None;
+        record.updated_at = now;
+        self.log_transition(record, &old_status);
+    }
+    
+    /// Points `record` at the purchase `source` tracks, for paths whose
+    /// contents share one CID and one purchase. A purchase `record` was
+    /// tracking before is archived first.
+    pub fn share_purchase(&self, record: &mut FileRecord, source: &FileRecord) {
+        if record.purchase_id.is_some() && record.purchase_id != source.purchase_id {
+            self.archive_record(record, &format!("Shared purchase replaced via {}", source.file_path.display()));
+        }
+        record.original_cid = source.original_cid.clone();
+        record.storage_cid = source.storage_cid.clone();
+        record.purchase_id = source.purchase_id.clone();
+        record.codex_endpoint = source.codex_endpoint.clone();
+        record.created_at = source.created_at;
+        let old_status = std::mem::replace(&mut record.status, source.status.clone());
+        record.error = source.error.clone();
+        record.pending_since = source.pending_since;
+        record.expiry_minutes = source.expiry_minutes;
+        record.filled_slots = source.filled_slots;
+        record.storage_params = source.storage_params.clone();
+        record.uploaded_at = source.uploaded_at;
+        record.purchased_at = source.purchased_at;
+        record.active_at = source.active_at;
+        record.updated_at = Utc::now();
+        self.log_transition(record, &old_status);
+    }
+    
     pub fn mark_record_active(&self, record: &mut FileRecord) {
-        record.status = FileStatus::Active;
+        let old_status = std::mem::replace(&mut record.status, FileStatus::Active);
+        // A degraded purchase becoming retrievable again is still the same one.
+        if !matches!(old_status, FileStatus::Active | FileStatus::Degraded) {
+            record.active_at = Some(Utc::now());
+        }
+        record.pending_since = None;
         record.updated_at = Utc::now();
+        self.log_transition(record, &old_status);
     }
     
     pub fn needs_new_purchase(&self, record: &FileRecord, expiry_buffer: chrono::Duration) -> bool {
         match record.status {
             FileStatus::Failed | FileStatus::Expired => true,
-            FileStatus::Active => {
-                let time_until_expiry = record.created_at + chrono::Duration::days(6) - Utc::now();
-                time_until_expiry < expiry_buffer
-            }
+            FileStatus::Active | FileStatus::Degraded => record.expires_at(self.duration)
+                .is_some_and(|expires_at| expires_at - Utc::now() < expiry_buffer),
             _ => false
         }
     }
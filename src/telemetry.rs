@@ -0,0 +1,190 @@
+//! Optional OpenTelemetry export of pipeline spans and upload/purchase
+//! counters. Compiled in with the `otlp` cargo feature; without it every
+//! function here is a no-op.
+
+use anyhow::Result;
+use std::future::Future;
+
+use crate::config::Config;
+
+/// Keeps the exporters alive; call `shutdown` before exiting so buffered
+/// spans and metrics are flushed.
+#[derive(Default)]
+pub struct Telemetry {
+    #[cfg(feature = "otlp")]
+    providers: Option<otlp::Providers>,
+}
+
+impl Telemetry {
+    pub fn init(config: &Config) -> Result<Self> {
+        #[cfg(feature = "otlp")]
+        {
+            let providers = match &config.otlp_endpoint {
+                Some(endpoint) => Some(otlp::Providers::install(endpoint)?),
+                None => None,
+            };
+            Ok(Self { providers })
+        }
+
+        #[cfg(not(feature = "otlp"))]
+        {
+            let _ = config;
+            Ok(Self::default())
+        }
+    }
+
+    pub fn shutdown(self) {
+        #[cfg(feature = "otlp")]
+        if let Some(providers) = self.providers {
+            providers.shutdown();
+        }
+    }
+}
+
+/// Runs `future` inside a span named `name`, marking the span as errored if
+/// the future fails.
+pub async fn traced<T, F>(name: &'static str, attributes: Vec<(&'static str, String)>, future: F) -> Result<T>
+where
+    F: Future<Output = Result<T>>,
+{
+    #[cfg(feature = "otlp")]
+    {
+        otlp::traced(name, attributes, future).await
+    }
+
+    #[cfg(not(feature = "otlp"))]
+    {
+        let _ = (name, attributes);
+        future.await
+    }
+}
+
+pub fn record_upload(bytes: u64) {
+    #[cfg(feature = "otlp")]
+    {
+        let instruments = otlp::instruments();
+        instruments.uploads.add(1, &[]);
+        instruments.uploaded_bytes.add(bytes, &[]);
+    }
+
+    #[cfg(not(feature = "otlp"))]
+    let _ = bytes;
+}
+
+pub fn record_storage_request() {
+    #[cfg(feature = "otlp")]
+    otlp::instruments().storage_requests.add(1, &[]);
+}
+
+pub fn record_failure(stage: &'static str) {
+    #[cfg(feature = "otlp")]
+    otlp::instruments().failures.add(1, &[opentelemetry::KeyValue::new("stage", stage)]);
+
+    #[cfg(not(feature = "otlp"))]
+    let _ = stage;
+}
+
+#[cfg(feature = "otlp")]
+mod otlp {
+    use anyhow::{anyhow, Result};
+    use log::warn;
+    use opentelemetry::metrics::Counter;
+    use opentelemetry::trace::{FutureExt, Status, TraceContextExt, Tracer};
+    use opentelemetry::{global, Context, KeyValue};
+    use opentelemetry_otlp::{MetricExporter, SpanExporter, WithExportConfig};
+    use opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider};
+    use opentelemetry_sdk::trace::TracerProvider;
+    use opentelemetry_sdk::{runtime, Resource};
+    use std::future::Future;
+    use std::sync::OnceLock;
+
+    const SERVICE_NAME: &str = "codex-filehog";
+
+    pub struct Providers {
+        tracer: TracerProvider,
+        meter: SdkMeterProvider,
+    }
+
+    impl Providers {
+        pub fn install(endpoint: &str) -> Result<Self> {
+            let endpoint = endpoint.trim_end_matches('/');
+            let resource = Resource::new([KeyValue::new("service.name", SERVICE_NAME)]);
+
+            let span_exporter = SpanExporter::builder()
+                .with_http()
+                .with_endpoint(format!("{}/v1/traces", endpoint))
+                .build()
+                .map_err(|e| anyhow!("Failed to create OTLP span exporter for {}: {}", endpoint, e))?;
+            let tracer = TracerProvider::builder()
+                .with_batch_exporter(span_exporter, runtime::Tokio)
+                .with_resource(resource.clone())
+                .build();
+
+            let metric_exporter = MetricExporter::builder()
+                .with_http()
+                .with_endpoint(format!("{}/v1/metrics", endpoint))
+                .build()
+                .map_err(|e| anyhow!("Failed to create OTLP metric exporter for {}: {}", endpoint, e))?;
+            let meter = SdkMeterProvider::builder()
+                .with_reader(PeriodicReader::builder(metric_exporter, runtime::Tokio).build())
+                .with_resource(resource)
+                .build();
+
+            global::set_tracer_provider(tracer.clone());
+            global::set_meter_provider(meter.clone());
+
+            Ok(Self { tracer, meter })
+        }
+
+        pub fn shutdown(self) {
+            if let Err(e) = self.tracer.shutdown() {
+                warn!("Failed to flush OTLP spans: {}", e);
+            }
+            if let Err(e) = self.meter.shutdown() {
+                warn!("Failed to flush OTLP metrics: {}", e);
+            }
+        }
+    }
+
+    pub struct Instruments {
+        pub uploads: Counter<u64>,
+        pub uploaded_bytes: Counter<u64>,
+        pub storage_requests: Counter<u64>,
+        pub failures: Counter<u64>,
+    }
+
+    /// Created on first use, which must come after `Providers::install` for
+    /// the counters to be exported.
+    pub fn instruments() -> &'static Instruments {
+        static INSTRUMENTS: OnceLock<Instruments> = OnceLock::new();
+        INSTRUMENTS.get_or_init(|| {
+            let meter = global::meter(SERVICE_NAME);
+            Instruments {
+                uploads: meter.u64_counter("filehog.uploads").build(),
+                uploaded_bytes: meter.u64_counter("filehog.uploaded_bytes").with_unit("By").build(),
+                storage_requests: meter.u64_counter("filehog.storage_requests").build(),
+                failures: meter.u64_counter("filehog.failures").build(),
+            }
+        })
+    }
+
+    pub async fn traced<T, F>(name: &'static str, attributes: Vec<(&'static str, String)>, future: F) -> Result<T>
+    where
+        F: Future<Output = Result<T>>,
+    {
+        let tracer = global::tracer(SERVICE_NAME);
+        let span = tracer
+            .span_builder(name)
+            .with_attributes(attributes.into_iter().map(|(key, value)| KeyValue::new(key, value)))
+            .start(&tracer);
+        let cx = Context::current_with_span(span);
+
+        let result = future.with_context(cx.clone()).await;
+        let span = cx.span();
+        if let Err(e) = &result {
+            span.set_status(Status::error(e.to_string()));
+        }
+        span.end();
+        result
+    }
+}
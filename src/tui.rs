@@ -0,0 +1,275 @@
+//! Live terminal dashboard for `--tui`, drawn from the same in-memory
+//! records and counters the monitor works with. Compiled in with the `tui`
+//! cargo feature.
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::widgets::{Block, Borders, Paragraph, Row, Table};
+use ratatui::Frame;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::OpenOptions;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+use tokio_util::sync::CancellationToken;
+
+use crate::codex::Client as CodexClient;
+use crate::config::Config;
+use crate::file_processor::FileProcessor;
+use crate::storage::{FileRecord, FileStatus};
+use crate::usage::UsageTracker;
+
+/// Where logs go in the output folder while the dashboard owns the terminal.
+pub const LOG_FILE_NAME: &str = "filehog.log";
+
+/// How often the dashboard is redrawn.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Upload throughput is averaged over this much time.
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(10);
+
+/// Sends log output to `LOG_FILE_NAME` in `output_folder`, appending, with
+/// the same `RUST_LOG` filtering as logging to the terminal.
+pub fn log_to_file(output_folder: &Path) -> Result<PathBuf> {
+    std::fs::create_dir_all(output_folder)
+        .map_err(|e| anyhow!("Failed to create output folder {}: {}", output_folder.display(), e))?;
+    let path = output_folder.join(LOG_FILE_NAME);
+    let file = OpenOptions::new().create(true).append(true).open(&path)
+        .map_err(|e| anyhow!("Failed to open log file {}: {}", path.display(), e))?;
+    env_logger::Builder::from_default_env()
+        .target(env_logger::Target::Pipe(Box::new(file)))
+        .init();
+    Ok(path)
+}
+
+/// One row of the recent files table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecentFile {
+    pub relative_path: String,
+    pub status: FileStatus,
+    pub updated_at: DateTime<Utc>,
+    pub cid: Option<String>,
+}
+
+/// Everything one frame of the dashboard shows.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DashboardView {
+    pub total: usize,
+    /// Record count per status, keyed by the status name.
+    pub counts: BTreeMap<String, usize>,
+    /// Most recently updated first.
+    pub recent: Vec<RecentFile>,
+    /// Each configured endpoint and whether it is healthy.
+    pub endpoints: Vec<(String, bool)>,
+    pub bytes_uploaded: u64,
+    pub bytes_per_sec: f64,
+}
+
+impl DashboardView {
+    /// The view of `records`, listing at most `recent` of them.
+    pub fn from_records(records: &HashMap<PathBuf, FileRecord>, target_folder: &Path, recent: usize) -> Self {
+        let mut counts = BTreeMap::new();
+        for record in records.values() {
+            *counts.entry(format!("{:?}", record.status)).or_default() += 1;
+        }
+
+        let mut newest: Vec<(&PathBuf, &FileRecord)> = records.iter().collect();
+        newest.sort_by(|(a_path, a), (b_path, b)| b.updated_at.cmp(&a.updated_at).then_with(|| a_path.cmp(b_path)));
+        let recent = newest.into_iter()
+            .take(recent)
+            .map(|(path, record)| RecentFile {
+                relative_path: path.strip_prefix(target_folder).unwrap_or(path).display().to_string(),
+                status: record.status.clone(),
+                updated_at: record.updated_at,
+                cid: record.original_cid.clone(),
+            })
+            .collect();
+
+        Self {
+            total: records.len(),
+            counts,
+            recent,
+            endpoints: Vec::new(),
+            bytes_uploaded: 0,
+            bytes_per_sec: 0.0,
+        }
+    }
+}
+
+/// Draws `view` over the whole frame.
+pub fn render(frame: &mut Frame, view: &DashboardView, tz: chrono_tz::Tz) {
+    let [summary_area, files_area, help_area] = Layout::vertical([
+        Constraint::Length(5),
+        Constraint::Min(3),
+        Constraint::Length(1),
+    ]).areas(frame.area());
+    let [counts_area, endpoints_area, throughput_area] = Layout::horizontal([
+        Constraint::Percentage(40),
+        Constraint::Percentage(35),
+        Constraint::Percentage(25),
+    ]).areas(summary_area);
+
+    let counts = view.counts.iter()
+        .map(|(status, count)| format!("{} {}", status, count))
+        .collect::<Vec<_>>()
+        .join("  ");
+    frame.render_widget(
+        Paragraph::new(format!("{} files\n{}", view.total, counts))
+            .wrap(ratatui::widgets::Wrap { trim: true })
+            .block(Block::default().borders(Borders::ALL).title("Files")),
+        counts_area,
+    );
+
+    let endpoints: Vec<ratatui::text::Line> = view.endpoints.iter()
+        .map(|(endpoint, healthy)| {
+            let (label, color) = if *healthy { ("up", Color::Green) } else { ("down", Color::Red) };
+            ratatui::text::Line::from(vec![
+                ratatui::text::Span::styled(format!("{:<5}", label), Style::default().fg(color)),
+                ratatui::text::Span::raw(endpoint.clone()),
+            ])
+        })
+        .collect();
+    frame.render_widget(
+        Paragraph::new(endpoints).block(Block::default().borders(Borders::ALL).title("Endpoints")),
+        endpoints_area,
+    );
+
+    frame.render_widget(
+        Paragraph::new(format!("{}/s\n{} uploaded",
+                               format_bytes(view.bytes_per_sec as u64), format_bytes(view.bytes_uploaded)))
+            .block(Block::default().borders(Borders::ALL).title("Throughput")),
+        throughput_area,
+    );
+
+    let rows = view.recent.iter().map(|file| Row::new(vec![
+        file.updated_at.with_timezone(&tz).format("%H:%M:%S").to_string(),
+        format!("{:?}", file.status),
+        file.relative_path.clone(),
+        file.cid.clone().unwrap_or_default(),
+    ]).style(Style::default().fg(status_color(&file.status))));
+    let table = Table::new(rows, [
+        Constraint::Length(8),
+        Constraint::Length(12),
+        Constraint::Percentage(60),
+        Constraint::Percentage(40),
+    ])
+        .header(Row::new(vec!["Updated", "Status", "File", "CID"]).style(Style::default().add_modifier(Modifier::BOLD)))
+        .block(Block::default().borders(Borders::ALL).title("Recent files"));
+    frame.render_widget(table, files_area);
+
+    frame.render_widget(Paragraph::new("q: stop FileHog"), help_area);
+}
+
+fn status_color(status: &FileStatus) -> Color {
+    match status {
+        FileStatus::Active => Color::Green,
+        FileStatus::Failed | FileStatus::Expired => Color::Red,
+        FileStatus::Pending | FileStatus::WaitingLong | FileStatus::Degraded => Color::Yellow,
+        _ => Color::Reset,
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// The dashboard for one monitor run. Quitting it requests shutdown, and it
+/// closes once shutdown has been requested by any other means.
+pub struct Dashboard {
+    config: Arc<Config>,
+    records: Arc<RwLock<HashMap<PathBuf, FileRecord>>>,
+    usage: Arc<UsageTracker>,
+    codex_client: Arc<CodexClient>,
+    shutdown: CancellationToken,
+}
+
+impl Dashboard {
+    pub fn new(file_processor: &FileProcessor) -> Self {
+        Self {
+            config: file_processor.config.clone(),
+            records: file_processor.records.clone(),
+            usage: file_processor.usage.clone(),
+            codex_client: file_processor.codex_client.clone(),
+            shutdown: file_processor.shutdown.clone(),
+        }
+    }
+
+    /// Runs the dashboard on a blocking thread until shutdown, restoring the
+    /// terminal afterwards.
+    pub fn spawn(self) -> tokio::task::JoinHandle<Result<()>> {
+        tokio::task::spawn_blocking(move || {
+            let mut terminal = ratatui::init();
+            let result = self.run(&mut terminal);
+            ratatui::restore();
+            result
+        })
+    }
+
+    fn run(&self, terminal: &mut ratatui::DefaultTerminal) -> Result<()> {
+        let tz = self.config.display_tz();
+        let mut samples: VecDeque<(Instant, u64)> = VecDeque::new();
+        let mut next_draw = Instant::now();
+
+        while !self.shutdown.is_cancelled() {
+            if Instant::now() >= next_draw {
+                let view = self.view(terminal.size()?.height, &mut samples);
+                terminal.draw(|frame| render(frame, &view, tz))
+                    .map_err(|e| anyhow!("Failed to draw dashboard: {}", e))?;
+                next_draw = Instant::now() + REFRESH_INTERVAL;
+            }
+
+            // Raw mode turns Ctrl+C into a key press instead of a signal.
+            if event::poll(Duration::from_millis(200))? {
+                if let Event::Key(key) = event::read()? {
+                    let ctrl_c = key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+                    if key.kind == KeyEventKind::Press && (key.code == KeyCode::Char('q') || ctrl_c) {
+                        log::info!("Dashboard closed, finishing files in progress");
+                        self.shutdown.cancel();
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The current view, sized to fill a terminal `height` rows tall.
+    fn view(&self, height: u16, samples: &mut VecDeque<(Instant, u64)>) -> DashboardView {
+        // Everything but the recent files table, its borders and header.
+        let recent = usize::from(height.saturating_sub(9));
+        let mut view = DashboardView::from_records(&self.records.blocking_read(), &self.config.target_folder, recent);
+
+        let healthy = self.codex_client.healthy_endpoints();
+        view.endpoints = self.codex_client.endpoints().iter()
+            .map(|endpoint| (endpoint.clone(), healthy.contains(endpoint)))
+            .collect();
+
+        let now = Instant::now();
+        view.bytes_uploaded = self.usage.session_bytes_uploaded();
+        samples.push_back((now, view.bytes_uploaded));
+        while samples.front().is_some_and(|(at, _)| now.duration_since(*at) > THROUGHPUT_WINDOW) {
+            samples.pop_front();
+        }
+        if let Some((oldest_at, oldest_bytes)) = samples.front() {
+            let elapsed = now.duration_since(*oldest_at).as_secs_f64();
+            if elapsed > 0.0 {
+                view.bytes_per_sec = (view.bytes_uploaded - oldest_bytes) as f64 / elapsed;
+            }
+        }
+        view
+    }
+}
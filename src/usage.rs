@@ -0,0 +1,129 @@
+use anyhow::{anyhow, Result};
+use chrono::{Datelike, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::fs;
+use tokio::sync::Mutex;
+
+pub const USAGE_FILE_NAME: &str = "usage.json";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct DailyUsage {
+    pub bytes_uploaded: u64,
+    pub uploads: u64,
+    pub storage_requests: u64,
+}
+
+/// Per-day upload totals, persisted so they survive restarts.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UsageLedger {
+    pub days: BTreeMap<NaiveDate, DailyUsage>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UsagePeriod {
+    Day,
+    Month,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UsageRow {
+    pub period: String,
+    pub usage: DailyUsage,
+}
+
+impl UsageLedger {
+    pub async fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let content = fs::read_to_string(path).await
+            .map_err(|e| anyhow!("Failed to read usage file {}: {}", path.display(), e))?;
+        serde_json::from_str(&content)
+            .map_err(|e| anyhow!("Failed to parse usage file {}: {}", path.display(), e))
+    }
+
+    /// Written beside `path` and renamed over it, so a crash mid-write
+    /// leaves the previous totals intact.
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        let content = serde_json::to_string_pretty(self)
+            .map_err(|e| anyhow!("Failed to serialize usage: {}", e))?;
+        let temp_path = path.with_extension("json.tmp");
+        fs::write(&temp_path, content).await
+            .map_err(|e| anyhow!("Failed to write usage file {}: {}", temp_path.display(), e))?;
+        fs::rename(&temp_path, path).await
+            .map_err(|e| anyhow!("Failed to replace usage file {}: {}", path.display(), e))
+    }
+
+    pub fn add(&mut self, date: NaiveDate, usage: DailyUsage) {
+        let day = self.days.entry(date).or_default();
+        day.bytes_uploaded += usage.bytes_uploaded;
+        day.uploads += usage.uploads;
+        day.storage_requests += usage.storage_requests;
+    }
+
+    /// Totals per day or month, oldest first, starting at `since` if given.
+    pub fn report(&self, since: Option<NaiveDate>, period: UsagePeriod) -> Vec<UsageRow> {
+        let mut rows: BTreeMap<String, DailyUsage> = BTreeMap::new();
+
+        for (date, usage) in &self.days {
+            if since.is_some_and(|since| *date < since) {
+                continue;
+            }
+
+            let key = match period {
+                UsagePeriod::Day => date.format("%Y-%m-%d").to_string(),
+                UsagePeriod::Month => format!("{:04}-{:02}", date.year(), date.month()),
+            };
+            let row = rows.entry(key).or_default();
+            row.bytes_uploaded += usage.bytes_uploaded;
+            row.uploads += usage.uploads;
+            row.storage_requests += usage.storage_requests;
+        }
+
+        rows.into_iter()
+            .map(|(period, usage)| UsageRow { period, usage })
+            .collect()
+    }
+}
+
+/// Appends usage to the ledger in the output folder as it happens.
+pub struct UsageTracker {
+    path: PathBuf,
+    lock: Mutex<()>,
+    /// Bytes uploaded since this tracker was created, ledger or not.
+    session_bytes_uploaded: AtomicU64,
+}
+
+impl UsageTracker {
+    pub fn new(output_folder: &Path) -> Self {
+        Self {
+            path: output_folder.join(USAGE_FILE_NAME),
+            lock: Mutex::new(()),
+            session_bytes_uploaded: AtomicU64::new(0),
+        }
+    }
+
+    pub fn session_bytes_uploaded(&self) -> u64 {
+        self.session_bytes_uploaded.load(Ordering::Relaxed)
+    }
+
+    pub async fn record_upload(&self, bytes: u64) -> Result<()> {
+        self.session_bytes_uploaded.fetch_add(bytes, Ordering::Relaxed);
+        self.record(DailyUsage { bytes_uploaded: bytes, uploads: 1, storage_requests: 0 }).await
+    }
+
+    pub async fn record_storage_request(&self) -> Result<()> {
+        self.record(DailyUsage { bytes_uploaded: 0, uploads: 0, storage_requests: 1 }).await
+    }
+
+    async fn record(&self, usage: DailyUsage) -> Result<()> {
+        let _guard = self.lock.lock().await;
+        let mut ledger = UsageLedger::load(&self.path).await?;
+        ledger.add(Utc::now().date_naive(), usage);
+        ledger.save(&self.path).await
+    }
+}